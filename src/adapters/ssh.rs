@@ -1,10 +1,71 @@
 // Port target for trikeshed-channel-impl/.../SshProtocolAdapter.kt
 
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// RFC 4253 §4.2: identification string including CR LF is at most 255 bytes
+pub const MAX_BANNER_LEN: usize = 255;
+
 pub fn ssh_adapter_name() -> &'static str {
     // placeholder for russh-based adapter
     "ssh::SshProtocolAdapter"
 }
 
+/// Validate a pinned identification string such as `SSH-2.0-OpenSSH_8.9`.
+/// Trailing CR/LF is stripped; the returned banner is terminated with CR LF.
+pub fn pinned_banner(banner: &str) -> io::Result<String> {
+    let trimmed = banner.trim_end_matches(['\r', '\n']);
+    if !trimmed.starts_with("SSH-2.0-") || trimmed.len() == "SSH-2.0-".len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "SSH banner must start with SSH-2.0- and name a software version"));
+    }
+    if trimmed.len() + 2 > MAX_BANNER_LEN || trimmed.bytes().any(|b| !(0x20..0x7f).contains(&b)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "SSH banner must be printable ASCII of at most 253 bytes"));
+    }
+    Ok(format!("{}\r\n", trimmed))
+}
+
+/// Read the peer's identification line. Returns the line (with its terminator)
+/// and any bytes that arrived after it in the same read.
+async fn read_banner<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(MAX_BANNER_LEN);
+    let mut chunk = [0u8; MAX_BANNER_LEN];
+    loop {
+        if let Some(pos) = buf.iter().position(|&b| b == b'\n') {
+            let rest = buf.split_off(pos + 1);
+            return Ok((buf, rest));
+        }
+        if buf.len() >= MAX_BANNER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "SSH identification line too long"));
+        }
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before SSH banner"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Relay an SSH session, optionally replacing the client's identification
+/// string with `pinned` (see `pinned_banner`). Everything after the banner,
+/// including bytes pipelined behind it, is forwarded unchanged.
+pub async fn relay_ssh<C, U>(mut client: C, mut upstream: U, pinned: Option<&str>) -> io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    if let Some(banner) = pinned {
+        let banner = pinned_banner(banner)?;
+        let (original, rest) = read_banner(&mut client).await?;
+        if !original.starts_with(b"SSH-") {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "client did not send an SSH identification string"));
+        }
+        log::debug!("SSH banner {:?} pinned to {:?}", String::from_utf8_lossy(&original).trim_end(), banner.trim_end());
+        upstream.write_all(banner.as_bytes()).await?;
+        upstream.write_all(&rest).await?;
+    }
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -12,4 +73,34 @@ mod tests {
     fn ssh_name() {
         assert_eq!(ssh_adapter_name(), "ssh::SshProtocolAdapter");
     }
+
+    #[test]
+    fn test_pinned_banner_validation() {
+        assert_eq!(pinned_banner("SSH-2.0-OpenSSH_8.9").unwrap(), "SSH-2.0-OpenSSH_8.9\r\n");
+        assert!(pinned_banner("SSH-1.99-OpenSSH_8.9").is_err());
+        assert!(pinned_banner("SSH-2.0-").is_err());
+        assert!(pinned_banner(&format!("SSH-2.0-{}", "x".repeat(250))).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_relay_rewrites_client_banner() {
+        let (mut client, proxy_client) = tokio::io::duplex(1024);
+        let (proxy_upstream, mut server) = tokio::io::duplex(1024);
+        let relay = tokio::spawn(relay_ssh(proxy_client, proxy_upstream, Some("SSH-2.0-OpenSSH_8.9")));
+
+        client.write_all(b"SSH-2.0-PuTTY_Release_0.78\r\n\x00\x00\x01\x0ckexinit").await.unwrap();
+        let expected = b"SSH-2.0-OpenSSH_8.9\r\n\x00\x00\x01\x0ckexinit";
+        let mut seen = vec![0u8; expected.len()];
+        server.read_exact(&mut seen).await.unwrap();
+        assert_eq!(&seen[..], &expected[..]);
+
+        server.write_all(b"SSH-2.0-dropbear\r\n").await.unwrap();
+        let mut reply = [0u8; 18];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"SSH-2.0-dropbear\r\n");
+
+        drop(client);
+        drop(server);
+        relay.await.unwrap().unwrap();
+    }
 }
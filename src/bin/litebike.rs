@@ -5,7 +5,13 @@ use literbike::syscall_net::{
 	get_default_local_ipv6,
 	guess_default_v6_interface,
 	list_interfaces,
+	list_ipv4_networks,
+	list_ipv6_networks,
+	list_socket_fds,
+	read_proc_net_sockets,
+	socket_inode_owners,
 	InterfaceAddr,
+	ProcNetSocket,
 	find_iface_by_ipv4,
 	classify_ipv4,
 	classify_ipv6,
@@ -39,6 +45,7 @@ const WAM_DISPATCH_TABLE: &[(&str, CommandAction)] = &[
 	("route", run_route_cmd),
 	("netstat", run_netstat),
 	("ip", run_ip),
+	("lsof", run_lsof),
 	
 	// Proxy operations (high frequency)
 	("proxy-quick", run_proxy_quick),
@@ -80,6 +87,10 @@ const WAM_DISPATCH_TABLE: &[(&str, CommandAction)] = &[
 	("raw-connect", run_raw_connect),
 	("trust-host", run_trust_host),
 	("bootstrap", run_bootstrap),
+	("selftest", run_selftest),
+	("conn-log-dump", run_conn_log_dump),
+	("config", run_config),
+	("caps", run_caps),
 	
 	// Integrated proxy (combines all components)
 	("integrated", run_integrated),
@@ -212,57 +223,194 @@ mod tests {
 	let args: Vec<String> = vec!["nonexistent_iface".to_string()];
 	run_ifconfig(&args);
     }
+
+    #[test]
+    fn test_lsof_inet_filter() {
+        let row = literbike::syscall_net::parse_proc_net_line(
+            "   0: 00000000:22B8 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 4242 1 0 100 0 0 10 0",
+        )
+        .unwrap();
+        assert_eq!(lsof_name("tcp", &row), "*:8888 (LISTEN)");
+
+        let port = InetFilter::parse(":8888").unwrap();
+        assert_eq!(port, InetFilter { port: Some(8888), ..Default::default() });
+        assert!(port.matches("tcp", &row));
+        assert!(!InetFilter::parse(":8889").unwrap().matches("tcp", &row));
+        assert!(InetFilter::parse("tcp:8888").unwrap().matches("tcp", &row));
+        assert!(!InetFilter::parse("udp").unwrap().matches("tcp", &row));
+        assert!(!InetFilter::parse("6").unwrap().matches("tcp", &row));
+        assert_eq!(InetFilter::parse("4TCP@127.0.0.1:80").unwrap(), InetFilter {
+            family: Some(4),
+            protocol: Some("tcp".to_string()),
+            host: Some("127.0.0.1".parse().unwrap()),
+            port: Some(80),
+        });
+        assert_eq!(InetFilter::parse("@[::1]:53").unwrap().host, Some("::1".parse().unwrap()));
+        assert_eq!(InetFilter::parse("").unwrap(), InetFilter::default());
+        assert!(InetFilter::parse("sctp").is_none());
+        assert!(InetFilter::parse(":http").is_none());
+    }
+
+    #[test]
+    fn test_ip_route_lines_match_iproute2() {
+        use std::net::Ipv4Addr;
+        let networks = vec![
+            ("lo".to_string(), Ipv4Addr::LOCALHOST, Ipv4Addr::new(255, 0, 0, 0)),
+            ("wlan0".to_string(), Ipv4Addr::new(192, 168, 1, 5), Ipv4Addr::new(255, 255, 255, 0)),
+            ("rmnet0".to_string(), Ipv4Addr::new(10, 20, 30, 40), Ipv4Addr::new(255, 255, 255, 252)),
+        ];
+        assert_eq!(format_ip_routes(Some(Ipv4Addr::new(192, 168, 1, 1)), &networks), vec![
+            "default via 192.168.1.1 dev wlan0",
+            "192.168.1.0/24 dev wlan0 proto kernel scope link src 192.168.1.5",
+            "10.20.30.40/30 dev rmnet0 proto kernel scope link src 10.20.30.40",
+        ]);
+        assert_eq!(format_ip_routes(Some(Ipv4Addr::new(172, 16, 0, 1)), &[])[0], "default via 172.16.0.1");
+        assert_eq!(ip_link_flags(0x11043), "<BROADCAST,MULTICAST,UP,LOWER_UP>");
+    }
 }
 
 fn run_ip(args: &[String]) {
-	if args.is_empty() {
-		eprintln!("Usage: ip [addr|route] [-6]");
+	const USAGE: &str = "Usage: ip [-4|-6] { addr | route } [show [dev IFACE]]";
+	// Support both: ip -6 addr ... and ip addr -6 ...
+	let want_v4 = !args.iter().any(|a| a == "-6");
+	let want_v6 = args.iter().any(|a| a == "-6");
+	let only_v4 = args.iter().any(|a| a == "-4");
+	let words: Vec<&str> = args.iter().map(String::as_str).filter(|a| !a.starts_with('-')).collect();
+	let Some((&object, rest)) = words.split_first() else {
+		eprintln!("{}", USAGE);
 		return;
+	};
+	// "show" is the only action, so it may be left out
+	let rest = match rest {
+		["show" | "list" | "ls", rest @ ..] => rest,
+		rest => rest,
+	};
+	let dev = match rest {
+		["dev", name, ..] | [name, ..] => Some(*name),
+		[] => None,
+	};
+	match object {
+		"a" | "addr" | "address" => run_ip_addr(dev, want_v4, !only_v4),
+		"r" | "ro" | "route" => run_ip_route(want_v6),
+		_ => eprintln!("ip: unknown command '{}'\n{}", object, USAGE),
 	}
-	// Support both: ip -6 addr ... and ip addr -6 ...
-	let (want_v6, subcmd_idx) = if !args.is_empty() && args[0] == "-6" {
-		(true, 1usize)
-	} else {
-		(args.iter().any(|a| a == "-6"), 0usize)
+}
+
+/// iproute2-style `<...>` flag list for an interface
+fn ip_link_flags(flags: u32) -> String {
+	// IFF_LOWER_UP is Linux-only and missing from libc's portable set
+	const IFF_LOWER_UP: u32 = 0x10000;
+	let names = [
+		(libc::IFF_LOOPBACK as u32, "LOOPBACK"),
+		(libc::IFF_BROADCAST as u32, "BROADCAST"),
+		(libc::IFF_POINTOPOINT as u32, "POINTOPOINT"),
+		(libc::IFF_MULTICAST as u32, "MULTICAST"),
+		(libc::IFF_NOARP as u32, "NOARP"),
+		(libc::IFF_UP as u32, "UP"),
+		(IFF_LOWER_UP, "LOWER_UP"),
+	];
+	let set: Vec<&str> = names.iter().filter(|(bit, _)| flags & bit != 0).map(|(_, name)| *name).collect();
+	format!("<{}>", set.join(","))
+}
+
+fn ip_scope(loopback: bool, link_local: bool) -> &'static str {
+	if loopback { "host" } else if link_local { "link" } else { "global" }
+}
+
+fn run_ip_addr(dev: Option<&str>, want_v4: bool, want_v6: bool) {
+	let ifaces = match list_interfaces() {
+		Ok(ifaces) => ifaces,
+		Err(e) => {
+			eprintln!("ip addr: {}", e);
+			return;
+		}
 	};
-	if subcmd_idx >= args.len() { eprintln!("Usage: ip [addr|route] [-6]"); return; }
-	match args[subcmd_idx].as_str() {
-		"addr" | "address" => {
-			match list_interfaces() {
-				Ok(ifaces) => {
-					let mut idx = 1u32;
-					for (name, iface) in ifaces {
-						println!("{}: {}: <UP> mtu 1500", idx, name);
-						for addr in iface.addrs {
-							match addr {
-								InterfaceAddr::V4(ip) => if !want_v6 { println!("    inet {}/24", ip) },
-								InterfaceAddr::V6(ip) => if want_v6 { println!("    inet6 {}/64", ip) },
-								InterfaceAddr::Link(_) => {}
-							}
-						}
-						idx += 1;
-					}
+	let v4 = list_ipv4_networks().unwrap_or_default();
+	let v6 = list_ipv6_networks().unwrap_or_default();
+	let mut ifaces: Vec<_> = ifaces.into_values().filter(|i| dev.is_none_or(|d| i.name == d)).collect();
+	if ifaces.is_empty() {
+		if let Some(d) = dev {
+			eprintln!("Device \"{}\" does not exist.", d);
+		}
+		return;
+	}
+	ifaces.sort_by_key(|i| i.index);
+	for iface in ifaces {
+		let loopback = iface.flags & libc::IFF_LOOPBACK as u32 != 0;
+		let mtu = fs::read_to_string(format!("/sys/class/net/{}/mtu", iface.name))
+			.map(|m| format!(" mtu {}", m.trim()))
+			.unwrap_or_default();
+		println!("{}: {}: {}{}", iface.index, iface.name, ip_link_flags(iface.flags), mtu);
+		for addr in &iface.addrs {
+			match addr {
+				InterfaceAddr::Link(mac) if mac.len() == 6 => {
+					let mac = mac.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":");
+					println!("    link/{} {}", if loopback { "loopback" } else { "ether" }, mac);
+				}
+				InterfaceAddr::V4(ip) if want_v4 => {
+					let prefix = v4.iter().find(|(n, a, _)| *n == iface.name && a == ip).map_or(32, |(_, _, m)| u32::from(*m).count_ones());
+					println!("    inet {}/{} scope {} {}", ip, prefix, ip_scope(loopback, ip.is_link_local()), iface.name);
+				}
+				InterfaceAddr::V6(ip) if want_v6 => {
+					let prefix = v6.iter().find(|(n, a, _)| *n == iface.name && a == ip).map_or(128, |(_, _, m)| u128::from(*m).count_ones());
+					let link_local = ip.segments()[0] & 0xffc0 == 0xfe80;
+					println!("    inet6 {}/{} scope {}", ip, prefix, ip_scope(loopback, link_local));
 				}
-				Err(e) => eprintln!("ip addr: {}", e),
+				_ => {}
 			}
 		}
-		"route" => {
-			if want_v6 {
-				match get_default_gateway_v6() {
-					Ok(gw) => println!("default via {} dev -", gw),
-					Err(e) => {
-						eprintln!("ip -6 route: {}", e);
-						if let Ok(ip) = get_default_local_ipv6() {
-							let iface = guess_default_v6_interface().unwrap_or_else(|| "-".to_string());
-							println!("(hint) src {} dev {}", ip, iface);
-						}
-					}
+	}
+}
+
+/// `ip route` lines: the default route, then a connected route per
+/// non-loopback IPv4 network. The default route's device is the interface
+/// whose network holds the gateway.
+fn format_ip_routes(gateway: Option<std::net::Ipv4Addr>, networks: &[(String, std::net::Ipv4Addr, std::net::Ipv4Addr)]) -> Vec<String> {
+	let network_of = |addr: std::net::Ipv4Addr, mask: std::net::Ipv4Addr| u32::from(addr) & u32::from(mask);
+	let mut lines = Vec::new();
+	if let Some(gw) = gateway {
+		match networks.iter().find(|(_, addr, mask)| network_of(*addr, *mask) == network_of(gw, *mask)) {
+			Some((dev, _, _)) => lines.push(format!("default via {} dev {}", gw, dev)),
+			None => lines.push(format!("default via {}", gw)),
+		}
+	}
+	for (dev, addr, mask) in networks.iter().filter(|(_, addr, _)| !addr.is_loopback()) {
+		let net = std::net::Ipv4Addr::from(network_of(*addr, *mask));
+		let line = format!("{}/{} dev {} proto kernel scope link src {}", net, u32::from(*mask).count_ones(), dev, addr);
+		if !lines.contains(&line) {
+			lines.push(line);
+		}
+	}
+	lines
+}
+
+fn run_ip_route(want_v6: bool) {
+	if want_v6 {
+		match get_default_gateway_v6() {
+			Ok(gw) => {
+				let dev = guess_default_v6_interface().unwrap_or_else(|| "-".to_string());
+				println!("default via {} dev {}", gw, dev);
+			}
+			Err(e) => {
+				eprintln!("ip -6 route: {}", e);
+				if let Ok(ip) = get_default_local_ipv6() {
+					let iface = guess_default_v6_interface().unwrap_or_else(|| "-".to_string());
+					println!("(hint) src {} dev {}", ip, iface);
 				}
-			} else {
-				run_route(&[]);
 			}
-		},
-	_ => eprintln!("ip: unknown command '{}'", args[subcmd_idx]),
+		}
+		return;
+	}
+	let gateway = match get_default_gateway() {
+		Ok(gw) => Some(gw),
+		Err(e) => {
+			eprintln!("ip route: {}", e);
+			None
+		}
+	};
+	let networks = list_ipv4_networks().unwrap_or_default();
+	for line in format_ip_routes(gateway, &networks) {
+		println!("{}", line);
 	}
 }
 
@@ -285,27 +433,21 @@ fn run_route(_args: &[String]) {
 }
 
 fn run_netstat(args: &[String]) {
-	// Flags: -a (all), -t (tcp), -u (udp), -l (listening), -n (numeric)
-	let mut show_tcp = true;
-	let mut show_udp = true;
-	let mut listening_only = false;
-	let _numeric = true; // always numeric in our output
-	let mut show_routes = false;
-	let mut show_ifaces = false;
-	if !args.is_empty() {
-		// Default if filters present but neither -t nor -u provided: show both
-		for a in args {
-			match a.as_str() {
-				"-t" => { show_tcp = true; show_udp = false; },
-				"-u" => { show_udp = true; show_tcp = false; },
-				"-a" => { show_tcp = true; show_udp = true; },
-				"-l" => listening_only = true,
-				"-r" => show_routes = true,
-				"-i" => show_ifaces = true,
-				_ => {}
-			}
-		}
-	}
+	// Flags: -a (all), -t (tcp), -u (udp), -l (listening), -n (numeric),
+	// -p (owning process); they may be bundled as in -tlnp
+	let flags: HashSet<char> = args
+		.iter()
+		.filter(|a| a.starts_with('-') && !a.starts_with("--"))
+		.flat_map(|a| a.chars().skip(1))
+		.collect();
+	// Neither -t nor -u (or both) shows both
+	let show_tcp = flags.contains(&'t') || !flags.contains(&'u');
+	let show_udp = flags.contains(&'u') || !flags.contains(&'t');
+	let listening_only = flags.contains(&'l');
+	let show_all = flags.contains(&'a');
+	let show_owner = flags.contains(&'p');
+	let show_routes = flags.contains(&'r');
+	let show_ifaces = flags.contains(&'i');
 
 	if show_routes { return run_netstat_route(); }
 	if show_ifaces { return run_netstat_interfaces(); }
@@ -314,9 +456,8 @@ fn run_netstat(args: &[String]) {
 	{
 		use std::fs::File;
 		if File::open("/proc/net/tcp").is_ok() {
-			println!("Active Internet connections (servers/established) - best-effort");
-			println!("Proto Recv-Q Send-Q Local Address           Foreign Address         State");
-			if print_proc_net_sockets_filtered(show_tcp, show_udp, listening_only) { return; }
+			let owners = show_owner.then(socket_inode_owners);
+			if print_proc_net_sockets_filtered(show_tcp, show_udp, listening_only, show_all, owners.as_ref()) { return; }
 		}
 		// Fallback to external tools if /proc is blocked
 		let mut printed = false;
@@ -350,97 +491,63 @@ fn run_netstat(args: &[String]) {
 }
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
-fn print_proc_net_sockets_filtered(show_tcp: bool, show_udp: bool, listening_only: bool) -> bool {
-	use std::fs;
-
-	fn parse_hex_ip_port(hex: &str, v6: bool) -> (String, u16) {
-		let mut parts = hex.split(':');
-		let ip_hex = parts.next().unwrap_or("");
-		let port_hex = parts.next().unwrap_or("0");
-		let port = u16::from_str_radix(port_hex, 16).unwrap_or(0);
-		if !v6 {
-			if ip_hex.len() >= 8 {
-				let b0 = u8::from_str_radix(&ip_hex[6..8], 16).unwrap_or(0);
-				let b1 = u8::from_str_radix(&ip_hex[4..6], 16).unwrap_or(0);
-				let b2 = u8::from_str_radix(&ip_hex[2..4], 16).unwrap_or(0);
-				let b3 = u8::from_str_radix(&ip_hex[0..2], 16).unwrap_or(0);
-				return (format!("{}.{}.{}.{}", b0, b1, b2, b3), port);
-			}
-			return ("0.0.0.0".to_string(), port);
-		} else {
-			// IPv6: 32 hex chars, little-endian 32-bit words
-			if ip_hex.len() >= 32 {
-				let mut segs = Vec::new();
-				for i in (0..32).step_by(8) {
-					let w3 = &ip_hex[i..i + 2];
-					let w2 = &ip_hex[i + 2..i + 4];
-					let w1 = &ip_hex[i + 4..i + 6];
-					let w0 = &ip_hex[i + 6..i + 8];
-					segs.push(format!("{}{}:{}{}", w3, w2, w1, w0));
-				}
-				return (segs.join(":"), port);
-			}
-			return ("::".to_string(), port);
-		}
-	}
-
-	fn state_str(code: &str) -> &'static str {
-		match code {
-			"01" => "ESTABLISHED",
-			"02" => "SYN_SENT",
-			"03" => "SYN_RECV",
-			"04" => "FIN_WAIT1",
-			"05" => "FIN_WAIT2",
-			"06" => "TIME_WAIT",
-			"07" => "CLOSE",
-			"08" => "CLOSE_WAIT",
-			"09" => "LAST_ACK",
-			"0A" => "LISTEN",
-			"0B" => "CLOSING",
-			_ => "UNKNOWN",
-		}
-	}
-
-	fn print_file(path: &str, proto: &str, v6: bool, listening_only: bool) -> bool {
-		let Ok(content) = fs::read_to_string(path) else { return false };
-		for (i, line) in content.lines().enumerate() {
-			if i == 0 { continue; }
-			let cols: Vec<&str> = line.split_whitespace().collect();
-			if cols.len() < 10 { continue; }
-			let (lip, lport) = parse_hex_ip_port(cols[1], v6);
-			let (rip, rport) = parse_hex_ip_port(cols[2], v6);
-			let st = state_str(cols[3]);
-			// Filter: show LISTEN/ESTABLISHED for TCP, and all for UDP
-			if proto.starts_with("TCP") {
-				if listening_only {
-					if st != "LISTEN" { continue; }
-				} else if st != "LISTEN" && st != "ESTABLISHED" {
-					continue;
-				}
-			}
-			println!(
-				"{:<5} {:>5} {:>5} {:<22} {:<22} {}",
-				proto,
-				0, // Recv-Q (not parsed here)
-				0, // Send-Q
-				format!("{}:{}", lip, lport),
-				format!("{}:{}", rip, rport),
-				st
-			);
-		}
-		true
-	}
-
-	let mut any = false;
+fn print_proc_net_sockets_filtered(
+	show_tcp: bool,
+	show_udp: bool,
+	listening_only: bool,
+	show_all: bool,
+	owners: Option<&HashMap<u64, (u32, String)>>,
+) -> bool {
+	let mut tables = Vec::new();
 	if show_tcp {
-		any |= print_file("/proc/net/tcp", "TCP", false, listening_only);
-		any |= print_file("/proc/net/tcp6", "TCP6", true, listening_only);
+		tables.extend([("/proc/net/tcp", "tcp"), ("/proc/net/tcp6", "tcp6")]);
 	}
 	if show_udp {
-		any |= print_file("/proc/net/udp", "UDP", false, false);
-		any |= print_file("/proc/net/udp6", "UDP6", true, false);
+		tables.extend([("/proc/net/udp", "udp"), ("/proc/net/udp6", "udp6")]);
+	}
+	let readable: Vec<(&str, Vec<ProcNetSocket>)> = tables
+		.into_iter()
+		.filter_map(|(path, proto)| read_proc_net_sockets(path).ok().map(|rows| (proto, rows)))
+		.collect();
+	if readable.is_empty() {
+		return false;
+	}
+	let endpoint = |addr: std::net::SocketAddr| match addr.port() {
+		0 => format!("{}:*", addr.ip()),
+		port => format!("{}:{}", addr.ip(), port),
+	};
+
+	println!("{}", match (listening_only, show_all) {
+		(true, _) => "Active Internet connections (only servers)",
+		(false, true) => "Active Internet connections (servers and established)",
+		(false, false) => "Active Internet connections (w/o servers)",
+	});
+	let owner_header = if owners.is_some() { " PID/Program name" } else { "" };
+	println!("Proto Recv-Q Send-Q Local Address           Foreign Address         State      {}", owner_header);
+	for (proto, row) in readable.into_iter().flat_map(|(proto, rows)| rows.into_iter().map(move |row| (proto, row))) {
+		let tcp = proto.starts_with("tcp");
+		// An unconnected UDP socket is the UDP notion of listening
+		let listening = if tcp { row.state_name() == "LISTEN" } else { row.remote.port() == 0 };
+		if listening_only && !listening || !listening_only && !show_all && listening {
+			continue;
+		}
+		let state = if tcp { row.state_name() } else if listening { "" } else { "ESTABLISHED" };
+		let owner = match owners {
+			Some(owners) => owners.get(&row.inode).map_or_else(|| "-".to_string(), |(pid, cmd)| format!("{}/{}", pid, cmd)),
+			None => String::new(),
+		};
+		println!(
+			"{:<5} {:>6} {:>6} {:<23} {:<23} {:<11}{}",
+			proto,
+			row.rx_queue,
+			row.tx_queue,
+			endpoint(row.local),
+			endpoint(row.remote),
+			state,
+			owner
+		);
 	}
-	any
+	true
 }
 
 #[allow(unused)]
@@ -465,6 +572,165 @@ use literbike::syscall_net::{InterfaceAddr, Interface, list_interfaces};
 	false
 }
 
+/// An `lsof -i [46][protocol][@host][:port]` address selector
+#[derive(Debug, Default, PartialEq, Eq)]
+struct InetFilter {
+	/// 4 or 6; either when unset
+	family: Option<u8>,
+	/// "tcp" or "udp"
+	protocol: Option<String>,
+	host: Option<std::net::IpAddr>,
+	port: Option<u16>,
+}
+
+impl InetFilter {
+	fn parse(spec: &str) -> Option<Self> {
+		let mut filter = InetFilter::default();
+		let mut rest = spec;
+		if let Some(family) = rest.chars().next().and_then(|c| c.to_digit(10)) {
+			if family != 4 && family != 6 {
+				return None;
+			}
+			filter.family = Some(family as u8);
+			rest = &rest[1..];
+		}
+		// IPv6 hosts are bracketed, so the first ':' outside brackets starts the port
+		let (protocol, host, port) = match rest.split_once('@') {
+			Some((protocol, address)) => {
+				let (host, port) = match address.strip_prefix('[') {
+					Some(bracketed) => {
+						let (host, after) = bracketed.split_once(']')?;
+						(host, after.strip_prefix(':'))
+					}
+					None => address.split_once(':').map_or((address, None), |(host, port)| (host, Some(port))),
+				};
+				(protocol, Some(host), port)
+			}
+			None => rest.split_once(':').map_or((rest, None, None), |(protocol, port)| (protocol, None, Some(port))),
+		};
+		match protocol.to_ascii_lowercase().as_str() {
+			"" => {}
+			p @ ("tcp" | "udp") => filter.protocol = Some(p.to_string()),
+			_ => return None,
+		}
+		if let Some(host) = host {
+			filter.host = Some(host.parse().ok()?);
+		}
+		if let Some(port) = port {
+			filter.port = Some(port.parse().ok()?);
+		}
+		Some(filter)
+	}
+
+	fn matches(&self, protocol: &str, row: &ProcNetSocket) -> bool {
+		let family = if row.local.is_ipv6() { 6 } else { 4 };
+		self.family.is_none_or(|f| f == family)
+			&& self.protocol.as_deref().is_none_or(|p| protocol.starts_with(p))
+			&& self.host.is_none_or(|h| row.local.ip() == h || row.remote.ip() == h)
+			&& self.port.is_none_or(|p| row.local.port() == p || row.remote.port() == p)
+	}
+}
+
+/// lsof's NAME column: `local->remote (STATE)`, with wildcards as `*`
+fn lsof_name(protocol: &str, row: &ProcNetSocket) -> String {
+	let endpoint = |addr: std::net::SocketAddr| {
+		let host = match addr.ip() {
+			ip if ip.is_unspecified() => "*".to_string(),
+			std::net::IpAddr::V6(ip) => format!("[{}]", ip),
+			ip => ip.to_string(),
+		};
+		match addr.port() {
+			0 => format!("{}:*", host),
+			port => format!("{}:{}", host, port),
+		}
+	};
+	let mut name = endpoint(row.local);
+	if row.remote.port() != 0 {
+		name = format!("{}->{}", name, endpoint(row.remote));
+	}
+	if protocol.starts_with("tcp") {
+		name = format!("{} ({})", name, row.state_name());
+	}
+	name
+}
+
+/// Network sockets per process, from `/proc/*/fd` and `/proc/net`.
+/// `-i` with an optional `[46][tcp|udp][@host][:port]` selector; other flags
+/// (-n, -P) are accepted and ignored since output is always numeric.
+fn run_lsof(args: &[String]) {
+	let mut filter = InetFilter::default();
+	let mut inet = false;
+	let mut iter = args.iter().peekable();
+	while let Some(arg) = iter.next() {
+		let Some(spec) = arg.strip_prefix("-i") else { continue };
+		inet = true;
+		let spec = if spec.is_empty() {
+			match iter.peek() {
+				Some(next) if !next.starts_with('-') => iter.next().map(String::as_str).unwrap_or_default(),
+				_ => "",
+			}
+		} else {
+			spec
+		};
+		match InetFilter::parse(spec) {
+			Some(parsed) => filter = parsed,
+			None => {
+				eprintln!("lsof: unsupported -i address: {}", spec);
+				return;
+			}
+		}
+	}
+	if !inet {
+		eprintln!("Usage: lsof -i [46][tcp|udp][@host][:port]");
+		return;
+	}
+
+	let mut sockets: HashMap<u64, (&str, ProcNetSocket)> = HashMap::new();
+	for (path, protocol) in [("/proc/net/tcp", "tcp"), ("/proc/net/tcp6", "tcp6"), ("/proc/net/udp", "udp"), ("/proc/net/udp6", "udp6")] {
+		for row in read_proc_net_sockets(path).unwrap_or_default() {
+			sockets.insert(row.inode, (protocol, row));
+		}
+	}
+	let users: HashMap<u32, String> = fs::read_to_string("/etc/passwd")
+		.unwrap_or_default()
+		.lines()
+		.filter_map(|line| {
+			let fields: Vec<&str> = line.split(':').collect();
+			Some((fields.get(2)?.parse().ok()?, fields[0].to_string()))
+		})
+		.collect();
+
+	let mut fds = list_socket_fds();
+	fds.sort_by_key(|fd| (fd.pid, fd.fd));
+	let mut header = false;
+	for fd in fds {
+		let Some((protocol, row)) = sockets.get(&fd.inode) else { continue };
+		if !filter.matches(protocol, row) {
+			continue;
+		}
+		if !header {
+			println!("{:<15} {:>7} {:<10} {:>5} {:<5} {:>10} {:<4} NAME", "COMMAND", "PID", "USER", "FD", "TYPE", "DEVICE", "NODE");
+			header = true;
+		}
+		let user = users.get(&fd.uid).cloned().unwrap_or_else(|| fd.uid.to_string());
+		println!(
+			"{:<15} {:>7} {:<10} {:>4}u {:<5} {:>10} {:<4} {}",
+			fd.command,
+			fd.pid,
+			user,
+			fd.fd,
+			if row.local.is_ipv6() { "IPv6" } else { "IPv4" },
+			fd.inode,
+			if protocol.starts_with("tcp") { "TCP" } else { "UDP" },
+			lsof_name(protocol, row)
+		);
+	}
+	if !header {
+		// lsof exits non-zero when nothing matched; scripts test for it
+		std::process::exit(1);
+	}
+}
+
 fn run_netstat_route() {
 	println!("Routing tables");
 	println!("Destination        Gateway            Flags  Refs    Use  Iface");
@@ -1657,11 +1923,30 @@ fn glob_match(pattern: &str, text: &str) -> bool {
 	(pattern.ends_with('*') && text.starts_with(&pattern[..pattern.len()-1]))
 }
 
+/// Protocols `run_proxy_server` really serves: plain HTTP proxy requests.
+/// Its SOCKS5 and CONNECT branches only answer the opening message.
+const UNIVERSAL_SERVER_PROTOCOLS: &[literbike::types::ProtocolType] = &[literbike::types::ProtocolType::Http];
+
 fn run_proxy_server(args: &[String]) {
 	let port = args.get(0).unwrap_or(&"8888".to_string()).parse::<u16>().unwrap_or(8888);
+	// --port-range=FIRST-LAST listens on a random free port of the range; port 0 on an ephemeral one
+	let port_range = match args.iter().find_map(|arg| arg.strip_prefix("--port-range=")) {
+		Some(spec) => match literbike::universal_listener::parse_port_range(spec) {
+			Ok(range) => Some(range),
+			Err(e) => {
+				eprintln!("proxy-server: {}", e);
+				std::process::exit(2);
+			}
+		},
+		None => None,
+	};
+	
+	// --reuse-port lets several worker processes share the port
+	let mut tuning = literbike::tcp_fingerprint::TcpTuningOptions::listener();
+	tuning.reuse_port = args.iter().any(|arg| arg == "--reuse-port");
 	
 	// Get ingress interface pattern from args or env  
-	let ingress_pattern = args.get(1).map(|s| s.as_str())
+	let ingress_pattern = args.get(1).filter(|a| !a.starts_with("--")).map(|s| s.as_str())
 		.or_else(|| args.iter().find_map(|arg| arg.strip_prefix("--ingress=")))
 		.unwrap_or("s?w?lan*");
 		
@@ -1681,27 +1966,22 @@ fn run_proxy_server(args: &[String]) {
 	}
 	let bind_ip = env::var("BIND_IP").unwrap_or_else(|_| local_ip.to_string());
 	
+	// Start server - bind to specific IP or all interfaces
+	// Still bind to all for compatibility, whatever the ingress address
+	let bind_addr = std::net::SocketAddr::from((std::net::Ipv4Addr::UNSPECIFIED, port));
+	let bound = match port_range {
+		Some(range) => literbike::universal_listener::bind_in_range(std::net::Ipv4Addr::UNSPECIFIED.into(), range, &tuning),
+		None => literbike::tcp_fingerprint::bind_with_options(bind_addr, &tuning),
+	};
+	// Everything printed and advertised below names the port actually bound
+	let port = bound.as_ref().ok().and_then(|l| l.local_addr().ok()).map_or(port, |a| a.port());
+	
 	println!("Starting Universal Proxy Server");
 	println!("  Binding to: {}:{}", bind_ip, port);
 	println!("  Protocols: HTTP/HTTPS/SOCKS5/TLS/DoH");
 	println!("  PAC file: {}", pac_url(bind_ip.as_str(), port));
 	println!("  WPAD: {}", wpad_url(bind_ip.as_str(), port));
 	
-	// PAC file content
-	let pac_content = format!(
-		r#"function FindProxyForURL(url, host) {{
-    if (isPlainHostName(host) ||
-        shExpMatch(host, "*.local") ||
-        isInNet(dnsResolve(host), "10.0.0.0", "255.0.0.0") ||
-        isInNet(dnsResolve(host), "172.16.0.0", "255.240.0.0") ||
-        isInNet(dnsResolve(host), "192.168.0.0", "255.255.0.0") ||
-        isInNet(dnsResolve(host), "127.0.0.0", "255.255.255.0"))
-        return "DIRECT";
-    return "PROXY {}:{}; SOCKS5 {}:{}; DIRECT";
-}}"#,
-		bind_ip, port, bind_ip, port
-	);
-	
 	// Print configuration URLs (from proxy-bridge)
 	println!("\nAuto-Discovery URLs:");
 	println!("  PAC URL:     {}", pac_url(bind_ip.as_str(), port));
@@ -1732,17 +2012,17 @@ fn ssh_forward_cmd(ip: &str, port: u16) -> String {
 	format!("ssh -L {}:{}:{} user@{} -p 8022", port, ip, port, ip)
 }
 	
-	// Start server - bind to specific IP or all interfaces
-	let bind_addr = if bind_ip == "127.0.0.1" {
-		format!("0.0.0.0:{}", port)
-	} else {
-		format!("0.0.0.0:{}", port) // Still bind to all for compatibility
-	};
-	
-	if let Ok(tcp_listener) = std::net::TcpListener::bind(&bind_addr) {
-		println!("\n✓ Listening on {}", bind_addr);
+	if let Ok(tcp_listener) = bound {
+		println!("\n✓ Listening on 0.0.0.0:{}", port);
 		println!("✓ Supports: HTTP, HTTPS, SOCKS5, TLS, DoH, PAC/WPAD");
 
+		// PAC advertises the listener clients can actually reach, never the wildcard
+		let advertised_host = if bind_ip == "0.0.0.0" { local_ip.to_string() } else { bind_ip.clone() };
+		let advertised_port = tcp_listener.local_addr().map(|a| a.port()).unwrap_or(port);
+		let pac = literbike::pac::PacConfig::new(&advertised_host, advertised_port).with_env_order();
+		let pac_content = pac.generate();
+		println!("✓ PAC order: {}", pac.proxy_list());
+
 		// Prepare RBCursive and parsers once (idempotent, no state per-conn)
 	let rbc = RBCursive::new();
 		let http = rbc.http_parser();
@@ -1752,6 +2032,9 @@ fn ssh_forward_cmd(ip: &str, port: u16) -> String {
 		);
 		// Note: socks5/json parsers available from rbc as needed
 
+		// Live counters for the dock manifest; connections are served one at a time
+		let mut stats = literbike::dock::DockStats { active_connections: 1, ..Default::default() };
+
 		for stream in tcp_listener.incoming() {
 			if let Ok(mut stream) = stream {
 				// Read up to a small window; if NeedMore, read a bit more once
@@ -1770,6 +2053,7 @@ fn ssh_forward_cmd(ip: &str, port: u16) -> String {
 							classified = proto_listener.classify(&req);
 						}
 					}
+					stats.max_read_up = stats.max_read_up.max(len);
 
 					match classified {
 						Classify::Protocol(protocol) => {
@@ -1791,95 +2075,118 @@ fn ssh_forward_cmd(ip: &str, port: u16) -> String {
 									println!("→ HTTP/2 (ALPN likely via CONNECT/TLS)");
 								}
 								ProtocolType::Http(_method) => {
-									let request_str = String::from_utf8_lossy(&req);
-
-									if request_str.contains("GET /proxy.pac") || request_str.contains("GET /wpad.dat") {
-										// Serve PAC file
-										let response = format!(
-											"HTTP/1.1 200 OK\r\n\
-											Content-Type: application/x-ns-proxy-autoconfig\r\n\
-											Content-Length: {}\r\n\
-											Cache-Control: no-cache\r\n\
-											\r\n\
-											{}",
-											pac_content.len(),
-											pac_content
-										);
-										let _ = stream.write_all(response.as_bytes());
-										println!("→ Served PAC file");
-									} else if request_str.contains("CONNECT ") {
-										// HTTPS proxy CONNECT request
-										let _ = stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n");
-										println!("→ HTTPS CONNECT tunnel");
-									} else {
-										// HTTP proxy request - parse and forward
-										println!("→ HTTP proxy request");
-										
-										// Parse the HTTP request line to extract URL
-										if let Some(first_line_end) = request_str.find("\r\n") {
-											let first_line = &request_str[..first_line_end];
-											let parts: Vec<&str> = first_line.split(' ').collect();
+									// Keep reading until the request head is complete
+									let mut parsed = literbike::http::RequestHead::parse(&req);
+									while matches!(parsed, Err(literbike::http::HttpParseError::Incomplete)) {
+										match stream.read(&mut buffer) {
+											Ok(n) if n > 0 => {
+												req.extend_from_slice(&buffer[..n]);
+												parsed = literbike::http::RequestHead::parse(&req);
+											}
+											_ => break,
+										}
+									}
+
+									match parsed {
+										Ok((head, _)) if head.method == "GET" && matches!(head.target.split('?').next(), Some("/proxy.pac" | "/wpad.dat")) => {
+											// Serve PAC file
+											let response = format!(
+												"HTTP/1.1 200 OK\r\n\
+												Content-Type: application/x-ns-proxy-autoconfig\r\n\
+												Content-Length: {}\r\n\
+												Cache-Control: no-cache\r\n\
+												\r\n\
+												{}",
+												pac_content.len(),
+												pac_content
+											);
+											let _ = stream.write_all(response.as_bytes());
+											println!("→ Served PAC file");
+										}
+										Ok((head, _)) if head.method == "GET" && head.target.split('?').next() == Some("/litebike.json") => {
+											// Dock manifest advertised as our SSDP LOCATION
+											let caps = literbike::dock::DockCapabilities {
+												has_proxy: true,
+												has_knox: false,
+												has_socks5: false,
+												protocols: UNIVERSAL_SERVER_PROTOCOLS,
+											};
+											let manifest = literbike::dock::build_manifest_json_with_stats("litebike", advertised_port, &caps, &stats);
+											let response = format!(
+												"HTTP/1.1 200 OK\r\n\
+												Content-Type: application/json\r\n\
+												Content-Length: {}\r\n\
+												Cache-Control: no-cache\r\n\
+												\r\n\
+												{}",
+												manifest.len(),
+												manifest
+											);
+											let _ = stream.write_all(response.as_bytes());
+											println!("→ Served dock manifest");
+										}
+										Ok((head, _)) if head.is_connect() => {
+											// HTTPS proxy CONNECT request
+											let _ = stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n");
+											println!("→ HTTPS CONNECT tunnel");
+										}
+										Ok((head, _)) => {
+											// HTTP proxy request - forward with an origin-form request line
+											println!("→ HTTP proxy request");
 											
-											if parts.len() >= 3 {
-												let method = parts[0];
-												let url = parts[1];
-												let version = parts[2];
+											// Parse URL to extract host and path
+											if let Some((host, port, path)) = parse_proxy_url(&head.target) {
+												// Everything after the original request line: headers and any body bytes
+												let first_line_end = req.windows(2).position(|w| w == b"\r\n").map(|p| p + 2).unwrap_or(req.len());
 												
-												// Parse URL to extract host and path
-												if let Some(url_parts) = parse_proxy_url(url) {
-													let (host, port, path) = url_parts;
+												// Connect to target server
+												if let Ok(mut target_stream) = std::net::TcpStream::connect(format!("{}:{}", host, port)) {
+													// Forward the request to target server
+													let mut forwarded_request = format!("{} {} {}\r\n", head.method, path, head.version).into_bytes();
+													forwarded_request.extend_from_slice(&req[first_line_end..]);
 													
-													// Connect to target server
-													if let Ok(mut target_stream) = std::net::TcpStream::connect(format!("{}:{}", host, port)) {
-														// Forward the request to target server
-														let forwarded_request = format!("{} {} {}\r\n{}", 
-															method, path, version, 
-															&request_str[first_line_end + 2..]);
-														
-														if target_stream.write_all(forwarded_request.as_bytes()).is_ok() {
-															// Read response from target server
-															let mut response_buffer = vec![0u8; 8192];
-															if let Ok(bytes_read) = target_stream.read(&mut response_buffer) {
-																if bytes_read > 0 {
-																	// Forward response back to client
-																	let _ = stream.write_all(&response_buffer[..bytes_read]);
-																	println!("→ Forwarded {} bytes from {}", bytes_read, host);
-																} else {
-																	// No content response
-																	let error_response = "HTTP/1.1 204 No Content\r\n\r\n";
-																	let _ = stream.write_all(error_response.as_bytes());
-																}
+													if target_stream.write_all(&forwarded_request).is_ok() {
+														// Read response from target server
+														let mut response_buffer = vec![0u8; 8192];
+														if let Ok(bytes_read) = target_stream.read(&mut response_buffer) {
+															stats.max_read_down = stats.max_read_down.max(bytes_read);
+															if bytes_read > 0 {
+																// Forward response back to client
+																let _ = stream.write_all(&response_buffer[..bytes_read]);
+																println!("→ Forwarded {} bytes from {}", bytes_read, host);
 															} else {
-																// Read error
-																let error_response = "HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n";
+																// No content response
+																let error_response = "HTTP/1.1 204 No Content\r\n\r\n";
 																let _ = stream.write_all(error_response.as_bytes());
 															}
 														} else {
-															// Write error
+															// Read error
 															let error_response = "HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n";
 															let _ = stream.write_all(error_response.as_bytes());
 														}
 													} else {
-														// Connection failed
+														// Write error
 														let error_response = "HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n";
 														let _ = stream.write_all(error_response.as_bytes());
-														println!("→ Failed to connect to {}", host);
 													}
 												} else {
-													// Invalid URL format
-													let error_response = "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n";
+													// Connection failed
+													let error_response = "HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n";
 													let _ = stream.write_all(error_response.as_bytes());
-													println!("→ Invalid URL format: {}", url);
+													println!("→ Failed to connect to {}", host);
 												}
 											} else {
-												// Invalid request line
+												// Invalid URL format
 												let error_response = "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n";
 												let _ = stream.write_all(error_response.as_bytes());
+												println!("→ Invalid URL format: {}", head.target);
 											}
-										} else {
-											// No CRLF found
+										}
+										Err(e) => {
+											// Malformed or oversized request head
 											let error_response = "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n";
 											let _ = stream.write_all(error_response.as_bytes());
+											println!("→ {}", e);
 										}
 									}
 								}
@@ -2689,6 +2996,86 @@ fn run_proxy_quick(args: &[String]) {
 	println!("🎯 Usage: litebike knox-proxy --enable-tethering-bypass");
 }
 
+/// End-to-end diagnostics: bind, loopback SOCKS5/HTTP, gateway, SSDP
+fn run_selftest(args: &[String]) {
+	let mut options = literbike::selftest::SelftestOptions::default();
+
+	let mut ports = Vec::new();
+	let mut i = 0;
+	while i < args.len() {
+		match args[i].as_str() {
+			"--port" => {
+				if let Some(port) = args.get(i + 1).and_then(|p| p.parse().ok()) {
+					ports.push(port);
+					i += 1;
+				}
+			}
+			"--no-gateway" => options.probe_gateway = false,
+			"--no-ssdp" => options.ssdp_timeout = None,
+			"--help" => {
+				println!("Usage: litebike selftest [--port N]... [--no-gateway] [--no-ssdp]");
+				return;
+			}
+			_ => {}
+		}
+		i += 1;
+	}
+	if !ports.is_empty() {
+		options.bind_ports = ports;
+	}
+
+	println!("🩺 LiteBike self-test");
+	let rt = tokio::runtime::Runtime::new().unwrap();
+	let report = rt.block_on(literbike::selftest::run_selftest(&options));
+	println!("{}", report);
+	if !report.passed() {
+		std::process::exit(1);
+	}
+}
+
+/// Print a binary connection log (`[knox] conn_log`) one record per line
+fn run_conn_log_dump(args: &[String]) {
+	let Some(path) = args.first() else {
+		eprintln!("Usage: litebike conn-log-dump <file>");
+		std::process::exit(2);
+	};
+	let records = fs::File::open(path).and_then(literbike::conn_log::read_records);
+	match records {
+		Ok(records) => {
+			for record in &records {
+				println!("{}", record);
+			}
+			eprintln!("{} records", records.len());
+		}
+		Err(e) => {
+			eprintln!("conn-log-dump: {}: {}", path, e);
+			std::process::exit(1);
+		}
+	}
+}
+
+/// `caps`: which proxy protocols are implemented and which are stubs
+fn run_caps(_args: &[String]) {
+	for (protocol, status) in literbike::capabilities::implemented_protocols(literbike::knox_proxy::DISPATCHED_PROTOCOLS) {
+		println!("{:<12} {}", protocol.to_string(), status);
+	}
+}
+
+/// `config dump [FILE]`: the effective configuration as TOML, secrets redacted
+fn run_config(args: &[String]) {
+	if args.first().map(String::as_str) != Some("dump") || args.len() > 2 {
+		eprintln!("Usage: litebike config dump [config.toml]");
+		std::process::exit(2);
+	}
+	match literbike::config::load_effective(args.get(1).map(Path::new)) {
+		Ok(config) => print!("{}", config.to_effective_string()),
+		Err(e) => {
+			eprintln!("config: {}", e);
+			std::process::exit(1);
+		}
+	}
+}
+
 /// Self-replicating bootstrap agent
 fn run_bootstrap(args: &[String]) {
 	println!("🔄 Litebike Self-Bootstrap Agent");
@@ -0,0 +1,88 @@
+// Protocol capabilities - which protocols a listener really serves
+// Several protocols have a type, a detector or a module in the tree but no
+// handler on the proxy listener. `implemented_protocols` tells the two
+// apart for the dock manifest, `litebike caps` and the selftest, given the
+// table of the listener being described (`knox_proxy::DISPATCHED_PROTOCOLS`
+// for the Knox proxy).
+
+use std::fmt;
+
+use crate::types::ProtocolType;
+
+/// Whether a protocol has a handler behind it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Impl {
+    Real,
+    Stub,
+}
+
+impl fmt::Display for Impl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Impl::Real => "real",
+            Impl::Stub => "stub",
+        })
+    }
+}
+
+/// Proxy protocols litebike has code for, whether or not it is wired up
+const KNOWN_PROTOCOLS: &[ProtocolType] = &[
+    ProtocolType::Http,
+    ProtocolType::Connect,
+    ProtocolType::Socks5,
+    ProtocolType::Socks4,
+    ProtocolType::Udp,
+    ProtocolType::Shadowsocks,
+    ProtocolType::H2c,
+    ProtocolType::Quic,
+];
+
+/// Every known proxy protocol, `Real` when it is in `served` and `Stub`
+/// otherwise
+pub fn implemented_protocols(served: &[ProtocolType]) -> Vec<(ProtocolType, Impl)> {
+    KNOWN_PROTOCOLS
+        .iter()
+        .map(|&protocol| {
+            let status = if served.contains(&protocol) { Impl::Real } else { Impl::Stub };
+            (protocol, status)
+        })
+        .collect()
+}
+
+/// `implemented_protocols` as a JSON object, e.g. `{"http":"real",...}`
+pub fn protocols_json(served: &[ProtocolType]) -> String {
+    let fields: Vec<String> = implemented_protocols(served)
+        .iter()
+        .map(|(protocol, status)| format!(r#""{}":"{}""#, protocol.to_string().to_ascii_lowercase(), status))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::knox_proxy::DISPATCHED_PROTOCOLS;
+
+    #[test]
+    fn test_http_and_socks5_real_shadowsocks_stub() {
+        let protocols = implemented_protocols(DISPATCHED_PROTOCOLS);
+        let status = |wanted| protocols.iter().find(|(p, _)| *p == wanted).map(|(_, s)| *s);
+        assert_eq!(status(ProtocolType::Http), Some(Impl::Real));
+        assert_eq!(status(ProtocolType::Socks5), Some(Impl::Real));
+        assert_eq!(status(ProtocolType::Shadowsocks), Some(Impl::Stub));
+        // Every dispatched protocol is listed; h2c has no handler on the proxy listener
+        assert!(DISPATCHED_PROTOCOLS.iter().all(|&p| status(p) == Some(Impl::Real)));
+        assert_eq!(status(ProtocolType::H2c), Some(Impl::Stub));
+
+        let caps = crate::dock::DockCapabilities { protocols: DISPATCHED_PROTOCOLS, ..Default::default() };
+        let manifest = crate::dock::parse_manifest_json(&crate::dock::build_manifest_json("caps", 8888, &caps)).unwrap();
+        assert_eq!(manifest.protocols.get("socks5").map(String::as_str), Some("real"));
+        assert_eq!(manifest.protocols.get("shadowsocks").map(String::as_str), Some("stub"));
+
+        // The table belongs to the server serving the manifest
+        let http_only = crate::dock::DockCapabilities { protocols: &[ProtocolType::Http], ..Default::default() };
+        let manifest = crate::dock::parse_manifest_json(&crate::dock::build_manifest_json("caps", 8888, &http_only)).unwrap();
+        assert_eq!(manifest.protocols.get("http").map(String::as_str), Some("real"));
+        assert_eq!(manifest.protocols.get("socks5").map(String::as_str), Some("stub"));
+    }
+}
@@ -0,0 +1,131 @@
+// Traffic capture - opt-in per-connection tee of relayed bytes to a file
+// A lightweight stand-in for a kernel capture when debugging one client.
+// Frames are written off the relay path; a capture that cannot be written
+// is logged and dropped, the connection carries on.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::util::BackgroundWriter;
+
+/// File magic written once at the start of every capture
+pub const CAPTURE_MAGIC: &[u8; 8] = b"LBCAP001";
+
+/// Direction of a captured frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Direction {
+    ClientToUpstream = 0,
+    UpstreamToClient = 1,
+}
+
+/// One captured chunk. On disk: direction (u8), length (u32 BE),
+/// timestamp in microseconds since the epoch (u64 BE), then the payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureFrame {
+    pub direction: Direction,
+    pub timestamp_us: u64,
+    pub data: Vec<u8>,
+}
+
+/// Which clients to capture and where to put the files
+#[derive(Debug, Clone)]
+pub struct CaptureConfig {
+    pub client_ips: Vec<IpAddr>,
+    pub dir: PathBuf,
+}
+
+impl CaptureConfig {
+    pub fn matches(&self, ip: IpAddr) -> bool {
+        self.client_ips.contains(&ip)
+    }
+
+    /// Start a capture file for `peer` if it is one of the watched clients
+    pub fn open_for(&self, peer: SocketAddr) -> Option<CaptureSink> {
+        if !self.matches(peer.ip()) {
+            return None;
+        }
+        let name = format!("litebike-{}-{}-{}.cap", peer.ip(), peer.port(), now_us());
+        Some(CaptureSink::create(self.dir.join(name)))
+    }
+}
+
+fn now_us() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0)
+}
+
+/// Capture file shared by both relay directions of one connection. The file
+/// is created and written by a `BackgroundWriter`.
+#[derive(Debug)]
+pub struct CaptureSink {
+    path: PathBuf,
+    writer: BackgroundWriter,
+}
+
+impl CaptureSink {
+    pub fn create(path: PathBuf) -> Self {
+        let target = path.clone();
+        let writer = BackgroundWriter::spawn(format!("capture {}", path.display()), move || {
+            let mut file = BufWriter::new(File::create(target)?);
+            file.write_all(CAPTURE_MAGIC)?;
+            Ok(file)
+        });
+        Self { path, writer }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Queue one frame
+    pub fn record(&self, direction: Direction, data: &[u8]) {
+        let mut frame = Vec::with_capacity(13 + data.len());
+        frame.push(direction as u8);
+        frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&now_us().to_be_bytes());
+        frame.extend_from_slice(data);
+        self.writer.write(frame);
+    }
+
+    /// Wait until the queued frames are written
+    pub async fn flush(&self) {
+        self.writer.flush().await
+    }
+
+    /// Whether writing the file failed, so the capture stopped
+    pub fn has_failed(&self) -> bool {
+        self.writer.has_failed()
+    }
+}
+
+/// Read back every frame of a capture file
+pub fn read_capture(path: &Path) -> io::Result<Vec<CaptureFrame>> {
+    let mut raw = Vec::new();
+    File::open(path)?.read_to_end(&mut raw)?;
+    if !raw.starts_with(CAPTURE_MAGIC) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a litebike capture"));
+    }
+    let truncated = || io::Error::new(io::ErrorKind::UnexpectedEof, "truncated capture frame");
+    let mut frames = Vec::new();
+    let mut rest = &raw[CAPTURE_MAGIC.len()..];
+    while !rest.is_empty() {
+        if rest.len() < 13 {
+            return Err(truncated());
+        }
+        let direction = match rest[0] {
+            0 => Direction::ClientToUpstream,
+            1 => Direction::UpstreamToClient,
+            other => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad capture direction {}", other))),
+        };
+        let len = u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+        let mut ts = [0u8; 8];
+        ts.copy_from_slice(&rest[5..13]);
+        let data = rest.get(13..13 + len).ok_or_else(truncated)?.to_vec();
+        frames.push(CaptureFrame { direction, timestamp_us: u64::from_be_bytes(ts), data });
+        rest = &rest[13 + len..];
+    }
+    Ok(frames)
+}
@@ -8,6 +8,9 @@ pub use abstract_channel_provider::{AbstractChannelProvider, ChannelCapabilities
 pub use proxy_channel::{ProxyChannel, ProxyChannelConfig};
 
 use std::collections::HashMap;
+use std::time::Duration;
+use crate::routing::RoutingTable;
+use crate::util::Backoff;
 
 /// Channel type enumeration
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -20,6 +23,37 @@ pub enum ChannelType {
     Raw,
 }
 
+/// Stats summed over every active channel
+#[derive(Debug, Clone, Default)]
+pub struct AggregatedChannelStats {
+    /// Active channels whose stats were collected
+    pub channels: usize,
+    /// Active channels whose provider failed to report
+    pub unavailable: usize,
+    pub active_connections: usize,
+    pub total_connections: u64,
+    pub bytes_transferred: u64,
+    pub errors: u64,
+    /// The same sums per channel type; `uptime_seconds` is the longest
+    pub by_type: HashMap<ChannelType, ChannelStats>,
+}
+
+impl AggregatedChannelStats {
+    fn add(&mut self, channel_type: ChannelType, stats: &ChannelStats) {
+        self.channels += 1;
+        self.active_connections += stats.active_connections;
+        self.total_connections += stats.total_connections;
+        self.bytes_transferred += stats.bytes_transferred;
+        self.errors += stats.errors;
+        let entry = self.by_type.entry(channel_type).or_default();
+        entry.active_connections += stats.active_connections;
+        entry.total_connections += stats.total_connections;
+        entry.bytes_transferred += stats.bytes_transferred;
+        entry.errors += stats.errors;
+        entry.uptime_seconds = entry.uptime_seconds.max(stats.uptime_seconds);
+    }
+}
+
 /// Channel manager for coordinating multiple proxy connections
 pub struct ChannelManager {
     channels: HashMap<String, Box<dyn AbstractChannelProvider>>,
@@ -52,6 +86,35 @@ impl ChannelManager {
         }
     }
     
+    /// Open a channel, retrying up to `max_attempts` times in all. The wait
+    /// before retry n is `base_delay * 2^(n-1)` (see `util::Backoff`), less
+    /// up to half as jitter.
+    /// Returns the attempts used; after the last failure its error, with
+    /// `Ok(false)` from the provider reported as `ConnectionFailed`.
+    pub async fn open_channel_with_retry(
+        &mut self,
+        name: &str,
+        channel_type: ChannelType,
+        max_attempts: u32,
+        base_delay: Duration,
+    ) -> Result<u32, ChannelError> {
+        let mut backoff = Backoff::new(base_delay, Duration::MAX).with_jitter(0.5);
+        let mut attempt = 1;
+        loop {
+            let error = match self.open_channel(name, channel_type.clone()).await {
+                Ok(true) => return Ok(attempt),
+                Ok(false) => ChannelError::ConnectionFailed(format!("{} refused to open", name)),
+                Err(ChannelError::ProviderNotFound(name)) => return Err(ChannelError::ProviderNotFound(name)),
+                Err(e) => e,
+            };
+            if attempt >= max_attempts {
+                return Err(error);
+            }
+            tokio::time::sleep(backoff.next_delay()).await;
+            attempt += 1;
+        }
+    }
+    
     /// Close a channel
     pub async fn close_channel(&mut self, name: &str) -> Result<(), ChannelError> {
         if let Some(provider) = self.channels.get(name) {
@@ -70,6 +133,29 @@ impl ChannelManager {
             .collect()
     }
     
+    /// The active channel `routes` sends `host` to; `None` when no rule
+    /// matches or the routed channel is not open, so the caller goes direct
+    pub fn channel_for(&self, routes: &RoutingTable, host: &str) -> Option<(String, ChannelType)> {
+        let name = routes.route(host)?;
+        self.active_channels.get(name).map(|channel_type| (name.to_string(), channel_type.clone()))
+    }
+    
+    /// Ask each active channel's provider for its stats and sum them
+    pub async fn aggregate_stats(&self) -> AggregatedChannelStats {
+        let mut total = AggregatedChannelStats::default();
+        for (name, channel_type) in &self.active_channels {
+            let stats = match self.channels.get(name) {
+                Some(provider) => provider.get_stats(name).await,
+                None => Err(ChannelError::ProviderNotFound(name.clone())),
+            };
+            match stats {
+                Ok(stats) => total.add(channel_type.clone(), &stats),
+                Err(_) => total.unavailable += 1,
+            }
+        }
+        total
+    }
+    
     /// Get channel capabilities
     pub fn get_capabilities(&self, name: &str) -> Option<ChannelCapabilities> {
         self.channels.get(name)
@@ -82,3 +168,158 @@ impl Default for ChannelManager {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use tokio::net::TcpStream;
+
+    /// Reports fixed stats for one channel name
+    struct FixedStats(&'static str, ChannelStats);
+
+    #[async_trait]
+    impl AbstractChannelProvider for FixedStats {
+        async fn open_channel(&self, _name: &str) -> Result<bool, ChannelError> {
+            Ok(true)
+        }
+
+        async fn close_channel(&self, _name: &str) -> Result<(), ChannelError> {
+            Ok(())
+        }
+
+        fn get_capabilities(&self) -> ChannelCapabilities {
+            ChannelCapabilities::default()
+        }
+
+        async fn handle_connection(&self, _stream: TcpStream, _channel_name: &str) -> Result<(), ChannelError> {
+            Ok(())
+        }
+
+        async fn health_check(&self, _name: &str) -> Result<bool, ChannelError> {
+            Ok(true)
+        }
+
+        async fn get_stats(&self, name: &str) -> Result<ChannelStats, ChannelError> {
+            match name == self.0 {
+                true => Ok(self.1.clone()),
+                false => Err(ChannelError::ProviderNotFound(name.to_string())),
+            }
+        }
+    }
+
+    /// Fails to open until its `failures` run out, erroring every other time
+    struct Flaky(std::sync::atomic::AtomicU32);
+
+    #[async_trait]
+    impl AbstractChannelProvider for Flaky {
+        async fn open_channel(&self, _name: &str) -> Result<bool, ChannelError> {
+            use std::sync::atomic::Ordering;
+            match self.0.fetch_sub(1, Ordering::SeqCst) {
+                0 => {
+                    self.0.store(0, Ordering::SeqCst);
+                    Ok(true)
+                }
+                n if n % 2 == 0 => Err(ChannelError::Timeout),
+                _ => Ok(false),
+            }
+        }
+
+        async fn close_channel(&self, _name: &str) -> Result<(), ChannelError> {
+            Ok(())
+        }
+
+        fn get_capabilities(&self) -> ChannelCapabilities {
+            ChannelCapabilities::default()
+        }
+
+        async fn handle_connection(&self, _stream: TcpStream, _channel_name: &str) -> Result<(), ChannelError> {
+            Ok(())
+        }
+
+        async fn health_check(&self, _name: &str) -> Result<bool, ChannelError> {
+            Ok(true)
+        }
+
+        async fn get_stats(&self, _name: &str) -> Result<ChannelStats, ChannelError> {
+            Ok(ChannelStats::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_open_channel_with_retry_backs_off_until_success() {
+        let mut manager = ChannelManager::new();
+        manager.register_channel("lte".to_string(), Box::new(Flaky(3.into())));
+        let started = std::time::Instant::now();
+        let attempts = manager.open_channel_with_retry("lte", ChannelType::Knox, 5, Duration::from_millis(10)).await.unwrap();
+        assert_eq!(attempts, 4);
+        assert_eq!(manager.list_active_channels(), vec![("lte".to_string(), ChannelType::Knox)]);
+        // Waits of 10, 20 and 40ms, each at least halved by jitter
+        assert!(started.elapsed() >= Duration::from_millis(35));
+
+        // Exhausted attempts leave the channel inactive and return the last error
+        manager.register_channel("wifi".to_string(), Box::new(Flaky(5.into())));
+        let err = manager.open_channel_with_retry("wifi", ChannelType::Raw, 3, Duration::from_millis(1)).await.unwrap_err();
+        assert!(matches!(err, ChannelError::ConnectionFailed(_)));
+        assert_eq!(manager.list_active_channels().len(), 1);
+
+        // An unregistered name is not retried
+        let started = std::time::Instant::now();
+        let err = manager.open_channel_with_retry("none", ChannelType::Raw, 5, Duration::from_secs(1)).await.unwrap_err();
+        assert!(matches!(err, ChannelError::ProviderNotFound(_)));
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_stats_sums_active_channels_by_type() {
+        let stats = |connections, bytes, errors, uptime| ChannelStats {
+            active_connections: connections,
+            total_connections: connections as u64 * 10,
+            bytes_transferred: bytes,
+            errors,
+            uptime_seconds: uptime,
+        };
+        let mut manager = ChannelManager::new();
+        manager.register_channel("http-a".to_string(), Box::new(FixedStats("http-a", stats(1, 100, 0, 5))));
+        manager.register_channel("http-b".to_string(), Box::new(FixedStats("http-b", stats(2, 200, 1, 9))));
+        manager.register_channel("socks".to_string(), Box::new(FixedStats("socks", stats(3, 300, 2, 7))));
+        manager.register_channel("broken".to_string(), Box::new(FixedStats("other", stats(9, 900, 9, 9))));
+        manager.register_channel("idle".to_string(), Box::new(FixedStats("idle", stats(9, 900, 9, 9))));
+        let opened = [
+            ("http-a", ChannelType::Http),
+            ("http-b", ChannelType::Http),
+            ("socks", ChannelType::Socks5),
+            ("broken", ChannelType::Raw),
+        ];
+        for (name, channel_type) in opened {
+            assert!(manager.open_channel(name, channel_type).await.unwrap());
+        }
+
+        // "idle" is registered but never opened, so it is left out
+        let total = manager.aggregate_stats().await;
+        assert_eq!((total.channels, total.unavailable), (3, 1));
+        assert_eq!(total.active_connections, 6);
+        assert_eq!(total.total_connections, 60);
+        assert_eq!(total.bytes_transferred, 600);
+        assert_eq!(total.errors, 3);
+
+        let http = &total.by_type[&ChannelType::Http];
+        assert_eq!((http.total_connections, http.bytes_transferred, http.errors, http.uptime_seconds), (30, 300, 1, 9));
+        assert_eq!(total.by_type[&ChannelType::Socks5].bytes_transferred, 300);
+        assert!(!total.by_type.contains_key(&ChannelType::Raw));
+    }
+
+    #[tokio::test]
+    async fn test_channel_for_routes_to_active_channels_only() {
+        let mut manager = ChannelManager::new();
+        manager.register_channel("socks".to_string(), Box::new(FixedStats("socks", ChannelStats::default())));
+        manager.register_channel("idle".to_string(), Box::new(FixedStats("idle", ChannelStats::default())));
+        assert!(manager.open_channel("socks", ChannelType::Socks5).await.unwrap());
+
+        let routes = RoutingTable::new().with_rule("*.video.example", "socks").with_rule("idle.example", "idle");
+        assert_eq!(manager.channel_for(&routes, "cdn.video.example"), Some(("socks".to_string(), ChannelType::Socks5)));
+        // A rule naming a closed channel and an unmatched host both go direct
+        assert_eq!(manager.channel_for(&routes, "idle.example"), None);
+        assert_eq!(manager.channel_for(&routes, "example.org"), None);
+    }
+}
@@ -0,0 +1,112 @@
+// Circuit breaker per upstream host
+// After `failure_threshold` consecutive connect failures a host is refused
+// straight away for `cooldown`. Then one probe is let through (half-open);
+// success closes the circuit, failure opens it for another cooldown.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Thresholds shared by every host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakerConfig {
+    /// Consecutive failures that open the circuit
+    pub failure_threshold: u32,
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self { failure_threshold: 5, cooldown: Duration::from_secs(30) }
+    }
+}
+
+/// A connect refused without dialling
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CircuitOpen {
+    pub host: String,
+    /// Until the next probe is allowed
+    pub retry_in: Duration,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "circuit open for {}, retry in {}ms", self.host, self.retry_in.as_millis())
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+impl From<CircuitOpen> for io::Error {
+    fn from(e: CircuitOpen) -> Self {
+        io::Error::new(io::ErrorKind::ConnectionRefused, e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HostState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    /// A probe is in flight; another is allowed if it never reports back
+    HalfOpen { since: Instant },
+}
+
+/// Circuit state per host, shared by every connection task
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    hosts: Mutex<HashMap<String, HostState>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self { config, hosts: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn config(&self) -> BreakerConfig {
+        self.config
+    }
+
+    /// Whether `host` may be dialled now. After the cooldown the first
+    /// caller becomes the probe and the rest keep failing until it reports.
+    pub fn check(&self, host: &str) -> Result<(), CircuitOpen> {
+        let now = Instant::now();
+        let mut hosts = self.hosts.lock().unwrap();
+        let Some(state) = hosts.get_mut(host) else {
+            return Ok(());
+        };
+        let reopens_at = match *state {
+            HostState::Closed { .. } => return Ok(()),
+            HostState::Open { until } => until,
+            HostState::HalfOpen { since } => since + self.config.cooldown,
+        };
+        if now >= reopens_at {
+            *state = HostState::HalfOpen { since: now };
+            return Ok(());
+        }
+        Err(CircuitOpen { host: host.to_string(), retry_in: reopens_at - now })
+    }
+
+    pub fn record_success(&self, host: &str) {
+        self.hosts.lock().unwrap().remove(host);
+    }
+
+    pub fn record_failure(&self, host: &str) {
+        let open = HostState::Open { until: Instant::now() + self.config.cooldown };
+        let mut hosts = self.hosts.lock().unwrap();
+        let state = hosts.entry(host.to_string()).or_insert(HostState::Closed { failures: 0 });
+        *state = match *state {
+            HostState::Closed { failures } if failures + 1 < self.config.failure_threshold => {
+                HostState::Closed { failures: failures + 1 }
+            }
+            _ => open,
+        };
+    }
+
+    /// Whether `host` is currently refused
+    pub fn is_open(&self, host: &str) -> bool {
+        matches!(self.hosts.lock().unwrap().get(host), Some(HostState::Open { until }) if *until > Instant::now())
+    }
+}
@@ -1,5 +1,19 @@
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
 use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::capture::CaptureConfig;
+use crate::circuit_breaker::{BreakerConfig, CircuitBreaker};
+use crate::conn_log::ConnLog;
+use crate::device_profile::DeviceProfile;
+use crate::integrated_proxy::IntegratedProxyConfig;
+use crate::knox_proxy::{BindOptions, ConnectResponse, EgressOptions, HeartbeatConfig, PortSelection, ReadHighWater, Socks5Auth, SourcePortRange};
+use crate::quota::{QuotaConfig, QuotaTracker};
+use crate::routing::DomainRule;
 
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -84,4 +98,711 @@ impl Config {
             env::set_var("EGRESS_BIND_IP", ip.to_string());
         }
     }
-}
\ No newline at end of file
+}
+/// Error from `load_from_toml`, with the 1-based line it was found on; 0
+/// when it is not about a particular line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            0 => f.write_str(&self.message),
+            line => write!(f, "line {}: {}", line, self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Serialize `config` as TOML that `load_from_toml` reads back unchanged.
+/// Unset optional values are left out rather than written as sentinels.
+pub fn to_toml(config: &IntegratedProxyConfig) -> String {
+    let mut out = String::new();
+    let knox = &config.knox_config;
+
+    out.push_str(&format!("bind_addresses = {}\n", toml_string_array(&config.bind_addresses)));
+    out.push_str(&format!("enable_p2p_subsumption = {}\n", config.enable_p2p_subsumption));
+    out.push_str(&format!("enable_pattern_matching = {}\n", config.enable_pattern_matching));
+    out.push_str(&format!("enable_gate_routing = {}\n", config.enable_gate_routing));
+    out.push_str(&format!("max_connections = {}\n", config.max_connections));
+    out.push_str(&format!("connection_timeout_seconds = {}\n", config.connection_timeout_seconds));
+    if !config.denied_clients.is_empty() {
+        let ips: Vec<String> = config.denied_clients.iter().map(|ip| ip.to_string()).collect();
+        out.push_str(&format!("denied_clients = {}\n", toml_string_array(&ips)));
+    }
+
+    out.push_str("\n[knox]\n");
+    out.push_str(&format!("bind_addr = {}\n", toml_string(&knox.bind_addr)));
+    if let Some(ref ports) = knox.port_range {
+        out.push_str(&format!("port_range = \"{}-{}\"\n", ports.start(), ports.end()));
+    }
+    if let Some(ref interface) = knox.listen_interface {
+        out.push_str(&format!("listen_interface = {}\n", toml_string(interface)));
+    }
+    out.push_str(&format!("reuse_port = {}\n", knox.listen_tuning.reuse_port));
+    out.push_str(&format!("socks_port = {}\n", knox.socks_port));
+    out.push_str(&format!("enable_knox_bypass = {}\n", knox.enable_knox_bypass));
+    out.push_str(&format!("enable_tethering_bypass = {}\n", knox.enable_tethering_bypass));
+    out.push_str(&format!("ttl_spoofing = {}\n", knox.ttl_spoofing));
+    out.push_str(&format!("max_connections = {}\n", knox.max_connections));
+    out.push_str(&format!("buffer_size = {}\n", knox.buffer_size));
+    out.push_str(&format!("tcp_fingerprint_enabled = {}\n", knox.tcp_fingerprint_enabled));
+    out.push_str(&format!("packet_fragmentation_enabled = {}\n", knox.packet_fragmentation_enabled));
+    out.push_str(&format!("tls_fingerprint_enabled = {}\n", knox.tls_fingerprint_enabled));
+    if let Some(lifetime) = knox.max_lifetime {
+        out.push_str(&format!("max_lifetime_seconds = {}\n", lifetime.as_secs()));
+    }
+    out.push_str(&format!("http_accounting = {}\n", knox.http_accounting));
+    out.push_str(&format!("udp_associate_enabled = {}\n", knox.udp_associate_enabled));
+    if let Some(limit) = knox.max_body_bytes {
+        out.push_str(&format!("max_body_bytes = {}\n", limit));
+    }
+    out.push_str(&format!("instance_name = {}\n", toml_string(&knox.instance_name)));
+    if let Some(ref alpn) = knox.upstream_alpn {
+        out.push_str(&format!("upstream_alpn = {}\n", toml_string_array(alpn)));
+    }
+    if !knox.warmup.is_empty() {
+        out.push_str(&format!("warmup = {}\n", toml_string_array(&knox.warmup)));
+    }
+    out.push_str(&format!("resolve_ttl_seconds = {}\n", knox.resolve_ttl.as_secs()));
+    if !knox.http_credentials.is_empty() {
+        out.push_str(&format!("http_credentials = {}\n", toml_string_array(&knox.http_credentials)));
+    }
+    if !knox.deny_targets.is_empty() {
+        out.push_str(&format!("deny_targets = {}\n", toml_string_array(&knox.deny_targets)));
+    }
+    if let Some(ref log) = knox.conn_log {
+        out.push_str(&format!("conn_log = {}\n", toml_string(&log.path().to_string_lossy())));
+    }
+    if let Socks5Auth::UserPass { ref username, ref password } = knox.socks5_auth {
+        out.push_str(&format!("socks5_username = {}\n", toml_string(username)));
+        out.push_str(&format!("socks5_password = {}\n", toml_string(password)));
+    }
+    if knox.connect_response.reason != ConnectResponse::default().reason {
+        out.push_str(&format!("connect_reason = {}\n", toml_string(&knox.connect_response.reason)));
+    }
+    if let Some(ref agent) = knox.connect_response.proxy_agent {
+        out.push_str(&format!("proxy_agent = {}\n", toml_string(agent)));
+    }
+    if let Some(ref peaks) = knox.read_peaks {
+        out.push_str(&format!("read_sample_every = {}\n", peaks.sample_every()));
+    }
+    if let Some(device) = knox.device_profile {
+        out.push_str(&format!("device_profile = {}\n", toml_string(device.name())));
+    }
+
+    out.push_str("\n[knox.egress]\n");
+    if let Some(ip) = knox.egress.bind_ip {
+        out.push_str(&format!("bind_ip = {}\n", toml_string(&ip.to_string())));
+    }
+    if let Some(ref interface) = knox.egress.interface {
+        out.push_str(&format!("interface = {}\n", toml_string(interface)));
+    }
+    out.push_str(&format!("proxy_protocol = {}\n", knox.egress.proxy_protocol));
+    if let Some(mss) = knox.egress.tcp_mss {
+        out.push_str(&format!("tcp_mss = {}\n", mss));
+    }
+    if let Some(ttl) = knox.egress.ttl {
+        out.push_str(&format!("ttl = {}\n", ttl));
+    }
+    if let Some(ref upstream) = knox.egress.upstream_proxy {
+        out.push_str(&format!("upstream_proxy = {}\n", toml_string(upstream)));
+    }
+    if let Some(ref ports) = knox.egress.source_ports {
+        out.push_str(&format!("source_ports = {}\n", toml_string(&ports.to_string())));
+        out.push_str(&format!("source_port_selection = {}\n", toml_string(&ports.selection.to_string())));
+    }
+
+    if knox.socks5_bind != BindOptions::default() {
+        out.push_str("\n[knox.bind]\n");
+        if let Some(ip) = knox.socks5_bind.listen_ip {
+            out.push_str(&format!("listen_ip = {}\n", toml_string(&ip.to_string())));
+        }
+        if let Some(ip) = knox.socks5_bind.advertise_ip {
+            out.push_str(&format!("advertise_ip = {}\n", toml_string(&ip.to_string())));
+        }
+    }
+
+    if let Some(ref capture) = knox.capture {
+        let ips: Vec<String> = capture.client_ips.iter().map(|ip| ip.to_string()).collect();
+        out.push_str("\n[knox.capture]\n");
+        out.push_str(&format!("client_ips = {}\n", toml_string_array(&ips)));
+        out.push_str(&format!("dir = {}\n", toml_string(&capture.dir.to_string_lossy())));
+    }
+
+    if let Some(ref heartbeat) = knox.heartbeat {
+        out.push_str("\n[knox.heartbeat]\n");
+        out.push_str(&format!("interval_ms = {}\n", heartbeat.interval.as_millis()));
+        out.push_str(&format!("jitter_ms = {}\n", heartbeat.jitter.as_millis()));
+        out.push_str(&format!("payload = {}\n", toml_string(&String::from_utf8_lossy(&heartbeat.payload))));
+        out.push_str(&format!("routes = {}\n", toml_string_array(&heartbeat.routes)));
+    }
+
+    if !knox.routes.is_empty() {
+        let rules: Vec<String> = knox.routes.rules().iter().map(|rule| rule.to_string()).collect();
+        out.push_str("\n[knox.routes]\n");
+        out.push_str(&format!("rules = {}\n", toml_string_array(&rules)));
+    }
+
+    let mut channels: Vec<_> = knox.channel_egress.iter().collect();
+    channels.sort_by_key(|(name, _)| name.as_str());
+    for (name, egress) in channels {
+        // Channels start from `[knox.egress]`, so only the differences are written
+        out.push_str(&format!("\n[knox.channel.{}]\n", name));
+        if let Some(ip) = egress.bind_ip.filter(|ip| knox.egress.bind_ip != Some(*ip)) {
+            out.push_str(&format!("bind_ip = {}\n", toml_string(&ip.to_string())));
+        }
+        if let Some(interface) = egress.interface.as_ref().filter(|i| knox.egress.interface.as_ref() != Some(*i)) {
+            out.push_str(&format!("interface = {}\n", toml_string(interface)));
+        }
+        if let Some(upstream) = egress.upstream_proxy.as_ref().filter(|u| knox.egress.upstream_proxy.as_ref() != Some(*u)) {
+            out.push_str(&format!("upstream_proxy = {}\n", toml_string(upstream)));
+        }
+    }
+
+    if let Some(ref breaker) = knox.egress.breaker {
+        let breaker = breaker.config();
+        out.push_str("\n[knox.breaker]\n");
+        out.push_str(&format!("failure_threshold = {}\n", breaker.failure_threshold));
+        out.push_str(&format!("cooldown_ms = {}\n", breaker.cooldown.as_millis()));
+    }
+
+    if let Some(ref tracker) = knox.quota {
+        let quota = tracker.config();
+        out.push_str("\n[knox.quota]\n");
+        if let Some(max) = quota.max_concurrent {
+            out.push_str(&format!("max_concurrent = {}\n", max));
+        }
+        if let Some(budget) = quota.daily_byte_budget {
+            out.push_str(&format!("daily_byte_budget = {}\n", budget));
+        }
+    }
+    out
+}
+
+/// Stands in for secrets in `redacted` output
+pub const REDACTED: &str = "<redacted>";
+
+/// The config a deployment actually runs with: defaults, then the TOML file
+/// at `path` if any, then `apply_env_overrides`. A file that cannot be read
+/// is reported as line 0.
+pub fn load_effective(path: Option<&Path>) -> Result<IntegratedProxyConfig, ConfigError> {
+    let mut config = match path {
+        Some(path) => {
+            let input = std::fs::read_to_string(path).map_err(|e| ConfigError {
+                line: 0,
+                message: format!("{}: {}", path.display(), e),
+            })?;
+            load_from_toml(&input)?
+        }
+        None => IntegratedProxyConfig::default(),
+    };
+    apply_env_overrides(&mut config);
+    Ok(config)
+}
+
+/// Let the variables `EgressOptions::from_env` reads win over a loaded
+/// file; unset or unparsable ones leave the file's values alone
+pub fn apply_env_overrides(config: &mut IntegratedProxyConfig) {
+    apply_overrides_from(config, |key| env::var(key).ok());
+}
+
+fn apply_overrides_from(config: &mut IntegratedProxyConfig, var: impl Fn(&str) -> Option<String>) {
+    let egress = &mut config.knox_config.egress;
+    if let Some(ip) = var("EGRESS_BIND_IP").and_then(|v| v.trim().parse().ok()) {
+        egress.bind_ip = Some(ip);
+    }
+    if let Some(interface) = var("EGRESS_INTERFACE").map(|v| v.trim().to_string()).filter(|v| !v.is_empty()) {
+        egress.interface = Some(interface);
+    }
+    if let Some(v) = var("EGRESS_PROXY_PROTOCOL") {
+        egress.proxy_protocol = matches!(v.trim(), "1" | "true" | "yes");
+    }
+    if let Some(mss) = var("EGRESS_TCP_MSS").and_then(|v| v.trim().parse().ok()) {
+        egress.tcp_mss = Some(mss);
+    }
+    if let Some(upstream) = var("PROXY_UPSTREAM").map(|v| v.trim().to_string()).filter(|v| !v.is_empty()) {
+        egress.upstream_proxy = Some(upstream);
+    }
+    if let Some(ports) = var("EGRESS_SOURCE_PORTS").and_then(|v| v.trim().parse::<SourcePortRange>().ok()) {
+        // The range comes from the environment; how ports are picked stays the file's
+        let selection = egress.source_ports.as_ref().map(|p| p.selection).unwrap_or_default();
+        egress.source_ports = Some(ports.with_selection(selection));
+    }
+}
+
+/// Copy of `config` safe to print: SOCKS5 and HTTP proxy passwords and the
+/// userinfo of upstream proxy addresses become `REDACTED`
+pub fn redacted(config: &IntegratedProxyConfig) -> IntegratedProxyConfig {
+    fn redact_userinfo(upstream: &mut Option<String>) {
+        if let Some(host) = upstream.as_ref().and_then(|u| u.rsplit_once('@')).map(|(_, host)| host.to_string()) {
+            *upstream = Some(format!("{}@{}", REDACTED, host));
+        }
+    }
+
+    let mut config = config.clone();
+    let knox = &mut config.knox_config;
+    if let Socks5Auth::UserPass { ref mut password, .. } = knox.socks5_auth {
+        *password = REDACTED.to_string();
+    }
+    for credential in &mut knox.http_credentials {
+        let user = credential.split_once(':').map_or("", |(user, _)| user);
+        *credential = format!("{}:{}", user, REDACTED);
+    }
+    redact_userinfo(&mut knox.egress.upstream_proxy);
+    for egress in knox.channel_egress.values_mut() {
+        redact_userinfo(&mut egress.upstream_proxy);
+    }
+    config
+}
+
+/// Parse the TOML written by `to_toml`. Keys that are absent keep their
+/// `IntegratedProxyConfig::default()` values; unknown keys are an error so
+/// typos do not silently fall back to defaults.
+pub fn load_from_toml(input: &str) -> Result<IntegratedProxyConfig, ConfigError> {
+    let mut config = IntegratedProxyConfig::default();
+    let mut section = String::new();
+    let mut capture_ips: Option<Vec<IpAddr>> = None;
+    let mut capture_dir: Option<PathBuf> = None;
+    let mut quota: Option<QuotaConfig> = None;
+    let mut breaker: Option<BreakerConfig> = None;
+    let mut source_port_selection: Option<PortSelection> = None;
+    // Keys set in each `[knox.channel.*]`, laid over `[knox.egress]` at the end
+    let mut channel_overrides: HashMap<String, EgressOptions> = HashMap::new();
+
+    for (index, raw) in input.lines().enumerate() {
+        let line_no = index + 1;
+        let err = |message: String| ConfigError { line: line_no, message };
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim().to_string();
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .map(|(k, v)| (k.trim(), TomlValue::parse(v.trim())))
+            .ok_or_else(|| err(format!("expected `key = value`, got {:?}", line)))?;
+        let value = value.map_err(err)?;
+        let knox = &mut config.knox_config;
+
+        match (section.as_str(), key) {
+            ("", "bind_addresses") => config.bind_addresses = value.strings().map_err(err)?,
+            ("", "enable_p2p_subsumption") => config.enable_p2p_subsumption = value.bool().map_err(err)?,
+            ("", "enable_pattern_matching") => config.enable_pattern_matching = value.bool().map_err(err)?,
+            ("", "enable_gate_routing") => config.enable_gate_routing = value.bool().map_err(err)?,
+            ("", "max_connections") => config.max_connections = value.int().map_err(err)?,
+            ("", "connection_timeout_seconds") => config.connection_timeout_seconds = value.int().map_err(err)?,
+            ("", "denied_clients") => {
+                let ips = value.strings().map_err(err)?;
+                let parsed = ips.iter().map(|ip| ip.parse::<IpAddr>()).collect::<Result<Vec<_>, _>>();
+                config.denied_clients = parsed.map_err(|e| err(e.to_string()))?;
+            }
+            ("knox", "bind_addr") => knox.bind_addr = value.string().map_err(err)?,
+            ("knox", "port_range") => {
+                knox.port_range = Some(crate::universal_listener::parse_port_range(&value.string().map_err(err)?).map_err(err)?)
+            }
+            ("knox", "listen_interface") => knox.listen_interface = Some(value.string().map_err(err)?),
+            ("knox", "reuse_port") => knox.listen_tuning.reuse_port = value.bool().map_err(err)?,
+            ("knox", "socks_port") => knox.socks_port = value.int().map_err(err)?,
+            ("knox", "enable_knox_bypass") => knox.enable_knox_bypass = value.bool().map_err(err)?,
+            ("knox", "enable_tethering_bypass") => knox.enable_tethering_bypass = value.bool().map_err(err)?,
+            ("knox", "ttl_spoofing") => knox.ttl_spoofing = value.int().map_err(err)?,
+            ("knox", "max_connections") => knox.max_connections = value.int().map_err(err)?,
+            ("knox", "buffer_size") => knox.buffer_size = value.int().map_err(err)?,
+            ("knox", "tcp_fingerprint_enabled") => knox.tcp_fingerprint_enabled = value.bool().map_err(err)?,
+            ("knox", "packet_fragmentation_enabled") => knox.packet_fragmentation_enabled = value.bool().map_err(err)?,
+            ("knox", "tls_fingerprint_enabled") => knox.tls_fingerprint_enabled = value.bool().map_err(err)?,
+            ("knox", "max_lifetime_seconds") => knox.max_lifetime = Some(Duration::from_secs(value.int().map_err(err)?)),
+            ("knox", "http_accounting") => knox.http_accounting = value.bool().map_err(err)?,
+            ("knox", "udp_associate_enabled") => knox.udp_associate_enabled = value.bool().map_err(err)?,
+            ("knox", "max_body_bytes") => knox.max_body_bytes = Some(value.int().map_err(err)?),
+            ("knox", "instance_name") => knox.instance_name = value.string().map_err(err)?,
+            ("knox", "upstream_alpn") => knox.upstream_alpn = Some(value.strings().map_err(err)?),
+            ("knox", "warmup") => knox.warmup = value.strings().map_err(err)?,
+            ("knox", "resolve_ttl_seconds") => knox.resolve_ttl = Duration::from_secs(value.int().map_err(err)?),
+            ("knox", "http_credentials") => knox.http_credentials = value.strings().map_err(err)?,
+            ("knox", "deny_targets") => knox.deny_targets = value.strings().map_err(err)?,
+            ("knox", "conn_log") => knox.conn_log = Some(Arc::new(ConnLog::new(value.string().map_err(err)?))),
+            ("knox", "socks5_username") => {
+                let username = value.string().map_err(err)?;
+                knox.socks5_auth = match std::mem::take(&mut knox.socks5_auth) {
+                    Socks5Auth::UserPass { password, .. } => Socks5Auth::UserPass { username, password },
+                    _ => Socks5Auth::UserPass { username, password: String::new() },
+                };
+            }
+            ("knox", "socks5_password") => {
+                let password = value.string().map_err(err)?;
+                knox.socks5_auth = match std::mem::take(&mut knox.socks5_auth) {
+                    Socks5Auth::UserPass { username, .. } => Socks5Auth::UserPass { username, password },
+                    _ => Socks5Auth::UserPass { username: String::new(), password },
+                };
+            }
+            ("knox", "connect_reason") => knox.connect_response.reason = value.string().map_err(err)?,
+            ("knox", "proxy_agent") => knox.connect_response.proxy_agent = Some(value.string().map_err(err)?),
+            ("knox", "read_sample_every") => knox.read_peaks = Some(Arc::new(ReadHighWater::new(value.int().map_err(err)?))),
+            ("knox", "device_profile") => {
+                let device: DeviceProfile = value.parsed().map_err(err)?;
+                *knox = std::mem::take(knox).with_device_profile(device);
+            }
+            ("knox.egress", "bind_ip") => knox.egress.bind_ip = Some(value.parsed().map_err(err)?),
+            ("knox.egress", "interface") => knox.egress.interface = Some(value.string().map_err(err)?),
+            ("knox.egress", "proxy_protocol") => knox.egress.proxy_protocol = value.bool().map_err(err)?,
+            ("knox.egress", "tcp_mss") => knox.egress.tcp_mss = Some(value.int().map_err(err)?),
+            ("knox.bind", "listen_ip") => knox.socks5_bind.listen_ip = Some(value.parsed().map_err(err)?),
+            ("knox.bind", "advertise_ip") => knox.socks5_bind.advertise_ip = Some(value.parsed().map_err(err)?),
+            ("knox.egress", "ttl") => knox.egress.ttl = Some(value.int().map_err(err)?),
+            ("knox.egress", "upstream_proxy") => knox.egress.upstream_proxy = Some(value.string().map_err(err)?),
+            ("knox.egress", "source_ports") => knox.egress.source_ports = Some(value.parsed().map_err(err)?),
+            ("knox.egress", "source_port_selection") => source_port_selection = Some(value.parsed().map_err(err)?),
+            ("knox.capture", "client_ips") => {
+                let ips = value.strings().map_err(err)?;
+                let parsed = ips.iter().map(|ip| ip.parse::<IpAddr>()).collect::<Result<Vec<_>, _>>();
+                capture_ips = Some(parsed.map_err(|e| err(e.to_string()))?);
+            }
+            ("knox.capture", "dir") => capture_dir = Some(PathBuf::from(value.string().map_err(err)?)),
+            ("knox.heartbeat", "interval_ms") => {
+                knox.heartbeat.get_or_insert_with(HeartbeatConfig::default).interval = Duration::from_millis(value.int().map_err(err)?);
+            }
+            ("knox.heartbeat", "jitter_ms") => {
+                knox.heartbeat.get_or_insert_with(HeartbeatConfig::default).jitter = Duration::from_millis(value.int().map_err(err)?);
+            }
+            ("knox.heartbeat", "payload") => {
+                knox.heartbeat.get_or_insert_with(HeartbeatConfig::default).payload = value.string().map_err(err)?.into_bytes();
+            }
+            ("knox.heartbeat", "routes") => {
+                knox.heartbeat.get_or_insert_with(HeartbeatConfig::default).routes = value.strings().map_err(err)?;
+            }
+            ("knox.quota", "max_concurrent") => {
+                quota.get_or_insert_with(QuotaConfig::default).max_concurrent = Some(value.int().map_err(err)?);
+            }
+            ("knox.quota", "daily_byte_budget") => {
+                quota.get_or_insert_with(QuotaConfig::default).daily_byte_budget = Some(value.int().map_err(err)?);
+            }
+            ("knox.breaker", "failure_threshold") => {
+                breaker.get_or_insert_with(BreakerConfig::default).failure_threshold = value.int().map_err(err)?;
+            }
+            ("knox.breaker", "cooldown_ms") => {
+                breaker.get_or_insert_with(BreakerConfig::default).cooldown = Duration::from_millis(value.int().map_err(err)?);
+            }
+            ("knox.routes", "rules") => {
+                let rules = value.strings().map_err(err)?;
+                for rule in rules {
+                    knox.routes.push(rule.parse::<DomainRule>().map_err(err)?);
+                }
+            }
+            (section, "bind_ip") if section.starts_with("knox.channel.") => {
+                let egress = channel_overrides.entry(section["knox.channel.".len()..].to_string()).or_default();
+                egress.bind_ip = Some(value.parsed().map_err(err)?);
+            }
+            (section, "interface") if section.starts_with("knox.channel.") => {
+                let egress = channel_overrides.entry(section["knox.channel.".len()..].to_string()).or_default();
+                egress.interface = Some(value.string().map_err(err)?);
+            }
+            (section, "upstream_proxy") if section.starts_with("knox.channel.") => {
+                let egress = channel_overrides.entry(section["knox.channel.".len()..].to_string()).or_default();
+                egress.upstream_proxy = Some(value.string().map_err(err)?);
+            }
+            (section, key) => {
+                let name = if section.is_empty() { key.to_string() } else { format!("{}.{}", section, key) };
+                return Err(err(format!("unknown key `{}`", name)));
+            }
+        }
+    }
+
+    if let Some(client_ips) = capture_ips {
+        config.knox_config.capture = Some(CaptureConfig {
+            client_ips,
+            dir: capture_dir.unwrap_or_else(std::env::temp_dir),
+        });
+    }
+    if let Some(quota) = quota {
+        config.knox_config.quota = Some(Arc::new(QuotaTracker::new(quota)));
+    }
+    if let Some(breaker) = breaker {
+        config.knox_config.egress.breaker = Some(Arc::new(CircuitBreaker::new(breaker)));
+    }
+    if let (Some(ports), Some(selection)) = (config.knox_config.egress.source_ports.take(), source_port_selection) {
+        config.knox_config.egress.source_ports = Some(ports.with_selection(selection));
+    }
+    for (name, overrides) in channel_overrides {
+        let mut egress = config.knox_config.egress.clone();
+        if overrides.bind_ip.is_some() {
+            egress.bind_ip = overrides.bind_ip;
+        }
+        if overrides.interface.is_some() {
+            egress.interface = overrides.interface;
+        }
+        if overrides.upstream_proxy.is_some() {
+            egress.upstream_proxy = overrides.upstream_proxy;
+        }
+        config.knox_config.channel_egress.insert(name, egress);
+    }
+    Ok(config)
+}
+
+/// The value types `to_toml` emits
+enum TomlValue {
+    Bool(bool),
+    Int(u64),
+    Str(String),
+    Array(Vec<String>),
+}
+
+impl TomlValue {
+    fn parse(text: &str) -> Result<Self, String> {
+        match text {
+            "true" => return Ok(TomlValue::Bool(true)),
+            "false" => return Ok(TomlValue::Bool(false)),
+            _ => {}
+        }
+        if text.starts_with('"') {
+            let (s, rest) = parse_toml_string(text)?;
+            return if rest.trim().is_empty() || rest.trim().starts_with('#') {
+                Ok(TomlValue::Str(s))
+            } else {
+                Err(format!("trailing data after string: {:?}", rest))
+            };
+        }
+        if let Some(body) = text.strip_prefix('[') {
+            let mut items = Vec::new();
+            let mut rest = body.trim_start();
+            loop {
+                if let Some(after) = rest.strip_prefix(']') {
+                    return if after.trim().is_empty() { Ok(TomlValue::Array(items)) } else { Err("trailing data after array".to_string()) };
+                }
+                let (item, after) = parse_toml_string(rest)?;
+                items.push(item);
+                rest = after.trim_start();
+                rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+            }
+        }
+        let number = text.split('#').next().unwrap_or("").trim().replace('_', "");
+        number
+            .parse()
+            .map(TomlValue::Int)
+            .map_err(|_| format!("unsupported value {:?}", text))
+    }
+
+    fn bool(self) -> Result<bool, String> {
+        match self {
+            TomlValue::Bool(b) => Ok(b),
+            _ => Err("expected a boolean".to_string()),
+        }
+    }
+
+    fn int<T: TryFrom<u64>>(self) -> Result<T, String> {
+        match self {
+            TomlValue::Int(n) => T::try_from(n).map_err(|_| format!("{} is out of range", n)),
+            _ => Err("expected an integer".to_string()),
+        }
+    }
+
+    fn string(self) -> Result<String, String> {
+        match self {
+            TomlValue::Str(s) => Ok(s),
+            _ => Err("expected a string".to_string()),
+        }
+    }
+
+    fn parsed<T: std::str::FromStr>(self) -> Result<T, String>
+    where
+        T::Err: fmt::Display,
+    {
+        self.string()?.parse().map_err(|e: T::Err| e.to_string())
+    }
+
+    fn strings(self) -> Result<Vec<String>, String> {
+        match self {
+            TomlValue::Array(items) => Ok(items),
+            _ => Err("expected an array of strings".to_string()),
+        }
+    }
+}
+
+/// Parse a leading basic string, returning it and the remaining input
+fn parse_toml_string(text: &str) -> Result<(String, &str), String> {
+    let body = text.strip_prefix('"').ok_or_else(|| format!("expected a string at {:?}", text))?;
+    let mut out = String::new();
+    let mut chars = body.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((out, &body[i + 1..])),
+            '\\' => match chars.next().map(|(_, e)| e) {
+                Some('"') => out.push('"'),
+                Some('\\') => out.push('\\'),
+                Some('n') => out.push('\n'),
+                Some('r') => out.push('\r'),
+                Some('t') => out.push('\t'),
+                other => return Err(format!("unsupported escape \\{}", other.unwrap_or(' '))),
+            },
+            c => out.push(c),
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+fn toml_string(s: &str) -> String {
+    let escaped = s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n").replace('\r', "\\r").replace('\t', "\\t");
+    format!("\"{}\"", escaped)
+}
+
+fn toml_string_array(items: &[String]) -> String {
+    let quoted: Vec<String> = items.iter().map(|s| toml_string(s)).collect();
+    format!("[{}]", quoted.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toml_round_trip_preserves_changes() {
+        let mut config = load_from_toml(&to_toml(&IntegratedProxyConfig::default())).unwrap();
+        config.bind_addresses = vec!["127.0.0.1:9090".to_string(), "[::1]:1081".to_string()];
+        config.enable_gate_routing = false;
+        config.connection_timeout_seconds = 42;
+        config.knox_config.socks_port = 2080;
+        config.knox_config.port_range = Some(40000..=49999);
+        config.knox_config.listen_interface = Some("swlan0".to_string());
+        config.knox_config.listen_tuning.reuse_port = true;
+        config.knox_config.resolve_ttl = Duration::from_secs(5);
+        config.knox_config.udp_associate_enabled = false;
+        config.knox_config.max_lifetime = Some(Duration::from_secs(600));
+        config.knox_config.instance_name = "roof \"antenna\"".to_string();
+        config.knox_config.egress.bind_ip = Some("fe80::1".parse().unwrap());
+        config.knox_config.capture = Some(CaptureConfig {
+            client_ips: vec!["192.168.43.20".parse().unwrap()],
+            dir: PathBuf::from("/tmp/litebike-capture"),
+        });
+        config.knox_config.quota = Some(Arc::new(QuotaTracker::new(QuotaConfig {
+            max_concurrent: Some(4),
+            daily_byte_budget: None,
+        })));
+        config.knox_config.heartbeat = Some(HeartbeatConfig {
+            routes: vec!["mqtt.example".to_string()],
+            ..Default::default()
+        });
+        config.knox_config.socks5_bind.advertise_ip = Some("203.0.113.7".parse().unwrap());
+        config.knox_config.egress.upstream_proxy = Some("proxy.corp.example:3128".to_string());
+        config.knox_config.egress.source_ports = Some(crate::knox_proxy::SourcePortRange::new(40000, 40999).with_selection(PortSelection::Random));
+        config.knox_config.egress.breaker = Some(Arc::new(CircuitBreaker::new(BreakerConfig {
+            failure_threshold: 3,
+            cooldown: Duration::from_secs(10),
+        })));
+        config.knox_config.socks5_auth = Socks5Auth::UserPass { username: "bike".to_string(), password: "p\"w".to_string() };
+        config.knox_config.routes = "*.video.example=wifi".parse().unwrap();
+        config.knox_config.channel_egress.insert(
+            "wifi".to_string(),
+            crate::knox_proxy::EgressOptions {
+                bind_ip: Some("192.168.1.5".parse().unwrap()),
+                interface: Some("wlan0".to_string()),
+                ..Default::default()
+            },
+        );
+
+        let reloaded = load_from_toml(&to_toml(&config)).unwrap();
+        assert_eq!(reloaded.bind_addresses, config.bind_addresses);
+        assert!(!reloaded.enable_gate_routing);
+        assert_eq!(reloaded.connection_timeout_seconds, 42);
+        assert_eq!(reloaded.knox_config.socks_port, 2080);
+        assert_eq!(reloaded.knox_config.port_range, Some(40000..=49999));
+        assert_eq!(reloaded.knox_config.listen_interface.as_deref(), Some("swlan0"));
+        assert_eq!(reloaded.knox_config.resolve_ttl, Duration::from_secs(5));
+        assert_eq!(reloaded.knox_config.listen_tuning, crate::tcp_fingerprint::TcpTuningOptions { reuse_port: true, ..crate::tcp_fingerprint::TcpTuningOptions::listener() });
+        assert!(!reloaded.knox_config.udp_associate_enabled);
+        assert_eq!(reloaded.knox_config.max_lifetime, Some(Duration::from_secs(600)));
+        assert_eq!(reloaded.knox_config.max_body_bytes, None);
+        assert_eq!(reloaded.knox_config.instance_name, "roof \"antenna\"");
+        assert_eq!(reloaded.knox_config.egress.bind_ip, config.knox_config.egress.bind_ip);
+        assert_eq!(reloaded.knox_config.egress.upstream_proxy.as_deref(), Some("proxy.corp.example:3128"));
+        assert_eq!(reloaded.knox_config.routes, config.knox_config.routes);
+        assert_eq!(reloaded.knox_config.egress.source_ports, config.knox_config.egress.source_ports);
+        assert_eq!(reloaded.knox_config.egress_for("cdn.video.example:443").bind_ip, Some("192.168.1.5".parse().unwrap()));
+        assert_eq!(reloaded.knox_config.egress_for("cdn.video.example:443").interface.as_deref(), Some("wlan0"));
+        assert_eq!(reloaded.knox_config.egress_for("example.org:443").bind_ip, config.knox_config.egress.bind_ip);
+        assert_eq!(reloaded.knox_config.egress_for("cdn.video.example:443").upstream_proxy.as_deref(), Some("proxy.corp.example:3128"));
+        assert_eq!(
+            reloaded.knox_config.egress.breaker.unwrap().config(),
+            BreakerConfig { failure_threshold: 3, cooldown: Duration::from_secs(10) }
+        );
+        let capture = reloaded.knox_config.capture.unwrap();
+        assert_eq!(capture.client_ips, vec!["192.168.43.20".parse::<IpAddr>().unwrap()]);
+        assert_eq!(capture.dir, PathBuf::from("/tmp/litebike-capture"));
+        let quota = reloaded.knox_config.quota.unwrap().config();
+        assert_eq!(quota, QuotaConfig { max_concurrent: Some(4), daily_byte_budget: None });
+        assert_eq!(reloaded.knox_config.heartbeat, config.knox_config.heartbeat);
+        assert_eq!(reloaded.knox_config.socks5_auth, config.knox_config.socks5_auth);
+        assert_eq!(reloaded.knox_config.socks5_bind, config.knox_config.socks5_bind);
+        assert_eq!(to_toml(&load_from_toml(&to_toml(&config)).unwrap()), to_toml(&config));
+
+        let err = load_from_toml("[knox]\nsocks_prot = 1\n").unwrap_err();
+        assert_eq!(err.line, 2);
+    }
+
+    #[test]
+    fn test_channel_egress_starts_from_the_default_egress() {
+        let config = load_from_toml(concat!(
+            "[knox.channel.wifi]\n",
+            "interface = \"wlan0\"\n",
+            "[knox.egress]\n",
+            "tcp_mss = 1400\n",
+            "proxy_protocol = true\n",
+            "interface = \"rmnet_data0\"\n",
+            "[knox.breaker]\n",
+            "failure_threshold = 2\n",
+        ))
+        .unwrap();
+        let wifi = &config.knox_config.channel_egress["wifi"];
+        assert_eq!(wifi.interface.as_deref(), Some("wlan0"));
+        assert_eq!(wifi.tcp_mss, Some(1400));
+        assert!(wifi.proxy_protocol);
+        assert!(wifi.breaker.is_some());
+        assert_eq!(config.knox_config.egress.interface.as_deref(), Some("rmnet_data0"));
+        assert!(to_toml(&config).contains("[knox.channel.wifi]\ninterface = \"wlan0\"\n"));
+    }
+
+    #[test]
+    fn test_effective_dump_applies_env_and_redacts() {
+        let mut config = load_from_toml(concat!(
+            "[knox]\n",
+            "socks5_username = \"bike\"\n",
+            "socks5_password = \"hunter2\"\n",
+            "http_credentials = [\"alice:s3cret\"]\n",
+            "[knox.egress]\n",
+            "tcp_mss = 1400\n",
+            "source_ports = \"40000-40999\"\n",
+            "source_port_selection = \"random\"\n",
+        ))
+        .unwrap();
+        apply_overrides_from(&mut config, |key| match key {
+            "EGRESS_TCP_MSS" => Some("1300".to_string()),
+            "EGRESS_SOURCE_PORTS" => Some("50000-50009".to_string()),
+            "PROXY_UPSTREAM" => Some("ops:pw@proxy.corp.example:3128".to_string()),
+            "EGRESS_BIND_IP" => Some("not an ip".to_string()),
+            _ => None,
+        });
+
+        let dump = config.to_effective_string();
+        assert!(dump.contains("tcp_mss = 1300\n"), "{}", dump);
+        assert!(dump.contains("source_ports = \"50000-50009\"\n"));
+        assert!(dump.contains("source_port_selection = \"random\"\n"));
+        assert!(!dump.contains("bind_ip"));
+        assert!(dump.contains("socks5_username = \"bike\"\n"));
+        assert!(dump.contains("socks5_password = \"<redacted>\"\n"));
+        assert!(dump.contains("http_credentials = [\"alice:<redacted>\"]\n"));
+        assert!(dump.contains("upstream_proxy = \"<redacted>@proxy.corp.example:3128\"\n"));
+        for secret in ["hunter2", "s3cret", "ops:pw"] {
+            assert!(!dump.contains(secret), "{} leaked", secret);
+        }
+        // Redaction works on a copy and the dump still loads
+        assert_eq!(config.knox_config.http_credentials, vec!["alice:s3cret".to_string()]);
+        assert!(load_from_toml(&dump).is_ok());
+    }
+}
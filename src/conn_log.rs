@@ -0,0 +1,358 @@
+// Connection log - compact binary records of connection metadata, no payloads
+// Lighter than JSONL at high volumes; `litebike conn-log-dump` prints it back
+//
+// File layout: the header `LBCL` + version byte, then records back to back.
+// All integers are big-endian (`bitbang_*`). One record:
+//
+//   u32  started, unix seconds
+//   u32  duration, milliseconds
+//   u8   protocol, a `ProtocolType` discriminant
+//   u8   close reason: 0 unknown, 1 client, 2 upstream, 3 max lifetime, 4 quota
+//   u8   client family, `AddressType` (0x01 / 0x04), then 4 or 16 address bytes
+//   u16  client port
+//   u8   target kind, `AddressType`: 4 or 16 address bytes, or u8 length + name
+//   u16  target port
+//   u64  bytes client to upstream
+//   u64  bytes upstream to client
+//
+// IPv6 zones are not recorded.
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::knox_proxy::CloseReason;
+use crate::util::BackgroundWriter;
+use crate::types::{bitbang_u16, bitbang_u32, bitbang_u64, unbang_u16, unbang_u32, unbang_u64, AddressType, ProtocolType, TargetAddress};
+
+const MAGIC: &[u8; 4] = b"LBCL";
+const VERSION: u8 = 1;
+
+/// Metadata of one finished connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnRecord {
+    /// Unix seconds
+    pub started: u32,
+    pub duration: Duration,
+    pub protocol: ProtocolType,
+    pub close: Option<CloseReason>,
+    pub client: SocketAddr,
+    pub target: TargetAddress,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+impl ConnRecord {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(64);
+        out.extend_from_slice(&bitbang_u32(self.started));
+        out.extend_from_slice(&bitbang_u32(self.duration.as_millis().min(u32::MAX as u128) as u32));
+        out.push(self.protocol as u8);
+        out.push(close_code(self.close));
+        push_ip(&mut out, self.client.ip());
+        out.extend_from_slice(&bitbang_u16(self.client.port()));
+        match &self.target {
+            TargetAddress::Ipv4 { addr, .. } => push_ip(&mut out, IpAddr::V4(*addr)),
+            TargetAddress::Ipv6 { addr, .. } => push_ip(&mut out, IpAddr::V6(*addr)),
+            TargetAddress::Domain { host, .. } => {
+                // Longer names are cut at a char boundary so they still read back as UTF-8
+                let mut len = host.len().min(u8::MAX as usize);
+                while !host.is_char_boundary(len) {
+                    len -= 1;
+                }
+                let name = &host.as_bytes()[..len];
+                out.push(AddressType::DomainName as u8);
+                out.push(name.len() as u8);
+                out.extend_from_slice(name);
+            }
+        }
+        out.extend_from_slice(&bitbang_u16(self.target.port()));
+        out.extend_from_slice(&bitbang_u64(self.bytes_up));
+        out.extend_from_slice(&bitbang_u64(self.bytes_down));
+        out
+    }
+
+    /// Read one record; `Ok(None)` at a clean end of input
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut fixed = [0u8; 10];
+        match reader.read(&mut fixed[..1])? {
+            0 => return Ok(None),
+            _ => reader.read_exact(&mut fixed[1..])?,
+        }
+        let started = unbang_u32(&fixed[0..4]);
+        let duration = Duration::from_millis(unbang_u32(&fixed[4..8]) as u64);
+        let protocol = ProtocolType::try_from(fixed[8]).map_err(|b| invalid(format!("unknown protocol 0x{:02x}", b)))?;
+        let close = close_from_code(fixed[9])?;
+
+        let family = read_u8(reader)?;
+        let client_ip = read_ip(reader, family)?;
+        let client = SocketAddr::new(client_ip, read_u16(reader)?);
+
+        let kind = read_u8(reader)?;
+        let host = if kind == AddressType::DomainName as u8 {
+            let mut name = vec![0u8; read_u8(reader)? as usize];
+            reader.read_exact(&mut name)?;
+            String::from_utf8(name).map_err(|_| invalid("target name is not UTF-8".to_string()))?
+        } else {
+            read_ip(reader, kind)?.to_string()
+        };
+        let target = TargetAddress::new(&host, read_u16(reader)?);
+
+        let mut counts = [0u8; 16];
+        reader.read_exact(&mut counts)?;
+        Ok(Some(Self {
+            started,
+            duration,
+            protocol,
+            close,
+            client,
+            target,
+            bytes_up: unbang_u64(&counts[..8]),
+            bytes_down: unbang_u64(&counts[8..]),
+        }))
+    }
+}
+
+impl fmt::Display for ConnRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let close = match self.close {
+            Some(reason) => format!("{:?}", reason),
+            None => "-".to_string(),
+        };
+        write!(
+            f,
+            "{} {:>8}ms {:<8} {} -> {} up={} down={} close={}",
+            self.started,
+            self.duration.as_millis(),
+            self.protocol.to_string(),
+            self.client,
+            self.target,
+            self.bytes_up,
+            self.bytes_down,
+            close
+        )
+    }
+}
+
+/// Write the file header
+pub fn write_header<W: Write>(writer: &mut W) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])
+}
+
+/// Check the file header and return every record after it
+pub fn read_records<R: Read>(mut reader: R) -> io::Result<Vec<ConnRecord>> {
+    let mut header = [0u8; 5];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        return Err(invalid("not a connection log".to_string()));
+    }
+    if header[4] != VERSION {
+        return Err(invalid(format!("unsupported connection log version {}", header[4])));
+    }
+    let mut records = Vec::new();
+    while let Some(record) = ConnRecord::read_from(&mut reader)? {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Append-only log file shared by every connection task. The writer (and
+/// the file) starts on the first record, so configuring a log has no side
+/// effects. Records are written off the connection tasks; if the file
+/// cannot be written the log warns once and stops.
+#[derive(Debug)]
+pub struct ConnLog {
+    path: PathBuf,
+    writer: OnceLock<BackgroundWriter>,
+}
+
+impl ConnLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), writer: OnceLock::new() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Queue `record`; the header goes first if the file is new
+    pub fn record(&self, record: &ConnRecord) {
+        self.writer().write(record.encode());
+    }
+
+    /// Wait until the queued records are written
+    pub async fn flush(&self) {
+        if let Some(writer) = self.writer.get() {
+            writer.flush().await;
+        }
+    }
+
+    fn writer(&self) -> &BackgroundWriter {
+        self.writer.get_or_init(|| {
+            let path = self.path.clone();
+            BackgroundWriter::spawn(format!("connection log {}", self.path.display()), move || {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                if file.metadata()?.len() == 0 {
+                    write_header(&mut file)?;
+                }
+                Ok(file)
+            })
+        })
+    }
+}
+
+/// Unix seconds now, as stored in `ConnRecord::started`
+pub fn unix_now() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as u32
+}
+
+fn close_code(close: Option<CloseReason>) -> u8 {
+    match close {
+        None => 0,
+        Some(CloseReason::ClientClosed) => 1,
+        Some(CloseReason::UpstreamClosed) => 2,
+        Some(CloseReason::MaxLifetime) => 3,
+        Some(CloseReason::QuotaExceeded) => 4,
+    }
+}
+
+fn close_from_code(code: u8) -> io::Result<Option<CloseReason>> {
+    match code {
+        0 => Ok(None),
+        1 => Ok(Some(CloseReason::ClientClosed)),
+        2 => Ok(Some(CloseReason::UpstreamClosed)),
+        3 => Ok(Some(CloseReason::MaxLifetime)),
+        4 => Ok(Some(CloseReason::QuotaExceeded)),
+        other => Err(invalid(format!("unknown close reason {}", other))),
+    }
+}
+
+fn push_ip(out: &mut Vec<u8>, ip: IpAddr) {
+    match ip {
+        IpAddr::V4(ip) => {
+            out.push(AddressType::Ipv4 as u8);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(AddressType::Ipv6 as u8);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+}
+
+fn read_ip<R: Read>(reader: &mut R, family: u8) -> io::Result<IpAddr> {
+    if family == AddressType::Ipv4 as u8 {
+        let mut octets = [0u8; 4];
+        reader.read_exact(&mut octets)?;
+        Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+    } else if family == AddressType::Ipv6 as u8 {
+        let mut octets = [0u8; 16];
+        reader.read_exact(&mut octets)?;
+        Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+    } else {
+        Err(invalid(format!("unknown address type 0x{:02x}", family)))
+    }
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut byte = [0u8; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut bytes = [0u8; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(unbang_u16(&bytes))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_records_round_trip_through_log_file() {
+        let records = vec![
+            ConnRecord {
+                started: 1_760_000_000,
+                duration: Duration::from_millis(1234),
+                protocol: ProtocolType::Connect,
+                close: Some(CloseReason::UpstreamClosed),
+                client: "192.168.43.20:51000".parse().unwrap(),
+                target: TargetAddress::new("example.com", 443),
+                bytes_up: 517,
+                bytes_down: 5_000_000_000,
+            },
+            ConnRecord {
+                started: 1_760_000_005,
+                duration: Duration::ZERO,
+                protocol: ProtocolType::Socks5,
+                close: Some(CloseReason::QuotaExceeded),
+                client: "[fe80::2]:40000".parse().unwrap(),
+                target: TargetAddress::new("2001:db8::1", 80),
+                bytes_up: 0,
+                bytes_down: 0,
+            },
+            ConnRecord {
+                started: 1_760_000_009,
+                duration: Duration::from_secs(60),
+                protocol: ProtocolType::Http,
+                close: None,
+                client: "10.0.0.2:1234".parse().unwrap(),
+                target: TargetAddress::new("93.184.216.34", 8080),
+                bytes_up: 80,
+                bytes_down: 1500,
+            },
+        ];
+
+        let path = std::env::temp_dir().join(format!("litebike-connlog-{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let log = ConnLog::new(&path);
+        for record in &records {
+            log.record(record);
+        }
+        log.flush().await;
+        // A fresh handle appends without a second header
+        let again = ConnLog::new(&path);
+        again.record(&records[0]);
+        again.flush().await;
+
+        let read = read_records(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(read.len(), 4);
+        assert_eq!(read[..3], records[..]);
+        assert_eq!(read[3], records[0]);
+        assert!(read[0].to_string().contains("192.168.43.20:51000 -> example.com:443"));
+
+        let mut truncated = std::fs::read(&path).unwrap();
+        truncated.truncate(truncated.len() - 3);
+        assert!(read_records(&truncated[..]).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_long_target_name_truncates_on_char_boundary() {
+        // 254 ASCII bytes, then a two-byte char straddling the 255 limit
+        let host = format!("{}é.example", "a".repeat(254));
+        let record = ConnRecord {
+            started: 0,
+            duration: Duration::ZERO,
+            protocol: ProtocolType::Connect,
+            close: None,
+            client: "10.0.0.2:1234".parse().unwrap(),
+            target: TargetAddress::Domain { host, port: 443 },
+            bytes_up: 0,
+            bytes_down: 0,
+        };
+        let read = ConnRecord::read_from(&mut &record.encode()[..]).unwrap().unwrap();
+        assert_eq!(read.target, TargetAddress::Domain { host: "a".repeat(254), port: 443 });
+    }
+}
@@ -0,0 +1,105 @@
+// Deterministic mode - one switch that makes the randomised subsystems reproducible
+// Set LITEBIKE_DETERMINISTIC=1 (read once, on first use) or call
+// `set_deterministic(true)`. While it is on, every RNG handed out by `rng()`
+// starts from `DETERMINISTIC_SEED` and jitter collapses to its fixed part.
+//
+// Affected:
+//   tls_fingerprint  profile selection, rotation interval, hello random,
+//                    key share bytes, cipher shuffle, handshake timing
+//   packet_fragment  fragment sizes, order, overlaps, sequence start,
+//                    inter-fragment delay, flush decisions
+//   tcp_fingerprint  profile selection, rotation interval, ISN
+//   knox_proxy       heartbeat jitter, random source-port selection
+//   util             Backoff jitter (channel reconnects, parent sync retries)
+//   universal_listener  the port `bind_in_range` picks
+//
+// Not affected: Shadowsocks salts and crypto gate nonces, which must never
+// repeat under one key, ICMP echo identifiers and QUIC connection IDs.
+// The key share in a deterministic ClientHello is random bytes rather than
+// a real x25519 key, so such hellos are for tests, not for handshakes.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+/// Seed of every RNG in deterministic mode ("litebike" in ASCII)
+pub const DETERMINISTIC_SEED: u64 = 0x6c69_7465_6269_6b65;
+
+const UNSET: u8 = 0;
+const OFF: u8 = 1;
+const ON: u8 = 2;
+
+static MODE: AtomicU8 = AtomicU8::new(UNSET);
+
+/// Whether deterministic mode is on
+pub fn is_deterministic() -> bool {
+    match MODE.load(Ordering::Relaxed) {
+        UNSET => {
+            let on = std::env::var("LITEBIKE_DETERMINISTIC")
+                .map(|v| matches!(v.trim(), "1" | "true" | "yes"))
+                .unwrap_or(false);
+            // A concurrent set_deterministic wins over the environment
+            let _ = MODE.compare_exchange(UNSET, if on { ON } else { OFF }, Ordering::Relaxed, Ordering::Relaxed);
+            MODE.load(Ordering::Relaxed) == ON
+        }
+        mode => mode == ON,
+    }
+}
+
+/// Turn deterministic mode on or off for the whole process, overriding
+/// `LITEBIKE_DETERMINISTIC`
+pub fn set_deterministic(on: bool) {
+    MODE.store(if on { ON } else { OFF }, Ordering::Relaxed);
+}
+
+/// A fresh RNG: seeded with `DETERMINISTIC_SEED` in deterministic mode,
+/// from the OS otherwise. Components keep the one they are built with, so
+/// two instances built in deterministic mode draw identical streams.
+pub fn rng() -> StdRng {
+    if is_deterministic() {
+        seeded_rng()
+    } else {
+        StdRng::from_entropy()
+    }
+}
+
+/// The RNG every component starts from in deterministic mode; also backs
+/// the per-instance `deterministic()` builders
+pub fn seeded_rng() -> StdRng {
+    StdRng::seed_from_u64(DETERMINISTIC_SEED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_fragment::{MobileFragmentPattern, PacketFragmenter};
+    use crate::tls_fingerprint::TlsFingerprintManager;
+
+    #[test]
+    fn test_deterministic_runs_are_identical() {
+        // The per-instance builders, so parallel tests keep their randomness
+        let run = || {
+            let mut tls = TlsFingerprintManager::new().deterministic();
+            tls.set_shuffle_ciphers(true);
+            let hellos = vec![
+                tls.generate_client_hello("example.com").unwrap(),
+                tls.generate_client_hello("example.org").unwrap(),
+            ];
+
+            let mut fragmenter = PacketFragmenter::new(MobileFragmentPattern::Aggressive).deterministic();
+            let fragments = fragmenter.fragment_packet(&hellos[0]);
+            let boundaries: Vec<(u16, usize)> = fragments.iter().map(|f| (f.sequence, f.data.len())).collect();
+            (tls.current_profile().name().to_string(), hellos, boundaries)
+        };
+
+        let (profile, hellos, boundaries) = run();
+        assert_eq!(run(), (profile, hellos.clone(), boundaries.clone()));
+        // Still a stream, not a constant: later hellos get fresh randoms
+        assert_ne!(hellos[0][11..43], hellos[1][11..43]);
+        assert!(boundaries.len() > 1);
+
+        let mut random = TlsFingerprintManager::new();
+        assert_ne!(random.generate_client_hello("example.com").unwrap(), hellos[0]);
+    }
+}
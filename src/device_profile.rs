@@ -0,0 +1,155 @@
+// Device profiles - one phone's fingerprint across TLS, TCP and HTTP
+// A Samsung ClientHello over a desktop TCP stack is itself a signal, so pick every layer together
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::tcp_fingerprint::{MobileProfile, TcpFingerprint};
+use crate::tls_fingerprint::{MobileBrowserProfile, TlsFingerprintManager};
+
+/// A phone and its stock browser
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceProfile {
+    IPhone15Safari,
+    SamsungS24Internet,
+    Pixel7Chrome,
+}
+
+const SAFARI_HEADER_ORDER: &[&str] = &["Host", "Accept", "User-Agent", "Accept-Language", "Accept-Encoding", "Connection"];
+const CHROMIUM_HEADER_ORDER: &[&str] = &["Host", "Connection", "Upgrade-Insecure-Requests", "User-Agent", "Accept", "Accept-Encoding", "Accept-Language"];
+
+impl DeviceProfile {
+    /// ClientHello layout of the device's browser
+    pub fn tls_profile(&self) -> MobileBrowserProfile {
+        match self {
+            DeviceProfile::IPhone15Safari => MobileBrowserProfile::Safari17,
+            DeviceProfile::SamsungS24Internet => MobileBrowserProfile::Samsung21,
+            DeviceProfile::Pixel7Chrome => MobileBrowserProfile::Chrome120Mobile,
+        }
+    }
+
+    /// TCP stack of the device's OS
+    pub fn tcp_profile(&self) -> MobileProfile {
+        match self {
+            DeviceProfile::IPhone15Safari => MobileProfile::IPhone15,
+            DeviceProfile::SamsungS24Internet => MobileProfile::SamsungS24,
+            DeviceProfile::Pixel7Chrome => MobileProfile::PixelPro7,
+        }
+    }
+
+    pub fn tcp_fingerprint(&self) -> TcpFingerprint {
+        self.tcp_profile().get_tcp_fingerprint()
+    }
+
+    /// Initial TTL of packets leaving the device
+    pub fn ttl(&self) -> u8 {
+        self.tcp_fingerprint().ttl
+    }
+
+    pub fn user_agent(&self) -> &'static str {
+        match self {
+            DeviceProfile::IPhone15Safari => "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.0 Mobile/15E148 Safari/604.1",
+            DeviceProfile::SamsungS24Internet => "Mozilla/5.0 (Linux; Android 14; SM-S921B) AppleWebKit/537.36 (KHTML, like Gecko) SamsungBrowser/24.0 Chrome/117.0.0.0 Mobile Safari/537.36",
+            DeviceProfile::Pixel7Chrome => "Mozilla/5.0 (Linux; Android 14; Pixel 7 Pro) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Mobile Safari/537.36",
+        }
+    }
+
+    /// Order in which the browser sends its request headers
+    pub fn header_order(&self) -> &'static [&'static str] {
+        match self {
+            DeviceProfile::IPhone15Safari => SAFARI_HEADER_ORDER,
+            DeviceProfile::SamsungS24Internet | DeviceProfile::Pixel7Chrome => CHROMIUM_HEADER_ORDER,
+        }
+    }
+
+    /// Headers the browser always sends, added when a request lacks them.
+    /// Accept-Encoding is left out: the client may not decode what it did not ask for.
+    pub fn default_headers(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            DeviceProfile::IPhone15Safari => &[
+                ("Accept", "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+                ("Accept-Language", "en-US,en;q=0.9"),
+            ],
+            DeviceProfile::SamsungS24Internet | DeviceProfile::Pixel7Chrome => &[
+                ("Accept", "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/apng,*/*;q=0.8"),
+                ("Accept-Language", "en-US,en;q=0.9"),
+            ],
+        }
+    }
+
+    /// Rewrite a forwarded request's headers as the device's browser would
+    /// send them: its User-Agent, its defaults where missing, its order.
+    /// Headers the browser does not order keep their relative order after it.
+    pub fn apply_headers(&self, headers: &mut Vec<(String, String)>) {
+        headers.retain(|(name, _)| !name.eq_ignore_ascii_case("User-Agent"));
+        headers.push(("User-Agent".to_string(), self.user_agent().to_string()));
+        for (name, value) in self.default_headers() {
+            if !headers.iter().any(|(n, _)| n.eq_ignore_ascii_case(name)) {
+                headers.push((name.to_string(), value.to_string()));
+            }
+        }
+        let order = self.header_order();
+        let rank = |name: &str| order.iter().position(|o| o.eq_ignore_ascii_case(name)).unwrap_or(order.len());
+        headers.sort_by_key(|(name, _)| rank(name));
+    }
+
+    /// TLS manager pinned to the device's browser, so no rotation breaks the pairing
+    pub fn tls_manager(&self) -> TlsFingerprintManager {
+        TlsFingerprintManager::sticky(self.tls_profile())
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceProfile::IPhone15Safari => "iphone15-safari",
+            DeviceProfile::SamsungS24Internet => "samsung-s24",
+            DeviceProfile::Pixel7Chrome => "pixel7-chrome",
+        }
+    }
+}
+
+impl fmt::Display for DeviceProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for DeviceProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [DeviceProfile::IPhone15Safari, DeviceProfile::SamsungS24Internet, DeviceProfile::Pixel7Chrome]
+            .into_iter()
+            .find(|p| p.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| format!("unknown device profile {:?}", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::knox_proxy::{upstream_tls_fingerprint, KnoxProxyConfig};
+
+    #[test]
+    fn test_samsung_profile_pairs_ttl_with_tls_profile() {
+        let device: DeviceProfile = "samsung-s24".parse().unwrap();
+        assert_eq!(device, DeviceProfile::SamsungS24Internet);
+
+        let config = KnoxProxyConfig::default().with_device_profile(device);
+        assert_eq!(config.egress.ttl, Some(64));
+        assert_eq!(config.egress.tcp_mss, Some(1440));
+        let mut manager = upstream_tls_fingerprint(&config);
+        manager.maybe_rotate_profile();
+        assert_eq!(*manager.current_profile(), MobileBrowserProfile::Samsung21);
+
+        let mut headers = vec![
+            ("Accept-Encoding".to_string(), "gzip".to_string()),
+            ("X-Custom".to_string(), "1".to_string()),
+            ("User-Agent".to_string(), "curl/8.0".to_string()),
+            ("Host".to_string(), "example.com".to_string()),
+        ];
+        device.apply_headers(&mut headers);
+        let names: Vec<&str> = headers.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["Host", "User-Agent", "Accept", "Accept-Encoding", "Accept-Language", "X-Custom"]);
+        assert!(headers[1].1.contains("SamsungBrowser"));
+    }
+}
@@ -7,7 +7,9 @@
 //   ST: urn:litebike:service:proxy:1
 //
 // A client looking for litebikes sends a standard M-SEARCH with that
-// ST and collects LOCATION headers from the replies.
+// ST and collects LOCATION headers from the replies.  Private fleets
+// sharing a LAN pick their own ST (say urn:litebike:service:proxy:teamA)
+// on both sides so they stay invisible to each other.
 //
 // The LOCATION points to a tiny HTTP endpoint that returns a JSON
 // manifest (capabilities, ports, instance name).  That endpoint is
@@ -15,19 +17,27 @@
 // server needed.  If the caller can reach the LOCATION, they can
 // dock.
 
-use std::io;
-use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
 use std::time::{Duration, Instant};
 use log::{debug, info};
+use serde::Deserialize;
+
+use crate::symmetrical::{ConnectivityStatus, GatewayCapabilities, ParentGateway};
+use crate::tcp_fingerprint::{bind_udp_with_options, TcpTuningOptions};
+use crate::types::ProtocolType;
 
 // ── Constants ───────────────────────────────────────────────────────
 
 /// Standard SSDP multicast group and port — not ours, everyone uses it.
 const SSDP_ADDR: &str = "239.255.255.250:1900";
+/// Longest we wait on one peer's manifest.
+const MANIFEST_TIMEOUT: Duration = Duration::from_secs(3);
 const SSDP_MULTICAST: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
 const SSDP_PORT: u16 = 1900;
 
-/// Litebike service type URN.  Anything doing M-SEARCH for this
+/// Default litebike service type URN.  Anything doing M-SEARCH for this
 /// will find us; everything else ignores it.
 pub const LITEBIKE_ST: &str = "urn:litebike:service:proxy:1";
 
@@ -54,8 +64,14 @@ impl std::fmt::Display for DockPeer {
 
 // ── Scanner (client side) ───────────────────────────────────────────
 
-/// Send an SSDP M-SEARCH for litebike instances and collect replies.
-pub fn dock_discover(timeout: Duration) -> io::Result<Vec<DockPeer>> {
+/// Send an SSDP M-SEARCH for litebike instances advertising `st`
+/// (normally `LITEBIKE_ST`) and collect replies.
+pub fn dock_discover(timeout: Duration, st: &str) -> io::Result<Vec<DockPeer>> {
+    dock_discover_via(timeout, st, SSDP_ADDR.parse().unwrap())
+}
+
+/// `dock_discover` with the M-SEARCH sent to `dst` instead of the group.
+fn dock_discover_via(timeout: Duration, st: &str, dst: SocketAddr) -> io::Result<Vec<DockPeer>> {
     let sock = UdpSocket::bind("0.0.0.0:0")?;
     sock.set_broadcast(true)?;
     sock.set_read_timeout(Some(Duration::from_millis(250)))?;
@@ -71,12 +87,11 @@ pub fn dock_discover(timeout: Duration) -> io::Result<Vec<DockPeer>> {
          ST: {}\r\n\
          MX: {}\r\n\
          \r\n",
-        SSDP_ADDR, LITEBIKE_ST, mx,
+        SSDP_ADDR, st, mx,
     );
 
-    let dst: SocketAddr = SSDP_ADDR.parse().unwrap();
     sock.send_to(msearch.as_bytes(), dst)?;
-    debug!("dock: sent M-SEARCH for {}", LITEBIKE_ST);
+    debug!("dock: sent M-SEARCH for {}", st);
 
     let mut peers = Vec::new();
     let deadline = Instant::now() + timeout;
@@ -86,7 +101,7 @@ pub fn dock_discover(timeout: Duration) -> io::Result<Vec<DockPeer>> {
         match sock.recv_from(&mut buf) {
             Ok((n, src)) => {
                 if let Ok(text) = std::str::from_utf8(&buf[..n]) {
-                    if let Some(peer) = parse_ssdp_response(text, src) {
+                    if let Some(peer) = parse_ssdp_response(text, src, st) {
                         info!("dock: found {}", peer);
                         peers.push(peer);
                     }
@@ -105,8 +120,96 @@ pub fn dock_discover(timeout: Duration) -> io::Result<Vec<DockPeer>> {
     Ok(peers)
 }
 
-/// Parse an SSDP response into a DockPeer if it matches our ST.
-fn parse_ssdp_response(text: &str, src: SocketAddr) -> Option<DockPeer> {
+/// Discover litebike peers advertising `st` (normally `LITEBIKE_ST`, or
+/// the responder's configured service type), fetch each one's manifest and
+/// return the quickest to answer among those `predicate` accepts, ready to
+/// use as an upstream.  `None` when no reachable peer qualifies.
+///
+/// Blocks for `timeout` while replies come in, then for the manifest fetches.
+pub fn discover_and_select<F>(timeout: Duration, st: &str, predicate: F) -> Option<ParentGateway>
+where
+    F: Fn(&DockManifest) -> bool,
+{
+    discover_and_select_via(timeout, st, SSDP_ADDR.parse().unwrap(), predicate)
+}
+
+fn discover_and_select_via<F>(timeout: Duration, st: &str, dst: SocketAddr, predicate: F) -> Option<ParentGateway>
+where
+    F: Fn(&DockManifest) -> bool,
+{
+    let mut peers = match dock_discover_via(timeout, st, dst) {
+        Ok(peers) => peers,
+        Err(e) => {
+            debug!("dock: discovery failed: {}", e);
+            return None;
+        }
+    };
+    peers.sort_by(|a, b| a.location.cmp(&b.location));
+    peers.dedup_by(|a, b| a.location == b.location);
+
+    peers
+        .iter()
+        .filter_map(|peer| match fetch_manifest(&peer.location, MANIFEST_TIMEOUT) {
+            Ok((manifest, rtt)) => Some((peer, manifest, rtt)),
+            Err(e) => {
+                debug!("dock: {} unreachable: {}", peer, e);
+                None
+            }
+        })
+        .filter(|(_, manifest, _)| predicate(manifest))
+        .min_by_key(|(_, _, rtt)| *rtt)
+        .and_then(|(peer, manifest, _)| {
+            let url = url::Url::parse(&peer.location).ok()?;
+            let mut parent = ParentGateway {
+                url: peer.location.clone(),
+                host: url.host_str()?.trim_start_matches('[').trim_end_matches(']').to_string(),
+                port: url.port_or_known_default()?,
+                capabilities: GatewayCapabilities::default(),
+                last_seen: Some(Instant::now()),
+                connectivity_status: ConnectivityStatus::Reachable,
+            };
+            parent.apply_manifest(&manifest);
+            info!("dock: selected {} ({}:{})", manifest.name, parent.host, parent.port);
+            Some(parent)
+        })
+}
+
+/// GET the manifest at `location`, returning it and how long it took.
+fn fetch_manifest(location: &str, timeout: Duration) -> io::Result<(DockManifest, Duration)> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", location, message));
+    let url = url::Url::parse(location).map_err(|_| invalid("bad LOCATION"))?;
+    if url.scheme() != "http" {
+        return Err(invalid("LOCATION is not http"));
+    }
+    let host = url.host_str().ok_or_else(|| invalid("LOCATION has no host"))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addr = (host.trim_start_matches('[').trim_end_matches(']'), port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| invalid("LOCATION host did not resolve"))?;
+
+    let started = Instant::now();
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    // One write, as write! would send each formatted piece as its own segment
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}:{}\r\nConnection: close\r\n\r\n", url.path(), host, port);
+    stream.write_all(request.as_bytes())?;
+    let mut response = Vec::new();
+    stream.take(64 * 1024).read_to_end(&mut response)?;
+    let rtt = started.elapsed();
+
+    let text = String::from_utf8_lossy(&response);
+    let (head, body) = text.split_once("\r\n\r\n").ok_or_else(|| invalid("truncated response"))?;
+    if head.split_whitespace().nth(1) != Some("200") {
+        return Err(invalid(head.lines().next().unwrap_or("")));
+    }
+    let manifest = parse_manifest_json(body.trim()).ok_or_else(|| invalid("invalid litebike.json"))?;
+    Ok((manifest, rtt))
+}
+
+/// Parse an SSDP response into a DockPeer if it matches `st`.
+fn parse_ssdp_response(text: &str, src: SocketAddr, st: &str) -> Option<DockPeer> {
     let mut location = None;
     let mut server = String::new();
     let mut name = String::new();
@@ -121,7 +224,7 @@ fn parse_ssdp_response(text: &str, src: SocketAddr) -> Option<DockPeer> {
             let val_orig = line.split_once(':').map(|(_, v)| v.trim()).unwrap_or("");
             match key {
                 "location" => location = Some(val_orig.to_string()),
-                "st" if val.trim() == LITEBIKE_ST.to_ascii_lowercase() => {
+                "st" if val.trim() == st.to_ascii_lowercase() => {
                     st_matches = true;
                 }
                 "server" => server = val_orig.to_string(),
@@ -164,6 +267,9 @@ pub struct DockResponderConfig {
     pub service_port: u16,
     /// Human-readable instance name.
     pub instance_name: String,
+    /// Service type we answer to and advertise; `LITEBIKE_ST` unless the
+    /// deployment namespaces its fleet.
+    pub st: String,
 }
 
 impl Default for DockResponderConfig {
@@ -172,6 +278,7 @@ impl Default for DockResponderConfig {
             location: String::new(),
             service_port: 8080,
             instance_name: "litebike".to_string(),
+            st: LITEBIKE_ST.to_string(),
         }
     }
 }
@@ -194,10 +301,10 @@ fn build_ssdp_response(config: &DockResponderConfig, local_ip: Ipv4Addr) -> Stri
          X-Litebike-Name: {}\r\n\
          \r\n",
         location,
-        LITEBIKE_ST,
+        config.st,
         // simple instance id from name hash
         simple_hash(&config.instance_name),
-        LITEBIKE_ST,
+        config.st,
         config.instance_name,
     )
 }
@@ -223,9 +330,9 @@ fn build_ssdp_notify(config: &DockResponderConfig, local_ip: Ipv4Addr) -> String
          \r\n",
         SSDP_ADDR,
         location,
-        LITEBIKE_ST,
+        config.st,
         simple_hash(&config.instance_name),
-        LITEBIKE_ST,
+        config.st,
         config.instance_name,
     )
 }
@@ -237,12 +344,12 @@ fn build_ssdp_notify(config: &DockResponderConfig, local_ip: Ipv4Addr) -> String
 /// Designed for `std::thread::spawn` — blocks forever.
 pub fn dock_respond(config: DockResponderConfig) -> io::Result<()> {
     let local_ip = guess_local_ip();
+    let mut networks = local_networks();
     let bind = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), SSDP_PORT);
-    let sock = UdpSocket::bind(bind)?;
+    // Reuse set before the bind, so other SSDP listeners on this host coexist
+    let reuse = TcpTuningOptions { reuse_address: true, reuse_port: true, ..Default::default() };
+    let sock = bind_udp_with_options(bind, &reuse)?;
     sock.set_broadcast(true)?;
-    // Allow port reuse so other SSDP listeners coexist.
-    // (set_reuse_address is called before bind on some platforms;
-    //  we do our best here — if it fails we keep going.)
     let _ = sock.join_multicast_v4(&SSDP_MULTICAST, &Ipv4Addr::UNSPECIFIED);
     sock.set_read_timeout(Some(Duration::from_secs(30)))?;
 
@@ -265,14 +372,20 @@ pub fn dock_respond(config: DockResponderConfig) -> io::Result<()> {
             let notify = build_ssdp_notify(&config, local_ip);
             let _ = sock.send_to(notify.as_bytes(), mcast_dst);
             last_notify = Instant::now();
+            networks = local_networks();
         }
 
         match sock.recv_from(&mut buf) {
             Ok((n, src)) => {
                 if let Ok(text) = std::str::from_utf8(&buf[..n]) {
-                    if is_msearch_for_us(text) {
-                        debug!("dock: M-SEARCH from {}", src);
-                        let response = build_ssdp_response(&config, local_ip);
+                    if is_msearch_for_us(text, &config.st) {
+                        // Advertise the address the requester can actually reach.
+                        let reply_ip = match src.ip() {
+                            std::net::IpAddr::V4(requester) => select_local_ip(requester, &networks, local_ip),
+                            std::net::IpAddr::V6(_) => local_ip,
+                        };
+                        debug!("dock: M-SEARCH from {}, answering with {}", src, reply_ip);
+                        let response = build_ssdp_response(&config, reply_ip);
                         let _ = sock.send_to(response.as_bytes(), src);
                     }
                 }
@@ -292,9 +405,9 @@ pub fn dock_respond(config: DockResponderConfig) -> io::Result<()> {
     }
 }
 
-/// Check if an incoming SSDP packet is an M-SEARCH for our service type
+/// Check if an incoming SSDP packet is an M-SEARCH for service type `st`
 /// (or the ssdp:all wildcard).
-fn is_msearch_for_us(text: &str) -> bool {
+fn is_msearch_for_us(text: &str, st: &str) -> bool {
     let lower = text.to_ascii_lowercase();
     if !lower.starts_with("m-search") {
         return false;
@@ -303,7 +416,7 @@ fn is_msearch_for_us(text: &str) -> bool {
     for line in lower.lines() {
         if let Some(val) = line.strip_prefix("st:") {
             let val = val.trim();
-            if val == LITEBIKE_ST.to_ascii_lowercase()
+            if val == st.to_ascii_lowercase()
                 || val == "ssdp:all"
                 || val == "upnp:rootdevice"
             {
@@ -322,6 +435,27 @@ fn guess_local_ip() -> Ipv4Addr {
     crate::syscall_net::get_default_local_ipv4().unwrap_or(Ipv4Addr::UNSPECIFIED)
 }
 
+/// Local (address, netmask) pairs, empty if enumeration fails.
+fn local_networks() -> Vec<(Ipv4Addr, Ipv4Addr)> {
+    crate::syscall_net::list_ipv4_networks()
+        .map(|nets| nets.into_iter().map(|(_, addr, mask)| (addr, mask)).collect())
+        .unwrap_or_default()
+}
+
+/// Pick the local address sharing a subnet with `requester`, so a
+/// multi-homed host hands each peer a LOCATION it can reach.  Falls
+/// back to `fallback` when no interface is on the requester's subnet.
+fn select_local_ip(requester: Ipv4Addr, networks: &[(Ipv4Addr, Ipv4Addr)], fallback: Ipv4Addr) -> Ipv4Addr {
+    let req = u32::from(requester);
+    networks
+        .iter()
+        .filter(|(addr, mask)| u32::from(*addr) & u32::from(*mask) == req & u32::from(*mask))
+        // most specific subnet wins
+        .max_by_key(|(_, mask)| u32::from(*mask).count_ones())
+        .map(|(addr, _)| *addr)
+        .unwrap_or(fallback)
+}
+
 /// Dirt-simple string hash for instance IDs — not crypto, just uniqueness.
 fn simple_hash(s: &str) -> u64 {
     let mut h: u64 = 0xcbf29ce484222325; // FNV offset basis
@@ -336,17 +470,63 @@ fn simple_hash(s: &str) -> u64 {
 /// Callers can embed this in their HTTP handler at `/litebike.json`.
 pub fn build_manifest_json(name: &str, service_port: u16, caps: &DockCapabilities) -> String {
     format!(
-        r#"{{"name":"{}","port":{},"proxy":{},"knox":{},"socks5":{},"version":"1.0"}}"#,
+        r#"{{"name":"{}","port":{},"proxy":{},"knox":{},"socks5":{},"protocols":{},"version":"1.0"}}"#,
         name, service_port, caps.has_proxy, caps.has_knox, caps.has_socks5,
+        crate::capabilities::protocols_json(caps.protocols),
     )
 }
 
+/// `build_manifest_json` plus a `stats` object with the server's live counters.
+pub fn build_manifest_json_with_stats(name: &str, service_port: u16, caps: &DockCapabilities, stats: &DockStats) -> String {
+    let mut json = build_manifest_json(name, service_port, caps);
+    json.pop();
+    json.push_str(&format!(
+        r#","stats":{{"active_connections":{},"max_read_up":{},"max_read_down":{}}}}}"#,
+        stats.active_connections, stats.max_read_up, stats.max_read_down
+    ));
+    json
+}
+
+/// A peer's manifest as served at its LOCATION. Fields a peer omits take
+/// their defaults; unknown fields (such as `stats`) are ignored.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct DockManifest {
+    pub name: String,
+    /// Port serving both HTTP proxy and SOCKS5
+    pub port: u16,
+    pub proxy: bool,
+    pub knox: bool,
+    pub socks5: bool,
+    /// Lower-case protocol name to "real" or "stub", as from
+    /// `capabilities::implemented_protocols`
+    pub protocols: BTreeMap<String, String>,
+    pub version: String,
+}
+
+/// Parse the JSON produced by `build_manifest_json`
+pub fn parse_manifest_json(json: &str) -> Option<DockManifest> {
+    serde_json::from_str(json).ok()
+}
+
 /// Capabilities advertised in the manifest.
 #[derive(Debug, Clone, Copy, Default)]
 pub struct DockCapabilities {
     pub has_proxy: bool,
     pub has_knox: bool,
     pub has_socks5: bool,
+    /// Protocols the serving listener really handles; the manifest marks
+    /// every other known protocol a stub
+    pub protocols: &'static [ProtocolType],
+}
+
+/// Live counters reported in the manifest.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DockStats {
+    pub active_connections: usize,
+    /// Largest single relay reads, per direction; 0 unless relays are sampled
+    pub max_read_up: usize,
+    pub max_read_down: usize,
 }
 
 // ── Tests ───────────────────────────────────────────────────────────
@@ -361,25 +541,25 @@ mod tests {
             "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nST: {}\r\nMX: 3\r\n\r\n",
             LITEBIKE_ST
         );
-        assert!(is_msearch_for_us(&msearch));
+        assert!(is_msearch_for_us(&msearch, LITEBIKE_ST));
     }
 
     #[test]
     fn msearch_wildcard() {
         let msearch = "M-SEARCH * HTTP/1.1\r\nST: ssdp:all\r\nMX: 1\r\n\r\n";
-        assert!(is_msearch_for_us(msearch));
+        assert!(is_msearch_for_us(msearch, LITEBIKE_ST));
     }
 
     #[test]
     fn msearch_wrong_st() {
         let msearch = "M-SEARCH * HTTP/1.1\r\nST: urn:schemas-upnp-org:device:something:1\r\nMX: 3\r\n\r\n";
-        assert!(!is_msearch_for_us(msearch));
+        assert!(!is_msearch_for_us(msearch, LITEBIKE_ST));
     }
 
     #[test]
     fn notify_not_msearch() {
         let notify = "NOTIFY * HTTP/1.1\r\nNT: urn:litebike:service:proxy:1\r\nNTS: ssdp:alive\r\n\r\n";
-        assert!(!is_msearch_for_us(notify));
+        assert!(!is_msearch_for_us(notify, LITEBIKE_ST));
     }
 
     #[test]
@@ -388,20 +568,65 @@ mod tests {
             location: String::new(),
             service_port: 9090,
             instance_name: "my-bike".to_string(),
+            ..Default::default()
         };
         let resp = build_ssdp_response(&cfg, Ipv4Addr::new(10, 0, 0, 5));
         let src: SocketAddr = "10.0.0.5:1900".parse().unwrap();
-        let peer = parse_ssdp_response(&resp, src).unwrap();
+        let peer = parse_ssdp_response(&resp, src, LITEBIKE_ST).unwrap();
         assert_eq!(peer.location, "http://10.0.0.5:9090/litebike.json");
         assert_eq!(peer.name, "my-bike");
     }
 
+    #[test]
+    fn custom_st_ignores_default_fleet() {
+        let team_st = "urn:litebike:service:proxy:teamA";
+        let src: SocketAddr = "10.0.0.5:1900".parse().unwrap();
+        let default_resp = build_ssdp_response(&DockResponderConfig::default(), Ipv4Addr::new(10, 0, 0, 5));
+        assert!(parse_ssdp_response(&default_resp, src, team_st).is_none());
+
+        let team = DockResponderConfig { st: team_st.to_string(), ..Default::default() };
+        let team_resp = build_ssdp_response(&team, Ipv4Addr::new(10, 0, 0, 6));
+        assert!(team_resp.contains("ST: urn:litebike:service:proxy:teamA\r\n"));
+        assert!(parse_ssdp_response(&team_resp, src, team_st).is_some());
+        assert!(parse_ssdp_response(&team_resp, src, LITEBIKE_ST).is_none());
+
+        // Each responder only answers searches for its own ST
+        let search = |st: &str| format!("M-SEARCH * HTTP/1.1\r\nST: {}\r\nMX: 1\r\n\r\n", st);
+        assert!(is_msearch_for_us(&search(team_st), &team.st));
+        assert!(!is_msearch_for_us(&search(LITEBIKE_ST), &team.st));
+        assert!(!is_msearch_for_us(&search(team_st), LITEBIKE_ST));
+    }
+
+    #[test]
+    fn location_follows_requester_subnet() {
+        let networks = [
+            (Ipv4Addr::new(192, 168, 1, 5), Ipv4Addr::new(255, 255, 255, 0)),
+            (Ipv4Addr::new(10, 0, 0, 5), Ipv4Addr::new(255, 255, 255, 0)),
+        ];
+        let fallback = Ipv4Addr::new(192, 168, 1, 5);
+        let ip = select_local_ip(Ipv4Addr::new(10, 0, 0, 77), &networks, fallback);
+        assert_eq!(ip, Ipv4Addr::new(10, 0, 0, 5));
+
+        let cfg = DockResponderConfig {
+            location: String::new(),
+            service_port: 9090,
+            instance_name: "my-bike".to_string(),
+            ..Default::default()
+        };
+        let resp = build_ssdp_response(&cfg, ip);
+        assert!(resp.contains("LOCATION: http://10.0.0.5:9090/litebike.json\r\n"));
+
+        // no shared subnet: keep the guessed address
+        assert_eq!(select_local_ip(Ipv4Addr::new(172, 16, 0, 9), &networks, fallback), fallback);
+    }
+
     #[test]
     fn manifest_json() {
         let json = build_manifest_json("test", 8080, &DockCapabilities {
             has_proxy: true,
             has_knox: false,
             has_socks5: true,
+            ..Default::default()
         });
         assert!(json.contains("\"proxy\":true"));
         assert!(json.contains("\"knox\":false"));
@@ -409,6 +634,63 @@ mod tests {
         assert!(json.contains("\"port\":8080"));
     }
 
+    #[test]
+    fn discover_and_select_loopback() {
+        // Manifest endpoint, answering every request
+        let http = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let http_port = http.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for mut stream in http.incoming().flatten() {
+                let mut head = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !head.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => head.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let json = build_manifest_json("loopback-bike", 9050, &DockCapabilities {
+                    has_proxy: true,
+                    has_knox: false,
+                    has_socks5: true,
+                    ..Default::default()
+                });
+                let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", json.len(), json);
+            }
+        });
+
+        // Unicast SSDP responder advertising that endpoint
+        let ssdp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let ssdp_addr = ssdp.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let config = DockResponderConfig {
+                st: "urn:example:service:bike:1".to_string(),
+                location: format!("http://127.0.0.1:{}/litebike.json", http_port),
+                instance_name: "loopback-bike".to_string(),
+                ..Default::default()
+            };
+            let mut buf = [0u8; 2048];
+            while let Ok((n, src)) = ssdp.recv_from(&mut buf) {
+                if is_msearch_for_us(std::str::from_utf8(&buf[..n]).unwrap_or(""), &config.st) {
+                    let _ = ssdp.send_to(build_ssdp_response(&config, Ipv4Addr::LOCALHOST).as_bytes(), src);
+                }
+            }
+        });
+
+        let timeout = Duration::from_millis(1000);
+        let st = "urn:example:service:bike:1";
+        let parent = discover_and_select_via(timeout, st, ssdp_addr, |m| m.socks5).unwrap();
+        assert_eq!(parent.host, "127.0.0.1");
+        assert_eq!(parent.port, 9050);
+        assert!(parent.capabilities.socks5 && parent.capabilities.proxy && !parent.capabilities.knox);
+        assert_eq!(parent.connectivity_status, ConnectivityStatus::Reachable);
+        assert_eq!(parent.manifest_url(), format!("http://127.0.0.1:{}/litebike.json", http_port));
+
+        assert!(discover_and_select_via(timeout, st, ssdp_addr, |m| m.knox).is_none());
+        // A responder under a custom service type ignores the default one
+        assert!(discover_and_select_via(timeout, LITEBIKE_ST, ssdp_addr, |_| true).is_none());
+    }
+
     #[test]
     fn hash_deterministic() {
         assert_eq!(simple_hash("litebike"), simple_hash("litebike"));
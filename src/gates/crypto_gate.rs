@@ -1,14 +1,30 @@
 // Crypto Gate for LITEBIKE
 // Gates all cryptographic operations
+//
+// Once keyed, payloads are sealed with ChaCha20-Poly1305 under the
+// pre-shared key: a random 12-byte nonce, then the ciphertext and its tag.
+// The direction picks sealing (egress) or opening (ingress).
 
 use async_trait::async_trait;
 use std::sync::Arc;
 use parking_lot::RwLock;
 use std::collections::HashMap;
+use rand::RngCore;
+use ring::aead;
+
+/// Whether `process` seals outgoing payloads or opens incoming ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CryptoDirection {
+    #[default]
+    Encrypt,
+    Decrypt,
+}
 
 pub struct CryptoGate {
     enabled: Arc<RwLock<bool>>,
     allowed_methods: Arc<RwLock<HashMap<String, bool>>>,
+    key: Arc<RwLock<Option<aead::LessSafeKey>>>,
+    direction: Arc<RwLock<CryptoDirection>>,
 }
 
 impl CryptoGate {
@@ -30,9 +46,60 @@ impl CryptoGate {
         Self {
             enabled: Arc::new(RwLock::new(false)),
             allowed_methods: Arc::new(RwLock::new(allowed_methods)),
+            key: Arc::new(RwLock::new(None)),
+            direction: Arc::new(RwLock::new(CryptoDirection::default())),
+        }
+    }
+    
+    /// Key the gate with a pre-shared ChaCha20-Poly1305 key and open it
+    pub fn set_key(&self, key: [u8; 32]) {
+        let key = aead::UnboundKey::new(&aead::CHACHA20_POLY1305, &key).expect("32-byte ChaCha20-Poly1305 key");
+        *self.key.write() = Some(aead::LessSafeKey::new(key));
+        *self.enabled.write() = true;
+        if let Some(enabled) = self.allowed_methods.write().get_mut("chacha20-poly1305") {
+            *enabled = true;
         }
     }
     
+    pub fn set_direction(&self, direction: CryptoDirection) {
+        *self.direction.write() = direction;
+    }
+    
+    pub fn direction(&self) -> CryptoDirection {
+        *self.direction.read()
+    }
+    
+    /// Nonce, ciphertext and tag for `plaintext`
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        let key = self.key.read();
+        let key = key.as_ref().ok_or("Crypto gate has no key")?;
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mut sealed = plaintext.to_vec();
+        key.seal_in_place_append_tag(aead::Nonce::assume_unique_for_key(nonce), aead::Aad::empty(), &mut sealed)
+            .map_err(|_| "ChaCha20-Poly1305 seal failed".to_string())?;
+        let mut out = Vec::with_capacity(aead::NONCE_LEN + sealed.len());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&sealed);
+        Ok(out)
+    }
+    
+    /// Plaintext of a payload from `encrypt`; fails if it was altered or
+    /// sealed under another key
+    pub fn decrypt(&self, payload: &[u8]) -> Result<Vec<u8>, String> {
+        let key = self.key.read();
+        let key = key.as_ref().ok_or("Crypto gate has no key")?;
+        if payload.len() < aead::NONCE_LEN + key.algorithm().tag_len() {
+            return Err("Encrypted payload too short".to_string());
+        }
+        let (nonce, sealed) = payload.split_at(aead::NONCE_LEN);
+        let nonce = aead::Nonce::try_assume_unique_for_key(nonce).map_err(|_| "bad nonce".to_string())?;
+        let mut buffer = sealed.to_vec();
+        let plaintext = key.open_in_place(nonce, aead::Aad::empty(), &mut buffer)
+            .map_err(|_| "ChaCha20-Poly1305 authentication failed".to_string())?;
+        Ok(plaintext.to_vec())
+    }
+    
     pub fn enable_method(&self, method: &str) {
         let mut methods = self.allowed_methods.write();
         if let Some(enabled) = methods.get_mut(method) {
@@ -76,7 +143,7 @@ impl CryptoGate {
 #[async_trait]
 impl super::Gate for CryptoGate {
     async fn is_open(&self, _data: &[u8]) -> bool {
-        *self.enabled.read()
+        *self.enabled.read() && self.key.read().is_some()
     }
 
     async fn process(&self, data: &[u8]) -> Result<Vec<u8>, String> {
@@ -84,16 +151,15 @@ impl super::Gate for CryptoGate {
             return Err("Crypto gate is closed".to_string());
         }
         
-        // Check if this looks like encrypted data
-        let entropy = calculate_entropy(data);
-        if entropy < 6.0 {
-            return Err("Data doesn't appear to be encrypted".to_string());
+        match self.direction() {
+            CryptoDirection::Encrypt => self.encrypt(data),
+            CryptoDirection::Decrypt => self.decrypt(data),
         }
-        
-        println!("Processing through crypto gate (entropy: {:.2})", entropy);
-        
-        // Would perform crypto operations here based on allowed methods
-        Ok(data.to_vec())
+    }
+    
+    fn set_open(&self, open: bool) -> bool {
+        *self.enabled.write() = open;
+        true
     }
     
     fn name(&self) -> &str {
@@ -104,26 +170,3 @@ impl super::Gate for CryptoGate {
         vec![]
     }
 }
-
-fn calculate_entropy(data: &[u8]) -> f32 {
-    if data.is_empty() {
-        return 0.0;
-    }
-    
-    let mut frequencies = [0u32; 256];
-    for &byte in data {
-        frequencies[byte as usize] += 1;
-    }
-    
-    let len = data.len() as f32;
-    let mut entropy = 0.0;
-    
-    for &count in &frequencies {
-        if count > 0 {
-            let p = count as f32 / len;
-            entropy -= p * p.log2();
-        }
-    }
-    
-    entropy
-}
\ No newline at end of file
@@ -80,6 +80,11 @@ impl super::Gate for HTXGate {
         *self.enabled.read()
     }
 
+    fn accepts(&self, data: &[u8]) -> bool {
+        // Only the magic; ticket-sized payloads are too common to claim
+        data.starts_with(b"HTX/") || data.starts_with(b"betanet/htx")
+    }
+
     async fn process(&self, data: &[u8]) -> Result<Vec<u8>, String> {
         if !self.is_open(data).await {
             return Err("HTX gate is closed".to_string());
@@ -95,6 +100,11 @@ impl super::Gate for HTXGate {
         self.forward_to_htx(data).await
     }
     
+    fn set_open(&self, open: bool) -> bool {
+        if open { self.enable() } else { self.disable() }
+        true
+    }
+    
     fn name(&self) -> &str {
         "htx"
     }
@@ -164,6 +164,10 @@ impl Gate for KnoxGate {
         self.detect_knox_patterns(data)
     }
     
+    fn accepts(&self, data: &[u8]) -> bool {
+        self.detect_knox_patterns(data)
+    }
+    
     async fn process(&self, data: &[u8]) -> Result<Vec<u8>, String> {
         match self.process_connection(data, None).await {
             Ok(result) => Ok(result),
@@ -179,6 +183,11 @@ impl Gate for KnoxGate {
         self.process_knox_traffic(data, stream).await
     }
     
+    fn set_open(&self, open: bool) -> bool {
+        if open { self.enable() } else { self.disable() }
+        true
+    }
+    
     fn name(&self) -> &str {
         "knox"
     }
@@ -2,6 +2,8 @@
 // Enhanced hierarchical gating for protocols, crypto, and Knox integration
 // Integrated with ../literbike gate patterns
 
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use parking_lot::RwLock;
 use async_trait::async_trait;
@@ -19,6 +21,13 @@ pub trait Gate: Send + Sync {
     /// Check if gate allows passage for this data
     async fn is_open(&self, data: &[u8]) -> bool;
     
+    /// Whether the payload is recognisably this gate's protocol. Routing
+    /// hands a payload to the highest-priority open gate accepting it;
+    /// gates that cannot tell leave this false.
+    fn accepts(&self, _data: &[u8]) -> bool {
+        false
+    }
+    
     /// Process data through gate (legacy interface)
     async fn process(&self, data: &[u8]) -> Result<Vec<u8>, String>;
     
@@ -28,6 +37,11 @@ pub trait Gate: Send + Sync {
         self.process(data).await.map_err(|e| GateError::ProcessingFailed(e))
     }
     
+    /// Open or close the gate at runtime. Returns false for gates without a switch.
+    fn set_open(&self, _open: bool) -> bool {
+        false
+    }
+    
     /// Gate identifier
     fn name(&self) -> &str;
     
@@ -80,6 +94,14 @@ pub struct LitebikeGateController {
     htx_gate: Arc<htx_gate::HTXGate>,
     knox_gate: Arc<knox_gate::KnoxGate>,
     proxy_gate: Arc<proxy_gate::ProxyGate>,
+    counters: Arc<RwLock<HashMap<String, Arc<GateCounters>>>>,
+}
+
+/// Per-gate routing counters
+#[derive(Debug, Default)]
+struct GateCounters {
+    times_open: AtomicU64,
+    times_processed: AtomicU64,
 }
 
 impl LitebikeGateController {
@@ -108,9 +130,43 @@ impl LitebikeGateController {
             htx_gate,
             knox_gate,
             proxy_gate,
+            counters: Arc::new(RwLock::new(HashMap::new())),
         }
     }
     
+    fn counters_for(&self, name: &str) -> Arc<GateCounters> {
+        if let Some(counters) = self.counters.read().get(name) {
+            return counters.clone();
+        }
+        self.counters.write().entry(name.to_string()).or_default().clone()
+    }
+    
+    /// Open or close the named gate. Returns false if no gate by that name
+    /// exists or it cannot be switched.
+    pub fn set_open(&self, name: &str, open: bool) -> bool {
+        let gate = self.gates.read().iter().find(|g| g.name() == name).cloned();
+        match gate {
+            Some(gate) => gate.set_open(open),
+            None => false,
+        }
+    }
+    
+    /// How often each gate was open for routed data and how often it processed it
+    pub fn gate_stats(&self) -> Vec<GateStats> {
+        let gates: Vec<Arc<dyn Gate>> = self.gates.read().iter().cloned().collect();
+        gates
+            .iter()
+            .map(|gate| {
+                let counters = self.counters_for(gate.name());
+                GateStats {
+                    name: gate.name().to_string(),
+                    times_open: counters.times_open.load(Ordering::Relaxed),
+                    times_processed: counters.times_processed.load(Ordering::Relaxed),
+                }
+            })
+            .collect()
+    }
+    
     /// Enhanced routing with connection handling (legacy interface)
     pub async fn route(&self, data: &[u8]) -> Result<Vec<u8>, String> {
         match self.route_with_connection(data, None).await {
@@ -119,26 +175,46 @@ impl LitebikeGateController {
         }
     }
     
-    /// Route data through appropriate gate with connection support
+    /// Process `data` through `gate`, retrying the legacy interface when
+    /// connection handling fails
+    async fn run_gate(&self, gate: &Arc<dyn Gate>, data: &[u8], stream: Option<TcpStream>) -> Result<Vec<u8>, GateError> {
+        println!("🚪 Routing through gate: {} (priority: {})", gate.name(), gate.priority());
+        let counters = self.counters_for(gate.name());
+        counters.times_open.fetch_add(1, Ordering::Relaxed);
+
+        let result = match gate.process_connection(data, stream).await {
+            Err(GateError::ProcessingFailed(reason)) => gate.process(data).await.map_err(|_| GateError::ProcessingFailed(reason)),
+            result => result,
+        };
+        match result {
+            Ok(result) => {
+                counters.times_processed.fetch_add(1, Ordering::Relaxed);
+                Ok(result)
+            }
+            Err(e) => {
+                println!("⚠ Gate {} failed: {}", gate.name(), e);
+                Err(e)
+            }
+        }
+    }
+    
+    /// Route data through appropriate gate with connection support. The
+    /// highest-priority open gate that accepts the payload handles it
+    /// alone; when none does, open gates are tried in priority order.
     pub async fn route_with_connection(&self, data: &[u8], mut stream: Option<TcpStream>) -> Result<Vec<u8>, GateError> {
         // Clone gate Arcs and drop the lock before any .await
         let gates: Vec<Arc<dyn Gate>> = self.gates.read().iter().cloned().collect();
 
+        for gate in gates.iter() {
+            if gate.accepts(data) && gate.is_open(data).await {
+                return self.run_gate(gate, data, stream).await;
+            }
+        }
+
         for gate in gates.iter() {
             if gate.is_open(data).await {
-                println!("🚪 Routing through gate: {} (priority: {})", gate.name(), gate.priority());
-
-                match gate.process_connection(data, stream.take()).await {
-                    Ok(result) => return Ok(result),
-                    Err(GateError::ProcessingFailed(_)) => {
-                        if let Ok(result) = gate.process(data).await {
-                            return Ok(result);
-                        }
-                    }
-                    Err(e) => {
-                        println!("⚠ Gate {} failed: {}", gate.name(), e);
-                        continue;
-                    }
+                if let Ok(result) = self.run_gate(gate, data, stream.take()).await {
+                    return Ok(result);
                 }
             }
         }
@@ -149,11 +225,19 @@ impl LitebikeGateController {
     /// Route by specific protocol
     pub async fn route_by_protocol(&self, protocol: &str, data: &[u8], stream: Option<TcpStream>) -> Result<Vec<u8>, GateError> {
         let gates: Vec<Arc<dyn Gate>> = self.gates.read().iter().cloned().collect();
+        let candidates: Vec<&Arc<dyn Gate>> = gates.iter().filter(|gate| gate.can_handle_protocol(protocol)).collect();
+        let accepting = candidates.iter().filter(|gate| gate.accepts(data));
 
-        for gate in gates.iter() {
-            if gate.can_handle_protocol(protocol) && gate.is_open(data).await {
+        for gate in accepting.chain(candidates.iter()) {
+            if gate.is_open(data).await {
                 println!("🎯 Protocol-specific routing: {} -> {}", protocol, gate.name());
-                return gate.process_connection(data, stream).await;
+                let counters = self.counters_for(gate.name());
+                counters.times_open.fetch_add(1, Ordering::Relaxed);
+                let result = gate.process_connection(data, stream).await;
+                if result.is_ok() {
+                    counters.times_processed.fetch_add(1, Ordering::Relaxed);
+                }
+                return result;
             }
         }
 
@@ -192,6 +276,33 @@ impl LitebikeGateController {
         println!("🔓 Knox mode disabled");
     }
     
+    /// Relay Shadowsocks clients holding any of `passwords`; an empty list
+    /// closes the gate again
+    pub fn enable_shadowsocks(&self, passwords: Vec<String>) {
+        let open = !passwords.is_empty();
+        self.shadowsocks_gate.set_passwords(passwords);
+        if open {
+            self.shadowsocks_gate.enable();
+        } else {
+            self.shadowsocks_gate.disable();
+        }
+    }
+    
+    /// Hold Shadowsocks targets to `config`'s egress, deny list and quotas
+    pub fn set_shadowsocks_policy(&self, config: &crate::knox_proxy::KnoxProxyConfig) {
+        self.shadowsocks_gate.set_policy(config);
+    }
+    
+    /// Seal routed payloads with ChaCha20-Poly1305 under `key`
+    pub fn set_crypto_key(&self, key: [u8; 32]) {
+        self.crypto_gate.set_key(key);
+    }
+    
+    /// Whether the crypto gate seals egress or opens ingress payloads
+    pub fn set_crypto_direction(&self, direction: crypto_gate::CryptoDirection) {
+        self.crypto_gate.set_direction(direction);
+    }
+    
     /// Add HTX as a downstream consumer (legacy interface)
     pub fn connect_htx_downstream(&self, htx_endpoint: String) {
         self.htx_gate.set_endpoint(htx_endpoint);
@@ -216,8 +327,124 @@ pub struct GateInfo {
     pub children_count: usize,
 }
 
+/// Routing counters for one gate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GateStats {
+    pub name: String,
+    pub times_open: u64,
+    pub times_processed: u64,
+}
+
 impl Default for LitebikeGateController {
     fn default() -> Self {
         Self::new()
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crypto_stats(controller: &LitebikeGateController) -> GateStats {
+        controller.gate_stats().into_iter().find(|s| s.name == "crypto").unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_closed_crypto_gate_is_skipped() {
+        let controller = LitebikeGateController::new();
+        // Every byte value once; no other default gate accepts it
+        let ciphertext: Vec<u8> = (0..=255).collect();
+
+        // Unkeyed, the crypto gate stays shut even when switched open
+        assert!(controller.set_open("crypto", true));
+        assert!(controller.route(&ciphertext).await.is_err());
+        assert_eq!(crypto_stats(&controller).times_open, 0);
+
+        controller.set_crypto_key([7; 32]);
+        assert!(controller.route(&ciphertext).await.is_ok());
+        let opened = crypto_stats(&controller);
+        assert_eq!((opened.times_open, opened.times_processed), (1, 1));
+
+        assert!(controller.set_open("crypto", false));
+        assert!(controller.route(&ciphertext).await.is_err());
+        assert_eq!(crypto_stats(&controller), opened);
+        assert!(!controller.set_open("no-such-gate", true));
+    }
+
+    /// Always open; accepts payloads starting with its tag
+    struct Tagged(&'static str, u8);
+
+    #[async_trait]
+    impl Gate for Tagged {
+        async fn is_open(&self, _data: &[u8]) -> bool {
+            true
+        }
+
+        fn accepts(&self, data: &[u8]) -> bool {
+            data.starts_with(self.0.as_bytes())
+        }
+
+        async fn process(&self, _data: &[u8]) -> Result<Vec<u8>, String> {
+            Ok(self.0.as_bytes().to_vec())
+        }
+
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn children(&self) -> Vec<Arc<dyn Gate>> {
+            vec![]
+        }
+
+        fn priority(&self) -> u8 {
+            self.1
+        }
+    }
+
+    #[tokio::test]
+    async fn test_route_prefers_the_accepting_gate() {
+        let controller = LitebikeGateController::new();
+        // Keyed, the crypto gate is open to anything at priority 50
+        controller.set_crypto_key([3; 32]);
+        controller.add_gate(Arc::new(Tagged("tls", 10)));
+        controller.add_gate(Arc::new(Tagged("t", 5)));
+
+        // Both tagged gates accept this; the higher priority takes it, not crypto
+        assert_eq!(controller.route(b"tls record").await.unwrap(), b"tls");
+        assert_eq!(controller.route(b"tcp frame").await.unwrap(), b"t");
+        assert_eq!(crypto_stats(&controller).times_open, 0);
+
+        // Nothing accepts it: first open gate by priority, as before
+        let sealed = controller.route(b"udp datagram").await.unwrap();
+        assert_eq!(sealed.len(), 12 + 12 + 16);
+        assert_eq!(crypto_stats(&controller).times_processed, 1);
+    }
+
+    #[tokio::test]
+    async fn test_crypto_gate_seals_egress_and_opens_ingress() {
+        use crypto_gate::CryptoDirection;
+
+        let egress = LitebikeGateController::new();
+        egress.set_crypto_key([42; 32]);
+        let ingress = LitebikeGateController::new();
+        ingress.set_crypto_key([42; 32]);
+        ingress.set_crypto_direction(CryptoDirection::Decrypt);
+
+        let payload = b"\x00\x01 relay me".to_vec();
+        let sealed = egress.route(&payload).await.unwrap();
+        assert_eq!(sealed.len(), payload.len() + 12 + 16);
+        assert!(!sealed.windows(8).any(|w| w == b"relay me"));
+        // A fresh nonce each time
+        assert_ne!(egress.route(&payload).await.unwrap(), sealed);
+        assert_eq!(ingress.route(&sealed).await.unwrap(), payload);
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(ingress.route(&tampered).await.is_err());
+
+        let other = LitebikeGateController::new();
+        other.set_crypto_key([1; 32]);
+        other.set_crypto_direction(CryptoDirection::Decrypt);
+        assert!(other.route(&sealed).await.is_err());
+    }
 }
\ No newline at end of file
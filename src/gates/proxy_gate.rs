@@ -2,6 +2,7 @@
 // Integrates with existing proxy functionality
 
 use super::{Gate, GateError};
+use crate::http::RequestHead;
 use async_trait::async_trait;
 use std::sync::Arc;
 use parking_lot::RwLock;
@@ -79,23 +80,11 @@ impl ProxyGate {
         println!("🌐 Processing HTTP proxy request");
         
         // Parse HTTP request
-        let request = String::from_utf8_lossy(data);
-        let lines: Vec<&str> = request.lines().collect();
-        
-        if lines.is_empty() {
-            return Err(GateError::ProcessingFailed("Empty HTTP request".to_string()));
-        }
-        
-        let first_line = lines[0];
-        let parts: Vec<&str> = first_line.split_whitespace().collect();
-        
-        if parts.len() < 3 {
-            return Err(GateError::ProcessingFailed("Invalid HTTP request format".to_string()));
-        }
-        
-        let method = parts[0];
-        let target = parts[1];
-        let version = parts[2];
+        let (head, _) = RequestHead::parse(data)
+            .map_err(|e| GateError::ProcessingFailed(e.to_string()))?;
+        let method = head.method.as_str();
+        let target = head.target.as_str();
+        let version = head.version.as_str();
         
         println!("🔗 HTTP {} {} {}", method, target, version);
         
@@ -178,6 +167,10 @@ impl Gate for ProxyGate {
         self.detect_http_proxy(data) || self.detect_socks5_proxy(data)
     }
     
+    fn accepts(&self, data: &[u8]) -> bool {
+        self.detect_http_proxy(data) || self.detect_socks5_proxy(data)
+    }
+    
     async fn process(&self, data: &[u8]) -> Result<Vec<u8>, String> {
         match self.process_connection(data, None).await {
             Ok(result) => Ok(result),
@@ -200,6 +193,11 @@ impl Gate for ProxyGate {
         }
     }
     
+    fn set_open(&self, open: bool) -> bool {
+        if open { self.enable() } else { self.disable() }
+        true
+    }
+    
     fn name(&self) -> &str {
         "proxy"
     }
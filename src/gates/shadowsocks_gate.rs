@@ -4,6 +4,14 @@
 use async_trait::async_trait;
 use std::sync::Arc;
 use parking_lot::RwLock;
+use tokio::net::TcpStream;
+
+use super::GateError;
+use crate::knox_proxy::{EgressOptions, KnoxProxyConfig};
+use crate::quota::QuotaTracker;
+use crate::shadowsocks::{ShadowsocksCrypto, ShadowsocksHandler};
+use crate::types::ShadowsocksMethod;
+use crate::universal_listener::PrefixedStream;
 
 pub struct ShadowsocksGate {
     enabled: Arc<RwLock<bool>>,
@@ -15,6 +23,9 @@ struct ShadowsocksConfig {
     methods: Vec<String>,
     passwords: Vec<String>,
     ports: Vec<u16>,
+    egress: EgressOptions,
+    deny_targets: Vec<String>,
+    quota: Option<Arc<QuotaTracker>>,
 }
 
 impl ShadowsocksGate {
@@ -29,6 +40,9 @@ impl ShadowsocksGate {
                 ],
                 passwords: vec![],
                 ports: vec![8388, 8389, 8390],
+                egress: EgressOptions::default(),
+                deny_targets: Vec::new(),
+                quota: None,
             })),
         }
     }
@@ -41,34 +55,45 @@ impl ShadowsocksGate {
         *self.enabled.write() = false;
     }
     
-    fn detect_shadowsocks(&self, data: &[u8]) -> bool {
-        // Simple SS detection using RBCursive patterns
-        if data.len() < 32 {
-            return false;
-        }
-        
-        // Check for SS salt pattern (high entropy at start)
-        let entropy = self.calculate_entropy(&data[..32]);
-        entropy > 7.0 // High entropy indicates encrypted salt
+    pub fn set_passwords(&self, passwords: Vec<String>) {
+        self.config.write().passwords = passwords;
     }
     
-    fn calculate_entropy(&self, data: &[u8]) -> f32 {
-        let mut frequencies = [0u32; 256];
-        for &byte in data {
-            frequencies[byte as usize] += 1;
+    /// Apply the proxy's egress, target deny list and client quotas to
+    /// Shadowsocks connections too
+    pub fn set_policy(&self, proxy: &KnoxProxyConfig) {
+        let mut config = self.config.write();
+        config.egress = proxy.egress.clone();
+        config.deny_targets = proxy.deny_targets.clone();
+        config.quota = proxy.quota.clone();
+    }
+    
+    /// Handler with a user for every configured password under every
+    /// configured method we implement; `None` until a password is set
+    pub fn handler(&self) -> Option<ShadowsocksHandler> {
+        let config = self.config.read();
+        let methods: Vec<ShadowsocksMethod> = config
+            .methods
+            .iter()
+            .map(|name| (ShadowsocksMethod::from(name.as_str()), name))
+            .filter(|(method, name)| method.name() == name.to_lowercase())
+            .map(|(method, _)| method)
+            .collect();
+        let users: Vec<ShadowsocksCrypto> = config
+            .passwords
+            .iter()
+            .flat_map(|password| methods.iter().filter_map(|&method| ShadowsocksCrypto::new(method, password).ok()))
+            .collect();
+        if users.is_empty() {
+            return None;
         }
-        
-        let len = data.len() as f32;
-        let mut entropy = 0.0;
-        
-        for &count in &frequencies {
-            if count > 0 {
-                let p = count as f32 / len;
-                entropy -= p * p.log2();
-            }
+        let mut handler = ShadowsocksHandler::for_users(users)
+            .with_egress(config.egress.clone())
+            .with_deny_targets(config.deny_targets.clone());
+        if let Some(ref quota) = config.quota {
+            handler = handler.with_quota(quota.clone());
         }
-        
-        entropy
+        Some(handler)
     }
 }
 
@@ -78,20 +103,42 @@ impl super::Gate for ShadowsocksGate {
         *self.enabled.read()
     }
 
+    /// A salt and first chunk that one of the configured passwords opens
+    fn accepts(&self, data: &[u8]) -> bool {
+        self.handler().is_some_and(|handler| handler.recognizes(data))
+    }
+
     async fn process(&self, data: &[u8]) -> Result<Vec<u8>, String> {
         if !self.is_open(data).await {
             return Err("Shadowsocks gate is closed".to_string());
         }
-        
-        if !self.detect_shadowsocks(data) {
-            return Err("Not Shadowsocks protocol".to_string());
+        // Relaying needs the client's connection, see process_connection
+        Err("Shadowsocks needs the client connection".to_string())
+    }
+    
+    /// Decrypt the client's stream, whose first bytes are `data`, and relay
+    /// it to the target it names. Returns once the relay ends.
+    async fn process_connection(&self, data: &[u8], stream: Option<TcpStream>) -> Result<Vec<u8>, GateError> {
+        if !self.is_open(data).await {
+            return Err(GateError::ProcessingFailed("Shadowsocks gate is closed".to_string()));
         }
-        
-        // Process SS data (gated)
-        println!("Processing through Shadowsocks gate");
-        
-        // Would decrypt and forward here
-        Ok(data.to_vec())
+        let Some(stream) = stream else {
+            return Err(GateError::ProcessingFailed("Shadowsocks needs the client connection".to_string()));
+        };
+        let handler = self
+            .handler()
+            .ok_or_else(|| GateError::ConnectionFailed("no Shadowsocks password configured".to_string()))?;
+        let peer = stream.peer_addr().ok();
+        handler
+            .handle_from(PrefixedStream::new(stream, data.to_vec()), peer)
+            .await
+            .map_err(|e| GateError::ConnectionFailed(e.to_string()))?;
+        Ok(Vec::new())
+    }
+    
+    fn set_open(&self, open: bool) -> bool {
+        if open { self.enable() } else { self.disable() }
+        true
     }
     
     fn name(&self) -> &str {
@@ -101,4 +148,135 @@ impl super::Gate for ShadowsocksGate {
     fn children(&self) -> Vec<Arc<dyn super::Gate>> {
         vec![] // SS gate has no children
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gates::Gate;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Client side of a Shadowsocks connection to `target`, already sent
+    async fn connect(listener: &TcpListener, crypto: &ShadowsocksCrypto, target: &[u8]) -> (TcpStream, TcpStream) {
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (salt, mut encrypt) = crypto.new_session().unwrap();
+        let mut wire = salt;
+        encrypt.seal_chunks(&[target, b"ping"].concat(), &mut wire).unwrap();
+        client.write_all(&wire).await.unwrap();
+        (client, server)
+    }
+
+    /// Enabled gate that accepts either of two passwords
+    fn keyed_gate() -> Arc<ShadowsocksGate> {
+        let gate = Arc::new(ShadowsocksGate::new());
+        gate.set_passwords(vec!["first".to_string(), "second".to_string()]);
+        gate.enable();
+        gate
+    }
+
+    #[tokio::test]
+    async fn test_gate_relays_for_any_password() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut accepted, _) = echo.accept().await.unwrap();
+            let (mut r, mut w) = accepted.split();
+            tokio::io::copy(&mut r, &mut w).await.unwrap();
+        });
+        let gate = keyed_gate();
+
+        // The second password under the second method still opens the stream
+        let crypto = ShadowsocksCrypto::new(ShadowsocksMethod::Aes256Gcm, "second").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = [&[0x01, 127, 0, 0, 1][..], &echo_port.to_be_bytes()].concat();
+        let (mut client, mut server) = connect(&listener, &crypto, &target).await;
+        let mut first = [0u8; 16];
+        server.read_exact(&mut first).await.unwrap();
+        let relay = tokio::spawn(async move { gate.process_connection(&first, Some(server)).await });
+        let mut salt = [0u8; 32];
+        client.read_exact(&mut salt).await.unwrap();
+        let mut decrypt = crypto.cipher(&salt).unwrap();
+        assert_eq!(decrypt.read_chunk(&mut client).await.unwrap().unwrap(), b"ping");
+        client.shutdown().await.unwrap();
+        assert_eq!(decrypt.read_chunk(&mut client).await.unwrap(), None);
+        assert!(relay.await.unwrap().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_gate_refuses_denied_targets() {
+        let gate = keyed_gate();
+        gate.set_policy(&KnoxProxyConfig { deny_targets: vec!["localhost".to_string()], ..Default::default() });
+
+        let crypto = ShadowsocksCrypto::new(ShadowsocksMethod::Aes256Gcm, "first").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = [&[0x03, 9][..], b"localhost", &443u16.to_be_bytes()].concat();
+        let (_client, server) = connect(&listener, &crypto, &target).await;
+        let err = gate.process_connection(&[], Some(server)).await.unwrap_err();
+        assert!(matches!(err, GateError::ConnectionFailed(_)), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn test_gate_needs_keys_and_a_connection() {
+        assert!(ShadowsocksGate::new().handler().is_none());
+
+        // Without the connection there is nothing to relay
+        let gate = keyed_gate();
+        assert!(gate.process(b"data").await.is_err());
+        gate.disable();
+        assert!(matches!(gate.process_connection(&[], None).await, Err(GateError::ProcessingFailed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_controller_routes_shadowsocks_ahead_of_crypto() {
+        use crate::gates::LitebikeGateController;
+
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut accepted, _) = echo.accept().await.unwrap();
+            let (mut r, mut w) = accepted.split();
+            tokio::io::copy(&mut r, &mut w).await.unwrap();
+        });
+
+        // A keyed crypto gate is open to anything at the same priority
+        let controller = LitebikeGateController::new();
+        controller.set_crypto_key([9; 32]);
+        controller.enable_shadowsocks(vec!["secret".to_string()]);
+        let stats = |controller: &LitebikeGateController, name: &str| {
+            let stats = controller.gate_stats().into_iter().find(|s| s.name == name).unwrap();
+            (stats.times_open, stats.times_processed)
+        };
+
+        let crypto = ShadowsocksCrypto::new(ShadowsocksMethod::Chacha20IetfPoly1305, "secret").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = [&[0x01, 127, 0, 0, 1][..], &echo_port.to_be_bytes()].concat();
+        let (mut client, mut server) = connect(&listener, &crypto, &target).await;
+        let mut first = [0u8; 64];
+        server.read_exact(&mut first).await.unwrap();
+        let route = tokio::spawn(async move {
+            let result = controller.route_with_connection(&first, Some(server)).await;
+            (controller, result)
+        });
+        let mut salt = [0u8; 32];
+        client.read_exact(&mut salt).await.unwrap();
+        let mut decrypt = crypto.cipher(&salt).unwrap();
+        assert_eq!(decrypt.read_chunk(&mut client).await.unwrap().unwrap(), b"ping");
+        client.shutdown().await.unwrap();
+        let (controller, result) = route.await.unwrap();
+        assert!(result.unwrap().is_empty());
+        assert_eq!(stats(&controller, "shadowsocks"), (1, 1));
+        assert_eq!(stats(&controller, "crypto"), (0, 0));
+
+        // Under a password we do not know it is just opaque bytes; the
+        // fallback pass may try shadowsocks, but crypto is what handles it
+        let stranger = ShadowsocksCrypto::new(ShadowsocksMethod::Chacha20IetfPoly1305, "other").unwrap();
+        let (salt, mut encrypt) = stranger.new_session().unwrap();
+        let mut wire = salt;
+        encrypt.seal_chunks(&target, &mut wire).unwrap();
+        assert!(controller.route(&wire).await.is_ok());
+        assert_eq!(stats(&controller, "shadowsocks").1, 1);
+        assert_eq!(stats(&controller, "crypto"), (1, 1));
+    }
+}
@@ -66,6 +66,11 @@ impl Default for SyncOptions {
 
 impl GitRepoState {
     pub fn analyze(path: Option<PathBuf>) -> Result<Self, String> {
+        Self::analyze_with_options(path, &SyncOptions::default())
+    }
+
+    /// Like `analyze`, probing remotes with `options.ssh_timeout`
+    pub fn analyze_with_options(path: Option<PathBuf>, options: &SyncOptions) -> Result<Self, String> {
         let repo_path = path.unwrap_or_else(|| env::current_dir().unwrap_or_else(|_| PathBuf::from(".")));
         
         // Check if this is a git repository
@@ -107,7 +112,7 @@ impl GitRepoState {
         let has_untracked_files = status_text.lines().any(|line| line.starts_with("??"));
         
         // Get remotes
-        let remotes = get_remotes(&repo_path)?;
+        let remotes = get_remotes(&repo_path, options.ssh_timeout)?;
         
         // Get commit count and check if shallow
         let commit_count = get_commit_count(&repo_path);
@@ -154,7 +159,15 @@ impl GitRepoState {
 }
 
 impl GitRemote {
-    fn from_remote_line(line: &str) -> Option<Self> {
+    fn from_remote_line(line: &str, timeout: Duration) -> Option<Self> {
+        Self::from_remote_line_with(line, timeout, test_host_reachability)
+    }
+
+    /// Parse a `git remote -v` line, checking reachability with `probe(host, port, timeout)`
+    fn from_remote_line_with<P>(line: &str, timeout: Duration, probe: P) -> Option<Self>
+    where
+        P: Fn(&str, u16, Duration) -> bool,
+    {
         let parts: Vec<&str> = line.split_whitespace().collect();
         if parts.len() < 3 {
             return None;
@@ -173,9 +186,9 @@ impl GitRemote {
         };
         
         let is_reachable = if let Some(h) = &host {
-            test_ssh_connectivity(h, port.unwrap_or(22))
-        } else if url.starts_with("http") {
-            test_http_connectivity(&url)
+            probe(h, port.unwrap_or(22), timeout)
+        } else if let Some((h, p)) = extract_http_host_port(&url) {
+            probe(&h, p, timeout)
         } else {
             false
         };
@@ -224,6 +237,11 @@ pub fn run_git_sync(args: &[String]) -> Result<(), String> {
                 options.force = true;
                 i += 1;
             }
+            "--ssh-timeout" if i + 1 < args.len() => {
+                let secs: u64 = args[i + 1].parse().map_err(|_| format!("Invalid SSH timeout: {}", args[i + 1]))?;
+                options.ssh_timeout = Duration::from_secs(secs);
+                i += 2;
+            }
             "--no-cleanup" => {
                 options.cleanup_temp_remotes = false;
                 i += 1;
@@ -248,8 +266,8 @@ pub fn run_git_sync(args: &[String]) -> Result<(), String> {
     
     // Handle subcommands
     match args[0].as_str() {
-        "status" => cmd_sync_status(target_path),
-        "clean" => cmd_clean_remotes(target_path),
+        "status" => cmd_sync_status(target_path, &options),
+        "clean" => cmd_clean_remotes(target_path, &options),
         "clone" => cmd_clone_repo(target_url, target_path, &options),
         "push" => cmd_push_repo(target_url, target_path, &options),
         "pull" => cmd_pull_repo(target_url, target_path, &options),
@@ -268,8 +286,8 @@ pub fn run_git_sync(args: &[String]) -> Result<(), String> {
     }
 }
 
-fn cmd_sync_status(path: Option<PathBuf>) -> Result<(), String> {
-    let state = GitRepoState::analyze(path)?;
+fn cmd_sync_status(path: Option<PathBuf>, options: &SyncOptions) -> Result<(), String> {
+    let state = GitRepoState::analyze_with_options(path, options)?;
     
     println!("Git Repository Status");
     println!("  Path: {}", state.path.display());
@@ -308,8 +326,8 @@ fn cmd_sync_status(path: Option<PathBuf>) -> Result<(), String> {
     Ok(())
 }
 
-fn cmd_clean_remotes(path: Option<PathBuf>) -> Result<(), String> {
-    let state = GitRepoState::analyze(path)?;
+fn cmd_clean_remotes(path: Option<PathBuf>, options: &SyncOptions) -> Result<(), String> {
+    let state = GitRepoState::analyze_with_options(path, options)?;
     
     if !state.is_git_repo {
         return Err("Not a git repository".to_string());
@@ -385,7 +403,7 @@ fn cmd_clone_repo(url: Option<String>, path: Option<PathBuf>, options: &SyncOpti
 }
 
 fn cmd_push_repo(url: Option<String>, path: Option<PathBuf>, options: &SyncOptions) -> Result<(), String> {
-    let state = GitRepoState::analyze(path)?;
+    let state = GitRepoState::analyze_with_options(path, options)?;
     
     if !state.is_git_repo {
         return Err("Not a git repository".to_string());
@@ -436,7 +454,7 @@ fn cmd_push_repo(url: Option<String>, path: Option<PathBuf>, options: &SyncOptio
 }
 
 fn cmd_pull_repo(url: Option<String>, path: Option<PathBuf>, options: &SyncOptions) -> Result<(), String> {
-    let state = GitRepoState::analyze(path)?;
+    let state = GitRepoState::analyze_with_options(path, options)?;
     
     if !state.is_git_repo {
         return Err("Not a git repository".to_string());
@@ -487,7 +505,7 @@ fn cmd_bidirectional_sync(url: Option<String>, path: Option<PathBuf>, options: &
 }
 
 fn cmd_smart_sync(url: Option<String>, path: Option<PathBuf>, options: &SyncOptions) -> Result<(), String> {
-    let state = GitRepoState::analyze(path.clone())?;
+    let state = GitRepoState::analyze_with_options(path.clone(), options)?;
     
     if !state.is_git_repo {
         // Not a git repo - try to clone
@@ -511,7 +529,7 @@ fn cmd_smart_sync(url: Option<String>, path: Option<PathBuf>, options: &SyncOpti
     
     // Clean up stale remotes if requested
     if options.cleanup_temp_remotes {
-        let _ = cmd_clean_remotes(path.clone());
+        let _ = cmd_clean_remotes(path.clone(), options);
     }
     
     // Execute appropriate strategy
@@ -542,7 +560,7 @@ fn get_current_branch(repo_path: &Path) -> Option<String> {
     }
 }
 
-fn get_remotes(repo_path: &Path) -> Result<Vec<GitRemote>, String> {
+fn get_remotes(repo_path: &Path, timeout: Duration) -> Result<Vec<GitRemote>, String> {
     let output = Command::new("git")
         .current_dir(repo_path)
         .args(["remote", "-v"])
@@ -553,7 +571,7 @@ fn get_remotes(repo_path: &Path) -> Result<Vec<GitRemote>, String> {
     let mut remotes = Vec::new();
     
     for line in remotes_text.lines() {
-        if let Some(remote) = GitRemote::from_remote_line(line) {
+        if let Some(remote) = GitRemote::from_remote_line(line, timeout) {
             remotes.push(remote);
         }
     }
@@ -623,31 +641,35 @@ fn extract_ssh_host_port(url: &str) -> (Option<String>, Option<u16>) {
     (None, None)
 }
 
-fn test_ssh_connectivity(host: &str, port: u16) -> bool {
-    let timeout = Duration::from_secs(2);
+fn test_tcp_connectivity(host: &str, port: u16, timeout: Duration) -> bool {
     TcpStream::connect_timeout(
         &format!("{}:{}", host, port).parse().unwrap_or_else(|_| ([127,0,0,1], port).into()),
         timeout
     ).is_ok()
 }
 
-fn test_http_connectivity(url: &str) -> bool {
+/// TCP connect first; hosts that filter the port may still answer ICMP echo
+fn test_host_reachability(host: &str, port: u16, timeout: Duration) -> bool {
+    test_tcp_connectivity(host, port, timeout)
+        || host.parse().map(|ip| crate::syscall_net::ping(ip, timeout)).unwrap_or(false)
+}
+
+fn extract_http_host_port(url: &str) -> Option<(String, u16)> {
     // Simple URL parsing to extract host
-    if let Some(start) = url.find("://") {
-        let after_proto = &url[start + 3..];
-        if let Some(end) = after_proto.find('/') {
-            let host_port = &after_proto[..end];
-            if let Some(colon_pos) = host_port.find(':') {
-                let host = &host_port[..colon_pos];
-                let port: u16 = host_port[colon_pos + 1..].parse().unwrap_or(80);
-                return test_ssh_connectivity(host, port);
-            } else {
-                let port = if url.starts_with("https") { 443 } else { 80 };
-                return test_ssh_connectivity(host_port, port);
-            }
-        }
+    let start = url.find("://")?;
+    if !url.starts_with("http") {
+        return None;
+    }
+    let after_proto = &url[start + 3..];
+    let end = after_proto.find('/')?;
+    let host_port = &after_proto[..end];
+    if let Some(colon_pos) = host_port.find(':') {
+        let port: u16 = host_port[colon_pos + 1..].parse().unwrap_or(80);
+        Some((host_port[..colon_pos].to_string(), port))
+    } else {
+        let port = if url.starts_with("https") { 443 } else { 80 };
+        Some((host_port.to_string(), port))
     }
-    false
 }
 
 fn setup_remote(repo_path: &Path, remote_name: &str, url: &str) -> Result<(), String> {
@@ -968,6 +990,7 @@ fn show_git_sync_help() {
     println!("  --branch <name>        Target branch (default: current branch)");
     println!("  --force                Force push when needed");
     println!("  --no-cleanup           Don't clean stale remotes automatically");
+    println!("  --ssh-timeout <secs>   Remote reachability timeout (default: 5)");
     println!("  --path <path>          Repository path (default: current directory)");
     println!();
     println!("EXAMPLES:");
@@ -990,4 +1013,28 @@ fn show_git_sync_help() {
     println!("  - Reduced memory usage with shallow clones");
     println!("  - Shorter SSH timeouts for mobile connections");
     println!("  - Cleanup of temporary remotes to save space");
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_remote_probe_uses_configured_timeout() {
+        let options = SyncOptions { ssh_timeout: Duration::from_secs(12), ..Default::default() };
+        let seen = Cell::new(None);
+        let remote = GitRemote::from_remote_line_with(
+            "origin\tssh://git@10.0.0.2:2222/repo.git (fetch)",
+            options.ssh_timeout,
+            |host, port, timeout| {
+                seen.set(Some((host.to_string(), port, timeout)));
+                true
+            },
+        )
+        .unwrap();
+
+        assert!(remote.is_reachable);
+        assert_eq!(seen.take(), Some(("10.0.0.2".to_string(), 2222, Duration::from_secs(12))));
+    }
+}
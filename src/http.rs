@@ -0,0 +1,767 @@
+// HTTP request head parsing shared by the proxy, PAC/WPAD and auth paths
+// Bodies are never buffered; `HttpTally` only counts them as they stream past
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Upper bound on request line + headers, including the terminating blank line
+pub const MAX_HEAD_BYTES: usize = 16 * 1024;
+
+/// Parsed HTTP/1.x request line and headers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHead {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+}
+
+/// Errors from `RequestHead::parse`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HttpParseError {
+    /// The blank line ending the head has not arrived yet
+    Incomplete,
+    /// The head exceeds `MAX_HEAD_BYTES`
+    TooLarge,
+    MalformedRequestLine(String),
+    MalformedHeader(String),
+    /// A message body exceeded the configured limit
+    BodyTooLarge(u64),
+}
+
+impl fmt::Display for HttpParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpParseError::Incomplete => write!(f, "Incomplete HTTP request head"),
+            HttpParseError::TooLarge => write!(f, "HTTP request head exceeds {} bytes", MAX_HEAD_BYTES),
+            HttpParseError::MalformedRequestLine(line) => write!(f, "Malformed HTTP request line: {:?}", line),
+            HttpParseError::MalformedHeader(line) => write!(f, "Malformed HTTP header: {:?}", line),
+            HttpParseError::BodyTooLarge(limit) => write!(f, "HTTP message body exceeds {} bytes", limit),
+        }
+    }
+}
+
+impl std::error::Error for HttpParseError {}
+
+impl From<HttpParseError> for io::Error {
+    fn from(e: HttpParseError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Offset just past the `\r\n\r\n` ending the head, if present
+pub fn head_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n").map(|p| p + 4)
+}
+
+/// Decode standard base64 (RFC 4648 §4); trailing `=` padding is optional
+pub fn decode_base64(input: &str) -> Option<Vec<u8>> {
+    let digits = input.trim_end_matches('=');
+    if input.len() - digits.len() > 2 || digits.len() % 4 == 1 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(digits.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in digits.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn is_token(s: &[u8]) -> bool {
+    !s.is_empty() && s.iter().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(b))
+}
+
+/// Lines of a head split on CRLF, left as bytes so stray non-UTF-8 in one
+/// header value cannot spoil the rest
+fn head_lines(head: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = Some(head);
+    std::iter::from_fn(move || {
+        let line = rest?;
+        match line.windows(2).position(|w| w == b"\r\n") {
+            Some(i) => {
+                rest = Some(&line[i + 2..]);
+                Some(&line[..i])
+            }
+            None => {
+                rest = None;
+                Some(line)
+            }
+        }
+    })
+}
+
+/// Append `default_port` to an authority that lacks one (`[v6]` literals included)
+fn with_default_port(authority: &str, default_port: u16) -> String {
+    let has_port = match authority.rfind(']') {
+        Some(close) => authority[close..].contains(':'),
+        None => authority.contains(':'),
+    };
+    if has_port {
+        authority.to_string()
+    } else {
+        format!("{}:{}", authority, default_port)
+    }
+}
+
+/// Names must be ASCII tokens; values are decoded lossily
+fn parse_headers<'a>(lines: impl Iterator<Item = &'a [u8]>) -> Result<Vec<(String, String)>, HttpParseError> {
+    let mut headers = Vec::new();
+    for line in lines {
+        let malformed = || HttpParseError::MalformedHeader(String::from_utf8_lossy(line).into_owned());
+        let colon = line.iter().position(|&b| b == b':').ok_or_else(malformed)?;
+        let (name, value) = (&line[..colon], &line[colon + 1..]);
+        if !is_token(name) {
+            return Err(malformed());
+        }
+        headers.push((
+            String::from_utf8_lossy(name).into_owned(),
+            String::from_utf8_lossy(value).trim().to_string(),
+        ));
+    }
+    Ok(headers)
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+impl RequestHead {
+    /// Parse the head at the start of `buf`, returning it with the number of
+    /// bytes it occupied. Bytes after that offset belong to the body.
+    pub fn parse(buf: &[u8]) -> Result<(Self, usize), HttpParseError> {
+        let end = match head_end(buf) {
+            Some(end) if end > MAX_HEAD_BYTES => return Err(HttpParseError::TooLarge),
+            Some(end) => end,
+            None if buf.len() >= MAX_HEAD_BYTES => return Err(HttpParseError::TooLarge),
+            None => return Err(HttpParseError::Incomplete),
+        };
+
+        let mut lines = head_lines(&buf[..end - 4]);
+
+        // The request line must be ASCII; only header values may carry other bytes
+        let raw_line = lines.next().unwrap_or_default();
+        let request_line = match std::str::from_utf8(raw_line) {
+            Ok(line) if line.is_ascii() => line,
+            _ => return Err(HttpParseError::MalformedRequestLine(String::from_utf8_lossy(raw_line).into_owned())),
+        };
+        let parts: Vec<&str> = request_line.split(' ').collect();
+        if parts.len() != 3 || !is_token(parts[0].as_bytes()) || parts[1].is_empty() || !parts[2].starts_with("HTTP/") {
+            return Err(HttpParseError::MalformedRequestLine(request_line.to_string()));
+        }
+
+        let headers = parse_headers(lines)?;
+
+        Ok((
+            Self {
+                method: parts[0].to_string(),
+                target: parts[1].to_string(),
+                version: parts[2].to_string(),
+                headers,
+            },
+            end,
+        ))
+    }
+
+    /// First header value matching `name` case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// `user:pass` from a `Basic` credential in header `name`
+    /// (`Proxy-Authorization` for proxies)
+    pub fn basic_credentials(&self, name: &str) -> Option<String> {
+        let value = self.header(name)?.trim();
+        let (scheme, encoded) = value.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("Basic") {
+            return None;
+        }
+        String::from_utf8(decode_base64(encoded.trim())?).ok()
+    }
+
+    pub fn is_connect(&self) -> bool {
+        self.method == "CONNECT"
+    }
+
+    /// `host:port` to dial: the CONNECT target, the absolute-form URL
+    /// authority, or the Host header, in that order
+    pub fn authority(&self, default_port: u16) -> Option<String> {
+        if self.is_connect() {
+            return Some(with_default_port(&self.target, default_port));
+        }
+        if let Some(rest) = self.target.strip_prefix("http://") {
+            let host = rest.split('/').next().unwrap_or(rest);
+            if !host.is_empty() {
+                return Some(with_default_port(host, default_port));
+            }
+        }
+        self.header("Host")
+            .filter(|h| !h.is_empty())
+            .map(|h| with_default_port(h, default_port))
+    }
+
+    /// Origin-form path (`/index.html`) for forwarding upstream
+    pub fn path(&self) -> &str {
+        match self.target.strip_prefix("http://") {
+            Some(rest) => rest.find('/').map(|i| &rest[i..]).unwrap_or("/"),
+            None => &self.target,
+        }
+    }
+}
+
+/// How a message body is delimited (RFC 7230 §3.3.3)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyFraming {
+    None,
+    ContentLength(u64),
+    Chunked,
+    /// Response without length information: the body runs until close
+    UntilClose,
+}
+
+impl BodyFraming {
+    /// Framing declared by `headers`; `default` applies when neither
+    /// Transfer-Encoding nor Content-Length is present
+    pub fn from_headers(headers: &[(String, String)], default: BodyFraming) -> Result<Self, HttpParseError> {
+        if let Some(te) = find_header(headers, "Transfer-Encoding") {
+            let last = te.rsplit(',').next().unwrap_or("").trim();
+            return Ok(if last.eq_ignore_ascii_case("chunked") { BodyFraming::Chunked } else { BodyFraming::UntilClose });
+        }
+        match find_header(headers, "Content-Length") {
+            Some(cl) => cl
+                .parse::<u64>()
+                .map(BodyFraming::ContentLength)
+                .map_err(|_| HttpParseError::MalformedHeader(format!("Content-Length: {}", cl))),
+            None => Ok(default),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChunkState {
+    Size,
+    Extension,
+    SizeLf,
+    Data(u64),
+    DataCr,
+    DataLf,
+    TrailerStart,
+    Trailer,
+    EndLf,
+}
+
+/// Incremental body counter; consumes exactly the bytes of one body
+#[derive(Debug, Clone)]
+pub struct BodyCounter {
+    framing: BodyFraming,
+    chunk: ChunkState,
+    chunk_size: u64,
+    body_bytes: u64,
+    complete: bool,
+}
+
+impl BodyCounter {
+    pub fn new(framing: BodyFraming) -> Self {
+        Self {
+            framing,
+            chunk: ChunkState::Size,
+            chunk_size: 0,
+            body_bytes: 0,
+            complete: matches!(framing, BodyFraming::None | BodyFraming::ContentLength(0)),
+        }
+    }
+
+    /// Payload bytes seen so far (chunk framing excluded)
+    pub fn body_bytes(&self) -> u64 {
+        self.body_bytes
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Feed bytes, returning how many belonged to this body
+    pub fn feed(&mut self, data: &[u8]) -> Result<usize, HttpParseError> {
+        if self.complete {
+            return Ok(0);
+        }
+        match self.framing {
+            BodyFraming::None => Ok(0),
+            BodyFraming::UntilClose => {
+                self.body_bytes += data.len() as u64;
+                Ok(data.len())
+            }
+            BodyFraming::ContentLength(total) => {
+                let take = (total - self.body_bytes).min(data.len() as u64);
+                self.body_bytes += take;
+                self.complete = self.body_bytes == total;
+                Ok(take as usize)
+            }
+            BodyFraming::Chunked => self.feed_chunked(data),
+        }
+    }
+
+    fn feed_chunked(&mut self, data: &[u8]) -> Result<usize, HttpParseError> {
+        let bad = |what: &str| HttpParseError::MalformedHeader(format!("chunked body: {}", what));
+        let mut i = 0;
+        while i < data.len() && !self.complete {
+            let b = data[i];
+            match self.chunk {
+                ChunkState::Size => match b {
+                    b'0'..=b'9' | b'a'..=b'f' | b'A'..=b'F' => {
+                        let digit = (b as char).to_digit(16).unwrap_or(0) as u64;
+                        self.chunk_size = self.chunk_size
+                            .checked_mul(16)
+                            .and_then(|v| v.checked_add(digit))
+                            .ok_or_else(|| bad("chunk size overflow"))?;
+                    }
+                    b';' | b' ' | b'\t' => self.chunk = ChunkState::Extension,
+                    b'\r' => self.chunk = ChunkState::SizeLf,
+                    _ => return Err(bad("invalid chunk size")),
+                },
+                ChunkState::Extension => {
+                    if b == b'\r' {
+                        self.chunk = ChunkState::SizeLf;
+                    }
+                }
+                ChunkState::SizeLf => {
+                    if b != b'\n' {
+                        return Err(bad("expected LF after chunk size"));
+                    }
+                    self.chunk = if self.chunk_size == 0 { ChunkState::TrailerStart } else { ChunkState::Data(self.chunk_size) };
+                    self.chunk_size = 0;
+                }
+                ChunkState::Data(remaining) => {
+                    let take = remaining.min((data.len() - i) as u64);
+                    self.body_bytes += take;
+                    i += take as usize;
+                    self.chunk = if take == remaining { ChunkState::DataCr } else { ChunkState::Data(remaining - take) };
+                    continue;
+                }
+                ChunkState::DataCr => {
+                    if b != b'\r' {
+                        return Err(bad("expected CRLF after chunk data"));
+                    }
+                    self.chunk = ChunkState::DataLf;
+                }
+                ChunkState::DataLf => {
+                    if b != b'\n' {
+                        return Err(bad("expected CRLF after chunk data"));
+                    }
+                    self.chunk = ChunkState::Size;
+                }
+                ChunkState::TrailerStart => {
+                    self.chunk = if b == b'\r' { ChunkState::EndLf } else { ChunkState::Trailer };
+                }
+                ChunkState::Trailer => {
+                    if b == b'\n' {
+                        self.chunk = ChunkState::TrailerStart;
+                    }
+                }
+                ChunkState::EndLf => {
+                    if b != b'\n' {
+                        return Err(bad("expected final CRLF"));
+                    }
+                    self.complete = true;
+                }
+            }
+            i += 1;
+        }
+        Ok(i)
+    }
+}
+
+/// Which side of the exchange an `HttpTally` observes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Request,
+    Response,
+}
+
+/// Sizes of one completed HTTP message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageSize {
+    pub head_bytes: usize,
+    pub body_bytes: u64,
+}
+
+/// Streaming tally of HTTP/1.x message sizes in one direction of a relay.
+/// Heads are buffered (bounded by `MAX_HEAD_BYTES`); bodies are only counted.
+///
+/// Whether a response has a body depends on the request it answers, so a
+/// response tally frames each response with the next method queued by
+/// `expect_response`; with none queued it assumes GET.
+#[derive(Debug, Clone)]
+pub struct HttpTally {
+    kind: MessageKind,
+    head: Vec<u8>,
+    head_bytes: usize,
+    body: Option<BodyCounter>,
+    max_body_bytes: Option<u64>,
+    /// Request tallies: methods of heads seen, not yet taken by `take_methods`.
+    /// Response tallies: methods of requests still waiting for a response.
+    methods: VecDeque<String>,
+}
+
+impl HttpTally {
+    pub fn new(kind: MessageKind) -> Self {
+        Self { kind, head: Vec::new(), head_bytes: 0, body: None, max_body_bytes: None, methods: VecDeque::new() }
+    }
+
+    /// Response tallies: the next unanswered request used `method`
+    pub fn expect_response(&mut self, method: &str) {
+        self.methods.push_back(method.to_string());
+    }
+
+    /// Request tallies: methods of the request heads fed since the last call
+    pub fn take_methods(&mut self) -> impl Iterator<Item = String> + '_ {
+        self.methods.drain(..)
+    }
+
+    /// Fail with `BodyTooLarge` once a single body exceeds `limit` bytes
+    pub fn with_body_limit(mut self, limit: u64) -> Self {
+        self.max_body_bytes = Some(limit);
+        self
+    }
+
+    fn start_body(&mut self, end: usize) -> Result<(), HttpParseError> {
+        let mut lines = head_lines(&self.head[..end - 4]);
+        let start_line = String::from_utf8_lossy(lines.next().unwrap_or_default()).into_owned();
+        let headers = parse_headers(lines)?;
+        let framing = match self.kind {
+            MessageKind::Request => {
+                let method = start_line.split(' ').next().unwrap_or_default();
+                self.methods.push_back(method.to_string());
+                BodyFraming::from_headers(&headers, BodyFraming::None)?
+            }
+            MessageKind::Response => {
+                let status: u16 = start_line
+                    .split(' ')
+                    .nth(1)
+                    .and_then(|code| code.parse().ok())
+                    .ok_or_else(|| HttpParseError::MalformedRequestLine(start_line.clone()))?;
+                // Interim responses come before the final one to the same request
+                let method = match status {
+                    100..=199 if status != 101 => None,
+                    _ => self.methods.pop_front(),
+                };
+                if status == 101 {
+                    // The connection now carries another protocol
+                    BodyFraming::UntilClose
+                } else if (100..200).contains(&status) || status == 204 || status == 304 || method.as_deref() == Some("HEAD") {
+                    BodyFraming::None
+                } else {
+                    BodyFraming::from_headers(&headers, BodyFraming::UntilClose)?
+                }
+            }
+        };
+        self.head_bytes = end;
+        self.head.clear();
+        self.body = Some(BodyCounter::new(framing));
+        Ok(())
+    }
+
+    /// Feed relayed bytes; returns every message completed by them
+    pub fn feed(&mut self, mut data: &[u8]) -> Result<Vec<MessageSize>, HttpParseError> {
+        let mut done = Vec::new();
+        while !data.is_empty() || self.body.as_ref().is_some_and(|b| b.is_complete()) {
+            match self.body {
+                Some(ref mut body) => {
+                    let used = body.feed(data)?;
+                    data = &data[used..];
+                    if let Some(limit) = self.max_body_bytes {
+                        if body.body_bytes() > limit {
+                            return Err(HttpParseError::BodyTooLarge(limit));
+                        }
+                    }
+                    if body.is_complete() {
+                        done.push(MessageSize { head_bytes: self.head_bytes, body_bytes: body.body_bytes() });
+                        self.body = None;
+                    }
+                }
+                None => {
+                    // Only scan the bytes that can complete the head
+                    let scan_from = self.head.len().saturating_sub(3);
+                    let take = data.len().min(MAX_HEAD_BYTES + 1 - self.head.len().min(MAX_HEAD_BYTES));
+                    self.head.extend_from_slice(&data[..take]);
+                    match head_end(&self.head[scan_from..]).map(|e| e + scan_from) {
+                        Some(end) => {
+                            let unused = self.head.len() - end;
+                            data = &data[take - unused..];
+                            self.start_body(end)?;
+                        }
+                        None if self.head.len() > MAX_HEAD_BYTES => return Err(HttpParseError::TooLarge),
+                        None => data = &data[take..],
+                    }
+                }
+            }
+        }
+        Ok(done)
+    }
+
+    /// Report a body delimited by connection close, if one was in progress
+    pub fn finish(&mut self) -> Option<MessageSize> {
+        let body = self.body.take()?;
+        Some(MessageSize { head_bytes: self.head_bytes, body_bytes: body.body_bytes() })
+    }
+}
+
+/// Upstream stream wrapper that tallies request bodies written to it and
+/// response bodies read from it, logging one access line per response.
+///
+/// Accounting is passive: traffic it cannot parse is logged once and relayed
+/// untallied from then on. Only an exceeded body limit fails the stream, and
+/// the bytes over the limit are never passed on.
+pub struct TalliedStream<S> {
+    inner: S,
+    label: String,
+    requests: Option<HttpTally>,
+    responses: Option<HttpTally>,
+}
+
+impl<S> TalliedStream<S> {
+    pub fn new(inner: S, label: impl Into<String>, max_body_bytes: Option<u64>) -> Self {
+        let (mut requests, mut responses) = (HttpTally::new(MessageKind::Request), HttpTally::new(MessageKind::Response));
+        if let Some(limit) = max_body_bytes {
+            requests = requests.with_body_limit(limit);
+            responses = responses.with_body_limit(limit);
+        }
+        Self { inner, label: label.into(), requests: Some(requests), responses: Some(responses) }
+    }
+
+    fn log_response(&self, size: MessageSize) {
+        log::info!("HTTP {} response head {} bytes, body {} bytes", self.label, size.head_bytes, size.body_bytes);
+    }
+
+    /// Feed one side's tally. A limit breach is returned; any other parse
+    /// error switches tallying off for good (both sides, since responses
+    /// are framed by the requests' methods).
+    fn tally(&mut self, kind: MessageKind, data: &[u8]) -> Result<Vec<MessageSize>, HttpParseError> {
+        let tally = match kind {
+            MessageKind::Request => self.requests.as_mut(),
+            MessageKind::Response => self.responses.as_mut(),
+        };
+        let Some(tally) = tally else {
+            return Ok(Vec::new());
+        };
+        match tally.feed(data) {
+            Ok(done) => {
+                if let (Some(requests), Some(responses)) = (self.requests.as_mut(), self.responses.as_mut()) {
+                    for method in requests.take_methods() {
+                        responses.expect_response(&method);
+                    }
+                }
+                Ok(done)
+            }
+            Err(e @ HttpParseError::BodyTooLarge(_)) => Err(e),
+            Err(e) => {
+                log::warn!("HTTP {}: {}; no longer tallying this connection", self.label, e);
+                self.requests = None;
+                self.responses = None;
+                Ok(Vec::new())
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TalliedStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                let this = &mut *self;
+                let fresh = &buf.filled()[before..];
+                if fresh.is_empty() {
+                    if let Some(size) = this.responses.as_mut().and_then(HttpTally::finish) {
+                        this.log_response(size);
+                    }
+                } else {
+                    // An error here keeps the over-limit bytes from the caller
+                    for size in this.tally(MessageKind::Response, fresh)? {
+                        this.log_response(size);
+                    }
+                }
+                Poll::Ready(Ok(()))
+            }
+            other => other,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TalliedStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        // Refuse a write that would take a body over the limit before any of it goes out
+        if let Some(requests) = self.requests.as_ref().filter(|t| t.max_body_bytes.is_some()) {
+            if let Err(e @ HttpParseError::BodyTooLarge(_)) = requests.clone().feed(buf) {
+                return Poll::Ready(Err(e.into()));
+            }
+        }
+        match Pin::new(&mut self.inner).poll_write(cx, buf) {
+            Poll::Ready(Ok(n)) => {
+                for size in self.tally(MessageKind::Request, &buf[..n])? {
+                    log::debug!("HTTP {} request body {} bytes", self.label, size.body_bytes);
+                }
+                Poll::Ready(Ok(n))
+            }
+            other => other,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_connect() {
+        let raw = b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n";
+        let (head, used) = RequestHead::parse(raw).unwrap();
+        assert_eq!(used, raw.len());
+        assert!(head.is_connect());
+        assert_eq!(head.version, "HTTP/1.1");
+        assert_eq!(head.authority(443).as_deref(), Some("example.com:443"));
+        assert_eq!(head.header("host"), Some("example.com:443"));
+    }
+
+    #[test]
+    fn test_parse_absolute_form_get() {
+        let raw = b"GET http://example.com/a/b?c=1 HTTP/1.1\r\nHost: example.com\r\nUser-Agent: t\r\n\r\nbody";
+        let (head, used) = RequestHead::parse(raw).unwrap();
+        assert_eq!(&raw[used..], b"body");
+        assert_eq!(head.method, "GET");
+        assert_eq!(head.authority(80).as_deref(), Some("example.com:80"));
+        assert_eq!(head.path(), "/a/b?c=1");
+        assert_eq!(head.headers.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_malformed_request_line() {
+        let err = RequestHead::parse(b"GARBAGE\r\n\r\n").unwrap_err();
+        assert!(matches!(err, HttpParseError::MalformedRequestLine(_)));
+        assert_eq!(RequestHead::parse(b"GET / HTTP/1.1\r\nHost: x"), Err(HttpParseError::Incomplete));
+        assert_eq!(RequestHead::parse(&vec![b'a'; MAX_HEAD_BYTES]), Err(HttpParseError::TooLarge));
+        let err = RequestHead::parse(b"GET /\xff HTTP/1.1\r\n\r\n").unwrap_err();
+        assert!(matches!(err, HttpParseError::MalformedRequestLine(_)));
+    }
+
+    #[test]
+    fn test_parse_non_utf8_header_value() {
+        let raw = b"GET / HTTP/1.1\r\nX-Name: caf\xe9\r\nHost: example.com\r\n\r\n\xff\xfe";
+        let (head, used) = RequestHead::parse(raw).unwrap();
+        assert_eq!(used, raw.len() - 2);
+        assert_eq!(head.header("X-Name"), Some("caf\u{fffd}"));
+        assert_eq!(head.header("Host"), Some("example.com"));
+    }
+
+    #[test]
+    fn test_tally_chunked_response() {
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nX-Trailer: t\r\n\r\n";
+        let mut tally = HttpTally::new(MessageKind::Response);
+        let mut done = Vec::new();
+        // Feed in awkward slices to exercise the state machine boundaries
+        for piece in response.chunks(3) {
+            done.extend(tally.feed(piece).unwrap());
+        }
+        assert_eq!(done, vec![MessageSize { head_bytes: 47, body_bytes: 12 }]);
+        assert_eq!(tally.finish(), None);
+    }
+
+    #[test]
+    fn test_tally_content_length_responses() {
+        let mut tally = HttpTally::new(MessageKind::Response);
+        let pipelined = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789HTTP/1.1 304 Not Modified\r\n\r\n";
+        let done = tally.feed(pipelined).unwrap();
+        assert_eq!(done.len(), 2);
+        assert_eq!(done[0].body_bytes, 10);
+        assert_eq!(done[1], MessageSize { head_bytes: 29, body_bytes: 0 });
+
+        let mut limited = HttpTally::new(MessageKind::Response).with_body_limit(4);
+        let err = limited.feed(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789").unwrap_err();
+        assert_eq!(err, HttpParseError::BodyTooLarge(4));
+    }
+
+    #[test]
+    fn test_tally_frames_responses_by_request_method() {
+        let mut requests = HttpTally::new(MessageKind::Request);
+        let mut responses = HttpTally::new(MessageKind::Response);
+        requests.feed(b"HEAD /big HTTP/1.1\r\nHost: a\r\n\r\nGET /small HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        for method in requests.take_methods() {
+            responses.expect_response(&method);
+        }
+
+        // The HEAD reply advertises a length it never sends; the GET reply follows at once
+        let done = responses
+            .feed(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 5000\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+            .unwrap();
+        let bodies: Vec<u64> = done.iter().map(|m| m.body_bytes).collect();
+        assert_eq!(bodies, vec![0, 0, 2]);
+        assert_eq!(responses.finish(), None);
+    }
+
+    /// Upstream half of a duplex pipe wrapped the way the proxy wraps it
+    fn tallied(limit: Option<u64>) -> (TalliedStream<tokio::io::DuplexStream>, tokio::io::DuplexStream) {
+        let (near, far) = tokio::io::duplex(1 << 16);
+        (TalliedStream::new(near, "test", limit), far)
+    }
+
+    #[tokio::test]
+    async fn test_tallied_stream_survives_unparsable_traffic() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut stream, mut far) = tallied(Some(1024));
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").await.unwrap();
+        far.write_all(b"NOT HTTP AT ALL\r\n\r\nstill relayed").await.unwrap();
+        let mut reply = vec![0u8; 32];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert!(reply.ends_with(b"still relayed"));
+        assert!(stream.responses.is_none());
+
+        // Later bytes pass untallied, whatever they look like
+        stream.write_all(b"\x00\x01 anything").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tallied_stream_refuses_write_over_limit() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut stream, mut far) = tallied(Some(4));
+        stream.write_all(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n").await.unwrap();
+        let err = stream.write_all(b"0123456789").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        drop(stream);
+
+        // Nothing of the oversized chunk reached upstream
+        let mut upstream = Vec::new();
+        far.read_to_end(&mut upstream).await.unwrap();
+        assert_eq!(upstream, b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n");
+    }
+}
@@ -1,14 +1,16 @@
 // Integrated Proxy Architecture - Combines all litebike components
 // Channel management + Gate routing + Knox awareness + P2P subsumption
 
-use crate::channel::{ChannelManager, ChannelType, ProxyChannel};
+use crate::channel::{AggregatedChannelStats, ChannelManager, ChannelType, ProxyChannel};
 use crate::gates::{LitebikeGateController, GateError};
 use crate::knox_proxy::KnoxProxyConfig;
 use crate::rbcursive::{RBCursive, ProtocolDetection};
+use crate::universal_listener::{accept_next, reset_on_close};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tokio::net::{TcpListener, TcpStream};
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::time::Instant;
 
 /// Integrated proxy server combining all litebike components
@@ -31,6 +33,8 @@ pub struct IntegratedProxyConfig {
     pub enable_gate_routing: bool,
     pub max_connections: usize,
     pub connection_timeout_seconds: u64,
+    /// Client addresses refused straight after accept
+    pub denied_clients: Vec<IpAddr>,
 }
 
 impl Default for IntegratedProxyConfig {
@@ -46,10 +50,20 @@ impl Default for IntegratedProxyConfig {
             enable_gate_routing: true,
             max_connections: 1000,
             connection_timeout_seconds: 300,
+            denied_clients: Vec::new(),
         }
     }
 }
 
+impl IntegratedProxyConfig {
+    /// This config as `config::to_toml` writes it, secrets replaced by
+    /// `config::REDACTED`; pair with `config::load_effective` to see what a
+    /// deployment runs with
+    pub fn to_effective_string(&self) -> String {
+        crate::config::to_toml(&crate::config::redacted(self))
+    }
+}
+
 /// Connection information for monitoring
 #[derive(Debug, Clone)]
 struct ConnectionInfo {
@@ -74,6 +88,7 @@ impl IntegratedProxyServer {
         );
         
         let gate_controller = Arc::new(LitebikeGateController::new());
+        gate_controller.set_shadowsocks_policy(&config.knox_config);
         
         // Enable Knox mode if configured
         if config.knox_config.enable_knox_bypass {
@@ -151,11 +166,18 @@ impl IntegratedProxyServer {
         tokio::spawn(async move {
             println!("🎧 Listener started for {}", bind_addr);
             
-            while let Ok((stream, peer_addr)) = listener.accept().await {
+            loop {
+                // Accept errors are per connection and never end the listener
+                let (stream, peer_addr) = accept_next(&listener).await;
+                if config.denied_clients.contains(&peer_addr.ip()) {
+                    close_refused(stream);
+                    continue;
+                }
                 // Check connection limits
                 let current_connections = active_connections.read().await.len();
                 if current_connections >= config.max_connections {
                     println!("⚠ Connection limit reached, rejecting {}", peer_addr);
+                    close_refused(stream);
                     continue;
                 }
                 
@@ -224,10 +246,21 @@ impl IntegratedProxyServer {
             total_bytes_transferred: active_connections.values()
                 .map(|c| c.bytes_transferred)
                 .sum(),
+            channels: channel_manager.aggregate_stats().await,
         }
     }
 }
 
+/// Close a connection refused before any handler ran. The reset releases the
+/// socket at once: a scanning client that never closes its side cannot hold
+/// it in CLOSE_WAIT, nor our side leave it in TIME_WAIT.
+fn close_refused(stream: TcpStream) {
+    if let Err(e) = reset_on_close(&stream) {
+        println!("⚠ Could not set reset on close: {}", e);
+    }
+    drop(stream);
+}
+
 /// Connection handler for integrated proxy
 struct IntegratedConnectionHandler {
     conn_id: String,
@@ -323,6 +356,8 @@ pub struct IntegratedProxyStats {
     pub knox_enabled: bool,
     pub pattern_matching_enabled: bool,
     pub total_bytes_transferred: u64,
+    /// Summed from every active channel's provider
+    pub channels: AggregatedChannelStats,
 }
 
 /// Integrated proxy errors
@@ -365,6 +400,32 @@ mod tests {
         assert!(stats.uptime_seconds < 1);
     }
     
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_denied_clients_do_not_leak_fds() {
+        let open_fds = || std::fs::read_dir("/proc/self/fd").unwrap().count();
+        let config = IntegratedProxyConfig {
+            denied_clients: vec!["127.0.0.1".parse().unwrap()],
+            ..Default::default()
+        };
+        let proxy = IntegratedProxyServer::new(config);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = proxy.spawn_listener(listener, addr.to_string()).await;
+
+        let before = open_fds();
+        for _ in 0..200 {
+            // The reset may beat the handshake's completion on loopback
+            let Ok(mut client) = TcpStream::connect(addr).await else { continue };
+            use tokio::io::AsyncReadExt;
+            let mut buf = [0u8; 1];
+            let read = tokio::time::timeout(std::time::Duration::from_secs(2), client.read(&mut buf)).await.unwrap();
+            assert!(matches!(read, Ok(0) | Err(_)));
+        }
+        assert!(open_fds() <= before + 2, "fds grew from {} to {}", before, open_fds());
+        server.abort();
+    }
+
     #[test]
     fn integrated_config_defaults() {
         let config = IntegratedProxyConfig::default();
@@ -0,0 +1,263 @@
+// Interface watcher - notices the primary interface coming and going
+// swlan0 appears/disappears as Android tethering toggles; listeners bound at
+// startup need to move between it and the loopback fallback
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use log::{debug, info, warn};
+use tokio::net::TcpListener;
+
+use crate::syscall_net::{list_interfaces, InterfaceAddr};
+use crate::tcp_fingerprint::{bind_with_options, TcpTuningOptions};
+
+/// Where the watcher learns interface state; injectable for tests
+pub trait InterfaceStateSource: Send {
+    /// IPv4 address of `name` if the interface is up and addressed
+    fn ipv4_of(&mut self, name: &str) -> Option<Ipv4Addr>;
+}
+
+/// Live state from `getifaddrs`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SyscallInterfaceSource;
+
+impl InterfaceStateSource for SyscallInterfaceSource {
+    fn ipv4_of(&mut self, name: &str) -> Option<Ipv4Addr> {
+        let interfaces = list_interfaces().ok()?;
+        let iface = interfaces.get(name)?;
+        if iface.flags & libc::IFF_UP as u32 == 0 {
+            return None;
+        }
+        iface.addrs.iter().find_map(|a| match a {
+            InterfaceAddr::V4(ip) => Some(*ip),
+            _ => None,
+        })
+    }
+}
+
+/// Change in the watched interface's state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterfaceEvent {
+    Up(Ipv4Addr),
+    Down,
+}
+
+/// Polls one interface and reports transitions
+pub struct InterfaceWatcher<S> {
+    interface: String,
+    source: S,
+    last: Option<Ipv4Addr>,
+    poll_interval: Duration,
+}
+
+impl InterfaceWatcher<SyscallInterfaceSource> {
+    pub fn for_interface(interface: &str) -> Self {
+        Self::new(interface, SyscallInterfaceSource)
+    }
+}
+
+impl<S: InterfaceStateSource> InterfaceWatcher<S> {
+    /// The interface is assumed down until the first poll sees it
+    pub fn new(interface: &str, source: S) -> Self {
+        Self {
+            interface: interface.to_string(),
+            source,
+            last: None,
+            poll_interval: Duration::from_secs(2),
+        }
+    }
+
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Check once, returning an event if the state changed since the last poll
+    pub fn poll(&mut self) -> Option<InterfaceEvent> {
+        let now = self.source.ipv4_of(&self.interface);
+        if now == self.last {
+            return None;
+        }
+        self.last = now;
+        let event = match now {
+            Some(ip) => InterfaceEvent::Up(ip),
+            None => InterfaceEvent::Down,
+        };
+        debug!("Interface {} changed: {:?}", self.interface, event);
+        Some(event)
+    }
+
+    /// Poll forever, invoking `on_event` for each transition
+    pub async fn run<F>(mut self, mut on_event: F)
+    where
+        F: FnMut(InterfaceEvent),
+    {
+        loop {
+            if let Some(event) = self.poll() {
+                on_event(event);
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+}
+
+/// Listen on `ip`:`port` with `options` set before the bind
+fn bind_tuned(ip: Ipv4Addr, port: u16, options: &TcpTuningOptions) -> io::Result<TcpListener> {
+    let listener = bind_with_options(SocketAddr::new(IpAddr::V4(ip), port), options)?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+/// Bind `port` on the interface address for `Up`, or on loopback for `Down`.
+/// Callers swap their listener for the returned one, dropping the previous bind.
+pub async fn rebind_for_event(event: InterfaceEvent, port: u16, options: &TcpTuningOptions) -> io::Result<TcpListener> {
+    let ip = match event {
+        InterfaceEvent::Up(ip) => ip,
+        InterfaceEvent::Down => Ipv4Addr::LOCALHOST,
+    };
+    let listener = bind_tuned(ip, port, options)?;
+    info!("Listener re-bound to {}", listener.local_addr()?);
+    Ok(listener)
+}
+
+/// `listener` moved to where `event` says it belongs, keeping its port and
+/// re-applying `options`. Unchanged when it is already there or the new
+/// bind fails.
+pub async fn move_listener(listener: TcpListener, event: InterfaceEvent, options: &TcpTuningOptions) -> TcpListener {
+    let Ok(local) = listener.local_addr() else {
+        return listener;
+    };
+    let wanted = match event {
+        InterfaceEvent::Up(ip) => ip,
+        InterfaceEvent::Down => Ipv4Addr::LOCALHOST,
+    };
+    if local.ip() == IpAddr::V4(wanted) {
+        return listener;
+    }
+    match rebind_for_event(event, local.port(), options).await {
+        Ok(moved) => moved,
+        Err(e) => {
+            warn!("Keeping listener on {}, re-binding for {:?} failed: {}", local, event, e);
+            listener
+        }
+    }
+}
+
+/// Why `bind_with_fallback` ended up on loopback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FallbackReason {
+    /// The primary interface is absent, down or has no IPv4 address
+    InterfaceMissing,
+    /// The interface exists but binding its address failed
+    BindFailed(io::ErrorKind),
+}
+
+/// Which address `bind_with_fallback` bound
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindOutcome {
+    Primary(Ipv4Addr),
+    Fallback(FallbackReason),
+}
+
+/// Bind `port` on `interface`, falling back to loopback when that is not
+/// possible; either way with `options` applied. The outcome says which
+/// happened so embedders can warn or retry.
+pub async fn bind_with_fallback<S: InterfaceStateSource>(
+    interface: &str,
+    port: u16,
+    source: &mut S,
+    options: &TcpTuningOptions,
+) -> io::Result<(TcpListener, BindOutcome)> {
+    let reason = match source.ipv4_of(interface) {
+        Some(ip) => match bind_tuned(ip, port, options) {
+            Ok(listener) => return Ok((listener, BindOutcome::Primary(ip))),
+            Err(e) => {
+                warn!("Binding {} on {} ({}) failed: {}", port, interface, ip, e);
+                FallbackReason::BindFailed(e.kind())
+            }
+        },
+        None => {
+            warn!("Interface {} unavailable", interface);
+            FallbackReason::InterfaceMissing
+        }
+    };
+    let listener = bind_tuned(Ipv4Addr::LOCALHOST, port, options)?;
+    info!("Using loopback fallback {}", listener.local_addr()?);
+    Ok((listener, BindOutcome::Fallback(reason)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Scripted interface states, one per poll; the last state repeats
+    struct ScriptedSource(Arc<Mutex<Vec<Option<Ipv4Addr>>>>);
+
+    impl InterfaceStateSource for ScriptedSource {
+        fn ipv4_of(&mut self, _name: &str) -> Option<Ipv4Addr> {
+            let mut states = self.0.lock().unwrap();
+            if states.len() > 1 { states.remove(0) } else { states[0] }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_interface_up_triggers_rebind() {
+        let up = Ipv4Addr::new(127, 0, 0, 1);
+        let states = Arc::new(Mutex::new(vec![None, None, Some(up)]));
+        let watcher = InterfaceWatcher::new("swlan0", ScriptedSource(states))
+            .with_poll_interval(Duration::from_millis(5));
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let task = tokio::spawn(watcher.run(move |event| { let _ = tx.send(event); }));
+
+        let event = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await.unwrap().unwrap();
+        assert_eq!(event, InterfaceEvent::Up(up));
+        task.abort();
+
+        let listener = rebind_for_event(event, 0, &TcpTuningOptions::listener()).await.unwrap();
+        assert_eq!(listener.local_addr().unwrap().ip(), IpAddr::V4(up));
+    }
+
+    #[tokio::test]
+    async fn test_bind_with_fallback_reports_reason() {
+        let mut missing = ScriptedSource(Arc::new(Mutex::new(vec![None])));
+        let (listener, outcome) = bind_with_fallback("swlan0", 0, &mut missing, &TcpTuningOptions::listener()).await.unwrap();
+        assert_eq!(outcome, BindOutcome::Fallback(FallbackReason::InterfaceMissing));
+        assert_eq!(listener.local_addr().unwrap().ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        // TEST-NET-1 is never assigned locally, so the bind itself fails
+        let mut foreign = ScriptedSource(Arc::new(Mutex::new(vec![Some(Ipv4Addr::new(192, 0, 2, 1))])));
+        let (_, outcome) = bind_with_fallback("swlan0", 0, &mut foreign, &TcpTuningOptions::listener()).await.unwrap();
+        assert_eq!(outcome, BindOutcome::Fallback(FallbackReason::BindFailed(io::ErrorKind::AddrNotAvailable)));
+
+        let mut up = ScriptedSource(Arc::new(Mutex::new(vec![Some(Ipv4Addr::LOCALHOST)])));
+        let (_, outcome) = bind_with_fallback("swlan0", 0, &mut up, &TcpTuningOptions::listener()).await.unwrap();
+        assert_eq!(outcome, BindOutcome::Primary(Ipv4Addr::LOCALHOST));
+    }
+
+    #[tokio::test]
+    async fn test_rebinds_keep_tuning_options() {
+        // SO_REUSEPORT on every bind lets a second listener share the port
+        let shared = TcpTuningOptions { reuse_port: true, ..TcpTuningOptions::listener() };
+        let mut up = ScriptedSource(Arc::new(Mutex::new(vec![Some(Ipv4Addr::LOCALHOST)])));
+        let (first, _) = bind_with_fallback("swlan0", 0, &mut up, &shared).await.unwrap();
+        let port = first.local_addr().unwrap().port();
+
+        let (_, outcome) = bind_with_fallback("swlan0", port, &mut up, &shared).await.unwrap();
+        assert_eq!(outcome, BindOutcome::Primary(Ipv4Addr::LOCALHOST));
+        let mut missing = ScriptedSource(Arc::new(Mutex::new(vec![None])));
+        assert!(bind_with_fallback("swlan0", port, &mut missing, &shared).await.is_ok());
+        assert!(rebind_for_event(InterfaceEvent::Down, port, &shared).await.is_ok());
+        assert!(rebind_for_event(InterfaceEvent::Down, port, &TcpTuningOptions::listener()).await.is_err());
+    }
+
+    #[test]
+    fn test_interface_down_reported_once() {
+        let states = Arc::new(Mutex::new(vec![Some(Ipv4Addr::new(10, 0, 0, 1)), None]));
+        let mut watcher = InterfaceWatcher::new("swlan0", ScriptedSource(states));
+        assert_eq!(watcher.poll(), Some(InterfaceEvent::Up(Ipv4Addr::new(10, 0, 0, 1))));
+        assert_eq!(watcher.poll(), Some(InterfaceEvent::Down));
+        assert_eq!(watcher.poll(), None);
+    }
+}
@@ -1,19 +1,50 @@
 // Knox Proxy - Dedicated carrier bypass and tethering restoration module
 // Expert-level automation for TERMUX Knox environments
 
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::fmt;
 use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::AsRawFd;
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use log::{info, warn, error, debug};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use log::{info, warn, error, debug, trace};
+use rand::Rng;
 
+use crate::capture::{CaptureConfig, CaptureSink, Direction};
+use crate::circuit_breaker::CircuitBreaker;
+use crate::conn_log::{unix_now, ConnLog, ConnRecord};
+use crate::device_profile::DeviceProfile;
+use crate::dock::{build_manifest_json_with_stats, DockCapabilities, DockStats};
+use crate::http::{HttpParseError, RequestHead, TalliedStream, MAX_HEAD_BYTES};
+use crate::quota::{ClientQuota, QuotaRefusal, QuotaTracker};
+use crate::resolver::{ResolveCache, DEFAULT_RESOLVE_TTL};
+use crate::routing::RoutingTable;
+use crate::types::{build_socks4_reply, build_socks5_reply, AuthMethod, build_socks5_udp_datagram, parse_socks5_udp_datagram, ProtocolType, Socks5Command, Socks5Reply, TargetAddress};
 use crate::tethering_bypass::{TetheringBypass, enable_carrier_bypass};
-use crate::universal_listener::{Protocol, detect_protocol_posix};
+use crate::tcp_fingerprint::{bind_to_device, bind_with_options, set_ip_ttl, set_tcp_mss, TcpTuningOptions};
+use crate::tls_fingerprint::TlsFingerprintManager;
+use crate::interface_watcher::{bind_with_fallback, move_listener, BindOutcome, InterfaceEvent, InterfaceWatcher, SyscallInterfaceSource};
+use crate::universal_listener::{Protocol, PrefixedStream, accept_next, bind_in_range, detect_protocol_posix, emit_proxy_protocol_v2, reject};
 
 /// Knox proxy configuration
 #[derive(Debug)]
 pub struct KnoxProxyConfig {
     pub bind_addr: String,
+    /// Listen on a random free port of this range, at `bind_addr`'s IP,
+    /// instead of `bind_addr`'s port; the dock manifest reports the real one
+    pub port_range: Option<RangeInclusive<u16>>,
+    /// Listen on this interface's IPv4 address at `bind_addr`'s port, on
+    /// loopback while the interface is missing (e.g. `swlan0` with
+    /// tethering off)
+    pub listen_interface: Option<String>,
+    /// Set on the listener before it binds, e.g. `reuse_port` for several
+    /// worker processes on one port
+    pub listen_tuning: TcpTuningOptions,
     pub socks_port: u16,
     pub enable_knox_bypass: bool,
     pub enable_tethering_bypass: bool,
@@ -23,12 +54,63 @@ pub struct KnoxProxyConfig {
     pub tcp_fingerprint_enabled: bool,
     pub packet_fragmentation_enabled: bool,
     pub tls_fingerprint_enabled: bool,
+    pub egress: EgressOptions,
+    /// Hard cap on a relayed connection's total duration, regardless of activity
+    pub max_lifetime: Option<Duration>,
+    /// Tally plain-HTTP message bodies for the access log
+    pub http_accounting: bool,
+    /// Reject plain-HTTP messages whose body exceeds this many bytes
+    pub max_body_bytes: Option<u64>,
+    /// Tee matching clients' traffic to capture files
+    pub capture: Option<CaptureConfig>,
+    /// Instance name reported in the `/litebike.json` dock manifest
+    pub instance_name: String,
+    /// ALPN offered to origins on upstream TLS, overriding the fingerprint profile's
+    pub upstream_alpn: Option<Vec<String>>,
+    /// Upstream hosts ("host" or "host:port") resolved at startup
+    pub warmup: Vec<String>,
+    /// How long resolved upstream addresses are reused; applies to every
+    /// host, the system resolver does not report record TTLs
+    pub resolve_ttl: Duration,
+    /// Per client IP limits; the tracker is shared by clones of this config
+    pub quota: Option<Arc<QuotaTracker>>,
+    /// Hosts clients may not reach; an entry also covers its subdomains
+    pub deny_targets: Vec<String>,
+    /// No-op writes that keep idle tunnels' carrier NAT mappings alive
+    pub heartbeat: Option<HeartbeatConfig>,
+    /// Device whose TLS, TCP and HTTP fingerprints upstream traffic wears;
+    /// set with `with_device_profile` so the egress options follow it
+    pub device_profile: Option<DeviceProfile>,
+    /// Binary log of relayed connections' metadata
+    pub conn_log: Option<Arc<ConnLog>>,
+    /// Largest single reads seen on sampled relays, for sizing `buffer_size`
+    pub read_peaks: Option<Arc<ReadHighWater>>,
+    /// How SOCKS5 clients authenticate; no authentication by default
+    pub socks5_auth: Socks5Auth,
+    /// Where SOCKS5 BIND listens and the address it reports
+    pub socks5_bind: BindOptions,
+    /// Serve SOCKS5 UDP ASSOCIATE; when off it is answered "command not
+    /// supported" so clients fall back to TCP
+    pub udp_associate_enabled: bool,
+    /// Status line and headers answering a successful CONNECT
+    pub connect_response: ConnectResponse,
+    /// `user:pass` pairs accepted as `Proxy-Authorization: Basic`; when
+    /// empty the HTTP proxy needs no authentication
+    pub http_credentials: Vec<String>,
+    /// Domain rules naming the channel a target leaves through
+    pub routes: RoutingTable,
+    /// Egress of each routed channel; targets routed to a channel not
+    /// listed here use `egress`
+    pub channel_egress: HashMap<String, EgressOptions>,
 }
 
 impl Default for KnoxProxyConfig {
     fn default() -> Self {
         Self {
             bind_addr: "0.0.0.0:8080".to_string(),
+            port_range: None,
+            listen_interface: None,
+            listen_tuning: TcpTuningOptions::listener(),
             socks_port: 1080,
             enable_knox_bypass: true,
             enable_tethering_bypass: true,
@@ -38,352 +120,3332 @@ impl Default for KnoxProxyConfig {
             tcp_fingerprint_enabled: true,
             packet_fragmentation_enabled: true,
             tls_fingerprint_enabled: true,
+            egress: EgressOptions::from_env(),
+            max_lifetime: None,
+            http_accounting: false,
+            max_body_bytes: None,
+            capture: None,
+            instance_name: "litebike".to_string(),
+            upstream_alpn: None,
+            warmup: Vec::new(),
+            resolve_ttl: DEFAULT_RESOLVE_TTL,
+            quota: None,
+            deny_targets: Vec::new(),
+            heartbeat: None,
+            device_profile: None,
+            conn_log: None,
+            read_peaks: None,
+            socks5_auth: Socks5Auth::None,
+            socks5_bind: BindOptions::default(),
+            udp_associate_enabled: true,
+            connect_response: ConnectResponse::default(),
+            http_credentials: Vec::new(),
+            routes: RoutingTable::default(),
+            channel_egress: HashMap::new(),
         }
     }
 }
 
-/// Knox proxy server
-pub struct KnoxProxy {
-    config: KnoxProxyConfig,
-    tethering_bypass: Option<TetheringBypass>,
-    active_connections: Arc<std::sync::atomic::AtomicUsize>,
+impl KnoxProxyConfig {
+    /// Egress for `target` ("host:port"): its routed channel's, else the default
+    pub fn egress_for(&self, target: &str) -> &EgressOptions {
+        self.routes
+            .route_target(target)
+            .and_then(|channel| self.channel_egress.get(channel))
+            .unwrap_or(&self.egress)
+    }
+
+    /// Authenticate SOCKS5 clients with `verifier`
+    pub fn with_socks5_verifier(mut self, verifier: Arc<dyn Socks5Verifier>) -> Self {
+        self.socks5_auth = Socks5Auth::Verifier(verifier);
+        self
+    }
+
+    /// Present upstream traffic as `device` on every layer: its TTL and MSS
+    /// on outbound sockets, its browser's ClientHello and its HTTP headers
+    pub fn with_device_profile(mut self, device: DeviceProfile) -> Self {
+        let tcp = device.tcp_fingerprint();
+        self.ttl_spoofing = tcp.ttl;
+        self.egress.ttl = Some(tcp.ttl);
+        self.egress.tcp_mss = Some(tcp.mss);
+        self.device_profile = Some(device);
+        self
+    }
 }
 
-impl KnoxProxy {
-    pub fn new(config: KnoxProxyConfig) -> Self {
-        Self {
-            config,
-            tethering_bypass: None,
-            active_connections: Arc::new(std::sync::atomic::AtomicUsize::new(0)),
-        }
+/// Checks RFC 1929 credentials somewhere else, such as a user database
+/// or an HTTP auth service
+#[async_trait::async_trait]
+pub trait Socks5Verifier: Send + Sync {
+    async fn verify(&self, username: &str, password: &str) -> bool;
+}
+
+/// In-memory username to password map
+#[async_trait::async_trait]
+impl Socks5Verifier for HashMap<String, String> {
+    async fn verify(&self, username: &str, password: &str) -> bool {
+        self.get(username).is_some_and(|expected| expected == password)
     }
-    
-    /// Start the Knox proxy server
-    pub async fn start(&mut self) -> io::Result<()> {
-        info!("🚀 Starting Knox Proxy");
-        info!("   Bind address: {}", self.config.bind_addr);
-        info!("   SOCKS port: {}", self.config.socks_port);
-        info!("   Knox bypass: {}", self.config.enable_knox_bypass);
-        info!("   Tethering bypass: {}", self.config.enable_tethering_bypass);
-        info!("   TTL spoofing: {}", self.config.ttl_spoofing);
-        
-        // Setup tethering bypass
-        if self.config.enable_tethering_bypass {
-            info!("🔓 Enabling tethering bypass...");
-            let mut bypass = TetheringBypass::new();
-            match bypass.enable_bypass() {
-                Ok(()) => {
-                    info!("✅ Tethering bypass enabled");
-                    self.tethering_bypass = Some(bypass);
-                }
-                Err(e) => {
-                    warn!("⚠ Tethering bypass failed: {}", e);
-                }
-            }
+}
+
+/// SOCKS5 client authentication
+#[derive(Clone, Default)]
+pub enum Socks5Auth {
+    /// Method 0x00, for clients on a trusted LAN
+    #[default]
+    None,
+    /// RFC 1929 username/password, method 0x02
+    UserPass { username: String, password: String },
+    /// RFC 1929 username/password decided by a verifier
+    Verifier(Arc<dyn Socks5Verifier>),
+}
+
+impl fmt::Debug for Socks5Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Socks5Auth::None => f.write_str("None"),
+            Socks5Auth::UserPass { username, .. } => write!(f, "UserPass {{ username: {:?}, .. }}", username),
+            Socks5Auth::Verifier(_) => f.write_str("Verifier(..)"),
         }
-        
-        // Bind listener
-        let listener = TcpListener::bind(&self.config.bind_addr).await?;
-        info!("✅ Knox proxy listening on {}", self.config.bind_addr);
-        
-        // Print usage instructions
-        self.print_usage_instructions();
-        
-        // Accept connections
-        while let Ok((stream, peer_addr)) = listener.accept().await {
-            let current_connections = self.active_connections.load(std::sync::atomic::Ordering::Relaxed);
-            
-            if current_connections >= self.config.max_connections {
-                warn!("⚠ Max connections ({}) reached, dropping {}", self.config.max_connections, peer_addr);
-                continue;
+    }
+}
+
+/// Verifiers are equal only to themselves
+impl PartialEq for Socks5Auth {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Socks5Auth::None, Socks5Auth::None) => true,
+            (Socks5Auth::UserPass { username: u1, password: p1 }, Socks5Auth::UserPass { username: u2, password: p2 }) => {
+                u1 == u2 && p1 == p2
             }
-            
-            self.active_connections.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-            
-            let config = self.config.clone();
-            let active_connections = self.active_connections.clone();
-            
-            tokio::spawn(async move {
-                match Self::handle_connection(stream, &config).await {
-                    Ok(()) => {
-                        debug!("✓ Connection from {} completed", peer_addr);
-                    }
-                    Err(e) => {
-                        error!("❌ Connection from {} failed: {}", peer_addr, e);
-                    }
-                }
-                active_connections.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
-            });
+            (Socks5Auth::Verifier(a), Socks5Auth::Verifier(b)) => Arc::ptr_eq(a, b),
+            _ => false,
         }
-        
-        Ok(())
     }
-    
-    /// Handle individual connection with Knox bypass
-    async fn handle_connection(mut stream: TcpStream, config: &KnoxProxyConfig) -> io::Result<()> {
-        let peer_addr = stream.peer_addr()?;
-        debug!("New connection from {}", peer_addr);
-        
-        // Use Knox bypass for protocol detection if enabled
-        let protocol = if config.enable_knox_bypass {
-            detect_protocol_posix(&stream)?
-        } else {
-            // Fallback to regular detection
-            let mut buffer = vec![0u8; 512];
-            let n = {
-                let stream_ref = &mut stream;
-                stream_ref.read(&mut buffer).await?
-            };
-            
-            if n > 0 && buffer[0] == 0x05 {
-                Protocol::Socks5
-            } else if n > 0 {
-                if let Ok(text) = std::str::from_utf8(&buffer[..std::cmp::min(n, 256)]) {
-                    if text.starts_with("GET ") || text.starts_with("POST ") || 
-                       text.starts_with("PUT ") || text.starts_with("CONNECT ") {
-                        Protocol::Http
-                    } else {
-                        Protocol::Unknown
-                    }
-                } else {
-                    Protocol::Unknown
-                }
-            } else {
-                Protocol::Unknown
-            }
-        };
-        
-        match protocol {
-            Protocol::Http => {
-                info!("Handling HTTP connection from {}", peer_addr);
-                Self::handle_http_proxy(stream, config).await
-            }
-            Protocol::Socks5 => {
-                info!("Handling SOCKS5 connection from {}", peer_addr);
-                Self::handle_socks5_proxy(stream, config).await
-            }
-            _ => {
-                warn!("Unknown protocol from {}, treating as HTTP", peer_addr);
-                Self::handle_http_proxy(stream, config).await
-            }
+}
+
+impl Socks5Auth {
+    fn method(&self) -> AuthMethod {
+        match self {
+            Socks5Auth::None => AuthMethod::NoAuth,
+            Socks5Auth::UserPass { .. } | Socks5Auth::Verifier(_) => AuthMethod::UsernamePassword,
         }
     }
-    
-    /// Handle HTTP CONNECT proxy
-    async fn handle_http_proxy(mut stream: TcpStream, config: &KnoxProxyConfig) -> io::Result<()> {
-        let mut buffer = vec![0u8; config.buffer_size];
-        let n = stream.read(&mut buffer).await?;
-        
-        if n == 0 {
+
+    /// Run the RFC 1929 sub-negotiation, answering 0x01 0x00 when the
+    /// credentials match and 0x01 0x01 when they don't
+    async fn verify<S>(&self, stream: &mut S) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        if *self == Socks5Auth::None {
             return Ok(());
         }
-        
-        let request = String::from_utf8_lossy(&buffer[..n]);
-        let lines: Vec<&str> = request.lines().collect();
-        
-        if lines.is_empty() {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "Empty request"));
-        }
-        
-        let first_line = lines[0];
-        let parts: Vec<&str> = first_line.split_whitespace().collect();
-        
-        if parts.len() < 3 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid HTTP request"));
+        let mut version = [0u8; 2];
+        stream.read_exact(&mut version).await?;
+        if version[0] != 0x01 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid SOCKS5 auth version"));
         }
-        
-        let method = parts[0];
-        let target = parts[1];
-        
-        if method == "CONNECT" {
-            // HTTP CONNECT for HTTPS tunneling
-            let addr = if target.contains(':') {
-                target.to_string()
-            } else {
-                format!("{}:443", target)
-            };
-            
-            debug!("CONNECT to {}", addr);
-            
-            // Connect to target
-            let target_stream = match TcpStream::connect(&addr).await {
-                Ok(s) => s,
-                Err(e) => {
-                    let response = "HTTP/1.1 502 Bad Gateway\r\n\r\n";
-                    stream.write_all(response.as_bytes()).await?;
-                    return Err(e);
-                }
-            };
-            
-            // Send success response
-            let response = "HTTP/1.1 200 Connection established\r\n\r\n";
-            stream.write_all(response.as_bytes()).await?;
-            
-            // Start bidirectional copy
-            Self::copy_bidirectional(stream, target_stream).await?;
+        let mut uname = vec![0u8; version[1] as usize];
+        stream.read_exact(&mut uname).await?;
+        let mut plen = [0u8; 1];
+        stream.read_exact(&mut plen).await?;
+        let mut passwd = vec![0u8; plen[0] as usize];
+        stream.read_exact(&mut passwd).await?;
+
+        let accepted = match self {
+            Socks5Auth::None => true,
+            Socks5Auth::UserPass { username, password } => uname == username.as_bytes() && passwd == password.as_bytes(),
+            Socks5Auth::Verifier(verifier) => match (std::str::from_utf8(&uname), std::str::from_utf8(&passwd)) {
+                (Ok(uname), Ok(passwd)) => verifier.verify(uname, passwd).await,
+                _ => false,
+            },
+        };
+        if accepted {
+            stream.write_all(&[0x01, 0x00]).await
         } else {
-            // Regular HTTP proxy
-            debug!("HTTP {} to {}", method, target);
-            
-            // Parse target URL
-            let url = if target.starts_with("http://") {
-                target.to_string()
-            } else if !target.starts_with("/") {
-                format!("http://{}", target)
-            } else {
-                // Relative URL - need Host header
-                let host = lines.iter()
-                    .find(|line| line.to_lowercase().starts_with("host:"))
-                    .and_then(|line| line.split(':').nth(1))
-                    .map(|h| h.trim())
-                    .unwrap_or("localhost");
-                format!("http://{}{}", host, target)
-            };
-            
-            // Simple HTTP forwarding (basic implementation)
-            let response = "HTTP/1.1 200 OK\r\nContent-Length: 13\r\n\r\nProxy working";
-            stream.write_all(response.as_bytes()).await?;
+            stream.write_all(&[0x01, 0x01]).await?;
+            stream.shutdown().await?;
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "SOCKS5 authentication failed"))
         }
-        
-        Ok(())
     }
-    
-    /// Handle SOCKS5 proxy
-    async fn handle_socks5_proxy(mut stream: TcpStream, _config: &KnoxProxyConfig) -> io::Result<()> {
-        // SOCKS5 authentication
-        let mut buffer = [0u8; 256];
-        let n = stream.read(&mut buffer).await?;
-        
-        if n < 3 || buffer[0] != 0x05 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid SOCKS5 request"));
+}
+
+/// Response to a CONNECT whose tunnel is up. Always `200` with no body and
+/// no Content-Length or Transfer-Encoding (RFC 7231 §4.3.6); only the reason
+/// phrase and a `Proxy-agent` header vary, for clients that look at them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectResponse {
+    pub reason: String,
+    /// Sent as `Proxy-agent: <value>` when set
+    pub proxy_agent: Option<String>,
+}
+
+impl Default for ConnectResponse {
+    fn default() -> Self {
+        Self { reason: "Connection established".to_string(), proxy_agent: None }
+    }
+}
+
+impl ConnectResponse {
+    pub fn build(&self) -> String {
+        // CR/LF in configured values would end the head early
+        let clean = |s: &str| s.chars().filter(|c| *c != '\r' && *c != '\n').collect::<String>();
+        let mut response = format!("HTTP/1.1 200 {}\r\n", clean(&self.reason));
+        if let Some(ref agent) = self.proxy_agent {
+            response.push_str(&format!("Proxy-agent: {}\r\n", clean(agent)));
         }
-        
-        // Respond with no authentication required
-        stream.write_all(&[0x05, 0x00]).await?;
-        
-        // Read connection request
-        let n = stream.read(&mut buffer).await?;
-        
-        if n < 10 || buffer[0] != 0x05 || buffer[1] != 0x01 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid SOCKS5 connection request"));
+        response.push_str("\r\n");
+        response
+    }
+}
+
+/// SOCKS5 BIND listener placement. Behind NAT the listener's own address is
+/// not what peers dial, so the external IP forwarded to it is advertised instead.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BindOptions {
+    /// Interface address to listen on, instead of the route towards the peer
+    pub listen_ip: Option<IpAddr>,
+    /// Address reported in the first reply in place of the listener's
+    pub advertise_ip: Option<IpAddr>,
+}
+
+/// Outbound socket options applied by `connect_to_target`
+#[derive(Debug, Clone, Default)]
+pub struct EgressOptions {
+    /// Source address to bind before connecting (v4 or v6)
+    pub bind_ip: Option<IpAddr>,
+    /// Interface outbound sockets are tied to with `SO_BINDTODEVICE`, e.g.
+    /// `rmnet_data0` to leave over cellular while clients arrive on swlan0;
+    /// ignored off Linux/Android
+    pub interface: Option<String>,
+    /// Announce the real client to the upstream with a PROXY protocol v2 header
+    pub proxy_protocol: bool,
+    /// Clamp the MSS of outbound connections (Linux/Android only)
+    pub tcp_mss: Option<u16>,
+    /// Initial TTL (IPv6 hop limit) of outbound connections
+    pub ttl: Option<u8>,
+    /// `host:port` of an HTTP proxy to tunnel every connection through
+    pub upstream_proxy: Option<String>,
+    /// Refuse hosts that keep failing without dialling them
+    pub breaker: Option<Arc<CircuitBreaker>>,
+    /// Bind outbound sockets to a source port from this range
+    pub source_ports: Option<SourcePortRange>,
+}
+
+impl EgressOptions {
+    /// Read `EGRESS_INTERFACE` and `EGRESS_BIND_IP` as exported by
+    /// `Config::apply_env_side_effects`,
+    /// `EGRESS_PROXY_PROTOCOL=1` to enable PROXY v2 emission and
+    /// `EGRESS_TCP_MSS` to clamp the MSS, `PROXY_UPSTREAM=host:port` to
+    /// chain through an HTTP proxy and `EGRESS_SOURCE_PORTS=first-last` to
+    /// pin source ports
+    pub fn from_env() -> Self {
+        let bind_ip = std::env::var("EGRESS_BIND_IP")
+            .ok()
+            .and_then(|v| v.trim().parse::<IpAddr>().ok());
+        let interface = std::env::var("EGRESS_INTERFACE")
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+        let proxy_protocol = std::env::var("EGRESS_PROXY_PROTOCOL")
+            .map(|v| matches!(v.trim(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+        let tcp_mss = std::env::var("EGRESS_TCP_MSS")
+            .ok()
+            .and_then(|v| v.trim().parse::<u16>().ok());
+        let upstream_proxy = std::env::var("PROXY_UPSTREAM")
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+        let source_ports = std::env::var("EGRESS_SOURCE_PORTS")
+            .ok()
+            .and_then(|v| v.trim().parse::<SourcePortRange>().ok());
+        Self { bind_ip, interface, proxy_protocol, tcp_mss, ttl: None, upstream_proxy, breaker: None, source_ports }
+    }
+}
+
+/// How `SourcePortRange` picks the first port to try
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PortSelection {
+    /// Each connection starts one past the previous one
+    #[default]
+    RoundRobin,
+    Random,
+}
+
+impl std::str::FromStr for PortSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "round-robin" | "round_robin" => Ok(Self::RoundRobin),
+            "random" => Ok(Self::Random),
+            other => Err(format!("unknown port selection {:?}, expected round-robin or random", other)),
         }
-        
-        // Parse target address
-        let (target_addr, addr_len) = match buffer[3] {
-            0x01 => {
-                // IPv4
-                if n < 10 {
-                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid IPv4 address"));
-                }
-                let ip = format!("{}.{}.{}.{}", buffer[4], buffer[5], buffer[6], buffer[7]);
-                let port = u16::from_be_bytes([buffer[8], buffer[9]]);
-                (format!("{}:{}", ip, port), 10)
-            }
-            0x03 => {
-                // Domain name
-                let domain_len = buffer[4] as usize;
-                if n < 7 + domain_len {
-                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid domain name"));
-                }
-                let domain = String::from_utf8_lossy(&buffer[5..5 + domain_len]);
-                let port = u16::from_be_bytes([buffer[5 + domain_len], buffer[6 + domain_len]]);
-                (format!("{}:{}", domain, port), 7 + domain_len)
-            }
-            _ => {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, "Unsupported address type"));
-            }
-        };
-        
-        debug!("SOCKS5 connect to {}", target_addr);
-        
-        // Connect to target
-        let target_stream = match TcpStream::connect(&target_addr).await {
-            Ok(s) => s,
-            Err(_) => {
-                // Send connection failed response
-                let mut response = vec![0x05, 0x05, 0x00, 0x01]; // Connection refused
-                response.extend_from_slice(&[0, 0, 0, 0, 0, 0]); // Dummy bind address
-                stream.write_all(&response).await?;
-                return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "Target connection failed"));
-            }
+    }
+}
+
+impl fmt::Display for PortSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::RoundRobin => "round-robin",
+            Self::Random => "random",
+        })
+    }
+}
+
+/// Inclusive range of local ports outbound connections are bound to, for
+/// firewalls that only pass certain source ports. A port that is taken is
+/// skipped for the next one in the range; clones share the round-robin cursor.
+#[derive(Debug, Clone)]
+pub struct SourcePortRange {
+    pub first: u16,
+    pub last: u16,
+    pub selection: PortSelection,
+    cursor: Arc<AtomicUsize>,
+}
+
+/// Ports tried per connection before giving up on the range
+const SOURCE_PORT_ATTEMPTS: usize = 32;
+
+impl SourcePortRange {
+    pub fn new(first: u16, last: u16) -> Self {
+        Self { first: first.min(last), last: first.max(last), selection: PortSelection::default(), cursor: Arc::default() }
+    }
+
+    pub fn with_selection(mut self, selection: PortSelection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        (self.first..=self.last).contains(&port)
+    }
+
+    /// Ports to try for one connection, in order
+    fn candidates(&self) -> impl Iterator<Item = u16> + '_ {
+        let len = self.last as usize - self.first as usize + 1;
+        let start = match self.selection {
+            PortSelection::RoundRobin => self.cursor.fetch_add(1, Ordering::Relaxed),
+            // Deterministic mode walks the range in order instead
+            PortSelection::Random if !crate::determinism::is_deterministic() => rand::thread_rng().gen_range(0..len),
+            PortSelection::Random => self.cursor.fetch_add(1, Ordering::Relaxed),
         };
-        
-        // Send success response
-        let mut response = vec![0x05, 0x00, 0x00, 0x01]; // Success
-        response.extend_from_slice(&[0, 0, 0, 0, 0, 0]); // Dummy bind address
-        stream.write_all(&response).await?;
-        
-        // Start bidirectional copy
-        Self::copy_bidirectional(stream, target_stream).await?;
-        
-        Ok(())
+        (0..len.min(SOURCE_PORT_ATTEMPTS)).map(move |i| self.first + ((start + i) % len) as u16)
     }
-    
-    /// Bidirectional copy between two streams
-    async fn copy_bidirectional(mut stream1: TcpStream, mut stream2: TcpStream) -> io::Result<()> {
-        let (mut r1, mut w1) = stream1.split();
-        let (mut r2, mut w2) = stream2.split();
-        
-        let copy1 = tokio::io::copy(&mut r1, &mut w2);
-        let copy2 = tokio::io::copy(&mut r2, &mut w1);
-        
-        tokio::try_join!(copy1, copy2)?;
-        
-        Ok(())
+}
+
+impl PartialEq for SourcePortRange {
+    fn eq(&self, other: &Self) -> bool {
+        (self.first, self.last, self.selection) == (other.first, other.last, other.selection)
     }
-    
-    /// Print usage instructions
-    fn print_usage_instructions(&self) {
-        println!("");
-        println!("📱 Knox bypass features:");
-        if self.config.enable_knox_bypass {
-            println!("   ✓ POSIX socket operations bypass /proc restrictions");
-            println!("   ✓ Direct syscalls avoid Android security policies");
-        }
-        if self.config.enable_tethering_bypass {
-            println!("   ✓ TTL spoofing (set to {})", self.config.ttl_spoofing);
-            println!("   ✓ DNS override (8.8.8.8, 1.1.1.1)");
-            println!("   ✓ User-Agent rotation");
-            println!("   ✓ Traffic pattern mimicry");
+}
+
+impl std::str::FromStr for SourcePortRange {
+    type Err = String;
+
+    /// `first-last`, or a single port, read by `parse_port_range`; port 0
+    /// would leave the choice to the kernel, so it is refused
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match crate::universal_listener::parse_port_range(s) {
+            Ok(ports) if *ports.start() != 0 => Ok(Self::new(*ports.start(), *ports.end())),
+            _ => Err(format!("invalid source port range {:?}", s)),
         }
-        println!("");
-        println!("🔗 Usage:");
-        println!("   export http_proxy=http://{}", self.config.bind_addr);
-        println!("   export https_proxy=http://{}", self.config.bind_addr);
-        println!("   export all_proxy=socks5://127.0.0.1:{}", self.config.socks_port);
-        println!("");
-        println!("💡 Test with:");
-        println!("   curl -x http://{} http://httpbin.org/ip", self.config.bind_addr);
-        println!("   curl --socks5 127.0.0.1:{} http://httpbin.org/ip", self.config.socks_port);
-        println!("");
     }
 }
 
-impl Clone for KnoxProxyConfig {
-    fn clone(&self) -> Self {
+impl fmt::Display for SourcePortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+/// Application-level heartbeat for idle CONNECT and SOCKS5 tunnels. Carriers
+/// drop idle NAT mappings well before TCP keepalive fires; a tunnel with no
+/// traffic for about `interval` gets `payload` written towards the origin.
+/// Only routes listed in `routes` get it, since the bytes reach the origin
+/// and must be a no-op for whatever protocol the tunnel carries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    /// Each wait is `interval` moved by up to this much either way
+    pub jitter: Duration,
+    pub payload: Vec<u8>,
+    /// Target hosts that tolerate `payload`; an entry also covers its subdomains
+    pub routes: Vec<String>,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
         Self {
-            bind_addr: self.bind_addr.clone(),
-            socks_port: self.socks_port,
-            enable_knox_bypass: self.enable_knox_bypass,
-            enable_tethering_bypass: self.enable_tethering_bypass,
-            ttl_spoofing: self.ttl_spoofing,
-            max_connections: self.max_connections,
-            buffer_size: self.buffer_size,
-            packet_fragmentation_enabled: self.packet_fragmentation_enabled,
-            tcp_fingerprint_enabled: self.tcp_fingerprint_enabled,
-            tls_fingerprint_enabled: self.tls_fingerprint_enabled,
+            interval: Duration::from_secs(30),
+            jitter: Duration::from_secs(5),
+            // Blank lines are skipped between messages by HTTP/1.x and line-based protocols
+            payload: b"\r\n".to_vec(),
+            routes: Vec::new(),
         }
     }
 }
 
-/// Start Knox proxy with configuration
-pub async fn start_knox_proxy(config: KnoxProxyConfig) -> io::Result<()> {
-    let mut proxy = KnoxProxy::new(config);
-    proxy.start().await
+impl HeartbeatConfig {
+    /// Idle time before the next beat, drawn uniformly from `interval ± jitter`;
+    /// exactly `interval` in deterministic mode
+    pub fn next_delay(&self) -> Duration {
+        let jitter = self.jitter.min(self.interval);
+        if jitter.is_zero() || crate::determinism::is_deterministic() {
+            return self.interval;
+        }
+        let offset = rand::thread_rng().gen_range(0..=jitter.as_micros() as u64 * 2);
+        self.interval - jitter + Duration::from_micros(offset)
+    }
 }
 
-/// Quick start function for immediate carrier bypass
+/// Why a relayed connection ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    ClientClosed,
+    UpstreamClosed,
+    MaxLifetime,
+    /// The client used up its daily byte budget
+    QuotaExceeded,
+}
+
+/// Per-connection relay settings
+#[derive(Debug, Clone)]
+pub struct RelayOptions {
+    pub buffer_size: usize,
+    pub max_lifetime: Option<Duration>,
+    /// Tee of both directions, only set for clients matched by `KnoxProxyConfig::capture`
+    pub capture: Option<Arc<CaptureSink>>,
+    /// Byte accounting against the client's daily budget
+    pub quota: Option<ClientQuota>,
+    /// Keep the tunnel's NAT mapping alive while idle
+    pub heartbeat: Option<HeartbeatConfig>,
+    /// Record the relay in the connection log when it ends
+    pub conn_log: Option<ConnLogContext>,
+    /// Set when this relay is sampled for its largest reads
+    pub read_peaks: Option<Arc<ReadHighWater>>,
+}
+
+/// Largest number of bytes a single relay read returned, per direction,
+/// across sampled relays. Peaks pinned at `buffer_size` mean reads are
+/// capped by the buffer and a larger one would move more per syscall.
+#[derive(Debug, Default)]
+pub struct ReadHighWater {
+    /// One relay in `sample_every` is measured; 0 measures none
+    sample_every: u64,
+    relays: AtomicU64,
+    max_up: AtomicUsize,
+    max_down: AtomicUsize,
+}
+
+impl ReadHighWater {
+    pub fn new(sample_every: u64) -> Self {
+        Self { sample_every, ..Default::default() }
+    }
+
+    pub fn sample_every(&self) -> u64 {
+        self.sample_every
+    }
+
+    /// Whether the next relay should be measured
+    pub fn sample(&self) -> bool {
+        self.sample_every != 0 && self.relays.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.sample_every)
+    }
+
+    /// Fold in one relay's largest reads
+    pub fn observe(&self, up: usize, down: usize) {
+        self.max_up.fetch_max(up, Ordering::Relaxed);
+        self.max_down.fetch_max(down, Ordering::Relaxed);
+    }
+
+    /// Largest read from a client
+    pub fn max_up(&self) -> usize {
+        self.max_up.load(Ordering::Relaxed)
+    }
+
+    /// Largest read from an upstream
+    pub fn max_down(&self) -> usize {
+        self.max_down.load(Ordering::Relaxed)
+    }
+}
+
+/// Where, and as what, a relay is recorded in the connection log
+#[derive(Debug, Clone)]
+pub struct ConnLogContext {
+    pub log: Arc<ConnLog>,
+    pub protocol: ProtocolType,
+    pub client: SocketAddr,
+    pub target: TargetAddress,
+}
+
+impl ConnLogContext {
+    /// Write the record of a relay that began at `started` and ended for `close`
+    fn record(&self, started: (u32, std::time::Instant), close: CloseReason, bytes_up: u64, bytes_down: u64) {
+        self.log.record(&ConnRecord {
+            started: started.0,
+            duration: started.1.elapsed(),
+            protocol: self.protocol,
+            close: Some(close),
+            client: self.client,
+            target: self.target.clone(),
+            bytes_up,
+            bytes_down,
+        });
+    }
+}
+
+impl From<&KnoxProxyConfig> for RelayOptions {
+    fn from(config: &KnoxProxyConfig) -> Self {
+        Self {
+            buffer_size: config.buffer_size,
+            max_lifetime: config.max_lifetime,
+            capture: None,
+            quota: None,
+            heartbeat: None,
+            conn_log: None,
+            read_peaks: None,
+        }
+    }
+}
+
+impl RelayOptions {
+    /// Relay options for a connection from `peer`, opening a capture file when it is watched
+    pub fn for_peer(config: &KnoxProxyConfig, peer: Option<SocketAddr>) -> Self {
+        let mut opts = Self::from(config);
+        opts.read_peaks = config.read_peaks.clone().filter(|peaks| peaks.sample());
+        if let (Some(tracker), Some(peer)) = (config.quota.as_ref(), peer) {
+            opts.quota = Some(ClientQuota { tracker: tracker.clone(), ip: peer.ip() });
+        }
+        if let (Some(capture), Some(peer)) = (config.capture.as_ref(), peer) {
+            if let Some(sink) = capture.open_for(peer) {
+                info!("Capturing {} to {}", peer, sink.path().display());
+                opts.capture = Some(Arc::new(sink));
+            }
+        }
+        opts
+    }
+
+    /// Enable the heartbeat when `target` ("host:port") is one of its routes
+    pub fn for_route(mut self, config: &KnoxProxyConfig, target: &str) -> Self {
+        self.heartbeat = config.heartbeat.as_ref().filter(|hb| host_listed(&hb.routes, target)).cloned();
+        self
+    }
+
+    /// Record the relay as `protocol` from `peer` to `target` ("host:port")
+    /// when `config.conn_log` is set
+    pub fn logged(mut self, config: &KnoxProxyConfig, protocol: ProtocolType, peer: Option<SocketAddr>, target: &str) -> Self {
+        if let (Some(log), Some(client)) = (config.conn_log.as_ref(), peer) {
+            let (host, port) = target.rsplit_once(':').unwrap_or((target, "0"));
+            self.conn_log = Some(ConnLogContext {
+                log: log.clone(),
+                protocol,
+                client,
+                target: TargetAddress::new(host, port.parse().unwrap_or(0)),
+            });
+        }
+        self
+    }
+}
+
+/// Relay bytes in both directions until both sides have closed, the
+/// connection outlives `max_lifetime` or the client's quota runs out.
+/// Heartbeats go upstream while both sides are open and idle, and the
+/// connection log gets a record when the relay ends without an I/O error.
+/// Returns the side that closed first.
+pub async fn relay_streams<A, B>(client: A, upstream: B, opts: &RelayOptions) -> io::Result<CloseReason>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_r, mut client_w) = tokio::io::split(client);
+    let (mut upstream_r, mut upstream_w) = tokio::io::split(upstream);
+    let mut client_buf = vec![0u8; opts.buffer_size.max(1)];
+    let mut upstream_buf = vec![0u8; opts.buffer_size.max(1)];
+
+    let lifetime = async {
+        match opts.max_lifetime {
+            Some(limit) => tokio::time::sleep(limit).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(lifetime);
+
+    let heartbeat_delay = || opts.heartbeat.as_ref().map_or(Duration::ZERO, HeartbeatConfig::next_delay);
+    let heartbeat = tokio::time::sleep(heartbeat_delay());
+    tokio::pin!(heartbeat);
+
+    let started = (unix_now(), std::time::Instant::now());
+    let (mut bytes_up, mut bytes_down) = (0u64, 0u64);
+    let (mut peak_up, mut peak_down) = (0usize, 0usize);
+    let mut first_close = None;
+    let (mut client_open, mut upstream_open) = (true, true);
+    // Writes race the lifetime too: a peer that stops reading must not
+    // hold the relay past it
+    macro_rules! within_lifetime {
+        ($io:expr) => {
+            tokio::select! {
+                r = $io => r?,
+                _ = &mut lifetime => {
+                    debug!("Relay exceeded max lifetime {:?} while writing", opts.max_lifetime);
+                    first_close = Some(CloseReason::MaxLifetime);
+                    break;
+                }
+            }
+        };
+    }
+    while client_open || upstream_open {
+        tokio::select! {
+            _ = &mut lifetime => {
+                debug!("Relay exceeded max lifetime {:?}", opts.max_lifetime);
+                first_close = Some(CloseReason::MaxLifetime);
+                break;
+            }
+            _ = &mut heartbeat, if opts.heartbeat.is_some() && client_open && upstream_open => {
+                if let Some(ref hb) = opts.heartbeat {
+                    within_lifetime!(upstream_w.write_all(&hb.payload));
+                }
+                heartbeat.as_mut().reset(tokio::time::Instant::now() + heartbeat_delay());
+            }
+            r = client_r.read(&mut client_buf), if client_open => {
+                let n = r?;
+                if n == 0 {
+                    client_open = false;
+                    first_close.get_or_insert(CloseReason::ClientClosed);
+                    within_lifetime!(upstream_w.shutdown());
+                } else {
+                    if opts.quota.as_ref().is_some_and(|q| q.record(n).is_err()) {
+                        first_close = Some(CloseReason::QuotaExceeded);
+                        break;
+                    }
+                    if let Some(ref capture) = opts.capture {
+                        capture.record(Direction::ClientToUpstream, &client_buf[..n]);
+                    }
+                    within_lifetime!(upstream_w.write_all(&client_buf[..n]));
+                    bytes_up += n as u64;
+                    peak_up = peak_up.max(n);
+                    heartbeat.as_mut().reset(tokio::time::Instant::now() + heartbeat_delay());
+                }
+            }
+            r = upstream_r.read(&mut upstream_buf), if upstream_open => {
+                let n = r?;
+                if n == 0 {
+                    upstream_open = false;
+                    first_close.get_or_insert(CloseReason::UpstreamClosed);
+                    within_lifetime!(client_w.shutdown());
+                } else {
+                    if opts.quota.as_ref().is_some_and(|q| q.record(n).is_err()) {
+                        first_close = Some(CloseReason::QuotaExceeded);
+                        break;
+                    }
+                    if let Some(ref capture) = opts.capture {
+                        capture.record(Direction::UpstreamToClient, &upstream_buf[..n]);
+                    }
+                    within_lifetime!(client_w.write_all(&upstream_buf[..n]));
+                    bytes_down += n as u64;
+                    peak_down = peak_down.max(n);
+                    heartbeat.as_mut().reset(tokio::time::Instant::now() + heartbeat_delay());
+                }
+            }
+        }
+    }
+    if let Some(ref capture) = opts.capture {
+        capture.flush().await;
+    }
+    let close = first_close.unwrap_or(CloseReason::ClientClosed);
+    if let Some(ref peaks) = opts.read_peaks {
+        trace!("Relay largest reads: up {} down {} of {} byte buffers", peak_up, peak_down, opts.buffer_size);
+        peaks.observe(peak_up, peak_down);
+    }
+    if let Some(ref ctx) = opts.conn_log {
+        ctx.record(started, close, bytes_up, bytes_down);
+    }
+    Ok(close)
+}
+
+/// Connect to `target` ("host:port"), creating the socket in the family of the
+/// resolved address and binding the egress interface and IP when configured.
+///
+/// With an egress bind IP set, only resolved addresses of the same family are
+/// eligible, so a v6 egress never ends up on an AF_INET socket.
+///
+/// With a circuit breaker configured, hosts whose circuit is open fail
+/// immediately with `ConnectionRefused`.
+pub async fn connect_to_target(target: &str, egress: &EgressOptions) -> io::Result<TcpStream> {
+    let Some(ref breaker) = egress.breaker else {
+        return dial(target, egress).await;
+    };
+    let host = parse_target(target)?.host();
+    breaker.check(&host)?;
+    let result = dial(target, egress).await;
+    match result {
+        Ok(_) => breaker.record_success(&host),
+        Err(ref e) => {
+            breaker.record_failure(&host);
+            if breaker.is_open(&host) {
+                warn!("⚠ Circuit open for {} after: {}", host, e);
+            }
+        }
+    }
+    result
+}
+
+/// Direct or through the upstream proxy, without the circuit breaker
+async fn dial(target: &str, egress: &EgressOptions) -> io::Result<TcpStream> {
+    match egress.upstream_proxy {
+        Some(ref upstream) => {
+            let mut stream = connect_direct(upstream, egress).await?;
+            connect_via_upstream(&mut stream, upstream, target).await?;
+            Ok(stream)
+        }
+        None => connect_direct(target, egress).await,
+    }
+}
+
+/// Open a tunnel to `target` with `CONNECT` on a stream already connected
+/// to the HTTP proxy `upstream`; bytes after the response belong to the target
+async fn connect_via_upstream<S>(stream: &mut S, upstream: &str, target: &str) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target);
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read one byte at a time so nothing past the response head is consumed
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_BYTES {
+            return Err(HttpParseError::TooLarge.into());
+        }
+        if stream.read(&mut byte).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("upstream proxy {} closed before answering CONNECT", upstream),
+            ));
+        }
+        head.push(byte[0]);
+    }
+
+    let status_line = String::from_utf8_lossy(head.split(|&b| b == b'\r').next().unwrap_or_default()).into_owned();
+    let status: Option<u16> = status_line.split(' ').nth(1).and_then(|code| code.parse().ok());
+    let kind = match status {
+        Some(200..=299) => return Ok(()),
+        Some(407) => io::ErrorKind::PermissionDenied,
+        Some(502) => io::ErrorKind::ConnectionRefused,
+        Some(504) => io::ErrorKind::TimedOut,
+        Some(404) => io::ErrorKind::NotFound,
+        Some(_) => io::ErrorKind::Other,
+        None => io::ErrorKind::InvalidData,
+    };
+    Err(io::Error::new(
+        kind,
+        format!("upstream proxy {} refused CONNECT {}: {}", upstream, target, status_line),
+    ))
+}
+
+/// HTTP answer for a request whose outbound dial to `target` failed with
+/// `error`, so clients can tell a name that did not resolve (404) from a
+/// dial that timed out (504) or was refused (502)
+fn connect_error_response(error: &io::Error, target: &str) -> String {
+    let (status, body) = match error.kind() {
+        io::ErrorKind::TimedOut => ("504 Gateway Timeout", format!("Timed out connecting to {}.\n", target)),
+        io::ErrorKind::NotFound => ("404 Not Found", format!("Could not resolve {}.\n", target)),
+        io::ErrorKind::ConnectionRefused => ("502 Bad Gateway", format!("{} refused the connection.\n", target)),
+        _ => ("502 Bad Gateway", format!("Could not connect to {}.\n", target)),
+    };
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Head start each connection attempt gets before the next address is tried
+/// (RFC 8305 §5)
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+/// Connect straight to `target`, ignoring `egress.upstream_proxy`. Every
+/// resolved address is tried, IPv6 and IPv4 alternating as RFC 8305 has it
+/// and each attempt `HAPPY_EYEBALLS_DELAY` after the previous one, or at
+/// once when the previous one fails. The first to connect wins and the rest
+/// are aborted.
+async fn connect_direct(target: &str, egress: &EgressOptions) -> io::Result<TcpStream> {
+    let mut addrs = target_addrs(target).await?;
+    if let Some(bind_ip) = egress.bind_ip {
+        addrs.retain(|a| a.is_ipv6() == bind_ip.is_ipv6());
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("{} has no address matching egress {}", target, bind_ip),
+            ));
+        }
+    }
+    match addrs[..] {
+        [] => Err(io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve", target))),
+        [addr] => connect_addr(addr, egress).await,
+        _ => connect_happy_eyeballs(interleave_families(addrs), egress).await,
+    }
+}
+
+/// Race connections to `addrs` in order, staggered by `HAPPY_EYEBALLS_DELAY`
+async fn connect_happy_eyeballs(addrs: Vec<SocketAddr>, egress: &EgressOptions) -> io::Result<TcpStream> {
+    let mut pending = addrs.into_iter();
+    // Dropping the set aborts the attempts still running
+    let mut attempts = tokio::task::JoinSet::new();
+    let mut last_error = None;
+    loop {
+        if let Some(addr) = pending.next() {
+            let egress = egress.clone();
+            attempts.spawn(async move { connect_addr(addr, &egress).await });
+        }
+        if attempts.is_empty() {
+            break;
+        }
+        let more = pending.len() > 0;
+        tokio::select! {
+            Some(joined) = attempts.join_next() => match joined.map_err(io::Error::other).and_then(|r| r) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    debug!("happy eyeballs attempt failed: {}", e);
+                    last_error = Some(e);
+                }
+            },
+            _ = tokio::time::sleep(HAPPY_EYEBALLS_DELAY), if more => {}
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address to connect to")))
+}
+
+/// Reorder `addrs` to alternate families, starting with the family of the
+/// resolver's first answer and otherwise keeping its order
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(|a| a.is_ipv6());
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+    let mut ordered = Vec::with_capacity(preferred.len() + other.len());
+    let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connect to one resolved address, walking the source port range if any
+async fn connect_addr(addr: SocketAddr, egress: &EgressOptions) -> io::Result<TcpStream> {
+    let Some(ref ports) = egress.source_ports else {
+        return egress_socket(addr, 0, egress)?.connect(addr).await;
+    };
+    // A port can be bound elsewhere, or free to bind but already used
+    // towards this same destination; either way move on to the next
+    let mut last_error = None;
+    for port in ports.candidates() {
+        let attempt = match egress_socket(addr, port, egress) {
+            Ok(socket) => socket.connect(addr).await,
+            Err(e) => Err(e),
+        };
+        match attempt {
+            Err(e) if matches!(e.kind(), io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable) => {
+                debug!("source port {} unavailable for {}: {}", port, addr, e);
+                last_error = Some(e);
+            }
+            result => return result,
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("no usable source port in {} for {}: {}", ports, addr, last_error.map_or_else(String::new, |e| e.to_string())),
+    ))
+}
+
+/// Socket for `addr` with the egress options applied, bound to `port` when non-zero
+fn egress_socket(addr: SocketAddr, port: u16, egress: &EgressOptions) -> io::Result<TcpSocket> {
+    let socket = if addr.is_ipv6() { TcpSocket::new_v6()? } else { TcpSocket::new_v4()? };
+    if let Some(ref interface) = egress.interface {
+        bind_to_device(socket.as_raw_fd(), interface)
+            .map_err(|e| io::Error::new(e.kind(), format!("egress interface {}: {}", interface, e)))?;
+    }
+    if port != 0 {
+        // Lets a port still in TIME_WAIT from an earlier connection be reused
+        socket.set_reuseaddr(true)?;
+    }
+    match egress.bind_ip {
+        Some(bind_ip) => socket.bind(SocketAddr::new(bind_ip, port))?,
+        None if port != 0 => socket.bind(SocketAddr::new(unspecified_like(addr.ip()), port))?,
+        None => {}
+    }
+    if let Some(mss) = egress.tcp_mss {
+        set_tcp_mss(socket.as_raw_fd(), mss)?;
+    }
+    if let Some(ttl) = egress.ttl {
+        set_ip_ttl(socket.as_raw_fd(), ttl, addr.is_ipv6())?;
+    }
+    Ok(socket)
+}
+
+/// Split a `host:port` target. IPv6 literals must be bracketed
+/// (`[::1]:443`, `[fe80::1%eth0]:22`); an unbracketed one such as `::1:443`
+/// is rejected, since the port cannot be told apart from the last group.
+pub fn parse_target(target: &str) -> io::Result<TargetAddress> {
+    let invalid = |why: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("bad target {:?}: {}", target, why));
+    let (host, port) = match target.strip_prefix('[') {
+        Some(rest) => {
+            let (host, after) = rest.split_once(']').ok_or_else(|| invalid("unclosed '['"))?;
+            let port = after.strip_prefix(':').ok_or_else(|| invalid("missing port"))?;
+            if !matches!(TargetAddress::new(host, 0), TargetAddress::Ipv6 { .. }) {
+                return Err(invalid("brackets must hold an IPv6 address"));
+            }
+            (host, port)
+        }
+        None => {
+            let (host, port) = target.rsplit_once(':').ok_or_else(|| invalid("missing port"))?;
+            if host.contains(':') {
+                return Err(invalid("IPv6 literals need brackets, as in [::1]:443"));
+            }
+            (host, port)
+        }
+    };
+    if host.is_empty() {
+        return Err(invalid("missing host"));
+    }
+    let port = port.parse().map_err(|_| invalid("bad port"))?;
+    Ok(TargetAddress::new(host, port))
+}
+
+/// Addresses for `target`: IP literals (scoped IPv6 included) as they are,
+/// else whatever the resolver returns. Malformed targets fail before any lookup.
+async fn target_addrs(target: &str) -> io::Result<Vec<SocketAddr>> {
+    match parse_target(target)?.to_socket_addr(None) {
+        Some(addr) => Ok(vec![addr]),
+        None => ResolveCache::global().resolve(target).await,
+    }
+}
+
+/// Longest SOCKS4 userid or 4a hostname accepted
+const SOCKS4_FIELD_MAX: usize = 255;
+
+/// Read a SOCKS4 NUL-terminated field, without the NUL
+async fn read_nul_terminated<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut field = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            0 => return Ok(field),
+            _ if field.len() == SOCKS4_FIELD_MAX => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "SOCKS4 field too long"));
+            }
+            byte => field.push(byte),
+        }
+    }
+}
+
+/// How long a client refused over its quota gets to send its first byte,
+/// which picks the protocol the refusal is written in
+const QUOTA_REFUSAL_WAIT: Duration = Duration::from_secs(5);
+
+/// How long a SOCKS5 BIND waits for its peer to connect
+const BIND_ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Address a SOCKS5 BIND listens on: the egress IP when configured, else
+/// the source address of the route towards `target`, else the address the
+/// client reached us on. Never the unspecified address when any of these is
+/// known, since the reply must name somewhere the peer can connect to.
+async fn bind_listen_ip(target: &str, local: Option<SocketAddr>, egress: &EgressOptions) -> IpAddr {
+    if let Some(bind_ip) = egress.bind_ip {
+        return bind_ip;
+    }
+    let resolved = target_addrs(target).await.ok().and_then(|addrs| addrs.first().copied());
+    // Connecting a UDP socket picks the route without sending anything
+    let routed = resolved.filter(|addr| !addr.ip().is_unspecified()).and_then(|addr| {
+        let probe = std::net::UdpSocket::bind(SocketAddr::new(unspecified_like(addr.ip()), 0)).ok()?;
+        probe.connect(addr).ok()?;
+        probe.local_addr().ok().map(|a| a.ip())
+    });
+    routed
+        .or(local.map(|a| a.ip()))
+        .unwrap_or(IpAddr::from([0, 0, 0, 0]))
+}
+
+fn unspecified_like(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => IpAddr::from([0, 0, 0, 0]),
+        IpAddr::V6(_) => IpAddr::from(std::net::Ipv6Addr::UNSPECIFIED),
+    }
+}
+
+/// Largest UDP payload, so relayed datagrams are never truncated
+const UDP_DATAGRAM_MAX: usize = 65535;
+
+/// Socket for relayed UDP: on the egress IP when one is configured, else
+/// dual-stack `[::]`, falling back to `0.0.0.0` where IPv6 is unavailable.
+/// Tied to the egress interface when one is set.
+async fn bind_udp_egress(egress: &EgressOptions) -> io::Result<UdpSocket> {
+    let socket = match egress.bind_ip {
+        Some(bind_ip) => UdpSocket::bind(SocketAddr::new(bind_ip, 0)).await?,
+        None => match UdpSocket::bind("[::]:0").await {
+            Ok(socket) => socket,
+            Err(_) => UdpSocket::bind("0.0.0.0:0").await?,
+        },
+    };
+    if let Some(ref interface) = egress.interface {
+        bind_to_device(socket.as_raw_fd(), interface)
+            .map_err(|e| io::Error::new(e.kind(), format!("egress interface {}: {}", interface, e)))?;
+    }
+    if let Some(ttl) = egress.ttl {
+        set_ip_ttl(socket.as_raw_fd(), ttl, socket.local_addr()?.is_ipv6())?;
+    }
+    Ok(socket)
+}
+
+/// Listener for a SOCKS5 BIND, whose peer arrives the way egress traffic
+/// leaves: tied to the egress interface when one is set
+fn bind_inbound_listener(addr: SocketAddr, egress: &EgressOptions) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv6() { TcpSocket::new_v6()? } else { TcpSocket::new_v4()? };
+    if let Some(ref interface) = egress.interface {
+        bind_to_device(socket.as_raw_fd(), interface)
+            .map_err(|e| io::Error::new(e.kind(), format!("egress interface {}: {}", interface, e)))?;
+    }
+    socket.bind(addr)?;
+    socket.listen(1)
+}
+
+/// Resolve `target` ("host:port") to an address `socket` can send to.
+/// IPv4 goes out of a dual-stack socket as an IPv4-mapped address.
+async fn udp_destination(target: &str, socket: &UdpSocket) -> io::Result<SocketAddr> {
+    let addrs = target_addrs(target).await?;
+    let local = socket.local_addr()?;
+    let dual_stack = local.ip() == IpAddr::from(std::net::Ipv6Addr::UNSPECIFIED);
+    addrs
+        .into_iter()
+        .find_map(|addr| match addr {
+            SocketAddr::V4(v4) if dual_stack => Some(SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())),
+            addr if addr.is_ipv6() == local.is_ipv6() => Some(addr),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("{} has no address reachable from {}", target, local),
+            )
+        })
+}
+
+/// Whether `target` ("host:port") is covered by `config.deny_targets`
+fn target_denied(config: &KnoxProxyConfig, target: &str) -> bool {
+    host_listed(&config.deny_targets, target)
+}
+
+/// Whether the host of `target` ("host:port", or a bare host) or one of its
+/// parent domains is in `hosts`
+pub(crate) fn host_listed(hosts: &[String], target: &str) -> bool {
+    let host = match target.rsplit_once(':') {
+        Some((host, _)) if !host.contains(':') || host.ends_with(']') => host,
+        _ => target,
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']').trim_end_matches('.');
+    hosts.iter().any(|listed| {
+        host.eq_ignore_ascii_case(listed)
+            || (host.len() > listed.len()
+                && host.as_bytes()[host.len() - listed.len() - 1] == b'.'
+                && host[host.len() - listed.len()..].eq_ignore_ascii_case(listed))
+    })
+}
+
+/// `connect_to_target`, then announce `client` with a PROXY v2 header when
+/// `egress.proxy_protocol` is set
+pub async fn connect_for_client(target: &str, client: Option<SocketAddr>, egress: &EgressOptions) -> io::Result<TcpStream> {
+    let mut stream = connect_to_target(target, egress).await?;
+    if let (true, Some(client)) = (egress.proxy_protocol, client) {
+        let header = emit_proxy_protocol_v2(client, stream.peer_addr()?);
+        stream.write_all(&header).await?;
+    }
+    Ok(stream)
+}
+
+/// Fingerprint manager for TLS originated towards upstreams, pinned to the
+/// device profile's browser if any and offering `config.upstream_alpn` when set
+pub fn upstream_tls_fingerprint(config: &KnoxProxyConfig) -> TlsFingerprintManager {
+    let mut manager = match config.device_profile {
+        Some(device) => device.tls_manager(),
+        None => TlsFingerprintManager::new(),
+    };
+    manager.set_alpn(config.upstream_alpn.clone());
+    manager
+}
+
+/// Open a TLS connection attempt to `target`: connect, then send a
+/// fingerprinted ClientHello for `server_name`. The caller reads the
+/// server's handshake from the returned stream.
+pub async fn start_upstream_tls(
+    target: &str,
+    server_name: &str,
+    manager: &mut TlsFingerprintManager,
+    egress: &EgressOptions,
+) -> io::Result<TcpStream> {
+    let hello = manager
+        .generate_client_hello(server_name)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stream = connect_to_target(target, egress).await?;
+    stream.write_all(&hello).await?;
+    Ok(stream)
+}
+
+/// Handlers `KnoxProxy::handle_connection` dispatches to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProxyHandler {
+    Http,
+    Socks5,
+    Socks4,
+}
+
+/// Handler for a detected protocol; `None` for protocols without one of
+/// their own, which are served as HTTP
+fn proxy_handler(protocol: Protocol) -> Option<ProxyHandler> {
+    match protocol {
+        Protocol::Http => Some(ProxyHandler::Http),
+        Protocol::Socks5 => Some(ProxyHandler::Socks5),
+        Protocol::Socks4 => Some(ProxyHandler::Socks4),
+        _ => None,
+    }
+}
+
+/// Protocols `KnoxProxy::handle_connection` serves: every protocol
+/// `proxy_handler` dispatches, plus CONNECT on the HTTP handler and UDP on
+/// SOCKS5's UDP ASSOCIATE. Keep in step with `proxy_handler`.
+pub const DISPATCHED_PROTOCOLS: &[ProtocolType] = &[
+    ProtocolType::Http,
+    ProtocolType::Connect,
+    ProtocolType::Socks5,
+    ProtocolType::Socks4,
+    ProtocolType::Udp,
+];
+
+/// Knox proxy server
+pub struct KnoxProxy {
+    config: KnoxProxyConfig,
+    tethering_bypass: Option<TetheringBypass>,
+    active_connections: Arc<std::sync::atomic::AtomicUsize>,
+}
+
+impl KnoxProxy {
+    pub fn new(config: KnoxProxyConfig) -> Self {
+        Self {
+            config,
+            tethering_bypass: None,
+            active_connections: Arc::new(std::sync::atomic::AtomicUsize::new(0)),
+        }
+    }
+    
+    /// Start the Knox proxy server
+    pub async fn start(&mut self) -> io::Result<()> {
+        info!("🚀 Starting Knox Proxy");
+        info!("   Bind address: {}", self.config.bind_addr);
+        info!("   SOCKS port: {}", self.config.socks_port);
+        info!("   Knox bypass: {}", self.config.enable_knox_bypass);
+        info!("   Tethering bypass: {}", self.config.enable_tethering_bypass);
+        info!("   TTL spoofing: {}", self.config.ttl_spoofing);
+        
+        // Setup tethering bypass
+        if self.config.enable_tethering_bypass {
+            info!("🔓 Enabling tethering bypass...");
+            let mut bypass = TetheringBypass::new();
+            match bypass.enable_bypass() {
+                Ok(()) => {
+                    info!("✅ Tethering bypass enabled");
+                    self.tethering_bypass = Some(bypass);
+                }
+                Err(e) => {
+                    warn!("⚠ Tethering bypass failed: {}", e);
+                }
+            }
+        }
+        
+        ResolveCache::global().set_ttl(self.config.resolve_ttl);
+        if !self.config.warmup.is_empty() {
+            ResolveCache::global().warmup(&self.config.warmup).await;
+        }
+        
+        // Bind listener; with port 0 or a port range the real port is only
+        // known now, and the usage hints below must name it
+        let listener = bind_listener(&self.config).await?;
+        self.config.bind_addr = listener.local_addr()?.to_string();
+        info!("✅ Knox proxy listening on {}", self.config.bind_addr);
+        
+        // Print usage instructions
+        self.print_usage_instructions();
+        
+        let Some(interface) = self.config.listen_interface.clone() else {
+            return self.serve(listener).await;
+        };
+        // Follow the listen interface as it comes and goes
+        let (events, rx) = tokio::sync::mpsc::unbounded_channel();
+        let watcher = tokio::spawn(InterfaceWatcher::for_interface(&interface).run(move |event| {
+            let _ = events.send(event);
+        }));
+        let result = self.serve_following(listener, rx).await;
+        watcher.abort();
+        result
+    }
+    
+    /// Accept and proxy connections from an already bound listener. Failures
+    /// of a single connection, from accept onwards, never stop the loop.
+    pub async fn serve(&mut self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, peer_addr) = accept_next(&listener).await;
+            self.spawn_connection(stream, peer_addr);
+        }
+    }
+    
+    /// `serve`, moving the listener for each event: to the interface's
+    /// address when it comes up, to loopback when it goes down
+    pub async fn serve_following(
+        &mut self,
+        mut listener: TcpListener,
+        mut events: tokio::sync::mpsc::UnboundedReceiver<InterfaceEvent>,
+    ) -> io::Result<()> {
+        loop {
+            tokio::select! {
+                (stream, peer_addr) = accept_next(&listener) => self.spawn_connection(stream, peer_addr),
+                Some(event) = events.recv() => {
+                    listener = move_listener(listener, event, &self.config.listen_tuning).await;
+                    if let Ok(addr) = listener.local_addr() {
+                        self.config.bind_addr = addr.to_string();
+                    }
+                }
+            }
+        }
+    }
+    
+    fn spawn_connection(&self, stream: TcpStream, peer_addr: SocketAddr) {
+        let current_connections = self.active_connections.load(std::sync::atomic::Ordering::Relaxed);
+        
+        if current_connections >= self.config.max_connections {
+            warn!("⚠ Max connections ({}) reached, dropping {}", self.config.max_connections, peer_addr);
+            return;
+        }
+        
+        self.active_connections.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        
+        let config = self.config.clone();
+        let active_connections = self.active_connections.clone();
+        
+        tokio::spawn(async move {
+            match Self::handle_connection(stream, &config, &active_connections).await {
+                Ok(()) => {
+                    debug!("✓ Connection from {} completed", peer_addr);
+                }
+                Err(e) => {
+                    error!("❌ Connection from {} failed: {}", peer_addr, e);
+                }
+            }
+            active_connections.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+        });
+    }
+    
+    /// Handle individual connection with Knox bypass
+    async fn handle_connection(
+        mut stream: TcpStream,
+        config: &KnoxProxyConfig,
+        active_connections: &std::sync::atomic::AtomicUsize,
+    ) -> io::Result<()> {
+        let peer_addr = stream.peer_addr()?;
+        let local_addr = stream.local_addr().ok();
+        debug!("New connection from {}", peer_addr);
+        
+        // Held for the life of the connection
+        let _quota_slot = match config.quota {
+            Some(ref tracker) => match tracker.acquire(peer_addr.ip()) {
+                Ok(slot) => Some(slot),
+                Err(refusal) => {
+                    warn!("⚠ Refusing {}: {}", peer_addr, refusal);
+                    return Self::refuse_over_quota(stream, refusal, QUOTA_REFUSAL_WAIT).await;
+                }
+            },
+            None => None,
+        };
+        
+        // Use Knox bypass for protocol detection if enabled
+        let (protocol, consumed) = if config.enable_knox_bypass {
+            (detect_protocol_posix(&stream)?, Vec::new())
+        } else {
+            // Fallback to regular detection; the bytes read are replayed to the handler
+            let mut buffer = vec![0u8; 512];
+            let n = stream.read(&mut buffer).await?;
+            buffer.truncate(n);
+            
+            let protocol = if n > 0 && buffer[0] == 0x05 {
+                Protocol::Socks5
+            } else if n > 0 && buffer[0] == 0x04 {
+                Protocol::Socks4
+            } else if [&b"GET "[..], b"POST ", b"PUT ", b"CONNECT "].iter().any(|m| buffer.starts_with(m)) {
+                // Compare bytes: a body in the same read need not be UTF-8
+                Protocol::Http
+            } else {
+                Protocol::Unknown
+            };
+            (protocol, buffer)
+        };
+        let stream = PrefixedStream::new(stream, consumed);
+        let peer = Some(peer_addr);
+        let stats = DockStats {
+            active_connections: active_connections.load(Ordering::Relaxed),
+            max_read_up: config.read_peaks.as_ref().map_or(0, |p| p.max_up()),
+            max_read_down: config.read_peaks.as_ref().map_or(0, |p| p.max_down()),
+        };
+        
+        match proxy_handler(protocol) {
+            Some(ProxyHandler::Http) => {
+                info!("Handling HTTP connection from {}", peer_addr);
+                Self::handle_http_proxy(stream, peer, local_addr, &stats, config).await
+            }
+            Some(ProxyHandler::Socks5) => {
+                info!("Handling SOCKS5 connection from {}", peer_addr);
+                Self::handle_socks5_proxy(stream, peer, local_addr, config).await
+            }
+            Some(ProxyHandler::Socks4) => {
+                info!("Handling SOCKS4 connection from {}", peer_addr);
+                Self::handle_socks4_proxy(stream, peer, config).await
+            }
+            None => {
+                warn!("Unknown protocol from {}, treating as HTTP", peer_addr);
+                Self::handle_http_proxy(stream, peer, local_addr, &stats, config).await
+            }
+        }
+    }
+    
+    /// Read from `stream` until a complete request head is buffered
+    async fn read_request_head<S>(stream: &mut S, config: &KnoxProxyConfig) -> io::Result<Option<(RequestHead, Vec<u8>)>>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut buffer = Vec::with_capacity(config.buffer_size);
+        let mut chunk = vec![0u8; config.buffer_size.max(1)];
+        loop {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                if buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(HttpParseError::Incomplete.into());
+            }
+            buffer.extend_from_slice(&chunk[..n]);
+            match RequestHead::parse(&buffer) {
+                Ok((head, used)) => return Ok(Some((head, buffer.split_off(used)))),
+                Err(HttpParseError::Incomplete) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Handle HTTP CONNECT proxy
+    async fn handle_http_proxy<S>(
+        mut stream: S,
+        peer: Option<SocketAddr>,
+        local: Option<SocketAddr>,
+        stats: &DockStats,
+        config: &KnoxProxyConfig,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (head, body) = match Self::read_request_head(&mut stream, config).await? {
+            Some(parsed) => parsed,
+            None => return Ok(()),
+        };
+        
+        if head.method == "GET" && head.target.split('?').next() == Some("/litebike.json") {
+            // Origin-form request for the manifest our dock LOCATION points at
+            let caps = DockCapabilities {
+                has_proxy: true,
+                has_knox: config.enable_knox_bypass,
+                has_socks5: true,
+                protocols: DISPATCHED_PROTOCOLS,
+            };
+            let port = local.map(|a| a.port()).unwrap_or(config.socks_port);
+            let json = build_manifest_json_with_stats(&config.instance_name, port, &caps, stats);
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n{}",
+                json.len(),
+                json
+            );
+            stream.write_all(response.as_bytes()).await?;
+            debug!("Served dock manifest to {:?}", peer);
+            return Ok(());
+        }
+        
+        if !config.http_credentials.is_empty() {
+            let presented = head.basic_credentials("Proxy-Authorization");
+            if !presented.is_some_and(|c| config.http_credentials.contains(&c)) {
+                warn!("⚠ {:?} failed proxy authentication", peer);
+                stream.write_all(
+                    b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+                      Proxy-Authenticate: Basic realm=\"litebike\"\r\n\
+                      Content-Length: 0\r\nConnection: close\r\n\r\n",
+                ).await?;
+                stream.shutdown().await?;
+                return Ok(());
+            }
+        }
+        
+        if head.is_connect() {
+            // HTTP CONNECT for HTTPS tunneling
+            let addr = head.authority(443).unwrap_or_default();
+            
+            debug!("CONNECT to {}", addr);
+            if target_denied(config, &addr) {
+                warn!("⚠ {:?} denied CONNECT to {}", peer, addr);
+                return reject(&mut stream, Protocol::Http, &format!("Access to {} is not allowed.", addr)).await;
+            }
+            
+            // Connect to target
+            let target_stream = match connect_for_client(&addr, peer, config.egress_for(&addr)).await {
+                Ok(s) => s,
+                Err(e) => {
+                    stream.write_all(connect_error_response(&e, &addr).as_bytes()).await?;
+                    return Err(e);
+                }
+            };
+            
+            // Send success response
+            stream.write_all(config.connect_response.build().as_bytes()).await?;
+            
+            // Start bidirectional relay; bytes the client sent right behind
+            // the CONNECT head (an eager TLS hello) go up first
+            let opts = RelayOptions::for_peer(config, peer)
+                .for_route(config, &addr)
+                .logged(config, ProtocolType::Connect, peer, &addr);
+            let reason = relay_streams(PrefixedStream::new(stream, body), target_stream, &opts).await?;
+            debug!("CONNECT {} closed: {:?}", addr, reason);
+        } else {
+            // Regular HTTP proxy: forward the head in origin-form, then relay
+            let authority = head.authority(80)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "HTTP request without Host"))?;
+            debug!("HTTP {} to http://{}{}", head.method, authority, head.path());
+            if target_denied(config, &authority) {
+                warn!("⚠ {:?} denied HTTP to {}", peer, authority);
+                return reject(&mut stream, Protocol::Http, &format!("Access to {} is not allowed.", authority)).await;
+            }
+            
+            let target_stream = match connect_for_client(&authority, peer, config.egress_for(&authority)).await {
+                Ok(s) => s,
+                Err(e) => {
+                    stream.write_all(connect_error_response(&e, &authority).as_bytes()).await?;
+                    return Err(e);
+                }
+            };
+            
+            // Credentials are for us, not the origin
+            let mut headers = head.headers.clone();
+            headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Proxy-Authorization"));
+            if let Some(device) = config.device_profile {
+                device.apply_headers(&mut headers);
+            }
+            let mut forwarded = format!("{} {} {}\r\n", head.method, head.path(), head.version).into_bytes();
+            for (name, value) in &headers {
+                forwarded.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+            }
+            forwarded.extend_from_slice(b"\r\n");
+            forwarded.extend_from_slice(&body);
+            
+            let opts = RelayOptions::for_peer(config, peer).logged(config, ProtocolType::Http, peer, &authority);
+            let reason = if config.http_accounting || config.max_body_bytes.is_some() {
+                let upstream = TalliedStream::new(target_stream, authority.clone(), config.max_body_bytes);
+                Self::forward_http(stream, upstream, &forwarded, &opts).await?
+            } else {
+                Self::forward_http(stream, target_stream, &forwarded, &opts).await?
+            };
+            debug!("HTTP {} closed: {:?}", authority, reason);
+        }
+        
+        Ok(())
+    }
+    
+    /// Send the rewritten request upstream and relay the rest of the exchange
+    async fn forward_http<S, U>(stream: S, mut upstream: U, forwarded: &[u8], opts: &RelayOptions) -> io::Result<CloseReason>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+        U: AsyncRead + AsyncWrite + Unpin,
+    {
+        upstream.write_all(forwarded).await?;
+        relay_streams(stream, upstream, opts).await
+    }
+    
+    /// Tell a client over its quota why it is refused, in its own protocol:
+    /// SOCKS5 gets "no acceptable methods", anything else an HTTP 429. A
+    /// client silent for `wait` is closed without a word, so refused
+    /// connections cannot pile up.
+    async fn refuse_over_quota(mut stream: TcpStream, refusal: QuotaRefusal, wait: Duration) -> io::Result<()> {
+        let mut first = [0u8; 1];
+        let n = tokio::time::timeout(wait, stream.read(&mut first)).await.unwrap_or(Ok(0))?;
+        if n == 1 && first[0] == 0x05 {
+            stream.write_all(&[0x05, 0xFF]).await?;
+        } else if n == 1 {
+            let body = refusal.to_string();
+            let response = format!(
+                "HTTP/1.1 429 Too Many Requests\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await?;
+        }
+        stream.shutdown().await?;
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, refusal))
+    }
+    
+    /// Handle SOCKS5 proxy. Every field is read with `read_exact`, so a client
+    /// that pipelines the greeting and request in one write (or bytes already
+    /// buffered by detection in a `PrefixedStream`) stays aligned.
+    async fn handle_socks5_proxy<S>(
+        mut stream: S,
+        peer: Option<SocketAddr>,
+        local: Option<SocketAddr>,
+        config: &KnoxProxyConfig,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        // SOCKS5 greeting: version, method count, methods
+        let mut greeting = [0u8; 2];
+        stream.read_exact(&mut greeting).await?;
+        
+        if greeting[0] != 0x05 || greeting[1] == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid SOCKS5 request"));
+        }
+        let mut methods = vec![0u8; greeting[1] as usize];
+        stream.read_exact(&mut methods).await?;
+        
+        // Select the configured method if offered; 0xFF tells the client none is acceptable
+        let method = config.socks5_auth.method();
+        if !methods.contains(&(method as u8)) {
+            stream.write_all(&[0x05, AuthMethod::NoAcceptable as u8]).await?;
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "No acceptable SOCKS5 auth method"));
+        }
+        stream.write_all(&[0x05, method as u8]).await?;
+        config.socks5_auth.verify(&mut stream).await?;
+        
+        // Read connection request: version, command, reserved, address type
+        let mut request = [0u8; 4];
+        stream.read_exact(&mut request).await?;
+        
+        if request[0] != 0x05 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid SOCKS5 connection request"));
+        }
+        
+        // Parse target address
+        let target_addr = match request[3] {
+            0x01 => {
+                // IPv4
+                let mut addr = [0u8; 6];
+                stream.read_exact(&mut addr).await?;
+                let ip = format!("{}.{}.{}.{}", addr[0], addr[1], addr[2], addr[3]);
+                let port = u16::from_be_bytes([addr[4], addr[5]]);
+                format!("{}:{}", ip, port)
+            }
+            0x03 => {
+                // Domain name
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len).await?;
+                let mut addr = vec![0u8; len[0] as usize + 2];
+                stream.read_exact(&mut addr).await?;
+                let domain_len = len[0] as usize;
+                let domain = String::from_utf8_lossy(&addr[..domain_len]);
+                let port = u16::from_be_bytes([addr[domain_len], addr[domain_len + 1]]);
+                // Clients sometimes send IP literals here; normalise them so
+                // IPv6 is bracketed and `connect_to_target` skips DNS
+                TargetAddress::new(&domain, port).to_string()
+            }
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Unsupported address type"));
+            }
+        };
+        
+        let unbound = SocketAddr::from(([0, 0, 0, 0], 0));
+        match request[1] {
+            c if c == Socks5Command::Connect as u8 => {}
+            c if c == Socks5Command::Bind as u8 => {
+                if target_denied(config, &target_addr) {
+                    warn!("⚠ {:?} denied SOCKS5 BIND for {}", peer, target_addr);
+                    stream.write_all(&build_socks5_reply(Socks5Reply::ConnectionNotAllowed, unbound)).await?;
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, "SOCKS5 BIND target denied"));
+                }
+                return Self::handle_socks5_bind(stream, peer, local, &target_addr, config, BIND_ACCEPT_TIMEOUT).await;
+            }
+            c if c == Socks5Command::UdpAssociate as u8 && !config.udp_associate_enabled => {
+                debug!("SOCKS5 UDP ASSOCIATE from {:?} refused: disabled", peer);
+                stream.write_all(&build_socks5_reply(Socks5Reply::CommandNotSupported, unbound)).await?;
+                stream.shutdown().await?;
+                return Ok(());
+            }
+            c if c == Socks5Command::UdpAssociate as u8 => {
+                return Self::handle_socks5_udp_associate(stream, peer, local, config).await;
+            }
+            _ => {
+                stream.write_all(&build_socks5_reply(Socks5Reply::CommandNotSupported, unbound)).await?;
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "Unsupported SOCKS5 command"));
+            }
+        }
+        
+        debug!("SOCKS5 connect to {}", target_addr);
+        if target_denied(config, &target_addr) {
+            warn!("⚠ {:?} denied SOCKS5 to {}", peer, target_addr);
+            return reject(&mut stream, Protocol::Socks5, &format!("Access to {} is not allowed.", target_addr)).await;
+        }
+        
+        // Connect to target
+        let target_stream = match connect_for_client(&target_addr, peer, config.egress_for(&target_addr)).await {
+            Ok(s) => s,
+            Err(e) => {
+                stream.write_all(&build_socks5_reply(Socks5Reply::for_connect_error(&e), unbound)).await?;
+                return Err(io::Error::new(e.kind(), format!("Target connection failed: {}", e)));
+            }
+        };
+        
+        // Success carries the outbound socket's address
+        let bound = target_stream.local_addr().unwrap_or(unbound);
+        stream.write_all(&build_socks5_reply(Socks5Reply::Succeeded, bound)).await?;
+        
+        // Start bidirectional relay
+        let opts = RelayOptions::for_peer(config, peer)
+            .for_route(config, &target_addr)
+            .logged(config, ProtocolType::Socks5, peer, &target_addr);
+        let reason = relay_streams(stream, target_stream, &opts).await?;
+        debug!("SOCKS5 {} closed: {:?}", target_addr, reason);
+        
+        Ok(())
+    }
+    
+    /// Handle SOCKS4 and SOCKS4a CONNECT. The request is VN, CD, DSTPORT,
+    /// DSTIP and a NUL-terminated userid; a DSTIP of 0.0.0.x (x != 0) marks
+    /// 4a, where a NUL-terminated hostname follows. SOCKS4 has no password,
+    /// so it is refused outright when SOCKS5 requires one.
+    async fn handle_socks4_proxy<S>(mut stream: S, peer: Option<SocketAddr>, config: &KnoxProxyConfig) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let unbound = SocketAddr::from(([0, 0, 0, 0], 0));
+        let mut request = [0u8; 8];
+        stream.read_exact(&mut request).await?;
+        if request[0] != 0x04 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid SOCKS4 request"));
+        }
+        let port = u16::from_be_bytes([request[2], request[3]]);
+        let ip = std::net::Ipv4Addr::new(request[4], request[5], request[6], request[7]);
+        let _userid = read_nul_terminated(&mut stream).await?;
+        let target_addr = if request[4..7] == [0, 0, 0] && request[7] != 0 {
+            let host = read_nul_terminated(&mut stream).await?;
+            TargetAddress::new(&String::from_utf8_lossy(&host), port).to_string()
+        } else {
+            SocketAddr::new(IpAddr::V4(ip), port).to_string()
+        };
+        
+        if request[1] != Socks5Command::Connect as u8 || config.socks5_auth != Socks5Auth::None {
+            stream.write_all(&build_socks4_reply(false, unbound)).await?;
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "SOCKS4 command not supported"));
+        }
+        debug!("SOCKS4 connect to {}", target_addr);
+        if target_denied(config, &target_addr) {
+            warn!("⚠ {:?} denied SOCKS4 to {}", peer, target_addr);
+            return reject(&mut stream, Protocol::Socks4, &format!("Access to {} is not allowed.", target_addr)).await;
+        }
+        
+        let target_stream = match connect_for_client(&target_addr, peer, config.egress_for(&target_addr)).await {
+            Ok(s) => s,
+            Err(e) => {
+                stream.write_all(&build_socks4_reply(false, unbound)).await?;
+                return Err(e);
+            }
+        };
+        let bound = target_stream.local_addr().unwrap_or(unbound);
+        stream.write_all(&build_socks4_reply(true, bound)).await?;
+        
+        let opts = RelayOptions::for_peer(config, peer)
+            .for_route(config, &target_addr)
+            .logged(config, ProtocolType::Socks4, peer, &target_addr);
+        let reason = relay_streams(stream, target_stream, &opts).await?;
+        debug!("SOCKS4 {} closed: {:?}", target_addr, reason);
+        Ok(())
+    }
+    
+    /// SOCKS5 BIND: listen for one inbound connection from `target`'s side.
+    /// The first reply reports the listening address, the second the peer
+    /// that connected; the relay starts after both. The listener is closed
+    /// once a peer is accepted or `accept_timeout` passes.
+    async fn handle_socks5_bind<S>(
+        mut stream: S,
+        peer: Option<SocketAddr>,
+        local: Option<SocketAddr>,
+        target: &str,
+        config: &KnoxProxyConfig,
+        accept_timeout: Duration,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let listen_ip = match config.socks5_bind.listen_ip {
+            Some(ip) => ip,
+            None => bind_listen_ip(target, local, &config.egress).await,
+        };
+        let listener = match bind_inbound_listener(SocketAddr::new(listen_ip, 0), &config.egress) {
+            Ok(listener) => listener,
+            Err(e) => {
+                stream.write_all(&build_socks5_reply(Socks5Reply::GeneralFailure, SocketAddr::new(listen_ip, 0))).await?;
+                return Err(e);
+            }
+        };
+        let bound = listener.local_addr()?;
+        let advertised = SocketAddr::new(config.socks5_bind.advertise_ip.unwrap_or(bound.ip()), bound.port());
+        stream.write_all(&build_socks5_reply(Socks5Reply::Succeeded, advertised)).await?;
+        debug!("SOCKS5 BIND for {} listening on {} as {}", target, bound, advertised);
+        
+        let accepted = tokio::time::timeout(accept_timeout, listener.accept()).await;
+        drop(listener);
+        let (inbound, from) = match accepted {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(e)) => {
+                stream.write_all(&build_socks5_reply(Socks5Reply::GeneralFailure, bound)).await?;
+                return Err(e);
+            }
+            Err(_) => {
+                stream.write_all(&build_socks5_reply(Socks5Reply::TtlExpired, bound)).await?;
+                return Err(io::Error::new(io::ErrorKind::TimedOut, format!("no peer connected to {} in time", bound)));
+            }
+        };
+        stream.write_all(&build_socks5_reply(Socks5Reply::Succeeded, from)).await?;
+        
+        let opts = RelayOptions::for_peer(config, peer)
+            .for_route(config, target)
+            .logged(config, ProtocolType::Socks5, peer, target);
+        let reason = relay_streams(stream, inbound, &opts).await?;
+        debug!("SOCKS5 BIND {} from {} closed: {:?}", bound, from, reason);
+        Ok(())
+    }
+    
+    /// SOCKS5 UDP ASSOCIATE: bind a relay socket on the address the client
+    /// reached us on and report it, then relay datagrams (RFC 1928 §7).
+    /// Client datagrams lose their header and leave through one egress
+    /// socket; replies come back wrapped in a header naming their source.
+    /// Fragments (FRAG != 0) are dropped. The association lives until the
+    /// TCP control connection closes.
+    async fn handle_socks5_udp_associate<S>(
+        mut stream: S,
+        peer: Option<SocketAddr>,
+        local: Option<SocketAddr>,
+        config: &KnoxProxyConfig,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let bind_ip = local.map(|a| a.ip()).unwrap_or(IpAddr::from([0, 0, 0, 0]));
+        let sockets = match UdpSocket::bind(SocketAddr::new(bind_ip, 0)).await {
+            Ok(relay) => bind_udp_egress(&config.egress).await.map(|egress| (relay, egress)),
+            Err(e) => Err(e),
+        };
+        let (relay, egress) = match sockets {
+            Ok(sockets) => sockets,
+            Err(e) => {
+                stream.write_all(&build_socks5_reply(Socks5Reply::GeneralFailure, SocketAddr::new(bind_ip, 0))).await?;
+                return Err(e);
+            }
+        };
+        let bound = relay.local_addr()?;
+        stream.write_all(&build_socks5_reply(Socks5Reply::Succeeded, bound)).await?;
+        debug!("SOCKS5 UDP association on {}", bound);
+        
+        let started = (unix_now(), std::time::Instant::now());
+        let lifetime = async {
+            match config.max_lifetime {
+                Some(limit) => tokio::time::sleep(limit).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(lifetime);
+
+        let mut client: Option<SocketAddr> = None;
+        // The association is logged against the first destination it relayed to
+        let mut first_target: Option<String> = None;
+        let (mut bytes_up, mut bytes_down) = (0u64, 0u64);
+        let mut control = [0u8; 64];
+        let mut inbound = vec![0u8; UDP_DATAGRAM_MAX];
+        let mut outbound = vec![0u8; UDP_DATAGRAM_MAX];
+        let close = loop {
+            tokio::select! {
+                read = stream.read(&mut control) => {
+                    // Control connection carries no further data; EOF ends the association
+                    if read? == 0 {
+                        break CloseReason::ClientClosed;
+                    }
+                }
+                _ = &mut lifetime => {
+                    debug!("SOCKS5 UDP association on {} reached its max lifetime", bound);
+                    break CloseReason::MaxLifetime;
+                }
+                received = relay.recv_from(&mut inbound) => {
+                    let (n, from) = received?;
+                    // Only the host that opened the association may use it
+                    if peer.is_some_and(|p| p.ip() != from.ip()) {
+                        continue;
+                    }
+                    let Some((frag, target, payload)) = parse_socks5_udp_datagram(&inbound[..n]) else {
+                        debug!("SOCKS5 UDP datagram from {} has no valid header", from);
+                        continue;
+                    };
+                    if frag != 0 {
+                        debug!("SOCKS5 UDP fragment from {} dropped", from);
+                        continue;
+                    }
+                    let target = target.to_string();
+                    if target_denied(config, &target) {
+                        warn!("⚠ {:?} denied SOCKS5 UDP to {}", peer, target);
+                        continue;
+                    }
+                    client = Some(from);
+                    let sent = match udp_destination(&target, &egress).await {
+                        Ok(dest) => egress.send_to(payload, dest).await.map(|_| ()),
+                        Err(e) => Err(e),
+                    };
+                    match sent {
+                        Ok(()) => bytes_up += payload.len() as u64,
+                        Err(e) => debug!("SOCKS5 UDP to {} failed: {}", target, e),
+                    }
+                    first_target.get_or_insert(target);
+                }
+                received = egress.recv_from(&mut outbound) => {
+                    let (n, from) = received?;
+                    let Some(client) = client else { continue };
+                    let source = SocketAddr::new(from.ip().to_canonical(), from.port());
+                    match relay.send_to(&build_socks5_udp_datagram(source, &outbound[..n]), client).await {
+                        Ok(_) => bytes_down += n as u64,
+                        Err(e) => debug!("SOCKS5 UDP reply to {} failed: {}", client, e),
+                    }
+                }
+            }
+        };
+        debug!("SOCKS5 UDP association on {} closed", bound);
+        if let Some(target) = first_target {
+            let opts = RelayOptions::from(config).logged(config, ProtocolType::Socks5, peer, &target);
+            if let Some(ctx) = opts.conn_log {
+                ctx.record(started, close, bytes_up, bytes_down);
+            }
+        }
+        Ok(())
+    }
+    
+    /// Print usage instructions
+    fn print_usage_instructions(&self) {
+        println!("");
+        println!("📱 Knox bypass features:");
+        if self.config.enable_knox_bypass {
+            println!("   ✓ POSIX socket operations bypass /proc restrictions");
+            println!("   ✓ Direct syscalls avoid Android security policies");
+        }
+        if self.config.enable_tethering_bypass {
+            println!("   ✓ TTL spoofing (set to {})", self.config.ttl_spoofing);
+            println!("   ✓ DNS override (8.8.8.8, 1.1.1.1)");
+            println!("   ✓ User-Agent rotation");
+            println!("   ✓ Traffic pattern mimicry");
+        }
+        println!("");
+        println!("🔗 Usage:");
+        println!("   export http_proxy=http://{}", self.config.bind_addr);
+        println!("   export https_proxy=http://{}", self.config.bind_addr);
+        println!("   export all_proxy=socks5://127.0.0.1:{}", self.config.socks_port);
+        println!("");
+        println!("💡 Test with:");
+        println!("   curl -x http://{} http://httpbin.org/ip", self.config.bind_addr);
+        println!("   curl --socks5 127.0.0.1:{} http://httpbin.org/ip", self.config.socks_port);
+        println!("");
+    }
+}
+
+impl Clone for KnoxProxyConfig {
+    fn clone(&self) -> Self {
+        Self {
+            bind_addr: self.bind_addr.clone(),
+            port_range: self.port_range.clone(),
+            listen_interface: self.listen_interface.clone(),
+            listen_tuning: self.listen_tuning.clone(),
+            socks_port: self.socks_port,
+            enable_knox_bypass: self.enable_knox_bypass,
+            enable_tethering_bypass: self.enable_tethering_bypass,
+            ttl_spoofing: self.ttl_spoofing,
+            max_connections: self.max_connections,
+            buffer_size: self.buffer_size,
+            packet_fragmentation_enabled: self.packet_fragmentation_enabled,
+            tcp_fingerprint_enabled: self.tcp_fingerprint_enabled,
+            tls_fingerprint_enabled: self.tls_fingerprint_enabled,
+            egress: self.egress.clone(),
+            max_lifetime: self.max_lifetime,
+            http_accounting: self.http_accounting,
+            max_body_bytes: self.max_body_bytes,
+            capture: self.capture.clone(),
+            instance_name: self.instance_name.clone(),
+            upstream_alpn: self.upstream_alpn.clone(),
+            warmup: self.warmup.clone(),
+            resolve_ttl: self.resolve_ttl,
+            quota: self.quota.clone(),
+            deny_targets: self.deny_targets.clone(),
+            heartbeat: self.heartbeat.clone(),
+            device_profile: self.device_profile,
+            conn_log: self.conn_log.clone(),
+            read_peaks: self.read_peaks.clone(),
+            socks5_auth: self.socks5_auth.clone(),
+            socks5_bind: self.socks5_bind.clone(),
+            udp_associate_enabled: self.udp_associate_enabled,
+            connect_response: self.connect_response.clone(),
+            http_credentials: self.http_credentials.clone(),
+            routes: self.routes.clone(),
+            channel_egress: self.channel_egress.clone(),
+        }
+    }
+}
+
+/// The listener `KnoxProxy::start` serves: `config.bind_addr`, a random
+/// free port of `config.port_range` on its IP, or `bind_addr`'s port on
+/// `config.listen_interface` with the loopback fallback. `listen_tuning`
+/// applies to all three; a port range with a listen interface is refused.
+pub async fn bind_listener(config: &KnoxProxyConfig) -> io::Result<TcpListener> {
+    if let Some(ref interface) = config.listen_interface {
+        if config.port_range.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("port_range cannot be combined with listen_interface {}", interface),
+            ));
+        }
+        let port = config.bind_addr.parse::<SocketAddr>().map(|a| a.port()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("bind address {:?} has no port", config.bind_addr))
+        })?;
+        let (listener, outcome) = bind_with_fallback(interface, port, &mut SyscallInterfaceSource, &config.listen_tuning).await?;
+        if let BindOutcome::Fallback(reason) = outcome {
+            warn!("⚠ {} not usable ({:?}), serving loopback only", interface, reason);
+        }
+        return Ok(listener);
+    }
+    let Some(ref ports) = config.port_range else {
+        let addr = tokio::net::lookup_host(&config.bind_addr).await?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("bind address {:?} resolves to nothing", config.bind_addr))
+        })?;
+        let listener = bind_with_options(addr, &config.listen_tuning)?;
+        listener.set_nonblocking(true)?;
+        return TcpListener::from_std(listener);
+    };
+    let ip = match config.bind_addr.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip(),
+        Err(_) => config.bind_addr.parse::<IpAddr>().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("bind address {:?} is not an IP", config.bind_addr))
+        })?,
+    };
+    let listener = bind_in_range(ip, ports.clone(), &config.listen_tuning)?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+/// Start Knox proxy with configuration
+pub async fn start_knox_proxy(config: KnoxProxyConfig) -> io::Result<()> {
+    let mut proxy = KnoxProxy::new(config);
+    proxy.start().await
+}
+
+/// Quick start function for immediate carrier bypass
 pub async fn quick_start_knox_proxy() -> io::Result<()> {
     let config = KnoxProxyConfig::default();
     start_knox_proxy(config).await
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connect_to_target_ipv6_egress() {
+        let listener = TcpListener::bind("[::1]:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accept = tokio::spawn(async move { listener.accept().await.map(|(_, peer)| peer) });
+
+        let egress = EgressOptions { bind_ip: Some("::1".parse().unwrap()), ..Default::default() };
+        let stream = connect_to_target(&format!("[::1]:{}", port), &egress).await.unwrap();
+
+        let local = stream.local_addr().unwrap();
+        assert!(local.is_ipv6());
+        assert_eq!(local.ip(), "::1".parse::<IpAddr>().unwrap());
+        assert_eq!(accept.await.unwrap().unwrap(), local);
+    }
+
+    #[tokio::test]
+    async fn test_relay_max_lifetime_closes_active_connection() {
+        let (mut client, proxy_client) = tokio::io::duplex(1024);
+        let (proxy_upstream, mut upstream) = tokio::io::duplex(1024);
+        let opts = RelayOptions { buffer_size: 512, max_lifetime: Some(Duration::from_millis(150)), capture: None, quota: None, heartbeat: None, conn_log: None, read_peaks: None };
+        let relay = tokio::spawn(async move { relay_streams(proxy_client, proxy_upstream, &opts).await });
+
+        // Keep traffic flowing in both directions past the lifetime
+        let pump = tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            loop {
+                if client.write_all(b"ping").await.is_err() { break; }
+                if upstream.read(&mut buf).await.unwrap_or(0) == 0 { break; }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+
+        let started = std::time::Instant::now();
+        let reason = tokio::time::timeout(Duration::from_secs(2), relay).await.unwrap().unwrap().unwrap();
+        assert_eq!(reason, CloseReason::MaxLifetime);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        pump.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_relay_max_lifetime_with_upstream_not_reading() {
+        let (mut client, proxy_client) = tokio::io::duplex(1 << 16);
+        // A small pipe the upstream never drains, so relay writes block
+        let (proxy_upstream, _upstream) = tokio::io::duplex(64);
+        let opts = RelayOptions { buffer_size: 512, max_lifetime: Some(Duration::from_millis(150)), capture: None, quota: None, heartbeat: None, conn_log: None, read_peaks: None };
+        let relay = tokio::spawn(async move { relay_streams(proxy_client, proxy_upstream, &opts).await });
+        client.write_all(&[0u8; 4096]).await.unwrap();
+
+        let started = std::time::Instant::now();
+        let reason = tokio::time::timeout(Duration::from_secs(2), relay).await.unwrap().unwrap().unwrap();
+        assert_eq!(reason, CloseReason::MaxLifetime);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_relay_heartbeat_on_idle_tunnel() {
+        let heartbeat = HeartbeatConfig {
+            interval: Duration::from_millis(100),
+            jitter: Duration::from_millis(20),
+            routes: vec!["mqtt.example".to_string()],
+            ..Default::default()
+        };
+        let config = KnoxProxyConfig { heartbeat: Some(heartbeat), ..Default::default() };
+        assert!(RelayOptions::from(&config).for_route(&config, "api.example:443").heartbeat.is_none());
+        let opts = RelayOptions::from(&config).for_route(&config, "broker.mqtt.example:8883");
+        assert!(opts.heartbeat.is_some());
+
+        let (client, proxy_client) = tokio::io::duplex(1024);
+        let (proxy_upstream, mut upstream) = tokio::io::duplex(1024);
+        let relay = tokio::spawn(async move { relay_streams(proxy_client, proxy_upstream, &opts).await });
+
+        let started = std::time::Instant::now();
+        let mut beats = Vec::new();
+        let mut buf = [0u8; 2];
+        while beats.len() < 4 {
+            upstream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"\r\n");
+            beats.push(started.elapsed());
+        }
+        for (i, beat) in beats.iter().enumerate() {
+            let expected = Duration::from_millis(100 * (i as u64 + 1));
+            assert!(*beat >= expected - Duration::from_millis(20 * (i as u64 + 1)), "beat {} at {:?}", i, beat);
+            assert!(*beat < expected + Duration::from_millis(20 * (i as u64 + 1) + 100), "beat {} at {:?}", i, beat);
+        }
+
+        drop(client);
+        drop(upstream);
+        relay.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_high_water_records_large_single_write() {
+        let peaks = Arc::new(ReadHighWater::new(2));
+        let config = KnoxProxyConfig { buffer_size: 65536, read_peaks: Some(peaks.clone()), ..Default::default() };
+        assert!(RelayOptions::for_peer(&config, None).read_peaks.is_some());
+        assert!(RelayOptions::for_peer(&config, None).read_peaks.is_none());
+        let opts = RelayOptions::for_peer(&config, None);
+
+        let (mut client, proxy_client) = tokio::io::duplex(65536);
+        let (proxy_upstream, mut upstream) = tokio::io::duplex(65536);
+        let relay = tokio::spawn(async move { relay_streams(proxy_client, proxy_upstream, &opts).await });
+
+        client.write_all(&[0x55; 20_000]).await.unwrap();
+        let mut buf = vec![0u8; 20_000];
+        upstream.read_exact(&mut buf).await.unwrap();
+        upstream.write_all(&[0xaa; 3000]).await.unwrap();
+        client.read_exact(&mut buf[..3000]).await.unwrap();
+
+        drop(client);
+        drop(upstream);
+        relay.await.unwrap().unwrap();
+        assert_eq!((peaks.max_up(), peaks.max_down()), (20_000, 3000));
+    }
+
+    #[tokio::test]
+    async fn test_serve_survives_clients_resetting_during_detection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = KnoxProxyConfig { enable_knox_bypass: true, ..Default::default() };
+        let server = tokio::spawn(async move { KnoxProxy::new(config).serve(listener).await });
+
+        // Connect and reset straight away, before or during the detection peek
+        for _ in 0..50 {
+            let socket = TcpSocket::new_v4().unwrap();
+            let stream = socket.connect(addr).await.unwrap();
+            crate::universal_listener::reset_on_close(&stream).unwrap();
+            drop(stream);
+        }
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        tokio::time::timeout(Duration::from_secs(2), client.read_exact(&mut method)).await.unwrap().unwrap();
+        assert_eq!(method, [0x05, 0x00]);
+        assert!(!server.is_finished());
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_denied_targets_are_rejected_per_protocol() {
+        let config = KnoxProxyConfig { deny_targets: vec!["blocked.example".to_string()], ..Default::default() };
+        assert!(target_denied(&config, "api.blocked.example:443"));
+        assert!(!target_denied(&config, "notblocked.example:443"));
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        let http_config = config.clone();
+        let handler = tokio::spawn(async move {
+            KnoxProxy::handle_http_proxy(server, None, None, &DockStats::default(), &http_config).await
+        });
+        client.write_all(b"CONNECT blocked.example:443 HTTP/1.1\r\nHost: blocked.example:443\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{}", response);
+        assert!(response.contains("blocked.example:443"));
+        handler.await.unwrap().unwrap();
+
+        let mut request = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x03, 15];
+        request.extend_from_slice(b"blocked.example");
+        request.extend_from_slice(&80u16.to_be_bytes());
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handler = tokio::spawn(async move {
+            KnoxProxy::handle_socks5_proxy(PrefixedStream::new(server, request), None, None, &config).await
+        });
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(&reply[..2], &[0x05, 0x00]);
+        assert_eq!(reply[3], Socks5Reply::ConnectionNotAllowed as u8);
+        handler.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks5_reply_reflects_connect_error() {
+        // Nothing listens on a port just released
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut request = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+        request.extend_from_slice(&port.to_be_bytes());
+        let (mut client, server) = tokio::io::duplex(1024);
+        let config = KnoxProxyConfig::default();
+        let handler = tokio::spawn(async move {
+            KnoxProxy::handle_socks5_proxy(PrefixedStream::new(server, request), None, None, &config).await
+        });
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(&reply[..2], &[0x05, 0x00]);
+        assert_eq!(reply[3], 0x05);
+        assert_eq!(handler.await.unwrap().unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+
+        let reply_for = |kind| Socks5Reply::for_connect_error(&io::Error::from(kind)) as u8;
+        assert_eq!(reply_for(io::ErrorKind::TimedOut), 0x06);
+        assert_eq!(reply_for(io::ErrorKind::NetworkUnreachable), 0x03);
+        assert_eq!(reply_for(io::ErrorKind::HostUnreachable), 0x04);
+        assert_eq!(Socks5Reply::for_connect_error(&io::Error::from_raw_os_error(libc::EHOSTUNREACH)) as u8, 0x04);
+        assert_eq!(reply_for(io::ErrorKind::PermissionDenied), 0x01);
+    }
+
+    #[tokio::test]
+    async fn test_relay_quota_accumulates_across_connections() {
+        use crate::quota::QuotaConfig;
+
+        let tracker = Arc::new(QuotaTracker::new(QuotaConfig { max_concurrent: None, daily_byte_budget: Some(20) }));
+        let config = KnoxProxyConfig { quota: Some(tracker.clone()), ..Default::default() };
+        let client_ip: IpAddr = "192.168.43.20".parse().unwrap();
+        let peer = SocketAddr::new(client_ip, 4000);
+
+        // Each connection sends 6 bytes up and gets 6 back; the second pushes usage to 24
+        let mut reasons = Vec::new();
+        for _ in 0..2 {
+            let opts = RelayOptions::for_peer(&config, Some(peer));
+            let (mut client, proxy_client) = tokio::io::duplex(1024);
+            let (proxy_upstream, mut upstream) = tokio::io::duplex(1024);
+            let relay = tokio::spawn(async move { relay_streams(proxy_client, proxy_upstream, &opts).await });
+            client.write_all(b"hello!").await.unwrap();
+            let mut buf = [0u8; 6];
+            upstream.read_exact(&mut buf).await.unwrap();
+            upstream.write_all(b"world!").await.unwrap();
+            drop(upstream);
+            let _ = client.read_to_end(&mut Vec::new()).await;
+            drop(client);
+            reasons.push(relay.await.unwrap().unwrap());
+        }
+        assert_eq!(reasons[1], CloseReason::QuotaExceeded);
+        // The refused chunk is charged but never delivered
+        assert_eq!(tracker.usage(client_ip).bytes_today, 24);
+        assert_eq!(tracker.acquire(client_ip).unwrap_err(), QuotaRefusal::DailyBytesExhausted { budget: 20 });
+    }
+
+    #[tokio::test]
+    async fn test_quota_refusal_does_not_wait_on_silent_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let refusal = QuotaRefusal::TooManyConnections { limit: 1 };
+        let wait = Duration::from_millis(50);
+
+        // Silent: closed once the wait is up, nothing written
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let refused = tokio::time::timeout(Duration::from_secs(2), KnoxProxy::refuse_over_quota(stream, refusal, wait)).await;
+        assert_eq!(refused.unwrap().unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        let mut rest = Vec::new();
+        silent.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+
+        // Speaking: answered in the protocol of the first byte, the only
+        // one read (more would be left unread and reset the connection)
+        for (first, reply) in [(&b"\x05"[..], &b"\x05\xff"[..]), (&b"G"[..], &b"HTTP/1.1 429"[..])] {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(first).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            assert!(KnoxProxy::refuse_over_quota(stream, refusal, wait).await.is_err());
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            assert!(response.starts_with(reply), "{:?}", String::from_utf8_lossy(&response));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_capture_records_both_directions() {
+        let dir = std::env::temp_dir().join(format!("litebike-capture-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = KnoxProxyConfig {
+            capture: Some(CaptureConfig { client_ips: vec!["127.0.0.1".parse().unwrap()], dir: dir.clone() }),
+            ..Default::default()
+        };
+        let unmatched = RelayOptions::for_peer(&config, Some("10.0.0.9:4000".parse().unwrap()));
+        assert!(unmatched.capture.is_none());
+        let opts = RelayOptions::for_peer(&config, Some("127.0.0.1:4000".parse().unwrap()));
+        let path = opts.capture.as_ref().expect("matched client is captured").path().to_path_buf();
+
+        let (mut client, proxy_client) = tokio::io::duplex(1024);
+        let (proxy_upstream, mut upstream) = tokio::io::duplex(1024);
+        let relay = tokio::spawn(async move { relay_streams(proxy_client, proxy_upstream, &opts).await });
+
+        client.write_all(b"request").await.unwrap();
+        let mut buf = [0u8; 7];
+        upstream.read_exact(&mut buf).await.unwrap();
+        upstream.write_all(b"response").await.unwrap();
+        let mut buf = [0u8; 8];
+        client.read_exact(&mut buf).await.unwrap();
+        drop(client);
+        drop(upstream);
+        relay.await.unwrap().unwrap();
+
+        let frames = crate::capture::read_capture(&path).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].direction, &frames[0].data[..]), (Direction::ClientToUpstream, &b"request"[..]));
+        assert_eq!((frames[1].direction, &frames[1].data[..]), (Direction::UpstreamToClient, &b"response"[..]));
+        assert!(frames[0].timestamp_us <= frames[1].timestamp_us);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_dispatched_protocols_match_proxy_handler() {
+        let handled: Vec<ProtocolType> = (0..=u8::MAX)
+            .filter_map(|byte| ProtocolType::try_from(byte).ok())
+            .filter(|&p| Protocol::try_from(p).ok().and_then(proxy_handler).is_some())
+            .collect();
+        assert!(handled.iter().all(|p| DISPATCHED_PROTOCOLS.contains(p)), "{:?}", handled);
+        for protocol in DISPATCHED_PROTOCOLS {
+            let carried = matches!(protocol, ProtocolType::Connect | ProtocolType::Udp);
+            assert!(carried || handled.contains(protocol), "{:?} has no handler", protocol);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_survives_unwritable_capture() {
+        let dir = std::env::temp_dir().join(format!("litebike-capture-missing-{}", std::process::id()));
+        let config = KnoxProxyConfig {
+            capture: Some(CaptureConfig { client_ips: vec!["127.0.0.1".parse().unwrap()], dir }),
+            ..Default::default()
+        };
+        let opts = RelayOptions::for_peer(&config, Some("127.0.0.1:4000".parse().unwrap()));
+        let capture = opts.capture.clone().expect("matched client is captured");
+
+        let (mut client, proxy_client) = tokio::io::duplex(1024);
+        let (proxy_upstream, mut upstream) = tokio::io::duplex(1024);
+        let relay = tokio::spawn(async move { relay_streams(proxy_client, proxy_upstream, &opts).await });
+
+        client.write_all(b"request").await.unwrap();
+        let mut buf = [0u8; 7];
+        upstream.read_exact(&mut buf).await.unwrap();
+        upstream.write_all(b"response").await.unwrap();
+        let mut buf = [0u8; 8];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"response");
+        drop(client);
+        drop(upstream);
+        relay.await.unwrap().unwrap();
+        assert!(capture.has_failed());
+    }
+
+    #[tokio::test]
+    async fn test_socks5_pipelined_through_prefixed_stream() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        let echo = tokio::spawn(async move {
+            let (mut s, _) = target.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            s.read_exact(&mut buf).await.unwrap();
+            s.write_all(&buf).await.unwrap();
+        });
+
+        // Greeting, CONNECT request and first payload bytes all arrive in one buffer
+        let mut pipelined = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+        pipelined.extend_from_slice(&port.to_be_bytes());
+        pipelined.extend_from_slice(b"ping");
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let config = KnoxProxyConfig { egress: EgressOptions::default(), ..Default::default() };
+        let handler = tokio::spawn(async move {
+            KnoxProxy::handle_socks5_proxy(PrefixedStream::new(server, pipelined), None, None, &config).await
+        });
+
+        let mut reply = [0u8; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[..2], &[0x05, 0x00]);
+        assert_eq!(&reply[2..4], &[0x05, 0x00]);
+        // BND.ADDR is the real outbound socket, not 0.0.0.0:0
+        assert_eq!(&reply[6..10], &[127, 0, 0, 1]);
+        assert_ne!(u16::from_be_bytes([reply[10], reply[11]]), 0);
+        let mut echoed = [0u8; 4];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+
+        echo.await.unwrap();
+        drop(client);
+        handler.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks5_domain_atyp_ip_literal_skips_dns() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        let accept = tokio::spawn(async move { target.accept().await.unwrap() });
+
+        let mut request = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x03, 9];
+        request.extend_from_slice(b"127.0.0.1");
+        request.extend_from_slice(&port.to_be_bytes());
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let config = KnoxProxyConfig { egress: EgressOptions::default(), ..Default::default() };
+        let handler = tokio::spawn(async move {
+            KnoxProxy::handle_socks5_proxy(PrefixedStream::new(server, request), None, None, &config).await
+        });
+
+        let mut reply = [0u8; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[2..4], &[0x05, 0x00]);
+        accept.await.unwrap();
+        // Nothing went through the resolver
+        assert_eq!(ResolveCache::global().cached(&format!("127.0.0.1:{}", port)), None);
+        let literal = |target: &str| parse_target(target).unwrap().to_socket_addr(None);
+        assert_eq!(literal("[fe80::1%7]:443").map(|a| a.to_string()), Some("[fe80::1%7]:443".to_string()));
+        assert_eq!(literal("example.com:443"), None);
+
+        drop(client);
+        let _ = handler.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks5_udp_associate_reports_bound_socket() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let local: SocketAddr = "127.0.0.1:1080".parse().unwrap();
+        let config = KnoxProxyConfig::default();
+        let handler = tokio::spawn(async move {
+            KnoxProxy::handle_socks5_proxy(server, None, Some(local), &config).await
+        });
+
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client.write_all(&[0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        let mut reply = [0u8; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[2..6], &[0x05, 0x00, 0x00, 0x01]);
+        assert_eq!(&reply[6..10], &[127, 0, 0, 1]);
+        let port = u16::from_be_bytes([reply[10], reply[11]]);
+        assert_ne!(port, 0);
+
+        // The advertised port is held by the association...
+        let err = std::net::UdpSocket::bind(("127.0.0.1", port)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        // ...until the control connection closes
+        drop(client);
+        handler.await.unwrap().unwrap();
+        std::net::UdpSocket::bind(("127.0.0.1", port)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks5_external_verifier() {
+        #[derive(Default)]
+        struct AuthService {
+            calls: std::sync::Mutex<Vec<String>>,
+        }
+
+        #[async_trait::async_trait]
+        impl Socks5Verifier for AuthService {
+            async fn verify(&self, username: &str, password: &str) -> bool {
+                self.calls.lock().unwrap().push(username.to_string());
+                tokio::task::yield_now().await;
+                username == "alice" && password == "letmein"
+            }
+        }
+
+        async fn authenticate(config: &KnoxProxyConfig, user: &[u8], pass: &[u8]) -> [u8; 2] {
+            let (mut client, server) = tokio::io::duplex(1024);
+            let config = config.clone();
+            let handler = tokio::spawn(async move { KnoxProxy::handle_socks5_proxy(server, None, None, &config).await });
+            let mut hello = vec![0x05, 0x01, 0x02, 0x01, user.len() as u8];
+            hello.extend_from_slice(user);
+            hello.push(pass.len() as u8);
+            hello.extend_from_slice(pass);
+            client.write_all(&hello).await.unwrap();
+            let mut reply = [0u8; 4];
+            client.read_exact(&mut reply).await.unwrap();
+            drop(client);
+            let _ = handler.await.unwrap();
+            [reply[2], reply[3]]
+        }
+
+        let service = Arc::new(AuthService::default());
+        let config = KnoxProxyConfig::default().with_socks5_verifier(service.clone());
+        assert_eq!(authenticate(&config, b"alice", b"letmein").await, [0x01, 0x00]);
+        assert_eq!(authenticate(&config, b"mallory", b"letmein").await, [0x01, 0x01]);
+        assert_eq!(*service.calls.lock().unwrap(), ["alice", "mallory"]);
+
+        // The in-memory map is a verifier too
+        let users: HashMap<String, String> = [("bob".to_string(), "pw".to_string())].into();
+        let config = KnoxProxyConfig::default().with_socks5_verifier(Arc::new(users));
+        assert_eq!(authenticate(&config, b"bob", b"pw").await, [0x01, 0x00]);
+        assert_eq!(authenticate(&config, b"bob", b"nope").await, [0x01, 0x01]);
+    }
+
+    #[tokio::test]
+    async fn test_socks4_and_socks4a_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut accepted, _)) = listener.accept().await {
+                let _ = accepted.write_all(b"hi").await;
+            }
+        });
+        let config = KnoxProxyConfig::default();
+
+        // SOCKS4: literal address
+        let mut request = vec![0x04, 0x01];
+        request.extend_from_slice(&port.to_be_bytes());
+        request.extend_from_slice(&[127, 0, 0, 1]);
+        request.extend_from_slice(b"user\0");
+        // SOCKS4a: 0.0.0.1 then the hostname
+        let mut request_4a = vec![0x04, 0x01];
+        request_4a.extend_from_slice(&port.to_be_bytes());
+        request_4a.extend_from_slice(&[0, 0, 0, 1, 0]);
+        request_4a.extend_from_slice(b"localhost\0");
+
+        for request in [request, request_4a] {
+            let (mut client, server) = tokio::io::duplex(1024);
+            let cfg = config.clone();
+            let handler = tokio::spawn(async move { KnoxProxy::handle_socks4_proxy(server, None, &cfg).await });
+            client.write_all(&request).await.unwrap();
+            let mut reply = [0u8; 10];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(&reply[..2], &[0x00, 0x5A]);
+            assert_eq!(&reply[8..], b"hi");
+            drop(client);
+            let _ = handler.await.unwrap();
+        }
+
+        // BIND is not offered over SOCKS4
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handler = tokio::spawn(async move { KnoxProxy::handle_socks4_proxy(server, None, &config).await });
+        client.write_all(&[0x04, 0x02, 0, 21, 127, 0, 0, 1, 0]).await.unwrap();
+        let mut reply = [0u8; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0x5B);
+        assert!(handler.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socks5_username_password_auth() {
+        let config = KnoxProxyConfig {
+            socks5_auth: Socks5Auth::UserPass { username: "bike".to_string(), password: "s3cret".to_string() },
+            ..Default::default()
+        };
+
+        // Client offering only no-auth gets 0xFF
+        let (mut client, server) = tokio::io::duplex(1024);
+        let cfg = config.clone();
+        let handler = tokio::spawn(async move { KnoxProxy::handle_socks5_proxy(server, None, None, &cfg).await });
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0xFF]);
+        assert!(handler.await.unwrap().is_err());
+
+        // Wrong password is answered 0x01 0x01 and closed
+        let (mut client, server) = tokio::io::duplex(1024);
+        let cfg = config.clone();
+        let handler = tokio::spawn(async move { KnoxProxy::handle_socks5_proxy(server, None, None, &cfg).await });
+        client.write_all(&[0x05, 0x02, 0x00, 0x02]).await.unwrap();
+        client.write_all(&[0x01, 4, b'b', b'i', b'k', b'e', 5, b'w', b'r', b'o', b'n', b'g']).await.unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, [0x05, 0x02, 0x01, 0x01]);
+        assert_eq!(handler.await.unwrap().unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        // Right credentials proceed to the request
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handler = tokio::spawn(async move { KnoxProxy::handle_socks5_proxy(server, None, None, &config).await });
+        client.write_all(&[0x05, 0x01, 0x02]).await.unwrap();
+        client.write_all(&[0x01, 4, b'b', b'i', b'k', b'e', 6, b's', b'3', b'c', b'r', b'e', b't']).await.unwrap();
+        let mut reply = [0u8; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x02, 0x01, 0x00]);
+        client.write_all(&[0x05, 0x09, 0x00, 0x01, 127, 0, 0, 1, 0, 80]).await.unwrap();
+        let mut refused = [0u8; 10];
+        client.read_exact(&mut refused).await.unwrap();
+        assert_eq!(refused[1], Socks5Reply::CommandNotSupported as u8);
+        assert!(handler.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socks5_udp_associate_disabled() {
+        let config = KnoxProxyConfig { udp_associate_enabled: false, ..Default::default() };
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handler = tokio::spawn(async move { KnoxProxy::handle_socks5_proxy(server, None, None, &config).await });
+        client.write_all(&[0x05, 0x01, 0x00, 0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        // Reply, then end of stream: the handler closes without an error
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x00, 0x05, 0x07, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+        handler.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks5_bind_sends_two_replies_then_relays() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let config = KnoxProxyConfig::default();
+        let handler = tokio::spawn(async move {
+            KnoxProxy::handle_socks5_proxy(server, None, None, &config).await
+        });
+
+        client.write_all(&[0x05, 0x01, 0x00, 0x05, 0x02, 0x00, 0x01, 127, 0, 0, 1, 0, 21]).await.unwrap();
+        let mut reply = [0u8; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[2..6], &[0x05, 0x00, 0x00, 0x01]);
+        // Reported on the route towards the target, not 0.0.0.0
+        assert_eq!(&reply[6..10], &[127, 0, 0, 1]);
+        let bound = SocketAddr::new(IpAddr::from([127, 0, 0, 1]), u16::from_be_bytes([reply[10], reply[11]]));
+
+        let mut remote = TcpStream::connect(bound).await.unwrap();
+        client.read_exact(&mut reply[2..]).await.unwrap();
+        assert_eq!(&reply[2..4], &[0x05, 0x00]);
+        assert_eq!(u16::from_be_bytes([reply[10], reply[11]]), remote.local_addr().unwrap().port());
+
+        remote.write_all(b"220 ready\r\n").await.unwrap();
+        let mut banner = [0u8; 11];
+        client.read_exact(&mut banner).await.unwrap();
+        assert_eq!(&banner, b"220 ready\r\n");
+        client.write_all(b"QUIT\r\n").await.unwrap();
+        let mut quit = [0u8; 6];
+        remote.read_exact(&mut quit).await.unwrap();
+        assert_eq!(&quit, b"QUIT\r\n");
+
+        drop(client);
+        drop(remote);
+        let _ = handler.await.unwrap();
+
+        // A BIND nobody connects to times out and frees its port
+        let (mut client, server) = tokio::io::duplex(1024);
+        let config = KnoxProxyConfig::default();
+        let handler = tokio::spawn(async move {
+            KnoxProxy::handle_socks5_bind(server, None, None, "127.0.0.1:21", &config, Duration::from_millis(100)).await
+        });
+        let mut first = [0u8; 10];
+        client.read_exact(&mut first).await.unwrap();
+        let port = u16::from_be_bytes([first[8], first[9]]);
+        let err = handler.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let mut second = [0u8; 10];
+        client.read_exact(&mut second).await.unwrap();
+        assert_eq!(second[1], Socks5Reply::TtlExpired as u8);
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socks5_bind_advertises_external_ip() {
+        let config = KnoxProxyConfig {
+            socks5_bind: BindOptions {
+                listen_ip: Some(IpAddr::from([127, 0, 0, 1])),
+                advertise_ip: Some(IpAddr::from([203, 0, 113, 7])),
+            },
+            ..Default::default()
+        };
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handler = tokio::spawn(async move {
+            KnoxProxy::handle_socks5_bind(server, None, None, "ftp.example:21", &config, Duration::from_secs(2)).await
+        });
+
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[..8], &[0x05, 0x00, 0x00, 0x01, 203, 0, 113, 7]);
+        let port = u16::from_be_bytes([reply[8], reply[9]]);
+
+        // The NAT forwards the advertised port to the configured interface
+        let remote = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[..8], &[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1]);
+        assert_eq!(u16::from_be_bytes([reply[8], reply[9]]), remote.local_addr().unwrap().port());
+
+        drop(remote);
+        drop(client);
+        let _ = handler.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks5_udp_associate_relays_and_drops_fragments() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            while let Ok((n, from)) = echo.recv_from(&mut buf).await {
+                let _ = echo.send_to(&buf[..n], from).await;
+            }
+        });
+
+        let udp_client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, server) = tokio::io::duplex(1024);
+        let peer = Some(udp_client.local_addr().unwrap());
+        let local: SocketAddr = "127.0.0.1:1080".parse().unwrap();
+        let config = KnoxProxyConfig::default();
+        let handler = tokio::spawn(async move {
+            KnoxProxy::handle_socks5_proxy(server, peer, Some(local), &config).await
+        });
+
+        client.write_all(&[0x05, 0x01, 0x00, 0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        let mut reply = [0u8; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        let relay = SocketAddr::new(IpAddr::from([127, 0, 0, 1]), u16::from_be_bytes([reply[10], reply[11]]));
+
+        let mut fragment = build_socks5_udp_datagram(echo_addr, b"fragment");
+        fragment[2] = 1;
+        udp_client.send_to(&fragment, relay).await.unwrap();
+        udp_client.send_to(&build_socks5_udp_datagram(echo_addr, b"ping"), relay).await.unwrap();
+
+        // Only the unfragmented datagram comes back, headed with the echo's address
+        let mut buf = [0u8; 1500];
+        let (n, from) = tokio::time::timeout(Duration::from_secs(2), udp_client.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(from, relay);
+        let (frag, source, payload) = parse_socks5_udp_datagram(&buf[..n]).unwrap();
+        assert_eq!(frag, 0);
+        assert_eq!(source, TargetAddress::new("127.0.0.1", echo_addr.port()));
+        assert_eq!(payload, b"ping");
+        assert!(tokio::time::timeout(Duration::from_millis(200), udp_client.recv_from(&mut buf)).await.is_err());
+
+        drop(client);
+        handler.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks5_udp_associate_is_logged() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            while let Ok((n, from)) = echo.recv_from(&mut buf).await {
+                let _ = echo.send_to(&buf[..n], from).await;
+            }
+        });
+
+        let path = std::env::temp_dir().join(format!("litebike-udp-connlog-{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let log = Arc::new(ConnLog::new(&path));
+        let udp_client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (mut client, server) = tokio::io::duplex(1024);
+        let peer = Some(udp_client.local_addr().unwrap());
+        let local: SocketAddr = "127.0.0.1:1080".parse().unwrap();
+        let config = KnoxProxyConfig { conn_log: Some(log.clone()), ..Default::default() };
+        let handler = tokio::spawn(async move {
+            KnoxProxy::handle_socks5_proxy(server, peer, Some(local), &config).await
+        });
+
+        client.write_all(&[0x05, 0x01, 0x00, 0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        let mut reply = [0u8; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        let relay = SocketAddr::new(IpAddr::from([127, 0, 0, 1]), u16::from_be_bytes([reply[10], reply[11]]));
+        udp_client.send_to(&build_socks5_udp_datagram(echo_addr, b"ping"), relay).await.unwrap();
+        let mut buf = [0u8; 1500];
+        tokio::time::timeout(Duration::from_secs(2), udp_client.recv_from(&mut buf)).await.unwrap().unwrap();
+
+        drop(client);
+        handler.await.unwrap().unwrap();
+        log.flush().await;
+        let records = crate::conn_log::read_records(std::fs::File::open(&path).unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].protocol, ProtocolType::Socks5);
+        assert_eq!(records[0].close, Some(CloseReason::ClientClosed));
+        assert_eq!(records[0].client, peer.unwrap());
+        assert_eq!(records[0].target, TargetAddress::new("127.0.0.1", echo_addr.port()));
+        assert_eq!((records[0].bytes_up, records[0].bytes_down), (4, 4));
+    }
+
+    #[tokio::test]
+    async fn test_socks5_udp_associate_ends_at_max_lifetime() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let peer: Option<SocketAddr> = Some("127.0.0.1:40000".parse().unwrap());
+        let local: SocketAddr = "127.0.0.1:1080".parse().unwrap();
+        let config = KnoxProxyConfig { max_lifetime: Some(Duration::from_millis(150)), ..Default::default() };
+        let handler = tokio::spawn(async move {
+            KnoxProxy::handle_socks5_proxy(server, peer, Some(local), &config).await
+        });
+
+        client.write_all(&[0x05, 0x01, 0x00, 0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        let mut reply = [0u8; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        // The control connection stays open, yet the association ends on its own
+        tokio::time::timeout(Duration::from_secs(2), handler).await.unwrap().unwrap().unwrap();
+        drop(client);
+    }
+
+    #[test]
+    fn test_build_socks5_reply_ipv6() {
+        let bound: SocketAddr = "[::1]:4242".parse().unwrap();
+        let reply = build_socks5_reply(Socks5Reply::Succeeded, bound);
+        assert_eq!(&reply[..4], &[0x05, 0x00, 0x00, 0x04]);
+        assert_eq!(&reply[4..20], &"::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        assert_eq!(&reply[20..], &4242u16.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_connect_response_has_no_body_before_tunnel() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let upstream = tokio::spawn(async move {
+            let (mut accepted, _) = listener.accept().await.unwrap();
+            accepted.write_all(b"SSH-2.0-test\r\n").await.unwrap();
+            let mut buf = [0u8; 1];
+            let _ = accepted.read(&mut buf).await;
+        });
+
+        let config = KnoxProxyConfig {
+            connect_response: ConnectResponse { reason: "Tunnel\r\nX-Evil: 1".to_string(), proxy_agent: Some("litebike".to_string()) },
+            ..Default::default()
+        };
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handler = tokio::spawn(async move {
+            KnoxProxy::handle_http_proxy(server, None, None, &DockStats::default(), &config).await
+        });
+        client.write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target).as_bytes()).await.unwrap();
+
+        let expected = b"HTTP/1.1 200 TunnelX-Evil: 1\r\nProxy-agent: litebike\r\n\r\nSSH-2.0-test\r\n";
+        let mut received = vec![0u8; expected.len()];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&received), String::from_utf8_lossy(expected));
+        assert_eq!(ConnectResponse::default().build(), "HTTP/1.1 200 Connection established\r\n\r\n");
+
+        drop(client);
+        upstream.await.unwrap();
+        let _ = handler.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_relays_bytes_pipelined_behind_the_head() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let upstream = tokio::spawn(async move {
+            let (mut accepted, _) = listener.accept().await.unwrap();
+            let mut pipelined = [0u8; 14];
+            tokio::time::timeout(Duration::from_secs(2), accepted.read_exact(&mut pipelined)).await.unwrap().unwrap();
+            assert_eq!(&pipelined, b"SSH-2.0-eager\n");
+            accepted.write_all(b"SSH-2.0-test\r\n").await.unwrap();
+            let mut buf = [0u8; 1];
+            let _ = accepted.read(&mut buf).await;
+        });
+
+        let config = KnoxProxyConfig::default();
+        let (mut client, server) = tokio::io::duplex(1024);
+        let handler = tokio::spawn(async move {
+            KnoxProxy::handle_http_proxy(server, None, None, &DockStats::default(), &config).await
+        });
+        // The client does not wait for the 200 before speaking
+        client.write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\nSSH-2.0-eager\n", target).as_bytes()).await.unwrap();
+
+        let expected = b"HTTP/1.1 200 Connection established\r\n\r\nSSH-2.0-test\r\n";
+        let mut received = vec![0u8; expected.len()];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&received), String::from_utf8_lossy(expected));
+
+        drop(client);
+        upstream.await.unwrap();
+        let _ = handler.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_http_proxy_requires_basic_auth_when_configured() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let upstream = tokio::spawn(async move {
+            let (mut accepted, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            let mut byte = [0u8; 1];
+            while !head.ends_with(b"\r\n\r\n") {
+                accepted.read_exact(&mut byte).await.unwrap();
+                head.push(byte[0]);
+            }
+            accepted.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+            String::from_utf8(head).unwrap()
+        });
+        let config = KnoxProxyConfig { http_credentials: vec!["bike:s3cret".to_string()], ..Default::default() };
+
+        // Missing and wrong credentials get a 407 and nothing is dialled
+        for auth in ["", "Proxy-Authorization: Basic YmlrZTp3cm9uZw==\r\n"] {
+            let (mut client, server) = tokio::io::duplex(4096);
+            let cfg = config.clone();
+            let handler = tokio::spawn(async move {
+                KnoxProxy::handle_http_proxy(server, None, None, &DockStats::default(), &cfg).await
+            });
+            client.write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n{1}\r\n", target, auth).as_bytes()).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 407 Proxy Authentication Required\r\n"));
+            assert!(response.contains("Proxy-Authenticate: Basic realm=\"litebike\"\r\n"));
+            handler.await.unwrap().unwrap();
+        }
+
+        // bike:s3cret passes and the header is not forwarded
+        let (mut client, server) = tokio::io::duplex(4096);
+        let handler = tokio::spawn(async move {
+            KnoxProxy::handle_http_proxy(server, None, None, &DockStats::default(), &config).await
+        });
+        client.write_all(format!(
+            "GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\nProxy-Authorization: Basic YmlrZTpzM2NyZXQ=\r\n\r\n",
+            target
+        ).as_bytes()).await.unwrap();
+        let mut status = [0u8; 12];
+        client.read_exact(&mut status).await.unwrap();
+        assert_eq!(&status, b"HTTP/1.1 204");
+        let forwarded = upstream.await.unwrap();
+        assert!(forwarded.starts_with("GET / HTTP/1.1\r\n"));
+        assert!(!forwarded.to_ascii_lowercase().contains("proxy-authorization"));
+        drop(client);
+        let _ = handler.await.unwrap();
+
+        assert_eq!(crate::http::decode_base64("YmlrZTpzM2NyZXQ").unwrap(), b"bike:s3cret");
+        assert!(crate::http::decode_base64("Ym!r").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_http_proxy_forwards_non_utf8_body() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = upstream.local_addr().unwrap();
+        let body: &[u8] = b"\xff\xfe\x00\x80binary\xc3";
+        let origin = tokio::spawn(async move {
+            let (mut accepted, _) = upstream.accept().await.unwrap();
+            let mut received = vec![0u8; 0];
+            let mut chunk = [0u8; 256];
+            while !received.ends_with(b"binary\xc3") {
+                let n = accepted.read(&mut chunk).await.unwrap();
+                assert!(n > 0);
+                received.extend_from_slice(&chunk[..n]);
+            }
+            accepted.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+            received
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move { KnoxProxy::new(KnoxProxyConfig::default()).serve(listener).await });
+
+        // Head and body arrive in one write, so detection sees the body bytes too
+        let mut request = format!(
+            "POST http://{0}/upload HTTP/1.1\r\nHost: {0}\r\nContent-Length: {1}\r\n",
+            target,
+            body.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(b"X-Note: caf\xe9\r\n\r\n");
+        request.extend_from_slice(body);
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        let mut status = [0u8; 12];
+        tokio::time::timeout(Duration::from_secs(2), client.read_exact(&mut status)).await.unwrap().unwrap();
+        assert_eq!(&status, b"HTTP/1.1 204");
+
+        let forwarded = origin.await.unwrap();
+        assert!(forwarded.starts_with(b"POST /upload HTTP/1.1\r\n"));
+        assert!(forwarded.ends_with(body));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_http_connect_status_reflects_connect_error() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let connect = |target: String| async move {
+            let (mut client, server) = tokio::io::duplex(4096);
+            let handler = tokio::spawn(async move {
+                let config = KnoxProxyConfig::default();
+                KnoxProxy::handle_http_proxy(server, None, None, &DockStats::default(), &config).await
+            });
+            client.write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target).as_bytes()).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            (response, handler.await.unwrap().unwrap_err().kind())
+        };
+
+        let (response, kind) = connect(closed.to_string()).await;
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"), "{}", response);
+        assert!(response.ends_with("refused the connection.\n"));
+        assert_eq!(kind, io::ErrorKind::ConnectionRefused);
+
+        let (response, kind) = connect("no-such-host.invalid:443".to_string()).await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+        assert!(response.ends_with("Could not resolve no-such-host.invalid:443.\n"));
+        assert_eq!(kind, io::ErrorKind::NotFound);
+
+        let timed_out = connect_error_response(&io::Error::from(io::ErrorKind::TimedOut), "example.com:443");
+        assert!(timed_out.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"));
+        let body = timed_out.split("\r\n\r\n").nth(1).unwrap();
+        assert!(timed_out.contains(&format!("Content-Length: {}\r\n", body.len())));
+    }
+
+    #[tokio::test]
+    async fn test_http_proxy_head_split_across_reads() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = upstream.local_addr().unwrap();
+        // The Host header is in the second read, the body straddles the second and third
+        let parts = [
+            format!("POST http://{}/form HTTP/1.1\r\nContent-Ty", target),
+            format!("pe: text/plain\r\nHost: {}\r\nContent-Length: 10\r\n\r\n0123", target),
+            "456789".to_string(),
+        ];
+        // Origin-form request line, everything else byte for byte
+        let expected = parts.concat().replacen(&format!("http://{}", target), "", 1);
+        let want = expected.len();
+        let origin = tokio::spawn(async move {
+            let (mut accepted, _) = upstream.accept().await.unwrap();
+            let mut received = Vec::new();
+            let mut chunk = [0u8; 256];
+            // Read past what is expected, so a body sent twice would show
+            while received.len() <= want {
+                match tokio::time::timeout(Duration::from_millis(200), accepted.read(&mut chunk)).await {
+                    Ok(Ok(n)) if n > 0 => received.extend_from_slice(&chunk[..n]),
+                    _ => break,
+                }
+            }
+            accepted.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+            received
+        });
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        let handler = tokio::spawn(async move {
+            let config = KnoxProxyConfig::default();
+            KnoxProxy::handle_http_proxy(server, None, None, &DockStats::default(), &config).await
+        });
+        for part in &parts {
+            client.write_all(part.as_bytes()).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut status = [0u8; 12];
+        client.read_exact(&mut status).await.unwrap();
+        assert_eq!(&status, b"HTTP/1.1 204");
+
+        assert_eq!(String::from_utf8(origin.await.unwrap()).unwrap(), expected);
+        drop(client);
+        handler.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_target_through_upstream_proxy() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let egress = EgressOptions { upstream_proxy: Some(upstream.local_addr().unwrap().to_string()), ..Default::default() };
+        let proxy = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            let responses = [
+                &b"HTTP/1.1 200 Connection established\r\n\r\nhello"[..],
+                b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n",
+                b"HTTP/1.1 502 Bad Gateway\r\n\r\n",
+            ];
+            for response in responses {
+                let (mut accepted, _) = upstream.accept().await.unwrap();
+                let mut head = Vec::new();
+                let mut byte = [0u8; 1];
+                while !head.ends_with(b"\r\n\r\n") {
+                    accepted.read_exact(&mut byte).await.unwrap();
+                    head.push(byte[0]);
+                }
+                accepted.write_all(response).await.unwrap();
+                requests.push(String::from_utf8(head).unwrap());
+            }
+            requests
+        });
+
+        // Bytes sent right after the 200 reach the caller as tunnel data
+        let mut stream = connect_to_target("example.com:443", &egress).await.unwrap();
+        let mut greeting = [0u8; 5];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"hello");
+
+        let denied = connect_to_target("example.com:443", &egress).await.unwrap_err();
+        assert_eq!(denied.kind(), io::ErrorKind::PermissionDenied);
+        let refused = connect_to_target("example.com:443", &egress).await.unwrap_err();
+        assert_eq!(refused.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(refused.to_string().contains("502 Bad Gateway"));
+
+        let requests = proxy.await.unwrap();
+        assert!(requests.iter().all(|r| r == "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_connect_for_client_prepends_proxy_header() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let client: SocketAddr = "192.168.43.20:51234".parse().unwrap();
+        let egress = EgressOptions { proxy_protocol: true, ..Default::default() };
+
+        let mut upstream = connect_for_client(&target.to_string(), Some(client), &egress).await.unwrap();
+        upstream.write_all(b"GET").await.unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+        let mut received = vec![0u8; 31];
+        accepted.read_exact(&mut received).await.unwrap();
+
+        let (announced, announced_target, len) = crate::universal_listener::parse_proxy_protocol_v2(&received).unwrap();
+        assert_eq!((announced, announced_target), (client, target));
+        assert_eq!(&received[len..], b"GET");
+    }
+
+    #[tokio::test]
+    async fn test_serves_dock_manifest() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = KnoxProxyConfig { instance_name: "dock-test".to_string(), ..Default::default() };
+        let server = tokio::spawn(async move { KnoxProxy::new(config).serve(listener).await });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET /litebike.json HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8(response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert!(head.contains("Content-Type: application/json"));
+
+        let manifest: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(manifest["name"], "dock-test");
+        assert_eq!(manifest["port"], addr.port());
+        assert_eq!(manifest["socks5"], true);
+        assert_eq!(manifest["stats"]["active_connections"], 1);
+        server.abort();
+    }
+
+    /// ALPN protocol names offered in a ClientHello record
+    fn offered_alpn(hello: &[u8]) -> Option<Vec<String>> {
+        // Record (5) + handshake header (4) + version (2) + random (32)
+        let mut at = 43;
+        at += 1 + hello[at] as usize; // session id
+        at += 2 + u16::from_be_bytes([hello[at], hello[at + 1]]) as usize; // cipher suites
+        at += 1 + hello[at] as usize; // compression methods
+        let end = at + 2 + u16::from_be_bytes([hello[at], hello[at + 1]]) as usize;
+        at += 2;
+        while at + 4 <= end {
+            let kind = u16::from_be_bytes([hello[at], hello[at + 1]]);
+            let len = u16::from_be_bytes([hello[at + 2], hello[at + 3]]) as usize;
+            let data = &hello[at + 4..at + 4 + len];
+            if kind == 0x0010 {
+                let mut names = Vec::new();
+                let mut rest = &data[2..];
+                while let Some((&n, tail)) = rest.split_first() {
+                    names.push(String::from_utf8_lossy(&tail[..n as usize]).into_owned());
+                    rest = &tail[n as usize..];
+                }
+                return Some(names);
+            }
+            at += 4 + len;
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn test_upstream_tls_offers_configured_alpn() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = origin.local_addr().unwrap();
+        let config = KnoxProxyConfig { upstream_alpn: Some(vec!["http/1.1".to_string()]), ..Default::default() };
+        let mut manager = upstream_tls_fingerprint(&config);
+
+        let _client = start_upstream_tls(&addr.to_string(), "example.com", &mut manager, &EgressOptions::default())
+            .await
+            .unwrap();
+        let (mut accepted, _) = origin.accept().await.unwrap();
+        let mut header = [0u8; 5];
+        accepted.read_exact(&mut header).await.unwrap();
+        let mut hello = header.to_vec();
+        hello.resize(5 + u16::from_be_bytes([header[3], header[4]]) as usize, 0);
+        accepted.read_exact(&mut hello[5..]).await.unwrap();
+        assert_eq!(offered_alpn(&hello), Some(vec!["http/1.1".to_string()]));
+
+        // Without an override the profile's own list is offered
+        let hello = upstream_tls_fingerprint(&KnoxProxyConfig::default()).generate_client_hello("example.com").unwrap();
+        assert!(offered_alpn(&hello).unwrap().contains(&"h2".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_target_parses_ipv6_literals() {
+        assert_eq!(parse_target("[::1]:443").unwrap(), TargetAddress::new("::1", 443));
+        assert_eq!(parse_target("127.0.0.1:80").unwrap(), TargetAddress::new("127.0.0.1", 80));
+        assert_eq!(parse_target("[fe80::1%eth0]:22").unwrap().to_string(), "[fe80::1%eth0]:22");
+        assert_eq!(parse_target("example.com:443").unwrap(), TargetAddress::new("example.com", 443));
+        for bad in ["::1:443", "[::1]", "[::1]443", "[example.com]:443", ":443", "example.com", "example.com:http"] {
+            assert_eq!(parse_target(bad).unwrap_err().kind(), io::ErrorKind::InvalidInput, "{}", bad);
+        }
+
+        // Ambiguous targets fail before any lookup or dial
+        let err = connect_to_target("::1:443", &EgressOptions::default()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(ResolveCache::global().cached("::1:443"), None);
+
+        let listener = TcpListener::bind("[::1]:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let stream = connect_to_target(&format!("[::1]:{}", port), &EgressOptions::default()).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_fails_fast_then_recovers() {
+        use crate::circuit_breaker::BreakerConfig;
+
+        // A port with nothing listening, reused below once the circuit is open
+        let port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+        let target = format!("127.0.0.1:{}", port);
+        let breaker = Arc::new(CircuitBreaker::new(BreakerConfig { failure_threshold: 3, cooldown: Duration::from_millis(300) }));
+        let egress = EgressOptions { breaker: Some(breaker.clone()), ..Default::default() };
+
+        for _ in 0..3 {
+            assert!(!breaker.is_open("127.0.0.1"));
+            connect_to_target(&target, &egress).await.unwrap_err();
+        }
+        assert!(breaker.is_open("127.0.0.1"));
+
+        // Open: refused without dialling, even though the host is now up
+        let listener = TcpListener::bind(&target).await.unwrap();
+        let started = std::time::Instant::now();
+        let err = connect_to_target(&target, &egress).await.unwrap_err();
+        assert!(started.elapsed() < Duration::from_millis(50));
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(err.to_string().contains("circuit open for 127.0.0.1"));
+        assert!(tokio::time::timeout(Duration::from_millis(20), listener.accept()).await.is_err());
+
+        // After the cooldown one probe goes through and closes the circuit
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        connect_to_target(&target, &egress).await.unwrap();
+        assert!(!breaker.is_open("127.0.0.1"));
+        connect_to_target(&target, &egress).await.unwrap();
+
+        // A failed probe reopens it at once
+        drop(listener);
+        for _ in 0..3 {
+            connect_to_target(&target, &egress).await.unwrap_err();
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        connect_to_target(&target, &egress).await.unwrap_err();
+        assert!(breaker.is_open("127.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn test_outbound_source_ports_stay_in_range() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        let range = free_port_range(4);
+        let ports: SourcePortRange = format!("{}-{}", range.start(), range.end()).parse().unwrap();
+        // The first port in the range is taken, so it must be skipped
+        let _squatter = std::net::TcpListener::bind(("127.0.0.1", *range.start())).unwrap();
+        let egress = EgressOptions { source_ports: Some(ports.clone()), ..Default::default() };
+
+        let mut streams = Vec::new();
+        for _ in 0..3 {
+            streams.push(connect_to_target(&target, &egress).await.unwrap());
+            listener.accept().await.unwrap();
+        }
+        let mut used: Vec<u16> = streams.iter().map(|s| s.local_addr().unwrap().port()).collect();
+        used.sort_unstable();
+        assert_eq!(used, (range.start() + 1..=*range.end()).collect::<Vec<u16>>());
+        assert!(used.iter().all(|&p| ports.contains(p)));
+
+        // Every port in the range is now busy towards this target
+        let err = connect_to_target(&target, &egress).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        let random = EgressOptions { source_ports: Some(ports.with_selection(PortSelection::Random)), ..Default::default() };
+        let other = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = connect_to_target(&other.local_addr().unwrap().to_string(), &random).await.unwrap();
+        assert!((range.start() + 1..=*range.end()).contains(&stream.local_addr().unwrap().port()));
+    }
+
+    /// `len` consecutive ports, starting at an OS-assigned one, that were all
+    /// free when checked
+    fn free_port_range(len: u16) -> RangeInclusive<u16> {
+        loop {
+            let first = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let start = first.local_addr().unwrap().port();
+            let Some(end) = start.checked_add(len - 1) else { continue };
+            let rest: io::Result<Vec<_>> = (start + 1..=end).map(|port| std::net::TcpListener::bind(("127.0.0.1", port))).collect();
+            if rest.is_ok() {
+                return start..=end;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_listener_port_reaches_manifest() {
+        async fn manifest_port(addr: SocketAddr) -> u16 {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(b"GET /litebike.json HTTP/1.1\r\nHost: dock\r\n\r\n").await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            let body = response.split("\r\n\r\n").nth(1).unwrap();
+            crate::dock::parse_manifest_json(body).unwrap().port
+        }
+
+        let config = KnoxProxyConfig {
+            bind_addr: "127.0.0.1:0".to_string(),
+            enable_knox_bypass: false,
+            ..Default::default()
+        };
+        let listener = bind_listener(&config).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        tokio::spawn(async move { KnoxProxy::new(config).serve(listener).await });
+        assert_eq!(manifest_port(addr).await, addr.port());
+
+        // A range: a random free port inside it, and an error once all are taken
+        let ranged = KnoxProxyConfig {
+            bind_addr: "127.0.0.1:8080".to_string(),
+            port_range: Some(free_port_range(2)),
+            enable_knox_bypass: false,
+            ..Default::default()
+        };
+        let first = bind_listener(&ranged).await.unwrap();
+        let second = bind_listener(&ranged).await.unwrap();
+        let mut ports = [first.local_addr().unwrap().port(), second.local_addr().unwrap().port()];
+        ports.sort_unstable();
+        let range = ranged.port_range.clone().unwrap();
+        assert_eq!(ports, [*range.start(), *range.end()]);
+        assert_eq!(bind_listener(&ranged).await.unwrap_err().kind(), io::ErrorKind::AddrInUse);
+        let addr = first.local_addr().unwrap();
+        tokio::spawn(async move { KnoxProxy::new(ranged).serve(first).await });
+        assert_eq!(manifest_port(addr).await, addr.port());
+
+        // A missing listen interface leaves the proxy on loopback, not on bind_addr's IP
+        let missing = KnoxProxyConfig {
+            bind_addr: "0.0.0.0:0".to_string(),
+            listen_interface: Some("nosuch0".to_string()),
+            enable_knox_bypass: false,
+            ..Default::default()
+        };
+        let listener = bind_listener(&missing).await.unwrap();
+        assert!(listener.local_addr().unwrap().ip().is_loopback());
+
+        // Which interface port a range would pick is undefined, so the pair is refused
+        let ranged_interface = KnoxProxyConfig { port_range: Some(free_port_range(2)), ..missing };
+        assert_eq!(bind_listener(&ranged_interface).await.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_listener_follows_interface_events() {
+        let config = KnoxProxyConfig { bind_addr: "127.0.0.1:0".to_string(), enable_knox_bypass: false, ..Default::default() };
+        let listener = bind_listener(&config).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (events, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move { KnoxProxy::new(config).serve_following(listener, rx).await });
+
+        // The interface comes up: the proxy moves there and leaves loopback
+        let up = std::net::Ipv4Addr::new(127, 0, 0, 2);
+        events.send(InterfaceEvent::Up(up)).unwrap();
+        let moved = SocketAddr::from((up, port));
+        let mut client = loop {
+            match TcpStream::connect(moved).await {
+                Ok(client) => break client,
+                Err(_) => tokio::time::sleep(Duration::from_millis(5)).await,
+            }
+        };
+        client.write_all(b"GET /litebike.json HTTP/1.1\r\nHost: dock\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[tokio::test]
+    async fn test_egress_interface_binds_device() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+
+        let lo = EgressOptions { interface: Some("lo".to_string()), ..Default::default() };
+        let stream = connect_to_target(&target, &lo).await.unwrap();
+        assert_eq!(crate::tcp_fingerprint::bound_device(stream.as_raw_fd()).unwrap().as_deref(), Some("lo"));
+
+        let udp = bind_udp_egress(&lo).await.unwrap();
+        assert_eq!(crate::tcp_fingerprint::bound_device(udp.as_raw_fd()).unwrap().as_deref(), Some("lo"));
+        let bind = bind_inbound_listener("127.0.0.1:0".parse().unwrap(), &lo).unwrap();
+        assert_eq!(crate::tcp_fingerprint::bound_device(bind.as_raw_fd()).unwrap().as_deref(), Some("lo"));
+
+        let missing = EgressOptions { interface: Some("nosuchif0".to_string()), ..Default::default() };
+        let err = connect_to_target(&target, &missing).await.unwrap_err();
+        assert!(err.to_string().contains("nosuchif0"), "{}", err);
+        assert!(bind_udp_egress(&missing).await.unwrap_err().to_string().contains("nosuchif0"));
+        assert!(bind_inbound_listener("127.0.0.1:0".parse().unwrap(), &missing).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs_falls_through_to_reachable_address() {
+        let v6 = |port| SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, port));
+        let v4 = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        assert_eq!(
+            interleave_families(vec![v6(1), v6(2), v6(3), v4(4), v4(5)]),
+            vec![v6(1), v4(4), v6(2), v4(5), v6(3)]
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let egress = EgressOptions::default();
+
+        // A refused attempt hands over at once instead of after the stagger
+        let started = std::time::Instant::now();
+        let stream = connect_happy_eyeballs(vec![v6(closed.port()), closed, open], &egress).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), open);
+        assert!(started.elapsed() < HAPPY_EYEBALLS_DELAY, "{:?}", started.elapsed());
+
+        // A listener whose accept queue is full drops further SYNs, so it
+        // never answers; it only costs the stagger
+        let stalled = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+        stalled.bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into()).unwrap();
+        stalled.listen(0).unwrap();
+        let blackhole = stalled.local_addr().unwrap().as_socket().unwrap();
+        let mut queued = Vec::new();
+        while let Ok(Ok(stream)) = tokio::time::timeout(Duration::from_millis(100), TcpStream::connect(blackhole)).await {
+            queued.push(stream);
+        }
+        let started = std::time::Instant::now();
+        let stream = connect_happy_eyeballs(vec![blackhole, open], &egress).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), open);
+        assert!((HAPPY_EYEBALLS_DELAY..Duration::from_secs(2)).contains(&started.elapsed()), "{:?}", started.elapsed());
+
+        let err = connect_happy_eyeballs(vec![closed, v6(closed.port())], &egress).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_target_family_mismatch() {
+        let egress = EgressOptions { bind_ip: Some("::1".parse().unwrap()), ..Default::default() };
+        let err = connect_to_target("127.0.0.1:9", &egress).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+}
@@ -22,6 +22,22 @@ pub mod ssh_tools;
 pub mod tls_fingerprint;
 pub mod universal_listener;
 pub mod packet_fragment;
+pub mod http;
+pub mod pac;
+pub mod selftest;
+pub mod network;
+pub mod capture;
+pub mod interface_watcher;
+pub mod resolver;
+pub mod quota;
+pub mod device_profile;
+pub mod conn_log;
+pub mod circuit_breaker;
+pub mod routing;
+pub mod capabilities;
+pub mod determinism;
+pub mod shadowsocks;
+pub mod util;
 
 // Integrated proxy architecture combining all components
 pub mod integrated_proxy;
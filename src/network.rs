@@ -0,0 +1,248 @@
+// Network usability - DHCP/BOOTP recognition and captive-portal heuristics
+// Hotspots that intercept traffic until a sign-in page is accepted look
+// connected but cannot carry proxied traffic. The RFC 8910 portal URI (DHCP
+// option 114) only reaches the system's DHCP client, so the heuristic rests
+// on the connectivity probe.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use log::debug;
+
+use crate::types::ProtocolType;
+
+/// Fixed BOOTP header length before the options magic cookie (RFC 2131 §2)
+const BOOTP_HEADER_LEN: usize = 236;
+/// DHCP options magic cookie
+pub const DHCP_MAGIC_COOKIE: [u8; 4] = [0x63, 0x82, 0x53, 0x63];
+const DHCP_OPTION_MESSAGE_TYPE: u8 = 53;
+
+/// Host and path answering 204 when the network is open
+pub const CONNECTIVITY_CHECK_HOST: &str = "connectivitycheck.gstatic.com";
+pub const CONNECTIVITY_CHECK_PATH: &str = "/generate_204";
+
+/// DHCP message type (option 53)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DhcpMessageType {
+    Discover,
+    Offer,
+    Request,
+    Decline,
+    Ack,
+    Nak,
+    Release,
+    Inform,
+    Other(u8),
+}
+
+impl From<u8> for DhcpMessageType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => DhcpMessageType::Discover,
+            2 => DhcpMessageType::Offer,
+            3 => DhcpMessageType::Request,
+            4 => DhcpMessageType::Decline,
+            5 => DhcpMessageType::Ack,
+            6 => DhcpMessageType::Nak,
+            7 => DhcpMessageType::Release,
+            8 => DhcpMessageType::Inform,
+            other => DhcpMessageType::Other(other),
+        }
+    }
+}
+
+/// The parts of a DHCP packet relevant to network usability
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhcpPacket {
+    /// BOOTP op: 1 request, 2 reply
+    pub op: u8,
+    pub message_type: Option<DhcpMessageType>,
+}
+
+/// Parse a BOOTP/DHCP datagram; `None` if it is not one
+pub fn parse_dhcp(packet: &[u8]) -> Option<DhcpPacket> {
+    if packet.len() < BOOTP_HEADER_LEN + 4 || !matches!(packet[0], 0x01 | 0x02) {
+        return None;
+    }
+    if packet[BOOTP_HEADER_LEN..BOOTP_HEADER_LEN + 4] != DHCP_MAGIC_COOKIE {
+        return None;
+    }
+
+    let mut dhcp = DhcpPacket { op: packet[0], message_type: None };
+    let mut options = &packet[BOOTP_HEADER_LEN + 4..];
+    while let Some((&code, rest)) = options.split_first() {
+        match code {
+            0 => {
+                options = rest;
+                continue;
+            }
+            255 => break,
+            _ => {}
+        }
+        let (&len, rest) = rest.split_first()?;
+        let value = rest.get(..len as usize)?;
+        if code == DHCP_OPTION_MESSAGE_TYPE && len == 1 {
+            dhcp.message_type = Some(value[0].into());
+        }
+        options = &rest[len as usize..];
+    }
+    Some(dhcp)
+}
+
+/// Classify a UDP payload by content
+pub fn detect_udp(packet: &[u8]) -> Option<ProtocolType> {
+    parse_dhcp(packet).map(|_| ProtocolType::Dhcp)
+}
+
+/// Result of fetching the connectivity check URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProbeOutcome {
+    /// 204 as expected: the network is open
+    NoContent,
+    /// Redirected, usually to the portal's sign-in page
+    Redirect(Option<String>),
+    /// Some other status, typically a portal page served in place
+    Intercepted(u16),
+    Unreachable,
+}
+
+/// Evidence gathered about a captive portal
+#[derive(Debug, Clone, Default)]
+pub struct CaptivePortalSignals {
+    pub probe: Option<ProbeOutcome>,
+}
+
+impl CaptivePortalSignals {
+    /// Only positive evidence counts: a redirect with a Location. An
+    /// unreachable or odd probe proves nothing.
+    pub fn is_likely(&self) -> bool {
+        matches!(self.probe, Some(ProbeOutcome::Redirect(Some(_))))
+    }
+}
+
+/// Fetch the connectivity check from `addr`, sending `host` in the Host header
+pub fn probe_connectivity_at(addr: SocketAddr, host: &str, timeout: Duration) -> ProbeOutcome {
+    match fetch_status(addr, host, timeout) {
+        Ok((204, _)) => ProbeOutcome::NoContent,
+        Ok((301 | 302 | 303 | 307 | 308, location)) => ProbeOutcome::Redirect(location),
+        Ok((status, _)) => ProbeOutcome::Intercepted(status),
+        Err(e) => {
+            debug!("Connectivity probe to {} failed: {}", addr, e);
+            ProbeOutcome::Unreachable
+        }
+    }
+}
+
+fn fetch_status(addr: SocketAddr, host: &str, timeout: Duration) -> io::Result<(u16, Option<String>)> {
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nUser-Agent: litebike\r\n\r\n",
+        CONNECTIVITY_CHECK_PATH, host
+    );
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !response.windows(4).any(|w| w == b"\r\n\r\n") && response.len() < 8192 {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        response.extend_from_slice(&buf[..n]);
+    }
+
+    let text = String::from_utf8_lossy(&response);
+    let mut lines = text.lines();
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP status line"))?;
+    let location = lines
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("location").then(|| value.trim().to_string())
+        });
+    Ok((status, location))
+}
+
+/// Gather signals, probing the public connectivity check
+pub fn captive_portal_signals(timeout: Duration) -> CaptivePortalSignals {
+    let probe = (CONNECTIVITY_CHECK_HOST, 80)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .map(|addr| probe_connectivity_at(addr, CONNECTIVITY_CHECK_HOST, timeout))
+        .unwrap_or(ProbeOutcome::Unreachable);
+    CaptivePortalSignals { probe: Some(probe) }
+}
+
+/// Whether the current network appears to sit behind a captive portal.
+/// Blocks for up to a few seconds on the connectivity probe.
+pub fn is_captive_portal_likely() -> bool {
+    captive_portal_signals(Duration::from_secs(3)).is_likely()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dhcp_packet(op: u8, options: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; BOOTP_HEADER_LEN];
+        packet[0] = op;
+        packet[1] = 1; // ethernet
+        packet[2] = 6; // hardware address length
+        packet.extend_from_slice(&DHCP_MAGIC_COOKIE);
+        packet.extend_from_slice(options);
+        packet.push(255);
+        packet
+    }
+
+    #[test]
+    fn test_dhcp_discover_classified() {
+        let discover = dhcp_packet(0x01, &[53, 1, 1, 55, 2, 1, 3]);
+        assert_eq!(detect_udp(&discover), Some(ProtocolType::Dhcp));
+        let parsed = parse_dhcp(&discover).unwrap();
+        assert_eq!(parsed.message_type, Some(DhcpMessageType::Discover));
+
+        // Same layout without the magic cookie is plain BOOTP noise
+        let mut bogus = discover.clone();
+        bogus[BOOTP_HEADER_LEN] = 0;
+        assert_eq!(detect_udp(&bogus), None);
+    }
+
+    #[test]
+    fn test_captive_portal_signals() {
+        assert!(!CaptivePortalSignals::default().is_likely());
+        let open = CaptivePortalSignals { probe: Some(ProbeOutcome::NoContent) };
+        assert!(!open.is_likely());
+
+        // Only a redirect that names the portal counts
+        let probed = |probe| CaptivePortalSignals { probe: Some(probe) }.is_likely();
+        assert!(probed(ProbeOutcome::Redirect(Some("http://10.0.0.1/login".to_string()))));
+        assert!(!probed(ProbeOutcome::Redirect(None)));
+        assert!(!probed(ProbeOutcome::Intercepted(200)));
+        assert!(!probed(ProbeOutcome::Unreachable));
+    }
+
+    #[test]
+    fn test_probe_detects_redirect() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let portal = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 302 Found\r\nLocation: http://10.0.0.1/login\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+        });
+
+        let outcome = probe_connectivity_at(addr, CONNECTIVITY_CHECK_HOST, Duration::from_secs(2));
+        assert_eq!(outcome, ProbeOutcome::Redirect(Some("http://10.0.0.1/login".to_string())));
+        portal.join().unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+// PAC/WPAD generation - the proxy auto-config served at /proxy.pac and /wpad.dat
+// Directive order matters: clients try each entry in turn, so UDP-capable
+// apps want SOCKS5 listed before PROXY. The listener speaks plain HTTP, so
+// there is no HTTPS directive: clients would try TLS to it and fail.
+
+use std::fmt;
+
+/// One proxy entry in the `FindProxyForURL` return string
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacDirective {
+    Socks5,
+    Proxy,
+}
+
+impl PacDirective {
+    pub fn keyword(&self) -> &'static str {
+        match self {
+            PacDirective::Socks5 => "SOCKS5",
+            PacDirective::Proxy => "PROXY",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "socks5" | "socks" => Some(PacDirective::Socks5),
+            "proxy" | "http" => Some(PacDirective::Proxy),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PacDirective {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.keyword())
+    }
+}
+
+/// Parse a comma separated order such as `socks5,proxy`
+pub fn parse_order(spec: &str) -> Result<Vec<PacDirective>, String> {
+    let mut order = Vec::new();
+    for name in spec.split(',').filter(|s| !s.trim().is_empty()) {
+        let directive = PacDirective::parse(name).ok_or_else(|| format!("unknown PAC directive '{}'", name.trim()))?;
+        if !order.contains(&directive) {
+            order.push(directive);
+        }
+    }
+    if order.is_empty() {
+        return Err("PAC order must name at least one directive".to_string());
+    }
+    Ok(order)
+}
+
+/// PAC file parameters: the advertised listener and the directive order
+#[derive(Debug, Clone)]
+pub struct PacConfig {
+    pub host: String,
+    pub port: u16,
+    pub order: Vec<PacDirective>,
+}
+
+impl PacConfig {
+    pub fn new(host: &str, port: u16) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            order: vec![PacDirective::Proxy, PacDirective::Socks5],
+        }
+    }
+
+    pub fn with_order(mut self, order: Vec<PacDirective>) -> Self {
+        if !order.is_empty() {
+            self.order = order;
+        }
+        self
+    }
+
+    /// Apply `PAC_ORDER` from the environment when set and valid
+    pub fn with_env_order(self) -> Self {
+        match std::env::var("PAC_ORDER").map(|spec| parse_order(&spec)) {
+            Ok(Ok(order)) => self.with_order(order),
+            Ok(Err(e)) => {
+                log::warn!("Ignoring PAC_ORDER: {}", e);
+                self
+            }
+            Err(_) => self,
+        }
+    }
+
+    /// Proxy list returned for non-local hosts, always ending in DIRECT
+    pub fn proxy_list(&self) -> String {
+        let mut entries: Vec<String> = self
+            .order
+            .iter()
+            .map(|d| format!("{} {}:{}", d, self.host, self.port))
+            .collect();
+        entries.push("DIRECT".to_string());
+        entries.join("; ")
+    }
+
+    pub fn generate(&self) -> String {
+        format!(
+            r#"function FindProxyForURL(url, host) {{
+    if (isPlainHostName(host) ||
+        shExpMatch(host, "*.local") ||
+        isInNet(dnsResolve(host), "10.0.0.0", "255.0.0.0") ||
+        isInNet(dnsResolve(host), "172.16.0.0", "255.240.0.0") ||
+        isInNet(dnsResolve(host), "192.168.0.0", "255.255.0.0") ||
+        isInNet(dnsResolve(host), "127.0.0.0", "255.255.255.0"))
+        return "DIRECT";
+    return "{}";
+}}"#,
+            self.proxy_list()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_socks5_listed_before_proxy() {
+        let order = parse_order("socks5,proxy").unwrap();
+        let pac = PacConfig::new("192.168.43.1", 8888).with_order(order).generate();
+        let socks = pac.find("SOCKS5 192.168.43.1:8888").unwrap();
+        let proxy = pac.find("PROXY 192.168.43.1:8888").unwrap();
+        assert!(socks < proxy);
+        assert!(pac.contains("PROXY 192.168.43.1:8888; DIRECT\""));
+        // The listener is plain HTTP; a TLS proxy entry would never connect
+        assert!(!PacConfig::new("192.168.43.1", 8888).generate().contains("HTTPS"));
+    }
+
+    #[test]
+    fn test_parse_order_rejects_unknown() {
+        assert!(parse_order("socks5,gopher").is_err());
+        assert!(parse_order("https").is_err());
+        assert_eq!(parse_order("proxy, proxy").unwrap(), vec![PacDirective::Proxy]);
+    }
+}
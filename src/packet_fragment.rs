@@ -3,6 +3,7 @@
 
 use std::collections::VecDeque;
 use std::time::{Duration, Instant};
+use rand::rngs::StdRng;
 use rand::Rng;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio::time::sleep;
@@ -44,8 +45,17 @@ pub enum MobileFragmentPattern {
     Aggressive,    // Heavy fragmentation, high evasion
     Adaptive,      // Dynamic based on detection
     Carrier(CarrierProfile),
+    /// Full-size segments for `mtu`, each shortened by up to `jitter` bytes
+    /// with small reductions far more likely, like real path-MTU traffic
+    MtuLike { mtu: u16, jitter: u16 },
 }
 
+/// Fragmentation strategy, as selected by callers
+pub type FragmentStrategy = MobileFragmentPattern;
+
+/// IPv4 + TCP header bytes subtracted from an MTU to get the payload size
+const IP_TCP_HEADER_LEN: usize = 40;
+
 #[derive(Debug, Clone)]
 pub enum CarrierProfile {
     Verizon,
@@ -123,6 +133,9 @@ pub struct PacketFragmenter {
     fragment_queue: VecDeque<PacketFragment>,
     next_sequence: u16,
     stats: FragmentStats,
+    rng: StdRng,
+    /// Inter-fragment delays fixed at their minimum
+    deterministic: bool,
 }
 
 #[derive(Debug, Default)]
@@ -156,17 +169,41 @@ impl PacketFragmenter {
             MobileFragmentPattern::Carrier(carrier) => {
                 carrier.get_mtu_characteristics().1
             },
+            MobileFragmentPattern::MtuLike { mtu, jitter } => {
+                let max_payload = (*mtu as usize).saturating_sub(IP_TCP_HEADER_LEN).max(1);
+                FragmentConfig {
+                    min_fragment_size: max_payload.saturating_sub(*jitter as usize).max(1),
+                    max_fragment_size: max_payload,
+                    fragment_delay_ms: Range { start: 0, end: 2 },
+                    randomize_order: false,
+                    duplicate_fragments: false,
+                    overlap_fragments: false,
+                }
+            },
         };
         
+        let mut rng = crate::determinism::rng();
         Self {
             config,
             pattern,
             fragment_queue: VecDeque::new(),
-            next_sequence: rand::thread_rng().gen(),
+            next_sequence: rng.gen(),
             stats: FragmentStats::default(),
+            rng,
+            deterministic: crate::determinism::is_deterministic(),
         }
     }
     
+    /// Reproducible fragmenter regardless of `LITEBIKE_DETERMINISTIC`: sizes,
+    /// order, overlaps and sequence numbers come from the fixed seed and
+    /// every delay is the configured minimum
+    pub fn deterministic(mut self) -> Self {
+        self.rng = crate::determinism::seeded_rng();
+        self.next_sequence = self.rng.gen();
+        self.deterministic = true;
+        self
+    }
+    
     /// Fragment data packet using mobile-specific patterns
     pub fn fragment_packet(&mut self, data: &[u8]) -> Vec<PacketFragment> {
         if data.is_empty() {
@@ -212,7 +249,7 @@ impl PacketFragmenter {
             if len > 2 {
                 let mut middle: Vec<_> = fragments.drain(1..len-1).collect();
                 use rand::seq::SliceRandom;
-                middle.shuffle(&mut rand::thread_rng());
+                middle.shuffle(&mut self.rng);
                 
                 let last = fragments.pop().unwrap();
                 fragments.extend(middle);
@@ -232,8 +269,8 @@ impl PacketFragmenter {
     }
     
     /// Calculate optimal fragment size based on pattern
-    fn calculate_fragment_size(&self, remaining: usize) -> usize {
-        let mut rng = rand::thread_rng();
+    fn calculate_fragment_size(&mut self, remaining: usize) -> usize {
+        let rng = &mut self.rng;
         
         match &self.pattern {
             MobileFragmentPattern::Conservative => {
@@ -258,17 +295,22 @@ impl PacketFragmenter {
             MobileFragmentPattern::Carrier(carrier) => {
                 // Carrier-specific behavior
                 let (mtu, _) = carrier.get_mtu_characteristics();
-                let max_payload = (mtu as usize).saturating_sub(40); // Account for headers
+                let max_payload = (mtu as usize).saturating_sub(IP_TCP_HEADER_LEN);
                 
                 std::cmp::min(remaining, max_payload)
             },
+            MobileFragmentPattern::MtuLike { .. } => {
+                // Minimum of two uniform draws weights the cut toward zero
+                let spread = self.config.max_fragment_size.saturating_sub(self.config.min_fragment_size);
+                let cut = std::cmp::min(rng.gen_range(0..=spread), rng.gen_range(0..=spread));
+                
+                std::cmp::min(remaining, self.config.max_fragment_size - cut)
+            },
         }
     }
     
     /// Adaptive fragment sizing based on detection risk
     fn adaptive_fragment_size(&self, remaining: usize) -> usize {
-        let rng = rand::thread_rng();
-        
         // Simple heuristic: vary size based on time and remaining data
         let time_factor = (Instant::now().elapsed().as_millis() % 1000) as f64 / 1000.0;
         let size_factor = if remaining > 10000 { 0.8 } else { 1.2 };
@@ -286,12 +328,12 @@ impl PacketFragmenter {
     }
     
     /// Add overlapping fragments for advanced DPI evasion
-    fn add_overlapping_fragments(&self, fragments: &mut Vec<PacketFragment>, original_data: &[u8]) {
+    fn add_overlapping_fragments(&mut self, fragments: &mut Vec<PacketFragment>, original_data: &[u8]) {
         if fragments.len() < 2 {
             return;
         }
         
-        let mut rng = rand::thread_rng();
+        let rng = &mut self.rng;
         let overlap_count = rng.gen_range(1..=2);
         
         for _ in 0..overlap_count {
@@ -332,9 +374,11 @@ impl PacketFragmenter {
         
         for fragment in fragments {
             // Apply inter-fragment delay
-            let delay_ms = rand::thread_rng().gen_range(
-                self.config.fragment_delay_ms.start..=self.config.fragment_delay_ms.end
-            );
+            let delay_ms = if self.deterministic {
+                self.config.fragment_delay_ms.start
+            } else {
+                self.rng.gen_range(self.config.fragment_delay_ms.start..=self.config.fragment_delay_ms.end)
+            };
             
             if delay_ms > 0 {
                 sleep(Duration::from_millis(delay_ms)).await;
@@ -352,15 +396,16 @@ impl PacketFragmenter {
     }
     
     /// Determine if fragment should be flushed immediately
-    fn should_flush_fragment(&self) -> bool {
+    fn should_flush_fragment(&mut self) -> bool {
         match &self.pattern {
             MobileFragmentPattern::Conservative => false, // Batch for efficiency
             MobileFragmentPattern::Aggressive => true,   // Immediate send for evasion
             MobileFragmentPattern::Adaptive => {
                 // Probabilistic flushing
-                rand::thread_rng().gen_bool(0.3)
+                self.rng.gen_bool(0.3)
             },
             MobileFragmentPattern::Carrier(_) => true,   // Carrier-specific immediate send
+            MobileFragmentPattern::MtuLike { .. } => true, // One write per segment, as on the wire
         }
     }
     
@@ -554,6 +599,20 @@ mod tests {
         // This is expected behavior for DPI evasion
     }
     
+    #[test]
+    fn test_mtu_like_fragments_cluster_near_mtu() {
+        let mut fragmenter = PacketFragmenter::new(FragmentStrategy::MtuLike { mtu: 1440, jitter: 64 });
+        let data = vec![0u8; 200_000];
+        let fragments = fragmenter.fragment_packet(&data);
+        
+        let full: Vec<usize> = fragments[..fragments.len() - 1].iter().map(|f| f.data.len()).collect();
+        assert!(full.iter().all(|&len| (1336..=1400).contains(&len)));
+        // Weighted jitter keeps most segments in the upper half of the range
+        let mean = full.iter().sum::<usize>() as f64 / full.len() as f64;
+        assert!(mean > 1368.0, "mean fragment size {}", mean);
+        assert_eq!(fragments.iter().map(|f| f.data.len()).sum::<usize>(), data.len());
+    }
+    
     #[test]
     fn test_carrier_specific_mtu() {
         let verizon_fragmenter = PacketFragmenter::new(
@@ -0,0 +1,158 @@
+// QUIC reachability with TCP fallback
+// Hotspots often drop UDP outright, so QUIC egress is probed with a short
+// deadline before committing to it
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use log::{debug, info};
+use tokio::net::UdpSocket;
+
+/// How long the QUIC probe may wait for the server before falling back
+pub const QUIC_HANDSHAKE_DEADLINE: Duration = Duration::from_millis(300);
+
+/// Minimum datagram size for a client's first flight (RFC 9000 §14.1)
+const MIN_INITIAL_SIZE: usize = 1200;
+
+/// Reserved version that forces a Version Negotiation reply (RFC 9000 §15)
+const PROBE_VERSION: u32 = 0x1a2a_3a4a;
+
+/// Which transport the connection ended up on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EgressPath {
+    Quic,
+    TcpFallback,
+}
+
+/// Result of `connect_or_fallback`: a connected UDP socket the QUIC server
+/// answered on, or whatever the TCP fallback produced
+#[derive(Debug)]
+pub enum QuicOrTcp<T> {
+    Quic(UdpSocket),
+    Tcp(T),
+}
+
+impl<T> QuicOrTcp<T> {
+    pub fn path(&self) -> EgressPath {
+        match self {
+            QuicOrTcp::Quic(_) => EgressPath::Quic,
+            QuicOrTcp::Tcp(_) => EgressPath::TcpFallback,
+        }
+    }
+}
+
+/// Long-header probe with a reserved version, padded to a full Initial
+fn build_probe_packet() -> Vec<u8> {
+    let mut packet = Vec::with_capacity(MIN_INITIAL_SIZE);
+    packet.push(0xc0 | (rand::random::<u8>() & 0x0f));
+    packet.extend_from_slice(&PROBE_VERSION.to_be_bytes());
+    // destination and source connection ids, 8 bytes each
+    packet.push(8);
+    packet.extend_from_slice(&rand::random::<[u8; 8]>());
+    packet.push(8);
+    packet.extend_from_slice(&rand::random::<[u8; 8]>());
+    packet.resize(MIN_INITIAL_SIZE, 0);
+    packet
+}
+
+/// A Version Negotiation packet: long header with version 0
+fn is_version_negotiation(packet: &[u8]) -> bool {
+    packet.len() >= 5 && packet[0] & 0x80 != 0 && packet[1..5] == [0, 0, 0, 0]
+}
+
+/// Probe `addr` for a QUIC server, waiting at most `deadline` for its answer
+async fn probe_quic(addr: SocketAddr, deadline: Duration) -> io::Result<UdpSocket> {
+    let local: SocketAddr = if addr.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    socket.send(&build_probe_packet()).await?;
+
+    let mut buf = [0u8; 1500];
+    let wait = async {
+        loop {
+            let n = socket.recv(&mut buf).await?;
+            if is_version_negotiation(&buf[..n]) {
+                return Ok(());
+            }
+        }
+    };
+    match tokio::time::timeout(deadline, wait).await {
+        Ok(Ok(())) => Ok(socket),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "no QUIC response before deadline")),
+    }
+}
+
+/// Try QUIC to `addr`, falling back to `tcp_fallback` if UDP looks blocked
+pub async fn connect_or_fallback<F, Fut, T>(server_name: &str, addr: SocketAddr, tcp_fallback: F) -> io::Result<QuicOrTcp<T>>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    connect_or_fallback_with_deadline(server_name, addr, QUIC_HANDSHAKE_DEADLINE, tcp_fallback).await
+}
+
+/// `connect_or_fallback` with an explicit probe deadline
+pub async fn connect_or_fallback_with_deadline<F, Fut, T>(
+    server_name: &str,
+    addr: SocketAddr,
+    deadline: Duration,
+    tcp_fallback: F,
+) -> io::Result<QuicOrTcp<T>>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    match probe_quic(addr, deadline).await {
+        Ok(socket) => {
+            info!("QUIC path to {} ({}) is open", server_name, addr);
+            Ok(QuicOrTcp::Quic(socket))
+        }
+        Err(e) => {
+            debug!("QUIC probe to {} ({}) failed: {}", server_name, addr, e);
+            info!("Falling back to TCP for {}", server_name);
+            tcp_fallback().await.map(QuicOrTcp::Tcp)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_blocked_udp_invokes_tcp_fallback() {
+        // Swallows datagrams without answering, like a hotspot dropping UDP
+        let blackhole = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = blackhole.local_addr().unwrap();
+
+        let result = connect_or_fallback_with_deadline("example.com", addr, Duration::from_millis(50), || async {
+            Ok::<_, io::Error>("tcp")
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(result.path(), EgressPath::TcpFallback);
+        assert!(matches!(result, QuicOrTcp::Tcp("tcp")));
+    }
+
+    #[tokio::test]
+    async fn test_version_negotiation_keeps_quic() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            let (n, from) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(n, MIN_INITIAL_SIZE);
+            server.send_to(&[0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1], from).await.unwrap();
+        });
+
+        let result = connect_or_fallback("example.com", addr, || async {
+            Err::<(), _>(io::Error::other("fallback should not run"))
+        })
+        .await
+        .unwrap();
+        assert_eq!(result.path(), EgressPath::Quic);
+    }
+}
@@ -1,3 +1,5 @@
 pub mod quic_protocol;
+pub mod fallback;
 
 pub use quic_protocol::encode_varint;
+pub use fallback::{connect_or_fallback, EgressPath, QuicOrTcp};
@@ -0,0 +1,241 @@
+// Client quotas - per source IP caps for shared hotspots
+// Concurrent connections are checked on accept, bytes while relaying
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// Limits applied to every non-loopback client IP; `None` means unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaConfig {
+    pub max_concurrent: Option<usize>,
+    /// Bytes relayed in either direction per UTC day
+    pub daily_byte_budget: Option<u64>,
+}
+
+/// Why a client was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaRefusal {
+    TooManyConnections { limit: usize },
+    DailyBytesExhausted { budget: u64 },
+}
+
+impl fmt::Display for QuotaRefusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaRefusal::TooManyConnections { limit } => write!(f, "client quota: more than {} concurrent connections", limit),
+            QuotaRefusal::DailyBytesExhausted { budget } => write!(f, "client quota: daily budget of {} bytes used up", budget),
+        }
+    }
+}
+
+impl std::error::Error for QuotaRefusal {}
+
+/// One client's usage
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub concurrent: usize,
+    pub bytes_today: u64,
+}
+
+#[derive(Debug, Default)]
+struct ClientEntry {
+    usage: QuotaUsage,
+    day: u64,
+}
+
+impl ClientEntry {
+    /// Nothing left to enforce: no open connections and no bytes that
+    /// still count against today's budget
+    fn is_idle(&self, config: &QuotaConfig, today: u64) -> bool {
+        self.usage.concurrent == 0
+            && (config.daily_byte_budget.is_none() || self.day != today || self.usage.bytes_today == 0)
+    }
+}
+
+#[derive(Debug, Default)]
+struct ClientTable {
+    entries: HashMap<IpAddr, ClientEntry>,
+    /// Day of the last sweep for entries left over from earlier days
+    swept_day: u64,
+}
+
+/// Usage per client IP, shared by every connection task. Entries go away
+/// once a client is idle, so the table only holds recent clients.
+#[derive(Debug)]
+pub struct QuotaTracker {
+    config: QuotaConfig,
+    clients: Mutex<ClientTable>,
+}
+
+impl QuotaTracker {
+    pub fn new(config: QuotaConfig) -> Self {
+        Self { config, clients: Mutex::new(ClientTable::default()) }
+    }
+
+    pub fn config(&self) -> QuotaConfig {
+        self.config
+    }
+
+    /// Admit a new connection from `ip`. The slot is held until the
+    /// returned guard drops. Loopback clients are never limited.
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Result<QuotaGuard, QuotaRefusal> {
+        if ip.is_loopback() {
+            return Ok(QuotaGuard { tracker: self.clone(), ip: None });
+        }
+        let mut clients = self.clients.lock().unwrap();
+        let entry = self.entry(&mut clients, ip);
+        if let Some(budget) = self.config.daily_byte_budget {
+            if entry.usage.bytes_today >= budget {
+                return Err(QuotaRefusal::DailyBytesExhausted { budget });
+            }
+        }
+        if let Some(limit) = self.config.max_concurrent {
+            if entry.usage.concurrent >= limit {
+                return Err(QuotaRefusal::TooManyConnections { limit });
+            }
+        }
+        entry.usage.concurrent += 1;
+        Ok(QuotaGuard { tracker: self.clone(), ip: Some(ip) })
+    }
+
+    /// Charge `bytes` relayed for `ip`; fails once the daily budget is spent
+    pub fn record_bytes(&self, ip: IpAddr, bytes: u64) -> Result<(), QuotaRefusal> {
+        if ip.is_loopback() {
+            return Ok(());
+        }
+        let mut clients = self.clients.lock().unwrap();
+        let entry = self.entry(&mut clients, ip);
+        entry.usage.bytes_today = entry.usage.bytes_today.saturating_add(bytes);
+        match self.config.daily_byte_budget {
+            Some(budget) if entry.usage.bytes_today > budget => Err(QuotaRefusal::DailyBytesExhausted { budget }),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn usage(&self, ip: IpAddr) -> QuotaUsage {
+        let clients = self.clients.lock().unwrap();
+        match clients.entries.get(&ip) {
+            Some(entry) if entry.day == today() => entry.usage,
+            Some(entry) => QuotaUsage { concurrent: entry.usage.concurrent, bytes_today: 0 },
+            None => QuotaUsage::default(),
+        }
+    }
+
+    /// Clients currently tracked
+    pub fn tracked_clients(&self) -> usize {
+        self.clients.lock().unwrap().entries.len()
+    }
+
+    /// The client's entry, with the byte count reset if a new day started.
+    /// The first call on a new day also drops idle entries from earlier days.
+    fn entry<'a>(&self, clients: &'a mut ClientTable, ip: IpAddr) -> &'a mut ClientEntry {
+        let today = today();
+        if clients.swept_day != today {
+            clients.swept_day = today;
+            clients.entries.retain(|_, entry| !entry.is_idle(&self.config, today));
+        }
+        let entry = clients.entries.entry(ip).or_default();
+        if entry.day != today {
+            entry.day = today;
+            entry.usage.bytes_today = 0;
+        }
+        entry
+    }
+
+    /// Give back one connection slot, forgetting the client once idle
+    fn release(&self, ip: IpAddr) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(entry) = clients.entries.get_mut(&ip) {
+            entry.usage.concurrent = entry.usage.concurrent.saturating_sub(1);
+            if entry.is_idle(&self.config, today()) {
+                clients.entries.remove(&ip);
+            }
+        }
+    }
+}
+
+fn today() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / SECONDS_PER_DAY
+}
+
+/// Holds one concurrent-connection slot
+#[derive(Debug)]
+pub struct QuotaGuard {
+    tracker: Arc<QuotaTracker>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for QuotaGuard {
+    fn drop(&mut self) {
+        if let Some(ip) = self.ip {
+            self.tracker.release(ip);
+        }
+    }
+}
+
+/// A tracker bound to one client, as carried by a relay
+#[derive(Debug, Clone)]
+pub struct ClientQuota {
+    pub tracker: Arc<QuotaTracker>,
+    pub ip: IpAddr,
+}
+
+impl ClientQuota {
+    pub fn record(&self, bytes: usize) -> Result<(), QuotaRefusal> {
+        self.tracker.record_bytes(self.ip, bytes as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrent_cap_refuses_until_slot_frees() {
+        let tracker = Arc::new(QuotaTracker::new(QuotaConfig { max_concurrent: Some(2), daily_byte_budget: None }));
+        let client: IpAddr = "192.168.43.20".parse().unwrap();
+
+        let first = tracker.acquire(client).unwrap();
+        let _second = tracker.acquire(client).unwrap();
+        assert_eq!(tracker.acquire(client).unwrap_err(), QuotaRefusal::TooManyConnections { limit: 2 });
+        // Other clients and loopback have their own allowance
+        let _other = tracker.acquire("192.168.43.21".parse().unwrap()).unwrap();
+        let _local: Vec<_> = (0..5).map(|_| tracker.acquire("127.0.0.1".parse().unwrap()).unwrap()).collect();
+
+        drop(first);
+        assert_eq!(tracker.usage(client).concurrent, 1);
+        assert!(tracker.acquire(client).is_ok());
+    }
+
+    #[test]
+    fn test_idle_clients_are_forgotten() {
+        let tracker = Arc::new(QuotaTracker::new(QuotaConfig { max_concurrent: Some(4), daily_byte_budget: Some(1000) }));
+        let quiet: IpAddr = "192.168.43.30".parse().unwrap();
+        let busy: IpAddr = "192.168.43.31".parse().unwrap();
+
+        drop(tracker.acquire(quiet).unwrap());
+        assert_eq!(tracker.tracked_clients(), 0);
+
+        // Bytes spent today outlive the connection, or reconnecting would reset the budget
+        let guard = tracker.acquire(busy).unwrap();
+        tracker.record_bytes(busy, 600).unwrap();
+        drop(guard);
+        assert_eq!(tracker.tracked_clients(), 1);
+        assert_eq!(tracker.usage(busy).bytes_today, 600);
+
+        // Once that day is over the next caller sweeps the entry away
+        {
+            let mut clients = tracker.clients.lock().unwrap();
+            clients.entries.get_mut(&busy).unwrap().day -= 1;
+            clients.swept_day -= 1;
+        }
+        assert_eq!(tracker.usage(busy), QuotaUsage::default());
+        drop(tracker.acquire(quiet).unwrap());
+        assert_eq!(tracker.tracked_clients(), 0);
+    }
+}
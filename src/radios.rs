@@ -1,7 +1,7 @@
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 
-use crate::syscall_net::{classify_ipv4, classify_ipv6, list_interfaces, InterfaceAddr};
+use crate::syscall_net::{classify_ipv4, classify_ipv6, list_interfaces, Interface, InterfaceAddr};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RadioIface {
@@ -32,6 +32,62 @@ fn domain_for_name(name: &str) -> &'static str {
     }
 }
 
+/// Names Android and hostapd give to access-point interfaces: `swlanN`,
+/// `apN`, the `ap_br_*` bridge and `wlanN_ap`. A bare `ap` prefix would also
+/// take in names like `apcli0` (a repeater's uplink).
+fn ap_name_hint(name: &str) -> bool {
+    indexed_name(name, "swlan")
+        || indexed_name(name, "ap")
+        || name.starts_with("ap_br_")
+        || name.strip_suffix("_ap").is_some_and(|n| indexed_name(n, "wlan"))
+}
+
+/// `name` is `prefix` followed by an interface index
+fn indexed_name(name: &str, prefix: &str) -> bool {
+    name.strip_prefix(prefix)
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Whether `iface` is a tethering/AP (downstream) interface rather than an uplink
+pub fn is_ap_interface(iface: &str) -> bool {
+    match list_interfaces() {
+        Ok(interfaces) => is_ap_interface_in(iface, &interfaces),
+        Err(_) => ap_name_hint(iface),
+    }
+}
+
+/// Names of the AP interfaces that are currently up
+pub fn active_ap_interfaces() -> Vec<String> {
+    let Ok(interfaces) = list_interfaces() else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = interfaces
+        .iter()
+        .filter(|(name, info)| info.flags & libc::IFF_UP as u32 != 0 && is_ap_interface_in(name, &interfaces))
+        .map(|(name, _)| name.clone())
+        .collect();
+    names.sort();
+    names
+}
+
+/// `is_ap_interface` against a given interface table. Loopback and
+/// point-to-point links (cellular, VPN) are never APs; otherwise the name
+/// decides, and a wifi interface holding a private `x.y.z.1` address (the
+/// hotspot gateway convention) also counts.
+pub fn is_ap_interface_in(iface: &str, interfaces: &HashMap<String, Interface>) -> bool {
+    let Some(info) = interfaces.get(iface) else {
+        return ap_name_hint(iface);
+    };
+    if info.flags & (libc::IFF_LOOPBACK | libc::IFF_POINTOPOINT) as u32 != 0 {
+        return false;
+    }
+    if ap_name_hint(iface) {
+        return true;
+    }
+    domain_for_name(iface) == "wifi"
+        && info.addrs.iter().any(|a| matches!(a, InterfaceAddr::V4(ip) if ip.is_private() && ip.octets()[3] == 1))
+}
+
 pub fn gather_radios() -> RadiosReport {
     let mut ifs = Vec::new();
     if let Ok(map) = list_interfaces() {
@@ -314,3 +370,43 @@ pub fn parse_ifconfig_like(text: &str) -> RadiosReport {
     }
     RadiosReport { interfaces: list, android_props: HashMap::new() }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn iface(name: &str, flags: i32, ip: Ipv4Addr) -> (String, Interface) {
+        let info = Interface {
+            name: name.to_string(),
+            index: 0,
+            flags: (libc::IFF_UP | flags) as u32,
+            addrs: vec![InterfaceAddr::V4(ip)],
+        };
+        (name.to_string(), info)
+    }
+
+    #[test]
+    fn test_ap_interface_classification() {
+        let interfaces: HashMap<String, Interface> = [
+            iface("swlan0", libc::IFF_BROADCAST, Ipv4Addr::new(192, 168, 43, 1)),
+            iface("rmnet0", libc::IFF_POINTOPOINT, Ipv4Addr::new(100, 64, 12, 7)),
+            iface("wlan0", libc::IFF_BROADCAST, Ipv4Addr::new(192, 168, 1, 23)),
+            iface("wlan1", libc::IFF_BROADCAST, Ipv4Addr::new(192, 168, 49, 1)),
+        ]
+        .into_iter()
+        .collect();
+
+        assert!(is_ap_interface_in("swlan0", &interfaces));
+        assert!(!is_ap_interface_in("rmnet0", &interfaces));
+        assert!(!is_ap_interface_in("wlan0", &interfaces));
+        assert!(is_ap_interface_in("wlan1", &interfaces));
+        assert!(is_ap_interface_in("wlan0_ap", &interfaces));
+        assert!(is_ap_interface_in("ap0", &interfaces));
+        assert!(is_ap_interface_in("ap_br_wlan2", &interfaces));
+        // Only exact AP names, not anything that happens to start with "ap"
+        for name in ["apcli0", "apple0", "ap", "swlan", "wlan_ap", "swlan0x"] {
+            assert!(!is_ap_interface_in(name, &interfaces), "{}", name);
+        }
+    }
+}
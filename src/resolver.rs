@@ -0,0 +1,193 @@
+// Resolver - short-lived cache in front of the system resolver
+// Lets startup warmup pay DNS latency before the first client does. The
+// system resolver does not report record TTLs, so every entry lives for the
+// one configured TTL; keep it short for hosts whose records change quickly.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use log::{debug, info, warn};
+
+/// How long a resolution is reused when no TTL is given
+pub const DEFAULT_RESOLVE_TTL: Duration = Duration::from_secs(60);
+
+/// Targets the global cache holds before evicting
+pub const DEFAULT_RESOLVE_CAPACITY: usize = 1024;
+
+/// Port assumed for warmup hosts given without one
+const WARMUP_DEFAULT_PORT: u16 = 443;
+
+/// "host:port" to resolved addresses, each entry expiring after `ttl`.
+/// Holds at most `capacity` targets: a full cache drops expired entries,
+/// then the oldest.
+#[derive(Debug)]
+pub struct ResolveCache {
+    state: Mutex<CacheState>,
+}
+
+#[derive(Debug)]
+struct CacheState {
+    ttl: Duration,
+    capacity: usize,
+    entries: HashMap<String, (Instant, Vec<SocketAddr>)>,
+}
+
+impl CacheState {
+    /// Make room for one more entry
+    fn evict(&mut self) {
+        if self.entries.len() < self.capacity {
+            return;
+        }
+        let ttl = self.ttl;
+        self.entries.retain(|_, (resolved_at, _)| resolved_at.elapsed() < ttl);
+        while self.entries.len() >= self.capacity.max(1) {
+            let Some(oldest) = self.entries.iter().min_by_key(|(_, (at, _))| *at).map(|(target, _)| target.clone()) else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+impl ResolveCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self::with_capacity(ttl, DEFAULT_RESOLVE_CAPACITY)
+    }
+
+    pub fn with_capacity(ttl: Duration, capacity: usize) -> Self {
+        Self { state: Mutex::new(CacheState { ttl, capacity, entries: HashMap::new() }) }
+    }
+
+    /// Process-wide cache used by `knox_proxy::connect_to_target`
+    pub fn global() -> &'static ResolveCache {
+        static GLOBAL: OnceLock<ResolveCache> = OnceLock::new();
+        GLOBAL.get_or_init(|| ResolveCache::new(DEFAULT_RESOLVE_TTL))
+    }
+
+    /// Reuse resolutions for `ttl` from now on; entries already cached
+    /// are judged by the new TTL too
+    pub fn set_ttl(&self, ttl: Duration) {
+        self.state.lock().unwrap().ttl = ttl;
+    }
+
+    /// Targets currently held, expired or not
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Unexpired addresses for `target`, without touching the resolver
+    pub fn cached(&self, target: &str) -> Option<Vec<SocketAddr>> {
+        let state = self.state.lock().unwrap();
+        state
+            .entries
+            .get(target)
+            .filter(|(resolved_at, _)| resolved_at.elapsed() < state.ttl)
+            .map(|(_, addrs)| addrs.clone())
+    }
+
+    /// Addresses for `target` ("host:port"), from the cache when fresh
+    pub async fn resolve(&self, target: &str) -> io::Result<Vec<SocketAddr>> {
+        if let Some(addrs) = self.cached(target) {
+            debug!("Resolved {} from cache", target);
+            return Ok(addrs);
+        }
+        // Resolver failures surface as NotFound so callers can tell them
+        // from dial errors; a malformed target keeps its InvalidInput
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host(target)
+            .await
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::InvalidInput => e,
+                _ => io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve: {}", target, e)),
+            })?
+            .collect();
+        if !addrs.is_empty() {
+            let mut state = self.state.lock().unwrap();
+            if !state.entries.contains_key(target) {
+                state.evict();
+            }
+            state.entries.insert(target.to_string(), (Instant::now(), addrs.clone()));
+        }
+        Ok(addrs)
+    }
+
+    /// Resolve every host up front. Entries without a port get 443.
+    /// Failures are logged and skipped; returns how many resolved.
+    pub async fn warmup(&self, hosts: &[String]) -> usize {
+        let mut resolved = 0;
+        for host in hosts {
+            let target = warmup_target(host);
+            match self.resolve(&target).await {
+                Ok(addrs) if !addrs.is_empty() => {
+                    debug!("Warmed {} -> {:?}", target, addrs);
+                    resolved += 1;
+                }
+                Ok(_) => warn!("⚠ Warmup: {} did not resolve", target),
+                Err(e) => warn!("⚠ Warmup: {} failed: {}", target, e),
+            }
+        }
+        info!("🔥 Warmed {}/{} upstream hosts", resolved, hosts.len());
+        resolved
+    }
+}
+
+fn warmup_target(host: &str) -> String {
+    let has_port = match host.rsplit_once(':') {
+        Some((name, port)) => port.parse::<u16>().is_ok() && (!name.contains(':') || name.ends_with(']')),
+        None => false,
+    };
+    if has_port {
+        host.to_string()
+    } else if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, WARMUP_DEFAULT_PORT)
+    } else {
+        format!("{}:{}", host, WARMUP_DEFAULT_PORT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_warmup_serves_resolve_from_cache() {
+        let cache = ResolveCache::new(DEFAULT_RESOLVE_TTL);
+        assert_eq!(cache.cached("localhost:443"), None);
+
+        let warmed = cache.warmup(&["localhost".to_string(), "127.0.0.1:8443".to_string()]).await;
+        assert_eq!(warmed, 2);
+
+        let cached = cache.cached("localhost:443").expect("warmed host should be cached");
+        assert!(!cached.is_empty());
+        assert_eq!(cache.resolve("localhost:443").await.unwrap(), cached);
+        assert_eq!(cache.cached("127.0.0.1:8443"), Some(vec!["127.0.0.1:8443".parse().unwrap()]));
+
+        cache.set_ttl(Duration::ZERO);
+        assert_eq!(cache.cached("127.0.0.1:8443"), None);
+    }
+
+    #[tokio::test]
+    async fn test_full_cache_evicts_expired_then_oldest() {
+        let cache = ResolveCache::with_capacity(DEFAULT_RESOLVE_TTL, 2);
+        for port in 1..=3 {
+            cache.resolve(&format!("127.0.0.1:{}", port)).await.unwrap();
+        }
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.cached("127.0.0.1:1"), None);
+        assert!(cache.cached("127.0.0.1:3").is_some());
+
+        // Re-resolving a held target replaces it without evicting another
+        cache.resolve("127.0.0.1:2").await.unwrap();
+        assert!(cache.cached("127.0.0.1:3").is_some());
+
+        // Expired entries go before fresh ones
+        cache.set_ttl(Duration::ZERO);
+        cache.resolve("127.0.0.1:4").await.unwrap();
+        assert_eq!(cache.len(), 1);
+    }
+}
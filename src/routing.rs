@@ -0,0 +1,149 @@
+// Destination routing - pick a channel for a target by its domain name
+// Consulted after the target is parsed; the first matching rule wins and a
+// target no rule covers goes out the default egress. Rules only look at the
+// destination, so they sit beside the client ACLs rather than inside them.
+//
+// Patterns, compared case-insensitively without a trailing dot:
+//   *              any host
+//   *.example.com  subdomains of example.com, not example.com itself
+//   example.com    example.com and its subdomains
+
+use std::fmt;
+use std::slice;
+use std::str::FromStr;
+
+use crate::knox_proxy::host_listed;
+
+/// One `pattern=channel` rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomainRule {
+    pub pattern: String,
+    pub channel: String,
+}
+
+impl DomainRule {
+    pub fn new(pattern: &str, channel: &str) -> Self {
+        Self {
+            pattern: pattern.trim().trim_end_matches('.').to_ascii_lowercase(),
+            channel: channel.trim().to_string(),
+        }
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        if self.pattern == "*" {
+            return true;
+        }
+        match self.pattern.strip_prefix("*.") {
+            Some(suffix) => {
+                let host = host.trim_start_matches('[').trim_end_matches(']').trim_end_matches('.');
+                !host.eq_ignore_ascii_case(suffix) && host_listed(&[suffix.to_string()], host)
+            }
+            None => host_listed(slice::from_ref(&self.pattern), host),
+        }
+    }
+}
+
+impl FromStr for DomainRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((pattern, channel)) if !pattern.trim().is_empty() && !channel.trim().is_empty() => {
+                Ok(Self::new(pattern, channel))
+            }
+            _ => Err(format!("invalid route {:?}, expected pattern=channel", s)),
+        }
+    }
+}
+
+impl fmt::Display for DomainRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.pattern, self.channel)
+    }
+}
+
+/// Ordered domain rules
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoutingTable {
+    rules: Vec<DomainRule>,
+}
+
+impl RoutingTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a rule; earlier rules take precedence
+    pub fn with_rule(mut self, pattern: &str, channel: &str) -> Self {
+        self.rules.push(DomainRule::new(pattern, channel));
+        self
+    }
+
+    pub fn push(&mut self, rule: DomainRule) {
+        self.rules.push(rule);
+    }
+
+    pub fn rules(&self) -> &[DomainRule] {
+        &self.rules
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Channel for `host`, or `None` for the default egress
+    pub fn route(&self, host: &str) -> Option<&str> {
+        self.rules.iter().find(|rule| rule.matches(host)).map(|rule| rule.channel.as_str())
+    }
+
+    /// Channel for a "host:port" target
+    pub fn route_target(&self, target: &str) -> Option<&str> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        let host = target.rsplit_once(':').map_or(target, |(host, _)| host);
+        self.route(host)
+    }
+}
+
+impl FromStr for RoutingTable {
+    type Err = String;
+
+    /// Comma-separated rules
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rules = s
+            .split(',')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suffix_rules_and_default_fallthrough() {
+        let table: RoutingTable = "*.cdn.example=wifi, example.com=rmnet_data0".parse().unwrap();
+
+        assert_eq!(table.route("example.com"), Some("rmnet_data0"));
+        assert_eq!(table.route("API.Example.com."), Some("rmnet_data0"));
+        assert_eq!(table.route_target("www.example.com:443"), Some("rmnet_data0"));
+        assert_eq!(table.route("img.cdn.example"), Some("wifi"));
+        // A wildcard needs at least one more label; a suffix needs a dot boundary
+        assert_eq!(table.route("cdn.example"), None);
+        assert_eq!(table.route("notexample.com"), None);
+        // Anything unmatched takes the default egress
+        assert_eq!(table.route_target("[2001:db8::1]:443"), None);
+        assert_eq!(table.route_target("other.org:80"), None);
+
+        let table = table.with_rule("*", "wlan0");
+        assert_eq!(table.route("other.org"), Some("wlan0"));
+        assert_eq!(table.route("example.com"), Some("rmnet_data0"));
+        assert_eq!(table.rules()[0].to_string(), "*.cdn.example=wifi");
+        assert!("example.com".parse::<RoutingTable>().is_err());
+    }
+}
@@ -0,0 +1,277 @@
+// Self-test - end to end diagnostics behind `litebike selftest`
+// Runs the real proxy handlers against an in-process instance on loopback,
+// then checks the pieces of the environment LiteBike depends on
+
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::capabilities::{implemented_protocols, Impl};
+use crate::config::Config;
+use crate::knox_proxy::{KnoxProxy, KnoxProxyConfig, DISPATCHED_PROTOCOLS};
+
+/// Upper bound for each network round trip
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+const ECHO_PAYLOAD: &[u8] = b"litebike-selftest";
+
+/// Which checks to run
+#[derive(Debug, Clone)]
+pub struct SelftestOptions {
+    /// Ports LiteBike is configured to listen on; by default the port from
+    /// `Config::from_env` (`LITEBIKE_BIND_PORT`)
+    pub bind_ports: Vec<u16>,
+    pub probe_gateway: bool,
+    /// SSDP discovery window; `None` skips discovery
+    pub ssdp_timeout: Option<Duration>,
+}
+
+impl Default for SelftestOptions {
+    fn default() -> Self {
+        Self {
+            bind_ports: vec![Config::from_env().bind_port],
+            probe_gateway: true,
+            ssdp_timeout: Some(Duration::from_secs(2)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckOutcome {
+    Pass(String),
+    Fail(String),
+    Skipped(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub name: String,
+    pub outcome: CheckOutcome,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SelftestReport {
+    pub checks: Vec<CheckResult>,
+}
+
+impl SelftestReport {
+    /// True when no check failed; skipped checks do not count against it
+    pub fn passed(&self) -> bool {
+        !self.checks.iter().any(|c| matches!(c.outcome, CheckOutcome::Fail(_)))
+    }
+
+    fn push(&mut self, name: impl Into<String>, result: io::Result<String>) {
+        let outcome = match result {
+            Ok(detail) => CheckOutcome::Pass(detail),
+            Err(e) => CheckOutcome::Fail(e.to_string()),
+        };
+        self.checks.push(CheckResult { name: name.into(), outcome });
+    }
+
+    fn skip(&mut self, name: &str, why: &str) {
+        self.checks.push(CheckResult { name: name.to_string(), outcome: CheckOutcome::Skipped(why.to_string()) });
+    }
+}
+
+impl fmt::Display for SelftestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            match &check.outcome {
+                CheckOutcome::Pass(detail) => writeln!(f, "✅ {:<14} {}", check.name, detail)?,
+                CheckOutcome::Fail(detail) => writeln!(f, "❌ {:<14} {}", check.name, detail)?,
+                CheckOutcome::Skipped(detail) => writeln!(f, "⏭  {:<14} {}", check.name, detail)?,
+            }
+        }
+        write!(f, "{}", if self.passed() { "PASS" } else { "FAIL" })
+    }
+}
+
+/// Run every enabled check and collect the results
+pub async fn run_selftest(options: &SelftestOptions) -> SelftestReport {
+    let mut report = SelftestReport::default();
+
+    for &port in &options.bind_ports {
+        report.push(format!("bind:{}", port), check_bind(port));
+    }
+
+    match start_instance().await {
+        Ok((proxy_addr, instance)) => {
+            report.push("instance", Ok(format!("proxy listening on {}", proxy_addr)));
+            report.push("socks5", with_timeout(socks5_round_trip(proxy_addr)).await);
+            report.push("http", with_timeout(http_round_trip(proxy_addr)).await);
+            instance.abort();
+        }
+        Err(e) => report.push("instance", Err(e)),
+    }
+
+    for (protocol, status) in implemented_protocols(DISPATCHED_PROTOCOLS) {
+        if status == Impl::Stub {
+            report.skip(&protocol.to_string().to_ascii_lowercase(), "not implemented");
+        }
+    }
+
+    if options.probe_gateway {
+        report.push("gateway", check_gateway());
+    } else {
+        report.skip("gateway", "disabled");
+    }
+
+    match options.ssdp_timeout {
+        Some(timeout) => report.push("ssdp", check_ssdp(timeout).await),
+        None => report.skip("ssdp", "disabled"),
+    }
+
+    report
+}
+
+async fn with_timeout<F>(check: F) -> io::Result<String>
+where
+    F: std::future::Future<Output = io::Result<String>>,
+{
+    tokio::time::timeout(CHECK_TIMEOUT, check)
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")))
+}
+
+fn check_bind(port: u16) -> io::Result<String> {
+    let listener = std::net::TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))?;
+    Ok(format!("{} is free", listener.local_addr()?))
+}
+
+/// Start a proxy on an ephemeral loopback port using the production handlers
+async fn start_instance() -> io::Result<(SocketAddr, tokio::task::JoinHandle<io::Result<()>>)> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let addr = listener.local_addr()?;
+    let config = KnoxProxyConfig {
+        bind_addr: addr.to_string(),
+        enable_knox_bypass: false,
+        enable_tethering_bypass: false,
+        ..Default::default()
+    };
+    let task = tokio::spawn(async move { KnoxProxy::new(config).serve(listener).await });
+    Ok((addr, task))
+}
+
+/// Loopback target that echoes one payload back
+async fn spawn_echo() -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        if let Ok((mut stream, _)) = listener.accept().await {
+            let mut buf = [0u8; ECHO_PAYLOAD.len()];
+            if stream.read_exact(&mut buf).await.is_ok() {
+                let _ = stream.write_all(&buf).await;
+            }
+        }
+    });
+    Ok(addr)
+}
+
+/// Loopback origin answering one request with `200 ok`
+async fn spawn_origin() -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        if let Ok((mut stream, _)) = listener.accept().await {
+            let mut head = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                match stream.read(&mut buf).await {
+                    Ok(n) if n > 0 => head.extend_from_slice(&buf[..n]),
+                    _ => return,
+                }
+            }
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+                .await;
+            let _ = stream.shutdown().await;
+        }
+    });
+    Ok(addr)
+}
+
+async fn socks5_round_trip(proxy: SocketAddr) -> io::Result<String> {
+    let target = spawn_echo().await?;
+    let mut stream = TcpStream::connect(proxy).await?;
+
+    stream.write_all(&[0x05, 0x01, 0x00]).await?;
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await?;
+    if method != [0x05, 0x00] {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected method reply {:02x?}", method)));
+    }
+
+    let mut request = vec![0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+    request.extend_from_slice(&target.port().to_be_bytes());
+    stream.write_all(&request).await?;
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0x00 {
+        return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("CONNECT reply code {:#04x}", reply[1])));
+    }
+
+    stream.write_all(ECHO_PAYLOAD).await?;
+    let mut echoed = [0u8; ECHO_PAYLOAD.len()];
+    stream.read_exact(&mut echoed).await?;
+    if echoed != ECHO_PAYLOAD {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "echo payload corrupted"));
+    }
+    Ok(format!("CONNECT {} echoed {} bytes", target, ECHO_PAYLOAD.len()))
+}
+
+async fn http_round_trip(proxy: SocketAddr) -> io::Result<String> {
+    let origin = spawn_origin().await?;
+    let mut stream = TcpStream::connect(proxy).await?;
+    let request = format!(
+        "GET http://{0}/selftest HTTP/1.1\r\nHost: {0}\r\nConnection: close\r\n\r\n",
+        origin
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    if !response.starts_with(b"HTTP/1.1 200") || !response.ends_with(b"ok") {
+        let status = String::from_utf8_lossy(&response).lines().next().unwrap_or("").to_string();
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected response '{}'", status)));
+    }
+    Ok(format!("GET via proxy returned 200 ({} bytes)", response.len()))
+}
+
+/// The gateway is routable if the kernel will pick a source address for it
+fn check_gateway() -> io::Result<String> {
+    let gateway = crate::syscall_net::get_default_gateway()?;
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect((gateway, 53))?;
+    Ok(format!("{} reachable from {}", gateway, socket.local_addr()?.ip()))
+}
+
+async fn check_ssdp(timeout: Duration) -> io::Result<String> {
+    let peers = tokio::task::spawn_blocking(move || crate::dock::dock_discover(timeout, crate::dock::LITEBIKE_ST))
+        .await
+        .map_err(io::Error::other)??;
+    Ok(format!("{} peer(s) answered", peers.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_selftest_passes_on_loopback() {
+        let options = SelftestOptions { bind_ports: vec![0], probe_gateway: false, ssdp_timeout: None };
+        let report = run_selftest(&options).await;
+        assert!(report.passed(), "{}", report);
+
+        let passed: Vec<&str> = report
+            .checks
+            .iter()
+            .filter(|c| matches!(c.outcome, CheckOutcome::Pass(_)))
+            .map(|c| c.name.as_str())
+            .collect();
+        assert_eq!(passed, vec!["bind:0", "instance", "socks5", "http"]);
+        assert_eq!(SelftestOptions::default().bind_ports, vec![Config::from_env().bind_port]);
+    }
+}
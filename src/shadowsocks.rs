@@ -0,0 +1,478 @@
+// Shadowsocks AEAD server (SIP004): AES-128/256-GCM and ChaCha20-Poly1305
+//
+// Each direction starts with a random salt; the session subkey is
+// HKDF-SHA1(master key, salt, "ss-subkey"). The stream is then a run of
+// chunks, each an encrypted big-endian u16 length and the encrypted payload,
+// both with their own 16-byte tag. The nonce is a little-endian counter
+// bumped after every seal or open. The first payload begins with the target
+// in SOCKS5 address form.
+//
+// A server with several users tries each one's key on the first length
+// chunk; the first that authenticates serves the connection.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use ring::{aead, hkdf};
+use rand::RngCore;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use log::debug;
+
+use crate::knox_proxy::{connect_for_client, host_listed, EgressOptions};
+use crate::quota::{ClientQuota, QuotaTracker};
+use crate::tls_fingerprint::md5;
+use crate::types::{parse_socks5_addr, ShadowsocksMethod, TargetAddress};
+
+/// Largest payload in one chunk
+pub const MAX_CHUNK: usize = 0x3FFF;
+const TAG_LEN: usize = 16;
+/// An encrypted chunk length and its tag
+const LEN_BLOCK: usize = 2 + TAG_LEN;
+/// ATYP + length + 255-byte name + port
+const MAX_ADDR_HEADER: usize = 1 + 1 + 255 + 2;
+
+/// Stretch a password into a `key_len` master key as OpenSSL's
+/// `EVP_BytesToKey` does with MD5, one iteration and no salt
+pub fn evp_bytes_to_key(password: &[u8], key_len: usize) -> Vec<u8> {
+    let mut key = Vec::with_capacity(key_len + 16);
+    let mut block: Vec<u8> = Vec::new();
+    while key.len() < key_len {
+        block.extend_from_slice(password);
+        let digest = md5(&block);
+        key.extend_from_slice(&digest);
+        block = digest.to_vec();
+    }
+    key.truncate(key_len);
+    key
+}
+
+/// Master key for one method and password
+#[derive(Clone)]
+pub struct ShadowsocksCrypto {
+    method: ShadowsocksMethod,
+    key: Vec<u8>,
+}
+
+impl std::fmt::Debug for ShadowsocksCrypto {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ShadowsocksCrypto({})", self.method.name())
+    }
+}
+
+impl ShadowsocksCrypto {
+    /// Fails with `Unsupported` for AES-192-GCM, which ring does not provide
+    pub fn new(method: ShadowsocksMethod, password: &str) -> io::Result<Self> {
+        algorithm(method)?;
+        Ok(Self { method, key: evp_bytes_to_key(password.as_bytes(), method.key_length()) })
+    }
+
+    pub fn method(&self) -> ShadowsocksMethod {
+        self.method
+    }
+
+    /// Cipher for the direction that starts with `salt`
+    pub fn cipher(&self, salt: &[u8]) -> io::Result<AeadCipher> {
+        let algorithm = algorithm(self.method)?;
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA1_FOR_LEGACY_USE_ONLY, salt).extract(&self.key);
+        let okm = prk
+            .expand(&[b"ss-subkey"], algorithm)
+            .map_err(|_| crypto_error("subkey derivation failed"))?;
+        Ok(AeadCipher { key: aead::LessSafeKey::new(aead::UnboundKey::from(okm)), counter: 0 })
+    }
+
+    /// Fresh random salt and its cipher, for the direction we send
+    pub fn new_session(&self) -> io::Result<(Vec<u8>, AeadCipher)> {
+        let mut salt = vec![0u8; self.method.salt_length()];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let cipher = self.cipher(&salt)?;
+        Ok((salt, cipher))
+    }
+}
+
+fn algorithm(method: ShadowsocksMethod) -> io::Result<&'static aead::Algorithm> {
+    match method {
+        ShadowsocksMethod::Aes128Gcm => Ok(&aead::AES_128_GCM),
+        ShadowsocksMethod::Aes256Gcm => Ok(&aead::AES_256_GCM),
+        ShadowsocksMethod::Chacha20IetfPoly1305 => Ok(&aead::CHACHA20_POLY1305),
+        ShadowsocksMethod::Aes192Gcm => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} is not supported", method.name()),
+        )),
+    }
+}
+
+fn crypto_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// One direction's session key and nonce counter
+#[derive(Debug)]
+pub struct AeadCipher {
+    key: aead::LessSafeKey,
+    counter: u64,
+}
+
+impl AeadCipher {
+    fn next_nonce(&mut self) -> aead::Nonce {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        nonce[..8].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        aead::Nonce::assume_unique_for_key(nonce)
+    }
+
+    /// Encrypt `data` in place and append its tag
+    pub fn seal(&mut self, data: &mut Vec<u8>) -> io::Result<()> {
+        let nonce = self.next_nonce();
+        self.key
+            .seal_in_place_append_tag(nonce, aead::Aad::empty(), data)
+            .map_err(|_| crypto_error("seal failed"))
+    }
+
+    /// Decrypt `data` (ciphertext and tag) in place, dropping the tag
+    pub fn open(&mut self, data: &mut Vec<u8>) -> io::Result<()> {
+        let nonce = self.next_nonce();
+        let len = self
+            .key
+            .open_in_place(nonce, aead::Aad::empty(), data)
+            .map_err(|_| crypto_error("chunk failed authentication"))?
+            .len();
+        data.truncate(len);
+        Ok(())
+    }
+
+    /// Append `payload` to `out` as one or more chunks
+    pub fn seal_chunks(&mut self, payload: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        for piece in payload.chunks(MAX_CHUNK) {
+            let mut len = (piece.len() as u16).to_be_bytes().to_vec();
+            self.seal(&mut len)?;
+            let mut data = piece.to_vec();
+            self.seal(&mut data)?;
+            out.extend_from_slice(&len);
+            out.extend_from_slice(&data);
+        }
+        Ok(())
+    }
+
+    /// Read and decrypt the next chunk; `None` on a clean end of stream
+    pub async fn read_chunk<R>(&mut self, reader: &mut R) -> io::Result<Option<Vec<u8>>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut len = vec![0u8; LEN_BLOCK];
+        match reader.read(&mut len[..1]).await? {
+            0 => return Ok(None),
+            _ => reader.read_exact(&mut len[1..]).await?,
+        };
+        let size = self.open_len(&mut len)?;
+        self.read_payload(reader, size).await.map(Some)
+    }
+
+    /// Decrypt a length block. Lengths above `MAX_CHUNK` are refused: the
+    /// framing is lost once one is misread.
+    fn open_len(&mut self, block: &mut Vec<u8>) -> io::Result<usize> {
+        self.open(block)?;
+        let size = u16::from_be_bytes([block[0], block[1]]) as usize;
+        if size > MAX_CHUNK {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("chunk length {} exceeds {}", size, MAX_CHUNK),
+            ));
+        }
+        Ok(size)
+    }
+
+    /// Read and decrypt the `size`-byte payload following a length block
+    async fn read_payload<R>(&mut self, reader: &mut R, size: usize) -> io::Result<Vec<u8>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut data = vec![0u8; size + TAG_LEN];
+        reader.read_exact(&mut data).await?;
+        self.open(&mut data)?;
+        Ok(data)
+    }
+}
+
+/// Accepts Shadowsocks clients, connects to the target each one names and
+/// relays with encryption on the client side
+#[derive(Debug, Clone)]
+pub struct ShadowsocksHandler {
+    /// One key per user, tried in order
+    users: Vec<ShadowsocksCrypto>,
+    egress: EgressOptions,
+    deny_targets: Vec<String>,
+    quota: Option<Arc<QuotaTracker>>,
+}
+
+/// What the first bytes of a connection decoded to
+#[derive(Debug)]
+struct Header {
+    target: TargetAddress,
+    /// Payload after the address
+    initial: Vec<u8>,
+    user: ShadowsocksCrypto,
+    decrypt: AeadCipher,
+    /// Bytes read past the header's chunks
+    pending: Vec<u8>,
+}
+
+impl ShadowsocksHandler {
+    pub fn new(crypto: ShadowsocksCrypto) -> Self {
+        Self::for_users(vec![crypto])
+    }
+
+    /// A server for several users; each connection is matched to the
+    /// first whose key opens its first chunk
+    pub fn for_users(users: Vec<ShadowsocksCrypto>) -> Self {
+        Self { users, egress: EgressOptions::default(), deny_targets: Vec::new(), quota: None }
+    }
+
+    pub fn with_egress(mut self, egress: EgressOptions) -> Self {
+        self.egress = egress;
+        self
+    }
+
+    /// Refuse targets on these hosts or their subdomains, as the proxy's
+    /// `deny_targets` does
+    pub fn with_deny_targets(mut self, hosts: Vec<String>) -> Self {
+        self.deny_targets = hosts;
+        self
+    }
+
+    /// Hold a connection slot per client and charge relayed bytes to it
+    pub fn with_quota(mut self, quota: Arc<QuotaTracker>) -> Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    /// Whether `data`, the first bytes of a connection, is a Shadowsocks
+    /// stream for one of our users: a salt and a length chunk one of their
+    /// keys opens. Needs `opening_len` bytes to say yes.
+    pub fn recognizes(&self, data: &[u8]) -> bool {
+        data.len() >= self.opening_len() && self.open_first_chunk(data).is_some()
+    }
+
+    /// Salt and first length block for the longest salt in use
+    pub fn opening_len(&self) -> usize {
+        self.users.iter().map(|user| user.method().salt_length()).max().unwrap_or(0) + LEN_BLOCK
+    }
+
+    /// The first user whose key opens the length chunk after the salt, with
+    /// the cipher, the chunk size and the bytes used
+    fn open_first_chunk(&self, opening: &[u8]) -> Option<(&ShadowsocksCrypto, AeadCipher, usize, usize)> {
+        self.users.iter().find_map(|user| {
+            let salt_len = user.method().salt_length();
+            let mut decrypt = user.cipher(&opening[..salt_len]).ok()?;
+            let mut block = opening[salt_len..salt_len + LEN_BLOCK].to_vec();
+            let size = decrypt.open_len(&mut block).ok()?;
+            Some((user, decrypt, size, salt_len + LEN_BLOCK))
+        })
+    }
+
+    /// `handle_from` for a client whose address is unknown, so no quota applies
+    pub async fn handle<S>(&self, stream: S) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.handle_from(stream, None).await
+    }
+
+    /// Read the salt and address header, then relay until both sides close
+    pub async fn handle_from<S>(&self, stream: S, peer: Option<SocketAddr>) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let quota = match (&self.quota, peer) {
+            (Some(tracker), Some(peer)) => Some(ClientQuota { tracker: tracker.clone(), ip: peer.ip() }),
+            _ => None,
+        };
+        // Held for the life of the connection
+        let _slot = match quota {
+            Some(ref quota) => Some(quota.tracker.acquire(quota.ip).map_err(io::Error::other)?),
+            None => None,
+        };
+        let charge = |bytes: usize| match quota {
+            Some(ref quota) => quota.record(bytes).map_err(io::Error::other),
+            None => Ok(()),
+        };
+
+        let (mut client_r, mut client_w) = tokio::io::split(stream);
+        let Header { target, initial, user, mut decrypt, pending } = self.read_header(&mut client_r).await?;
+        let target = target.to_string();
+        debug!("Shadowsocks ({}) to {}", user.method().name(), target);
+        if host_listed(&self.deny_targets, &target) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("access to {} is not allowed", target)));
+        }
+
+        let upstream = connect_for_client(&target, peer, &self.egress).await?;
+        let (mut upstream_r, mut upstream_w) = upstream.into_split();
+        let mut client_r = (&pending[..]).chain(client_r);
+        let up = async {
+            charge(initial.len())?;
+            upstream_w.write_all(&initial).await?;
+            while let Some(chunk) = decrypt.read_chunk(&mut client_r).await? {
+                charge(chunk.len())?;
+                upstream_w.write_all(&chunk).await?;
+            }
+            upstream_w.shutdown().await
+        };
+        let down = async {
+            let (salt, mut encrypt) = user.new_session()?;
+            client_w.write_all(&salt).await?;
+            let mut buf = vec![0u8; MAX_CHUNK];
+            let mut out = Vec::with_capacity(MAX_CHUNK + 2 * TAG_LEN + 2);
+            loop {
+                let n = upstream_r.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                charge(n)?;
+                out.clear();
+                encrypt.seal_chunks(&buf[..n], &mut out)?;
+                client_w.write_all(&out).await?;
+            }
+            client_w.shutdown().await
+        };
+        tokio::try_join!(up, down)?;
+        Ok(())
+    }
+
+    /// Match the connection to a user by its first length chunk, then read
+    /// until the target address is complete
+    async fn read_header<R>(&self, reader: &mut R) -> io::Result<Header>
+    where
+        R: AsyncRead + Unpin,
+    {
+        // A client with a shorter salt than the longest in use sent at least
+        // this much anyway
+        let mut opening = vec![0u8; self.opening_len()];
+        reader.read_exact(&mut opening).await?;
+
+        let Some((user, mut decrypt, size, used)) = self.open_first_chunk(&opening) else {
+            return Err(crypto_error("no configured password opens the first chunk"));
+        };
+        let rest = opening.split_off(used);
+
+        // The header may in principle span chunks
+        let mut reader = (&rest[..]).chain(reader);
+        let mut plain = decrypt.read_payload(&mut reader, size).await?;
+        loop {
+            if let Some((target, initial)) = parse_socks5_addr(&plain) {
+                let initial = initial.to_vec();
+                let (rest, _) = reader.into_inner();
+                return Ok(Header { target, initial, user: user.clone(), decrypt, pending: rest.to_vec() });
+            }
+            if plain.len() >= MAX_ADDR_HEADER {
+                return Err(crypto_error("bad Shadowsocks address header"));
+            }
+            match decrypt.read_chunk(&mut reader).await? {
+                Some(chunk) => plain.extend_from_slice(&chunk),
+                None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed before the address header")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_address_header_round_trip_and_relay() {
+        // md5("password") starts the stretched key
+        let key = evp_bytes_to_key(b"password", 32);
+        assert_eq!(key[..16], md5(b"password"));
+        assert_eq!(key[16..], md5(&[&md5(b"password")[..], b"password"].concat()));
+
+        for method in [ShadowsocksMethod::Aes256Gcm, ShadowsocksMethod::Chacha20IetfPoly1305] {
+            let crypto = ShadowsocksCrypto::new(method, "s3cret").unwrap();
+            let mut header = vec![0x03, 11];
+            header.extend_from_slice(b"example.com");
+            header.extend_from_slice(&443u16.to_be_bytes());
+            header.extend_from_slice(b"GET /");
+
+            let (salt, mut encrypt) = crypto.new_session().unwrap();
+            let mut wire = salt.clone();
+            encrypt.seal_chunks(&header, &mut wire).unwrap();
+            assert_eq!(wire.len(), salt.len() + 2 + TAG_LEN + header.len() + TAG_LEN);
+
+            let handler = ShadowsocksHandler::new(crypto.clone());
+            let header = handler.read_header(&mut &wire[..]).await.unwrap();
+            assert_eq!(header.target, TargetAddress::new("example.com", 443));
+            assert_eq!(header.initial, b"GET /");
+
+            // The wrong password fails authentication
+            let wrong = ShadowsocksHandler::new(ShadowsocksCrypto::new(method, "guess").unwrap());
+            assert_eq!(wrong.read_header(&mut &wire[..]).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+            // Any configured user matches, whatever its salt length
+            let users = vec![
+                ShadowsocksCrypto::new(ShadowsocksMethod::Aes128Gcm, "other").unwrap(),
+                ShadowsocksCrypto::new(method, "guess").unwrap(),
+                crypto.clone(),
+            ];
+            let header = ShadowsocksHandler::for_users(users).read_header(&mut &wire[..]).await.unwrap();
+            assert_eq!(header.user.method(), method);
+            assert_eq!(header.initial, b"GET /");
+            assert!(header.pending.is_empty());
+        }
+
+        // A short-salt user's stream stays aligned after the longer opening read
+        let short = ShadowsocksCrypto::new(ShadowsocksMethod::Aes128Gcm, "s3cret").unwrap();
+        let (salt, mut encrypt) = short.new_session().unwrap();
+        let mut wire = salt;
+        encrypt.seal_chunks(&[0x01, 10, 0, 0, 1, 0, 80], &mut wire).unwrap();
+        encrypt.seal_chunks(b"body", &mut wire).unwrap();
+        let long = ShadowsocksCrypto::new(ShadowsocksMethod::Aes256Gcm, "s3cret").unwrap();
+        let handler = ShadowsocksHandler::for_users(vec![long, short]);
+        let mut input = &wire[..];
+        let mut header = handler.read_header(&mut input).await.unwrap();
+        assert_eq!(header.target.to_string(), "10.0.0.1:80");
+        let mut rest = (&header.pending[..]).chain(input);
+        assert_eq!(header.decrypt.read_chunk(&mut rest).await.unwrap().unwrap(), b"body");
+
+        // Lengths above MAX_CHUNK are refused rather than masked
+        let crypto = ShadowsocksCrypto::new(ShadowsocksMethod::Aes256Gcm, "s3cret").unwrap();
+        let (salt, mut encrypt) = crypto.new_session().unwrap();
+        let mut len = (MAX_CHUNK as u16 + 1).to_be_bytes().to_vec();
+        encrypt.seal(&mut len).unwrap();
+        let mut decrypt = crypto.cipher(&salt).unwrap();
+        let err = decrypt.read_chunk(&mut &len[..]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(ShadowsocksCrypto::new(ShadowsocksMethod::Aes192Gcm, "s3cret").is_err());
+
+        // Full relay through a local echo server
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut accepted, _) = echo.accept().await.unwrap();
+            let (mut r, mut w) = accepted.split();
+            tokio::io::copy(&mut r, &mut w).await.unwrap();
+        });
+        let crypto = ShadowsocksCrypto::new(ShadowsocksMethod::Aes256Gcm, "s3cret").unwrap();
+        let handler = ShadowsocksHandler::new(crypto.clone());
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let relay = tokio::spawn(async move { handler.handle(server).await });
+
+        let (mut client_r, mut client_w) = tokio::io::split(client);
+        let mut request = vec![0x01];
+        request.extend_from_slice(&[127, 0, 0, 1]);
+        request.extend_from_slice(&echo_addr.port().to_be_bytes());
+        request.extend_from_slice(b"ping");
+        let (salt, mut encrypt) = crypto.new_session().unwrap();
+        let mut wire = salt;
+        encrypt.seal_chunks(&request, &mut wire).unwrap();
+        client_w.write_all(&wire).await.unwrap();
+
+        let mut salt = vec![0u8; 32];
+        client_r.read_exact(&mut salt).await.unwrap();
+        let mut decrypt = crypto.cipher(&salt).unwrap();
+        let reply = decrypt.read_chunk(&mut client_r).await.unwrap().unwrap();
+        assert_eq!(reply, b"ping");
+
+        client_w.shutdown().await.unwrap();
+        assert_eq!(decrypt.read_chunk(&mut client_r).await.unwrap(), None);
+        relay.await.unwrap().unwrap();
+    }
+}
@@ -12,6 +12,12 @@ use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use log::{info, warn, error, debug};
 use serde::{Serialize, Deserialize};
 
+use crate::dock::{parse_manifest_json, DockManifest};
+use crate::util::Backoff;
+
+/// First retry after a failed parent sync; later ones double up to the sync interval
+const PARENT_RETRY_DELAY: Duration = Duration::from_secs(2);
+
 /// Symmetrical operation mode
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SymmetricalMode {
@@ -48,6 +54,55 @@ pub struct GatewayCapabilities {
     pub gates: Vec<String>,
 }
 
+/// How traffic is sent to the parent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParentUpstream {
+    Http { host: String, port: u16 },
+    Socks5 { host: String, port: u16 },
+}
+
+impl ParentUpstream {
+    /// Proxy URL for HTTP clients; SOCKS5 as `socks5h` so the parent
+    /// resolves target names, as it would for a browser
+    pub fn proxy_url(&self) -> String {
+        match self {
+            ParentUpstream::Http { host, port } => format!("http://{}:{}", host, port),
+            ParentUpstream::Socks5 { host, port } => format!("socks5h://{}:{}", host, port),
+        }
+    }
+}
+
+impl ParentGateway {
+    /// URL of the parent's dock manifest; `url` may already be the LOCATION
+    pub fn manifest_url(&self) -> String {
+        if self.url.ends_with("/litebike.json") {
+            self.url.clone()
+        } else {
+            format!("{}/litebike.json", self.url.trim_end_matches('/'))
+        }
+    }
+
+    /// Take the port and capabilities a LiteBike parent advertises
+    pub fn apply_manifest(&mut self, manifest: &DockManifest) {
+        if manifest.port != 0 {
+            self.port = manifest.port;
+        }
+        self.capabilities.proxy = manifest.proxy;
+        self.capabilities.socks5 = manifest.socks5;
+        self.capabilities.knox = manifest.knox;
+    }
+
+    /// SOCKS5 when the parent offers it, since it carries any TCP; HTTP otherwise
+    pub fn upstream(&self) -> ParentUpstream {
+        let (host, port) = (self.host.clone(), self.port);
+        if self.capabilities.socks5 {
+            ParentUpstream::Socks5 { host, port }
+        } else {
+            ParentUpstream::Http { host, port }
+        }
+    }
+}
+
 /// Connectivity status
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ConnectivityStatus {
@@ -81,6 +136,9 @@ pub struct SymmetricalConfig {
     pub sync_interval: Duration,
     pub failover_enabled: bool,
     pub failover_threshold: u32,
+    /// Let auto mode fetch the public connectivity check to spot a captive
+    /// portal. Off by default since it contacts a third-party host.
+    pub probe_captive_portal: bool,
 }
 
 impl Default for SymmetricalConfig {
@@ -93,6 +151,7 @@ impl Default for SymmetricalConfig {
             sync_interval: Duration::from_secs(60),
             failover_enabled: true,
             failover_threshold: 3,
+            probe_captive_portal: false,
         }
     }
 }
@@ -110,6 +169,7 @@ pub struct SymmetricalGateway {
 /// Parent gateway client (upstream connection)
 struct ParentClient {
     gateway: ParentGateway,
+    upstream: ParentUpstream,
     http_client: reqwest::Client,
     socks5_client: Option<tokio::net::TcpStream>,
     health_check_interval: Duration,
@@ -191,13 +251,29 @@ impl SymmetricalGateway {
         // Discover local services
         let services = self.discover_local_services().await?;
 
+        // Behind a captive portal nothing upstream works until sign-in
+        let captive = self.config.read().await.probe_captive_portal
+            && tokio::task::spawn_blocking(crate::network::is_captive_portal_likely)
+                .await
+                .unwrap_or(false);
+
         // Analyze environment
-        let mode = if !parents.is_empty() && !services.is_empty() {
+        let mode = if captive {
+            warn!("⚠ Captive portal likely, serving LAN only until the network is usable");
+            SymmetricalMode::Downstream
+        } else if !parents.is_empty() && !services.is_empty() {
             info!("✓ Both parent and local services detected");
             SymmetricalMode::Symmetrical
         } else if !parents.is_empty() {
-            info!("✓ Parent gateway detected, no local services");
-            SymmetricalMode::Upstream
+            // Hosting a hotspot means there is a LAN to serve downstream as well
+            let ap_ifaces = crate::radios::active_ap_interfaces();
+            if ap_ifaces.is_empty() {
+                info!("✓ Parent gateway detected, no local services");
+                SymmetricalMode::Upstream
+            } else {
+                info!("✓ Parent gateway detected, serving AP interface(s) {}", ap_ifaces.join(","));
+                SymmetricalMode::Symmetrical
+            }
         } else if !services.is_empty() {
             info!("✓ Local services detected, no parent");
             SymmetricalMode::Downstream
@@ -229,11 +305,21 @@ impl SymmetricalGateway {
     async fn start_upstream_mode(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         let config = self.config.read().await.clone();
 
-        if let Some(parent) = config.parent {
+        if let Some(mut parent) = config.parent {
             info!("📡 Connecting to parent: {}", parent.url);
 
-            // Create HTTP proxy client
-            let proxy = reqwest::Proxy::all(&format!("http://{}:{}", parent.host, parent.port))?;
+            // A LiteBike parent says where its proxy lives; others keep the configured port
+            match Self::fetch_manifest(&parent).await {
+                Ok(manifest) => {
+                    info!("✓ Parent manifest: {} on port {} (socks5: {})", manifest.name, manifest.port, manifest.socks5);
+                    parent.apply_manifest(&manifest);
+                }
+                Err(e) => debug!("No manifest from {}: {}", parent.manifest_url(), e),
+            }
+            let upstream = parent.upstream();
+
+            // Create a client that goes through the parent
+            let proxy = reqwest::Proxy::all(upstream.proxy_url())?;
             let http_client = reqwest::Client::builder()
                 .proxy(proxy)
                 .timeout(Duration::from_secs(5))
@@ -241,6 +327,7 @@ impl SymmetricalGateway {
 
             self.parent_client = Some(ParentClient {
                 gateway: parent,
+                upstream,
                 http_client,
                 socks5_client: None,
                 health_check_interval: Duration::from_secs(30),
@@ -254,6 +341,20 @@ impl SymmetricalGateway {
         }
     }
 
+    async fn fetch_manifest(parent: &ParentGateway) -> Result<DockManifest, Box<dyn std::error::Error>> {
+        let client = reqwest::Client::builder()
+            .no_proxy()
+            .timeout(Duration::from_secs(3))
+            .build()?;
+        let text = client.get(parent.manifest_url()).send().await?.error_for_status()?.text().await?;
+        parse_manifest_json(&text).ok_or_else(|| "invalid litebike.json".into())
+    }
+
+    /// Upstream in use once upstream mode is active
+    pub fn parent_upstream(&self) -> Option<ParentUpstream> {
+        self.parent_client.as_ref().map(|client| client.upstream.clone())
+    }
+
     /// Start as downstream server
     async fn start_downstream_mode(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         let config = self.config.read().await.clone();
@@ -473,6 +574,18 @@ impl SymmetricalGateway {
             }
         }
 
+        // Distinguish a dead host from one whose service is not answering
+        if let Ok(ip) = parent.host.parse::<IpAddr>() {
+            let host_up = tokio::task::spawn_blocking(move || crate::syscall_net::ping(ip, Duration::from_secs(1)))
+                .await
+                .unwrap_or(false);
+            if host_up {
+                warn!("Parent host {} answers ping but not HTTP", parent.host);
+            } else {
+                debug!("Parent host {} does not answer ping", parent.host);
+            }
+        }
+
         false
     }
 
@@ -509,24 +622,26 @@ impl SymmetricalGateway {
 
         let config = self.config.clone();
         self.sync_task = Some(tokio::spawn(async move {
+            // A parent that stops answering is retried sooner than the
+            // sync interval, backing off towards it
+            let mut reconnect = Backoff::new(PARENT_RETRY_DELAY, interval).with_jitter(0.5);
+            let mut delay = interval;
             loop {
-                tokio::time::sleep(interval).await;
+                tokio::time::sleep(delay).await;
+                delay = interval;
 
                 // Sync with parent
-                if let Some(ref parent) = config.read().await.parent {
+                let parent = config.read().await.parent.clone();
+                if let Some(parent) = parent {
                     info!("🔄 Syncing with parent: {}", parent.url);
-
-                    // Fetch parent manifest
-                    let client = reqwest::Client::new();
-                    if let Ok(response) = client.get(&format!("{}/litebike.json", parent.url))
-                        .timeout(Duration::from_secs(5))
-                        .send()
-                        .await
-                    {
-                        if let Ok(text) = response.text().await {
-                            if let Ok(manifest) = serde_json::from_str::<GatewayCapabilities>(&text) {
-                                info!("✓ Parent capabilities: {:?}", manifest);
-                            }
+                    match Self::fetch_manifest(&parent).await {
+                        Ok(manifest) => {
+                            info!("✓ Parent manifest: {} on port {}", manifest.name, manifest.port);
+                            reconnect.reset();
+                        }
+                        Err(e) => {
+                            delay = reconnect.next_delay();
+                            warn!("Parent {} unreachable ({}), retrying in {:?}", parent.url, e, delay);
                         }
                     }
                 }
@@ -573,10 +688,55 @@ mod tests {
         assert_eq!(mode, SymmetricalMode::Auto);
     }
 
+    #[tokio::test]
+    async fn test_parent_manifest_selects_socks5_upstream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            let json = crate::dock::build_manifest_json(
+                "parent",
+                9050,
+                &crate::dock::DockCapabilities { has_proxy: true, has_knox: false, has_socks5: true, ..Default::default() },
+            );
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                json.len(),
+                json
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let config = SymmetricalConfig {
+            mode: SymmetricalMode::Upstream,
+            parent: Some(ParentGateway {
+                url: format!("http://{}", addr),
+                host: "127.0.0.1".to_string(),
+                port: 8080,
+                capabilities: GatewayCapabilities::default(),
+                last_seen: None,
+                connectivity_status: ConnectivityStatus::Unknown,
+            }),
+            ..Default::default()
+        };
+        let mut gateway = SymmetricalGateway::new(config);
+        gateway.start_upstream_mode().await.unwrap();
+        assert_eq!(
+            gateway.parent_upstream(),
+            Some(ParentUpstream::Socks5 { host: "127.0.0.1".to_string(), port: 9050 })
+        );
+        assert_eq!(gateway.parent_upstream().unwrap().proxy_url(), "socks5h://127.0.0.1:9050");
+        let http = ParentUpstream::Http { host: "10.0.0.1".to_string(), port: 8080 };
+        assert_eq!(http.proxy_url(), "http://10.0.0.1:8080");
+    }
+
     #[test]
     fn test_config_default() {
         let config = SymmetricalConfig::default();
         assert_eq!(config.mode, SymmetricalMode::Auto);
         assert_eq!(config.auto_sync, true);
+        assert!(!config.probe_captive_portal);
     }
 }
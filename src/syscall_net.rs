@@ -5,8 +5,9 @@
 use std::collections::HashMap;
 use std::ffi::CStr;
 use std::io;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4};
-use std::os::unix::io::RawFd;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::time::{Duration, Instant};
 
 /// Gets the default gateway IP address by using the most direct, low-level method available.
 ///
@@ -573,6 +574,61 @@ pub fn list_interfaces() -> io::Result<HashMap<String, Interface>> {
     Ok(interfaces)
 }
 
+/// IPv4 address and netmask of every addressed interface, via `getifaddrs`.
+///
+/// Returns `(interface, address, netmask)` triples; an interface with several
+/// IPv4 addresses appears once per address.
+pub fn list_ipv4_networks() -> io::Result<Vec<(String, Ipv4Addr, Ipv4Addr)>> {
+    let mut ifaddrs_ptr = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs_ptr) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut networks = Vec::new();
+    let mut current = ifaddrs_ptr;
+
+    while !current.is_null() {
+        let ifa = unsafe { &*current };
+        let addr = unsafe { sockaddr_to_interface_addr(ifa.ifa_addr) };
+        let mask = unsafe { sockaddr_to_interface_addr(ifa.ifa_netmask) };
+        if let (Some(InterfaceAddr::V4(addr)), Some(InterfaceAddr::V4(mask))) = (addr, mask) {
+            let name = unsafe { CStr::from_ptr(ifa.ifa_name).to_string_lossy().into_owned() };
+            networks.push((name, addr, mask));
+        }
+        current = ifa.ifa_next;
+    }
+
+    unsafe { libc::freeifaddrs(ifaddrs_ptr) };
+
+    Ok(networks)
+}
+
+/// IPv6 counterpart of `list_ipv4_networks`: `(interface, address, netmask)`.
+pub fn list_ipv6_networks() -> io::Result<Vec<(String, Ipv6Addr, Ipv6Addr)>> {
+    let mut ifaddrs_ptr = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs_ptr) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut networks = Vec::new();
+    let mut current = ifaddrs_ptr;
+
+    while !current.is_null() {
+        let ifa = unsafe { &*current };
+        let addr = unsafe { sockaddr_to_interface_addr(ifa.ifa_addr) };
+        let mask = unsafe { sockaddr_to_interface_addr(ifa.ifa_netmask) };
+        if let (Some(InterfaceAddr::V6(addr)), Some(InterfaceAddr::V6(mask))) = (addr, mask) {
+            let name = unsafe { CStr::from_ptr(ifa.ifa_name).to_string_lossy().into_owned() };
+            networks.push((name, addr, mask));
+        }
+        current = ifa.ifa_next;
+    }
+
+    unsafe { libc::freeifaddrs(ifaddrs_ptr) };
+
+    Ok(networks)
+}
+
 /// Converts a `sockaddr` pointer to a Rust-native `InterfaceAddr`.
 unsafe fn sockaddr_to_interface_addr(sockaddr: *const libc::sockaddr) -> Option<InterfaceAddr> {
     if sockaddr.is_null() {
@@ -811,10 +867,228 @@ pub fn socket_close(fd: RawFd) -> io::Result<()> {
     }
 }
 
+/// Sends one ICMP echo request to `addr` and reports whether a reply came back
+/// within `timeout`. Returns `false` when ICMP sockets are not permitted.
+pub fn ping(addr: IpAddr, timeout: Duration) -> bool {
+    try_ping(addr, timeout).unwrap_or(false)
+}
+
+/// Like `ping`, but distinguishes "no reply" (`Ok(false)`) from being unable
+/// to open an ICMP socket at all (`Err`, usually `PermissionDenied`).
+///
+/// Uses an unprivileged ICMP datagram socket (Linux `ping_group_range`,
+/// macOS), falling back to a raw socket when running as root.
+pub fn try_ping(addr: IpAddr, timeout: Duration) -> io::Result<bool> {
+    let (domain, protocol, echo_request, echo_reply) = match addr {
+        IpAddr::V4(_) => (libc::AF_INET, libc::IPPROTO_ICMP, 8u8, 0u8),
+        IpAddr::V6(_) => (libc::AF_INET6, libc::IPPROTO_ICMPV6, 128u8, 129u8),
+    };
+    let (fd, raw) = match socket_create(domain, libc::SOCK_DGRAM, protocol) {
+        Ok(fd) => (fd, false),
+        Err(_) => (socket_create(domain, libc::SOCK_RAW, protocol)?, true),
+    };
+    // UdpSocket is only used as an owned datagram fd with sendto/recv and timeouts
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    let ident = (std::process::id() as u16) ^ (fd as u16);
+    let sequence = rand::random::<u16>();
+    let mut request = vec![echo_request, 0, 0, 0];
+    request.extend_from_slice(&ident.to_be_bytes());
+    request.extend_from_slice(&sequence.to_be_bytes());
+    request.extend_from_slice(b"litebike-ping");
+    if addr.is_ipv4() {
+        // ICMPv6 checksums cover a pseudo-header and are filled in by the kernel
+        let checksum = icmp_checksum(&request);
+        request[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    socket.send_to(&request, SocketAddr::new(addr, 0))?;
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0u8; 1500];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(false);
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let n = match socket.recv(&mut buf) {
+            Ok(n) => n,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        // Raw IPv4 sockets deliver the IP header too
+        let offset = if raw && addr.is_ipv4() && n > 0 { ((buf[0] & 0x0f) as usize) * 4 } else { 0 };
+        let reply = match buf.get(offset..n) {
+            Some(reply) if reply.len() >= 8 => reply,
+            _ => continue,
+        };
+        // Datagram sockets rewrite the identifier, so only raw replies are matched on it
+        let ident_ok = !raw || reply[4..6] == ident.to_be_bytes();
+        if reply[0] == echo_reply && ident_ok && reply[6..8] == sequence.to_be_bytes() {
+            return Ok(true);
+        }
+    }
+}
+
+/// RFC 1071 internet checksum
+fn icmp_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// One row of `/proc/net/{tcp,tcp6,udp,udp6}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcNetSocket {
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+    /// Kernel TCP state, e.g. 0x0A for LISTEN; 0x07 for unconnected UDP
+    pub state: u8,
+    pub tx_queue: u32,
+    pub rx_queue: u32,
+    pub inode: u64,
+}
+
+impl ProcNetSocket {
+    /// netstat's name for `state`
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            0x01 => "ESTABLISHED",
+            0x02 => "SYN_SENT",
+            0x03 => "SYN_RECV",
+            0x04 => "FIN_WAIT1",
+            0x05 => "FIN_WAIT2",
+            0x06 => "TIME_WAIT",
+            0x07 => "CLOSE",
+            0x08 => "CLOSE_WAIT",
+            0x09 => "LAST_ACK",
+            0x0A => "LISTEN",
+            0x0B => "CLOSING",
+            _ => "UNKNOWN",
+        }
+    }
+}
+
+/// Reads a `/proc/net` socket table such as `/proc/net/tcp6`, skipping
+/// rows that do not parse.
+pub fn read_proc_net_sockets(path: &str) -> io::Result<Vec<ProcNetSocket>> {
+    let content = std::fs::read_to_string(path)?;
+    Ok(content.lines().skip(1).filter_map(parse_proc_net_line).collect())
+}
+
+/// Parses one `/proc/net/tcp`-style row. Addresses are hex, each 32-bit
+/// word in host byte order.
+pub fn parse_proc_net_line(line: &str) -> Option<ProcNetSocket> {
+    let cols: Vec<&str> = line.split_whitespace().collect();
+    if cols.len() < 10 {
+        return None;
+    }
+    let (tx_queue, rx_queue) = cols[4].split_once(':')?;
+    Some(ProcNetSocket {
+        local: parse_proc_net_addr(cols[1])?,
+        remote: parse_proc_net_addr(cols[2])?,
+        state: u8::from_str_radix(cols[3], 16).ok()?,
+        tx_queue: u32::from_str_radix(tx_queue, 16).ok()?,
+        rx_queue: u32::from_str_radix(rx_queue, 16).ok()?,
+        inode: cols[9].parse().ok()?,
+    })
+}
+
+fn parse_proc_net_addr(hex: &str) -> Option<SocketAddr> {
+    let (ip, port) = hex.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut words = Vec::with_capacity(4);
+    for i in (0..ip.len()).step_by(8) {
+        let word = u32::from_str_radix(ip.get(i..i + 8)?, 16).ok()?;
+        words.extend_from_slice(&word.to_ne_bytes());
+    }
+    let ip = match words.len() {
+        4 => IpAddr::V4(Ipv4Addr::new(words[0], words[1], words[2], words[3])),
+        16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(words).ok()?)),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// An open socket file descriptor of some process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketFd {
+    pub pid: u32,
+    /// `/proc/<pid>/comm`
+    pub command: String,
+    /// Owner of the process
+    pub uid: u32,
+    pub fd: u32,
+    pub inode: u64,
+}
+
+/// Every socket fd visible under `/proc/*/fd`, found through their
+/// `socket:[inode]` links. Processes whose fds cannot be read (other
+/// users, without root) are left out.
+pub fn list_socket_fds() -> Vec<SocketFd> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut sockets = Vec::new();
+    let Ok(procs) = std::fs::read_dir("/proc") else { return sockets };
+    for entry in procs.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else { continue };
+        let Ok(fds) = std::fs::read_dir(entry.path().join("fd")) else { continue };
+        let mut owner = None;
+        for fd in fds.flatten() {
+            let Some(fd_num) = fd.file_name().to_str().and_then(|n| n.parse::<u32>().ok()) else { continue };
+            let Ok(target) = std::fs::read_link(fd.path()) else { continue };
+            let inode = target
+                .to_str()
+                .and_then(|t| t.strip_prefix("socket:["))
+                .and_then(|t| t.strip_suffix(']'))
+                .and_then(|t| t.parse::<u64>().ok());
+            let Some(inode) = inode else { continue };
+            let (command, uid) = owner
+                .get_or_insert_with(|| {
+                    let command = std::fs::read_to_string(entry.path().join("comm")).map(|c| c.trim().to_string()).unwrap_or_default();
+                    let uid = entry.metadata().map(|m| m.uid()).unwrap_or(0);
+                    (command, uid)
+                })
+                .clone();
+            sockets.push(SocketFd { pid, command, uid, fd: fd_num, inode });
+        }
+    }
+    sockets
+}
+
+/// Maps socket inodes to the `(pid, command)` holding them; see `list_socket_fds`.
+pub fn socket_inode_owners() -> HashMap<u64, (u32, String)> {
+    let mut owners = HashMap::new();
+    for socket in list_socket_fds() {
+        owners.entry(socket.inode).or_insert((socket.pid, socket.command));
+    }
+    owners
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_ping_loopback() {
+        match try_ping(IpAddr::V4(Ipv4Addr::LOCALHOST), Duration::from_secs(1)) {
+            Ok(reachable) => assert!(reachable, "loopback should answer ICMP echo"),
+            // Sandboxes without ICMP sockets degrade to "unreachable" rather than failing
+            Err(_) => assert!(!ping(IpAddr::V4(Ipv4Addr::LOCALHOST), Duration::from_millis(10))),
+        }
+    }
+
+    #[test]
+    fn test_icmp_checksum() {
+        // Echo request, id 1, seq 1, no payload: 0x0800 + 0x0001 + 0x0001
+        assert_eq!(icmp_checksum(&[8, 0, 0, 0, 0, 1, 0, 1]), !0x0802);
+    }
+
     #[test]
     fn test_list_interfaces_syscall() {
         // This test performs a live syscall to list interfaces.
@@ -833,6 +1107,39 @@ mod tests {
         assert!(has_ipv4, "Loopback interface should have an IPv4 address");
     }
 
+    #[test]
+    fn test_list_ipv4_networks_loopback() {
+        let networks = list_ipv4_networks().expect("Failed to list IPv4 networks");
+        let loopback = networks.iter().find(|(_, addr, _)| addr.is_loopback());
+        let (_, _, mask) = loopback.expect("Loopback should have an IPv4 network");
+        assert_eq!(mask.octets()[0], 255);
+    }
+
+    #[test]
+    fn test_proc_net_rows_and_owner() {
+        let v4 = "   0: 0100007F:1F90 00000000:0000 0A 00000000:00000002 00:00000000 00000000  1000        0 424242 1 0000000000000000 100 0 0 10 0";
+        let row = parse_proc_net_line(v4).unwrap();
+        assert_eq!(row.local, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(row.remote, "0.0.0.0:0".parse().unwrap());
+        assert_eq!((row.state_name(), row.rx_queue, row.inode), ("LISTEN", 2, 424242));
+        let v6 = "   1: 00000000000000000000000001000000:0050 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 7 1 0000000000000000 100 0 0 10 0";
+        assert_eq!(parse_proc_net_line(v6).unwrap().local, "[::1]:80".parse().unwrap());
+        assert!(parse_proc_net_line("  sl  local_address rem_address   st tx_queue rx_queue").is_none());
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let row = read_proc_net_sockets("/proc/net/tcp")
+                .unwrap()
+                .into_iter()
+                .find(|row| row.local.port() == port && row.state_name() == "LISTEN")
+                .expect("listener should appear in /proc/net/tcp");
+            let (pid, _) = socket_inode_owners()[&row.inode];
+            assert_eq!(pid, std::process::id());
+        }
+    }
+
     #[test]
     fn test_tcp_socket_operations() {
         use std::net::Ipv4Addr;
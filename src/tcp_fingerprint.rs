@@ -1,9 +1,10 @@
 // TCP Fingerprint Mitigation for Knox Bypass
 // Mimics mobile device TCP characteristics to evade carrier detection
 
-use std::net::TcpStream;
-use std::os::fd::AsRawFd;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::os::fd::{AsRawFd, RawFd};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rand::rngs::StdRng;
 use rand::Rng;
 // Platform-conditional TCP constants: macOS doesn't export SOL_TCP/TCP_KEEPIDLE
 use libc::{setsockopt, SO_SNDBUF, SO_RCVBUF, TCP_NODELAY, TCP_KEEPINTVL};
@@ -108,20 +109,32 @@ pub struct TcpFingerprintManager {
     current_profile: MobileProfile,
     rotation_enabled: bool,
     profile_history: Vec<(SystemTime, MobileProfile)>,
+    rng: StdRng,
 }
 
 impl TcpFingerprintManager {
     pub fn new() -> Self {
+        let mut rng = crate::determinism::rng();
         Self {
-            current_profile: Self::select_random_profile(),
+            current_profile: Self::select_random_profile(&mut rng),
             rotation_enabled: true,
             profile_history: Vec::new(),
+            rng,
         }
     }
     
+    /// Reproducible manager regardless of `LITEBIKE_DETERMINISTIC`: profile
+    /// choice, rotation interval and the ISN's random part come from the
+    /// fixed seed
+    pub fn deterministic(mut self) -> Self {
+        self.rng = crate::determinism::seeded_rng();
+        self.current_profile = Self::select_random_profile(&mut self.rng);
+        self.profile_history.clear();
+        self
+    }
+    
     /// Select a random mobile profile weighted by popularity
-    fn select_random_profile() -> MobileProfile {
-        let mut rng = rand::thread_rng();
+    fn select_random_profile(rng: &mut StdRng) -> MobileProfile {
         let profiles = vec![
             (MobileProfile::IPhone14, 25),      // 25% weight
             (MobileProfile::IPhone15, 30),      // 30% weight  
@@ -154,10 +167,10 @@ impl TcpFingerprintManager {
         // Rotate every 10-30 minutes randomly
         if let Some((last_rotation, _)) = self.profile_history.last() {
             let elapsed = now.duration_since(*last_rotation).unwrap_or_default();
-            let rotation_interval = rand::thread_rng().gen_range(600..1800); // 10-30 min
+            let rotation_interval = self.rng.gen_range(600..1800); // 10-30 min
             
             if elapsed.as_secs() > rotation_interval {
-                let new_profile = Self::select_random_profile();
+                let new_profile = Self::select_random_profile(&mut self.rng);
                 self.profile_history.push((now, new_profile.clone()));
                 self.current_profile = new_profile;
                 
@@ -263,7 +276,7 @@ impl TcpFingerprintManager {
     }
     
     /// Generate realistic TCP Initial Sequence Number
-    pub fn generate_mobile_isn(&self) -> u32 {
+    pub fn generate_mobile_isn(&mut self) -> u32 {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap_or_default()
@@ -271,7 +284,7 @@ impl TcpFingerprintManager {
             
         // Mobile devices often use time-based ISN with some randomization
         let time_component = now.wrapping_mul(4096);
-        let random_component = rand::thread_rng().gen::<u16>() as u32;
+        let random_component = self.rng.gen::<u16>() as u32;
         
         time_component.wrapping_add(random_component)
     }
@@ -367,6 +380,212 @@ impl MobileCongestionControl {
     }
 }
 
+/// Clamp the MSS this socket advertises. Takes effect on the SYN, so set it
+/// before connecting; pairs with TTL spoofing to hide tethered traffic and
+/// avoids PMTU black holes on cellular paths.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_tcp_mss(fd: RawFd, mss: u16) -> std::io::Result<()> {
+    let value = mss as libc::c_int;
+    unsafe {
+        if setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_MAXSEG,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as u32,
+        ) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    log::debug!("Clamped TCP MSS to {}", mss);
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn set_tcp_mss(_fd: RawFd, _mss: u16) -> std::io::Result<()> {
+    // TCP_MAXSEG clamping is only wired up for Linux/Android
+    log::debug!("MSS clamping not supported on this platform");
+    Ok(())
+}
+
+/// Set the initial TTL (hop limit on IPv6) of packets sent from this socket
+pub fn set_ip_ttl(fd: RawFd, ttl: u8, ipv6: bool) -> std::io::Result<()> {
+    let value = ttl as libc::c_int;
+    let (level, name) = if ipv6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_TTL)
+    };
+    unsafe {
+        if setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as u32,
+        ) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Tie the socket to `interface` with `SO_BINDTODEVICE`, so its traffic
+/// leaves through that interface whatever the routing table prefers
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn bind_to_device(fd: RawFd, interface: &str) -> std::io::Result<()> {
+    if interface.is_empty() || interface.len() >= libc::IFNAMSIZ || interface.contains('\0') {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("bad interface name {:?}", interface),
+        ));
+    }
+    unsafe {
+        if setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            interface.as_ptr() as *const libc::c_void,
+            interface.len() as u32,
+        ) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    log::debug!("Bound socket to {}", interface);
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn bind_to_device(_fd: RawFd, interface: &str) -> std::io::Result<()> {
+    // SO_BINDTODEVICE is Linux-only; elsewhere the routing table decides.
+    // Said once, loudly enough to notice, rather than on every connection.
+    static WARNED: std::sync::Once = std::sync::Once::new();
+    WARNED.call_once(|| log::warn!("Binding to {} not supported on this platform, the routing table decides", interface));
+    Ok(())
+}
+
+/// Interface set by `bind_to_device`, if any
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn bound_device(fd: RawFd) -> std::io::Result<Option<String>> {
+    let mut name = [0u8; libc::IFNAMSIZ];
+    let mut len = name.len() as libc::socklen_t;
+    unsafe {
+        if libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+        ) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    let name = &name[..len as usize];
+    let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+    Ok((!name.is_empty()).then(|| String::from_utf8_lossy(name).into_owned()))
+}
+
+/// Current `TCP_MAXSEG`: the clamp before connecting, the path MSS after
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn tcp_mss(fd: RawFd) -> std::io::Result<u16> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    unsafe {
+        if libc::getsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_MAXSEG,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        ) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(value as u16)
+}
+
+/// Socket options that are not part of a mobile fingerprint: set on a
+/// socket before it binds, so they also hold for listeners
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TcpTuningOptions {
+    pub nodelay: bool,
+    pub send_buffer: Option<usize>,
+    pub recv_buffer: Option<usize>,
+    /// `SO_REUSEADDR`: rebind while old connections sit in TIME_WAIT
+    pub reuse_address: bool,
+    /// `SO_REUSEPORT`: let several listeners share the port, the kernel
+    /// spreading connections between them
+    pub reuse_port: bool,
+    /// `SO_KEEPALIVE` with this idle time before the first probe; accepted
+    /// connections inherit it from the listener
+    pub keepalive: Option<Duration>,
+}
+
+impl TcpTuningOptions {
+    /// Options of a plain `TcpListener::bind`: only `SO_REUSEADDR`
+    pub fn listener() -> Self {
+        Self { reuse_address: true, ..Default::default() }
+    }
+
+    /// Set every option on `fd`
+    pub fn apply(&self, fd: RawFd) -> std::io::Result<()> {
+        self.apply_reuse(fd)?;
+        set_int_option(fd, libc::IPPROTO_TCP, TCP_NODELAY, self.nodelay as libc::c_int)?;
+        if let Some(idle) = self.keepalive {
+            set_int_option(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+            set_int_option(fd, libc::IPPROTO_TCP, TCP_KEEPIDLE, idle.as_secs().max(1) as libc::c_int)?;
+        }
+        if let Some(size) = self.send_buffer {
+            set_int_option(fd, libc::SOL_SOCKET, SO_SNDBUF, size as libc::c_int)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            set_int_option(fd, libc::SOL_SOCKET, SO_RCVBUF, size as libc::c_int)?;
+        }
+        Ok(())
+    }
+
+    /// Set only the address and port reuse options, which UDP sockets take too
+    pub fn apply_reuse(&self, fd: RawFd) -> std::io::Result<()> {
+        set_int_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, self.reuse_address as libc::c_int)?;
+        set_int_option(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, self.reuse_port as libc::c_int)
+    }
+}
+
+fn set_int_option(fd: RawFd, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> std::io::Result<()> {
+    unsafe {
+        if setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as u32,
+        ) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Listen on `addr` with `options` applied before the bind
+pub fn bind_with_options(addr: SocketAddr, options: &TcpTuningOptions) -> std::io::Result<TcpListener> {
+    let domain = if addr.is_ipv6() { socket2::Domain::IPV6 } else { socket2::Domain::IPV4 };
+    let socket = socket2::Socket::new(domain, socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
+    options.apply(socket.as_raw_fd())?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// UDP socket on `addr` with the reuse options of `options` applied before
+/// the bind, so several listeners (SSDP, mDNS) can share a well-known port
+pub fn bind_udp_with_options(addr: SocketAddr, options: &TcpTuningOptions) -> std::io::Result<UdpSocket> {
+    let domain = if addr.is_ipv6() { socket2::Domain::IPV6 } else { socket2::Domain::IPV4 };
+    let socket = socket2::Socket::new(domain, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+    options.apply_reuse(socket.as_raw_fd())?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
 /// Mobile-specific TCP option handling
 pub struct MobileTcpOptions {
     pub mss: Option<u16>,
@@ -477,9 +696,81 @@ mod tests {
         assert_eq!(encoded.len() % 4, 0);
     }
     
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_tcp_mss_clamp_reads_back() {
+        use crate::syscall_net::{socket_close, socket_connect, socket_create};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = match listener.local_addr().unwrap() {
+            std::net::SocketAddr::V4(addr) => addr,
+            other => panic!("expected IPv4 listener, got {}", other),
+        };
+        let fd = socket_create(libc::AF_INET, libc::SOCK_STREAM, 0).unwrap();
+        set_tcp_mss(fd, 1200).unwrap();
+        assert_eq!(tcp_mss(fd).unwrap(), 1200);
+
+        // Loopback's MTU is far larger, so the connected MSS stays clamped
+        socket_connect(fd, &addr).unwrap();
+        let connected = tcp_mss(fd).unwrap();
+        assert!(connected > 0 && connected <= 1200, "connected MSS {}", connected);
+        socket_close(fd).unwrap();
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_bind_to_device_reads_back() {
+        use crate::syscall_net::{socket_close, socket_create};
+
+        let fd = socket_create(libc::AF_INET, libc::SOCK_STREAM, 0).unwrap();
+        assert_eq!(bound_device(fd).unwrap(), None);
+        bind_to_device(fd, "lo").unwrap();
+        assert_eq!(bound_device(fd).unwrap().as_deref(), Some("lo"));
+
+        let err = bind_to_device(fd, "nosuchif0").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENODEV));
+        let err = bind_to_device(fd, "an-interface-name-too-long").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        socket_close(fd).unwrap();
+    }
+
+    #[test]
+    fn test_reuse_port_listeners_share_a_port() {
+        let options = TcpTuningOptions { reuse_address: true, reuse_port: true, ..Default::default() };
+        let first = bind_with_options("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind_with_options(addr, &options).unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+
+        // Both accept: the kernel hands the connection to one of them
+        let _client = TcpStream::connect(addr).unwrap();
+        first.set_nonblocking(true).unwrap();
+        second.set_nonblocking(true).unwrap();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+        while first.accept().is_err() && second.accept().is_err() {
+            assert!(std::time::Instant::now() < deadline, "neither listener accepted");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        // Without SO_REUSEPORT the port is taken
+        let err = bind_with_options(addr, &TcpTuningOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+
+        // UDP sockets share a port the same way
+        let udp = bind_udp_with_options("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        bind_udp_with_options(udp.local_addr().unwrap(), &options).unwrap();
+
+        // Accepted connections inherit the listener's keepalive
+        let keepalive = TcpTuningOptions { keepalive: Some(Duration::from_secs(60)), ..TcpTuningOptions::listener() };
+        let listener = bind_with_options("127.0.0.1:0".parse().unwrap(), &keepalive).unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        assert!(socket2::SockRef::from(&accepted).keepalive().unwrap());
+    }
+
     #[test]
     fn test_isn_generation() {
-        let manager = TcpFingerprintManager::new();
+        let mut manager = TcpFingerprintManager::new();
         let isn1 = manager.generate_mobile_isn();
         let isn2 = manager.generate_mobile_isn();
         
@@ -488,5 +779,11 @@ mod tests {
         
         // Should be non-zero (extremely unlikely to be zero)
         assert_ne!(isn1, 0);
+        
+        // Reproducible managers agree, yet keep drawing from one stream
+        let mut a = TcpFingerprintManager::new().deterministic();
+        let b = TcpFingerprintManager::new().deterministic();
+        assert_eq!(format!("{:?}", a.current_profile()), format!("{:?}", b.current_profile()));
+        assert_ne!(a.generate_mobile_isn() & 0xffff, a.generate_mobile_isn() & 0xffff);
     }
 }
\ No newline at end of file
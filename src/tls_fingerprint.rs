@@ -2,8 +2,12 @@
 // Mimics mobile browser TLS behavior to evade detection
 
 use std::collections::HashMap;
+use std::io;
+use std::path::Path;
 use std::time::{SystemTime, UNIX_EPOCH};
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 
 /// TLS cipher suites commonly used by mobile browsers
@@ -29,6 +33,7 @@ pub const MOBILE_TLS_EXTENSIONS: &[u16] = &[
     0x0000, // server_name (SNI)
     0x000b, // ec_point_formats
     0x000a, // supported_groups
+    0x000d, // signature_algorithms
     0x0023, // session_ticket
     0x0010, // application_layer_protocol_negotiation
     0x0005, // status_request (OCSP stapling)
@@ -49,16 +54,29 @@ pub const MOBILE_ELLIPTIC_CURVES: &[u16] = &[
 ];
 
 /// Mobile browser profiles for TLS fingerprinting
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum MobileBrowserProfile {
     Safari17,
     Chrome120Mobile,
     Firefox121Mobile,
     Samsung21,
     Edge120Mobile,
+    /// A profile loaded by `TlsFingerprintManager::from_profiles_file`
+    Custom { name: String, fingerprint: Box<TlsFingerprint> },
 }
 
 impl MobileBrowserProfile {
+    pub fn name(&self) -> &str {
+        match self {
+            MobileBrowserProfile::Safari17 => "Safari17",
+            MobileBrowserProfile::Chrome120Mobile => "Chrome120Mobile",
+            MobileBrowserProfile::Firefox121Mobile => "Firefox121Mobile",
+            MobileBrowserProfile::Samsung21 => "Samsung21",
+            MobileBrowserProfile::Edge120Mobile => "Edge120Mobile",
+            MobileBrowserProfile::Custom { name, .. } => name,
+        }
+    }
+
     /// Get TLS fingerprint characteristics for browser
     pub fn get_tls_fingerprint(&self) -> TlsFingerprint {
         match self {
@@ -70,7 +88,7 @@ impl MobileBrowserProfile {
                 ],
                 extensions: vec![
                     0x0000, 0x000b, 0x000a, 0x0023, 0x0010,
-                    0x0005, 0x0033, 0x002b, 0x0029
+                    0x0005, 0x000d, 0x0033, 0x002b, 0x001b, 0x0029
                 ],
                 elliptic_curves: vec![0x001d, 0x0017, 0x0018],
                 signature_algorithms: vec![0x0403, 0x0503, 0x0603, 0x0804, 0x0805, 0x0806],
@@ -87,7 +105,7 @@ impl MobileBrowserProfile {
                 ],
                 extensions: vec![
                     0x0000, 0x000b, 0x000a, 0x0023, 0x0010, 0x0005,
-                    0x0012, 0x0033, 0x002b, 0x002a, 0x001b, 0x0029
+                    0x000d, 0x0012, 0x0033, 0x002b, 0x002a, 0x001b, 0x0029
                 ],
                 elliptic_curves: vec![0x001d, 0x0017, 0x0018, 0x0019],
                 signature_algorithms: vec![0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501],
@@ -102,9 +120,10 @@ impl MobileBrowserProfile {
                     0x1301, 0x1302, 0x1303,
                     0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8
                 ],
+                // Groups before point formats and signature algorithms late, as Firefox sends them
                 extensions: vec![
-                    0x0000, 0x000b, 0x000a, 0x0023, 0x0010,
-                    0x0033, 0x002b, 0x0029
+                    0x0000, 0x000a, 0x000b, 0x0023, 0x0010,
+                    0x0033, 0x002b, 0x000d, 0x0029
                 ],
                 elliptic_curves: vec![0x001d, 0x0017, 0x0018],
                 signature_algorithms: vec![0x0403, 0x0503, 0x0603, 0x0804, 0x0805, 0x0806],
@@ -121,7 +140,7 @@ impl MobileBrowserProfile {
                 ],
                 extensions: vec![
                     0x0000, 0x000b, 0x000a, 0x0023, 0x0010,
-                    0x0033, 0x002b, 0x0029
+                    0x000d, 0x0033, 0x002b, 0x0029
                 ],
                 elliptic_curves: vec![0x001d, 0x0017, 0x0018],
                 signature_algorithms: vec![0x0403, 0x0503, 0x0804, 0x0805],
@@ -138,7 +157,7 @@ impl MobileBrowserProfile {
                 ],
                 extensions: vec![
                     0x0000, 0x000b, 0x000a, 0x0023, 0x0010, 0x0005,
-                    0x0033, 0x002b, 0x002a, 0x0029
+                    0x000d, 0x0033, 0x002b, 0x002a, 0x001b, 0x0029
                 ],
                 elliptic_curves: vec![0x001d, 0x0017, 0x0018, 0x0019],
                 signature_algorithms: vec![0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501],
@@ -147,12 +166,13 @@ impl MobileBrowserProfile {
                 early_data: true,
                 session_ticket: true,
             },
+            MobileBrowserProfile::Custom { fingerprint, .. } => (**fingerprint).clone(),
         }
     }
 }
 
 /// TLS version enumeration
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum TlsVersion {
     Tls12,
     Tls13,
@@ -168,7 +188,7 @@ impl TlsVersion {
 }
 
 /// Complete TLS fingerprint configuration
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct TlsFingerprint {
     pub tls_version: TlsVersion,
     pub cipher_suites: Vec<u16>,
@@ -181,41 +201,205 @@ pub struct TlsFingerprint {
     pub session_ticket: bool,
 }
 
+/// One entry of a profiles file: a named fingerprint and how often
+/// rotation picks it relative to the other entries
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WeightedProfile {
+    pub name: String,
+    pub weight: u32,
+    #[serde(flatten)]
+    pub fingerprint: TlsFingerprint,
+}
+
+/// Built-in profiles weighted by mobile market share
+fn builtin_profile_weights() -> Vec<(MobileBrowserProfile, u32)> {
+    vec![
+        (MobileBrowserProfile::Chrome120Mobile, 65), // 65% market share
+        (MobileBrowserProfile::Safari17, 25),        // 25% market share
+        (MobileBrowserProfile::Samsung21, 5),        // 5% market share
+        (MobileBrowserProfile::Edge120Mobile, 3),    // 3% market share
+        (MobileBrowserProfile::Firefox121Mobile, 2), // 2% market share
+    ]
+}
+
+/// Longest DNS hostname (RFC 1035), the default SNI limit
+pub const MAX_SNI_LEN: usize = 253;
+
+/// Largest name whose server_name extension_data (name + 5 bytes) fits a u16 length
+pub const SNI_HARD_CAP: usize = 65530;
+
+/// Errors building a ClientHello
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TlsFingerprintError {
+    ServerNameTooLong { len: usize, max: usize },
+    HelloTooLarge(usize),
+    /// The system RNG could not produce a key share
+    KeyGeneration,
+}
+
+impl std::fmt::Display for TlsFingerprintError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TlsFingerprintError::ServerNameTooLong { len, max } => write!(f, "SNI of {} bytes exceeds limit of {}", len, max),
+            TlsFingerprintError::HelloTooLarge(len) => write!(f, "ClientHello of {} bytes does not fit a TLS record", len),
+            TlsFingerprintError::KeyGeneration => write!(f, "Failed to generate a key share"),
+        }
+    }
+}
+
+impl std::error::Error for TlsFingerprintError {}
+
+/// Piece of a cached ClientHello extension block
+#[derive(Debug, Clone)]
+enum HelloPart {
+    /// Bytes identical for every hello of the profile
+    Static(Vec<u8>),
+    /// server_name extension, filled in per connection
+    ServerName,
+    /// x25519 key_share, generated per connection
+    KeyShare,
+}
+
+/// Close off the static bytes gathered so far and append `part` after them
+fn push_per_connection(extensions: &mut Vec<HelloPart>, fixed: &mut Vec<u8>, part: HelloPart) {
+    if !fixed.is_empty() {
+        extensions.push(HelloPart::Static(std::mem::take(fixed)));
+    }
+    extensions.push(part);
+}
+
+/// Profile-specific ClientHello layout, built once and patched per connection
+#[derive(Debug, Clone)]
+struct HelloTemplate {
+    version: [u8; 2],
+    /// Session ID, cipher suites and compression methods
+    body: Vec<u8>,
+    extensions: Vec<HelloPart>,
+}
+
 /// TLS fingerprint manager for Knox bypass
 pub struct TlsFingerprintManager {
     current_profile: MobileBrowserProfile,
+    /// Profiles rotation picks from, with their weights
+    profile_weights: Vec<(MobileBrowserProfile, u32)>,
     rotation_enabled: bool,
     profile_history: Vec<(SystemTime, MobileBrowserProfile)>,
     ja3_cache: HashMap<String, String>,
+    template_cache: HashMap<MobileBrowserProfile, HelloTemplate>,
+    max_sni_len: usize,
+    shuffle_ciphers: bool,
+    cipher_rng: StdRng,
+    /// Profile picks, rotation intervals, hello random and, in deterministic
+    /// mode, the key share
+    rng: StdRng,
+    deterministic: bool,
+    last_cipher_order: Option<Vec<u16>>,
+    alpn_override: Option<Vec<String>>,
 }
 
 impl TlsFingerprintManager {
     pub fn new() -> Self {
+        let profile_weights = builtin_profile_weights();
+        let mut rng = crate::determinism::rng();
         Self {
-            current_profile: Self::select_weighted_profile(),
+            current_profile: Self::select_weighted_profile(&mut rng, &profile_weights),
+            profile_weights,
             rotation_enabled: true,
             profile_history: Vec::new(),
             ja3_cache: HashMap::new(),
+            template_cache: HashMap::new(),
+            max_sni_len: MAX_SNI_LEN,
+            shuffle_ciphers: false,
+            cipher_rng: crate::determinism::rng(),
+            rng,
+            deterministic: crate::determinism::is_deterministic(),
+            last_cipher_order: None,
+            alpn_override: None,
         }
     }
     
-    /// Select browser profile based on mobile market share
-    fn select_weighted_profile() -> MobileBrowserProfile {
-        let mut rng = rand::thread_rng();
-        let profiles = vec![
-            (MobileBrowserProfile::Chrome120Mobile, 65), // 65% market share
-            (MobileBrowserProfile::Safari17, 25),        // 25% market share
-            (MobileBrowserProfile::Samsung21, 5),        // 5% market share
-            (MobileBrowserProfile::Edge120Mobile, 3),    // 3% market share
-            (MobileBrowserProfile::Firefox121Mobile, 2), // 2% market share
-        ];
-        
+    /// Reproducible manager regardless of `LITEBIKE_DETERMINISTIC`: profile
+    /// choice, cipher shuffle, hello random and key share all come from the
+    /// fixed seed, so two such managers emit identical ClientHellos. The key
+    /// share is then plain random bytes, not a usable x25519 key.
+    pub fn deterministic(mut self) -> Self {
+        self.rng = crate::determinism::seeded_rng();
+        self.cipher_rng = crate::determinism::seeded_rng();
+        self.deterministic = true;
+        if self.rotation_enabled {
+            self.current_profile = Self::select_weighted_profile(&mut self.rng, &self.profile_weights);
+            self.profile_history.clear();
+            self.ja3_cache.clear();
+        }
+        self
+    }
+    
+    /// Manager pinned to `profile` for long-lived sessions: `maybe_rotate_profile`
+    /// never changes it, so an established tunnel keeps one fingerprint
+    pub fn sticky(profile: MobileBrowserProfile) -> Self {
+        Self {
+            current_profile: profile,
+            rotation_enabled: false,
+            ..Self::new()
+        }
+    }
+    
+    /// Manager rotating through the profiles in the JSON file at `path`
+    /// instead of the built-in ones: an array of `WeightedProfile`, i.e. a
+    /// `TlsFingerprint` with a `name` and a `weight`. Fails with
+    /// `InvalidData` on malformed JSON or when no entry has a non-zero weight.
+    pub fn from_profiles_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        let profiles: Vec<WeightedProfile> =
+            serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if profiles.iter().all(|p| p.weight == 0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "no profile has a non-zero weight"));
+        }
+        let profile_weights: Vec<_> = profiles
+            .into_iter()
+            .map(|p| (MobileBrowserProfile::Custom { name: p.name, fingerprint: Box::new(p.fingerprint) }, p.weight))
+            .collect();
+        let mut manager = Self::new();
+        manager.current_profile = Self::select_weighted_profile(&mut manager.rng, &profile_weights);
+        manager.profile_weights = profile_weights;
+        Ok(manager)
+    }
+    
+    /// Limit accepted SNI length; values above `SNI_HARD_CAP` are clamped
+    pub fn set_max_sni_len(&mut self, max: usize) {
+        self.max_sni_len = max.min(SNI_HARD_CAP);
+    }
+    
+    /// Permute the cipher list on every `generate_client_hello` so no two
+    /// hellos share a JA3. Off by default, which keeps the profile's order.
+    pub fn set_shuffle_ciphers(&mut self, enabled: bool) {
+        self.shuffle_ciphers = enabled;
+        self.last_cipher_order = None;
+        self.ja3_cache.clear();
+    }
+    
+    /// Offer `alpn` instead of the profile's ALPN list; `None` restores the profile's
+    pub fn set_alpn(&mut self, alpn: Option<Vec<String>>) {
+        self.alpn_override = alpn;
+        self.template_cache.clear();
+    }
+    
+    /// Make the cipher shuffle reproducible
+    pub fn seed_cipher_shuffle(&mut self, seed: u64) {
+        self.cipher_rng = StdRng::seed_from_u64(seed);
+    }
+    
+    /// Select a profile from `profiles` with probability proportional to its weight
+    fn select_weighted_profile(rng: &mut StdRng, profiles: &[(MobileBrowserProfile, u32)]) -> MobileBrowserProfile {
         let total_weight: u32 = profiles.iter().map(|(_, w)| w).sum();
+        if total_weight == 0 {
+            return MobileBrowserProfile::Chrome120Mobile;
+        }
         let mut choice = rng.gen_range(0..total_weight);
         
         for (profile, weight) in profiles {
-            if choice < weight {
-                return profile;
+            if choice < *weight {
+                return profile.clone();
             }
             choice -= weight;
         }
@@ -234,10 +418,10 @@ impl TlsFingerprintManager {
         // Rotate every 15-45 minutes randomly
         if let Some((last_rotation, _)) = self.profile_history.last() {
             let elapsed = now.duration_since(*last_rotation).unwrap_or_default();
-            let rotation_interval = rand::thread_rng().gen_range(900..2700); // 15-45 min
+            let rotation_interval = self.rng.gen_range(900..2700); // 15-45 min
             
             if elapsed.as_secs() > rotation_interval {
-                let new_profile = Self::select_weighted_profile();
+                let new_profile = Self::select_weighted_profile(&mut self.rng, &self.profile_weights);
                 self.profile_history.push((now, new_profile.clone()));
                 self.current_profile = new_profile;
                 
@@ -254,14 +438,91 @@ impl TlsFingerprintManager {
         }
     }
     
-    /// Generate TLS ClientHello based on current profile
-    pub fn generate_client_hello(&mut self, server_name: &str) -> Vec<u8> {
-        let fingerprint = self.current_profile.get_tls_fingerprint();
+    /// Build the static portions of the current profile's ClientHello
+    fn build_template(&self, fingerprint: &TlsFingerprint) -> HelloTemplate {
+        let mut body = Vec::new();
+        
+        // Session ID (empty for simplicity)
+        body.push(0x00);
+        
+        // Cipher Suites
+        let cipher_suites_len = (fingerprint.cipher_suites.len() * 2) as u16;
+        body.extend_from_slice(&cipher_suites_len.to_be_bytes());
+        for &cipher in &fingerprint.cipher_suites {
+            body.extend_from_slice(&cipher.to_be_bytes());
+        }
+        
+        // Compression Methods (null compression)
+        body.extend_from_slice(&[0x01, 0x00]);
+        
+        // Extensions in the profile's order; SNI and the key share are patched
+        // in per connection. pre_shared_key needs a ticket and is left out.
+        let tls13 = matches!(fingerprint.tls_version, TlsVersion::Tls13);
+        let mut extensions = Vec::new();
+        let mut fixed = Vec::new();
+        for &id in &fingerprint.extensions {
+            match id {
+                0x0000 => push_per_connection(&mut extensions, &mut fixed, HelloPart::ServerName),
+                0x0033 if tls13 => push_per_connection(&mut extensions, &mut fixed, HelloPart::KeyShare),
+                0x002b if tls13 => self.add_supported_versions_extension(&mut fixed),
+                0x0005 => self.add_status_request_extension(&mut fixed),
+                0x000a => self.add_supported_groups_extension(&mut fixed, fingerprint),
+                0x000b => self.add_ec_point_formats_extension(&mut fixed),
+                0x000d => self.add_signature_algorithms_extension(&mut fixed, fingerprint),
+                0x0010 => self.add_alpn_extension(&mut fixed, fingerprint),
+                0x0012 => self.add_sct_extension(&mut fixed),
+                0x001b if fingerprint.compress_certificate => self.add_compress_certificate_extension(&mut fixed),
+                0x0023 if fingerprint.session_ticket => self.add_session_ticket_extension(&mut fixed),
+                0x002a if fingerprint.early_data => self.add_early_data_extension(&mut fixed),
+                _ => {}
+            }
+        }
+        if !fixed.is_empty() {
+            extensions.push(HelloPart::Static(fixed));
+        }
+        
+        HelloTemplate {
+            // TLS 1.3 is offered in supported_versions; legacy_version stays
+            // at 1.2 as RFC 8446 §4.1.2 requires
+            version: TlsVersion::Tls12.to_bytes(),
+            body,
+            extensions,
+        }
+    }
+    
+    /// Generate TLS ClientHello based on current profile. The static portion is
+    /// cached per profile; only the random and SNI differ between calls.
+    /// Server names longer than the configured limit are rejected.
+    pub fn generate_client_hello(&mut self, server_name: &str) -> Result<Vec<u8>, TlsFingerprintError> {
+        if !self.template_cache.contains_key(&self.current_profile) {
+            let mut fingerprint = self.current_profile.get_tls_fingerprint();
+            if let Some(ref alpn) = self.alpn_override {
+                fingerprint.alpn_protocols = alpn.clone();
+            }
+            let template = self.build_template(&fingerprint);
+            self.template_cache.insert(self.current_profile.clone(), template);
+        }
+        let template = &self.template_cache[&self.current_profile];
+        
+        // Session ID (1) + cipher suites length (2), then the suites
+        let mut body = template.body.clone();
+        if self.shuffle_ciphers {
+            let count = u16::from_be_bytes([body[1], body[2]]) as usize / 2;
+            let mut ciphers: Vec<u16> = body[3..3 + count * 2]
+                .chunks(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            ciphers.shuffle(&mut self.cipher_rng);
+            for (slot, cipher) in body[3..3 + count * 2].chunks_mut(2).zip(&ciphers) {
+                slot.copy_from_slice(&cipher.to_be_bytes());
+            }
+            self.last_cipher_order = Some(ciphers);
+        }
         let mut client_hello = Vec::new();
         
         // TLS Record Header
         client_hello.push(0x16); // Content Type: Handshake
-        client_hello.extend_from_slice(&fingerprint.tls_version.to_bytes());
+        client_hello.extend_from_slice(&template.version);
         
         // Handshake message will be filled in
         let handshake_start = client_hello.len();
@@ -273,31 +534,22 @@ impl TlsFingerprintManager {
         client_hello.extend_from_slice(&[0x00, 0x00, 0x00]); // Length placeholder
         
         // Client Hello content
-        client_hello.extend_from_slice(&fingerprint.tls_version.to_bytes()); // Version
+        client_hello.extend_from_slice(&template.version); // Version
         
-        // Random (32 bytes)
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs() as u32;
-        client_hello.extend_from_slice(&timestamp.to_be_bytes());
-        
-        let mut random_bytes = [0u8; 28];
-        rand::thread_rng().fill(&mut random_bytes);
-        client_hello.extend_from_slice(&random_bytes);
-        
-        // Session ID (empty for simplicity)
-        client_hello.push(0x00);
-        
-        // Cipher Suites
-        let cipher_suites_len = (fingerprint.cipher_suites.len() * 2) as u16;
-        client_hello.extend_from_slice(&cipher_suites_len.to_be_bytes());
-        for &cipher in &fingerprint.cipher_suites {
-            client_hello.extend_from_slice(&cipher.to_be_bytes());
+        // Random (32 bytes); no clock in deterministic mode
+        let mut random_bytes = [0u8; 32];
+        self.rng.fill(&mut random_bytes);
+        if !self.deterministic {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs() as u32;
+            random_bytes[..4].copy_from_slice(&timestamp.to_be_bytes());
         }
+        client_hello.extend_from_slice(&random_bytes);
         
-        // Compression Methods (null compression)
-        client_hello.extend_from_slice(&[0x01, 0x00]);
+        // Session ID, cipher suites, compression methods
+        client_hello.extend_from_slice(&body);
         
         // Extensions
         let extensions_start = client_hello.len();
@@ -305,22 +557,15 @@ impl TlsFingerprintManager {
         
         let extensions_content_start = client_hello.len();
         
-        // Add extensions based on profile
-        self.add_sni_extension(&mut client_hello, server_name);
-        self.add_supported_groups_extension(&mut client_hello, &fingerprint);
-        self.add_signature_algorithms_extension(&mut client_hello, &fingerprint);
-        self.add_alpn_extension(&mut client_hello, &fingerprint);
-        
-        if fingerprint.session_ticket {
-            self.add_session_ticket_extension(&mut client_hello);
-        }
-        
-        if fingerprint.early_data {
-            self.add_early_data_extension(&mut client_hello);
-        }
-        
-        if fingerprint.compress_certificate {
-            self.add_compress_certificate_extension(&mut client_hello);
+        for part in &template.extensions {
+            match part {
+                HelloPart::Static(bytes) => client_hello.extend_from_slice(bytes),
+                HelloPart::ServerName => self.add_sni_extension(&mut client_hello, server_name)?,
+                HelloPart::KeyShare => {
+                    let rng = if self.deterministic { Some(&mut self.rng) } else { None };
+                    Self::add_key_share_extension(&mut client_hello, rng)?
+                }
+            }
         }
         
         // Update extensions length
@@ -337,16 +582,23 @@ impl TlsFingerprintManager {
         client_hello[hello_start + 2] = handshake_len_bytes[2];
         
         // Update record length
-        let record_len = client_hello.len() - handshake_start;
+        let record_len = client_hello.len() - handshake_start - 2;
+        if extensions_len > u16::MAX as usize || record_len > u16::MAX as usize {
+            return Err(TlsFingerprintError::HelloTooLarge(record_len));
+        }
         let record_len_bytes = (record_len as u16).to_be_bytes();
         client_hello[handshake_start] = record_len_bytes[0];
         client_hello[handshake_start + 1] = record_len_bytes[1];
         
-        client_hello
+        Ok(client_hello)
     }
     
     /// Add Server Name Indication (SNI) extension
-    fn add_sni_extension(&self, client_hello: &mut Vec<u8>, server_name: &str) {
+    fn add_sni_extension(&self, client_hello: &mut Vec<u8>, server_name: &str) -> Result<(), TlsFingerprintError> {
+        if server_name.len() > self.max_sni_len {
+            return Err(TlsFingerprintError::ServerNameTooLong { len: server_name.len(), max: self.max_sni_len });
+        }
+        
         client_hello.extend_from_slice(&[0x00, 0x00]); // Extension type: SNI
         
         let sni_len = 5 + server_name.len();
@@ -358,6 +610,7 @@ impl TlsFingerprintManager {
         client_hello.push(0x00); // Name type: hostname
         client_hello.extend_from_slice(&(server_name.len() as u16).to_be_bytes());
         client_hello.extend_from_slice(server_name.as_bytes());
+        Ok(())
     }
     
     /// Add supported groups (elliptic curves) extension
@@ -411,6 +664,58 @@ impl TlsFingerprintManager {
         client_hello.extend_from_slice(&protocols_data);
     }
     
+    /// Add supported versions extension offering TLS 1.3, then 1.2
+    fn add_supported_versions_extension(&self, client_hello: &mut Vec<u8>) {
+        client_hello.extend_from_slice(&[0x00, 0x2b]); // Extension type: supported_versions
+        client_hello.extend_from_slice(&[0x00, 0x05]); // Extension length
+        client_hello.push(0x04); // Versions length
+        client_hello.extend_from_slice(&TlsVersion::Tls13.to_bytes());
+        client_hello.extend_from_slice(&TlsVersion::Tls12.to_bytes());
+    }
+    
+    /// Add key share extension with a fresh x25519 public key. The private
+    /// half is dropped: the hello only has to look like a browser's. With
+    /// `seeded` the key is 32 bytes drawn from it instead.
+    fn add_key_share_extension(client_hello: &mut Vec<u8>, seeded: Option<&mut StdRng>) -> Result<(), TlsFingerprintError> {
+        let public = match seeded {
+            Some(rng) => rng.gen::<[u8; 32]>().to_vec(),
+            None => ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, &ring::rand::SystemRandom::new())
+                .and_then(|private| private.compute_public_key())
+                .map_err(|_| TlsFingerprintError::KeyGeneration)?
+                .as_ref()
+                .to_vec(),
+        };
+        let key = public.as_slice();
+        
+        client_hello.extend_from_slice(&[0x00, 0x33]); // Extension type: key_share
+        client_hello.extend_from_slice(&((2 + 4 + key.len()) as u16).to_be_bytes());
+        client_hello.extend_from_slice(&((4 + key.len()) as u16).to_be_bytes()); // Client shares length
+        client_hello.extend_from_slice(&[0x00, 0x1d]); // Group: x25519
+        client_hello.extend_from_slice(&(key.len() as u16).to_be_bytes());
+        client_hello.extend_from_slice(key);
+        Ok(())
+    }
+    
+    /// Add ec_point_formats extension offering uncompressed points only
+    fn add_ec_point_formats_extension(&self, client_hello: &mut Vec<u8>) {
+        client_hello.extend_from_slice(&[0x00, 0x0b]); // Extension type: ec_point_formats
+        client_hello.extend_from_slice(&[0x00, 0x02]); // Extension length
+        client_hello.extend_from_slice(&[0x01, 0x00]); // One format: uncompressed
+    }
+    
+    /// Add status request (OCSP stapling) extension
+    fn add_status_request_extension(&self, client_hello: &mut Vec<u8>) {
+        client_hello.extend_from_slice(&[0x00, 0x05]); // Extension type: status_request
+        client_hello.extend_from_slice(&[0x00, 0x05]); // Extension length
+        client_hello.extend_from_slice(&[0x01, 0x00, 0x00, 0x00, 0x00]); // OCSP, no responder IDs or extensions
+    }
+    
+    /// Add signed certificate timestamp extension
+    fn add_sct_extension(&self, client_hello: &mut Vec<u8>) {
+        client_hello.extend_from_slice(&[0x00, 0x12]); // Extension type: signed_certificate_timestamp
+        client_hello.extend_from_slice(&[0x00, 0x00]); // Empty extension
+    }
+    
     /// Add session ticket extension
     fn add_session_ticket_extension(&self, client_hello: &mut Vec<u8>) {
         client_hello.extend_from_slice(&[0x00, 0x23]); // Extension type: session_ticket
@@ -423,44 +728,32 @@ impl TlsFingerprintManager {
         client_hello.extend_from_slice(&[0x00, 0x00]); // Empty extension
     }
     
-    /// Add compress certificate extension
+    /// Add compress certificate extension offering brotli
     fn add_compress_certificate_extension(&self, client_hello: &mut Vec<u8>) {
         client_hello.extend_from_slice(&[0x00, 0x1b]); // Extension type: compress_certificate
-        client_hello.extend_from_slice(&[0x00, 0x02]); // Extension length
+        client_hello.extend_from_slice(&[0x00, 0x03]); // Extension length
+        client_hello.push(0x02); // Algorithms length
         client_hello.extend_from_slice(&[0x00, 0x02]); // brotli compression
     }
     
-    /// Generate JA3 fingerprint for current configuration
-    pub fn generate_ja3_fingerprint(&mut self, server_name: &str) -> String {
-        if let Some(cached) = self.ja3_cache.get(server_name) {
-            return cached.clone();
+    /// JA3 of the ClientHello `generate_client_hello` sends for `server_name`,
+    /// taken from the hello's own bytes so the two cannot disagree.
+    /// With cipher shuffling every call hashes a freshly shuffled hello and
+    /// nothing is cached.
+    pub fn generate_ja3_fingerprint(&mut self, server_name: &str) -> Result<String, TlsFingerprintError> {
+        if !self.shuffle_ciphers {
+            if let Some(cached) = self.ja3_cache.get(server_name) {
+                return Ok(cached.clone());
+            }
         }
         
-        let fingerprint = self.current_profile.get_tls_fingerprint();
-        
-        // JA3 format: version,ciphers,extensions,elliptic_curves,ec_point_formats
-        let version = match fingerprint.tls_version {
-            TlsVersion::Tls12 => "771",
-            TlsVersion::Tls13 => "772",
-        };
-        
-        let ciphers: Vec<String> = fingerprint.cipher_suites.iter().map(|c| c.to_string()).collect();
-        let extensions: Vec<String> = fingerprint.extensions.iter().map(|e| e.to_string()).collect();
-        let curves: Vec<String> = fingerprint.elliptic_curves.iter().map(|c| c.to_string()).collect();
-        
-        let ja3_string = format!(
-            "{},{},{},{},",
-            version,
-            ciphers.join("-"),
-            extensions.join("-"),
-            curves.join("-")
-        );
+        let hello = self.generate_client_hello(server_name)?;
+        let ja3_hash = ja3_from_client_hello(&hello).expect("generated ClientHellos parse");
         
-        // Simple hash (in practice, would use MD5)
-        let ja3_hash = format!("{:x}", calculate_simple_hash(&ja3_string));
-        
-        self.ja3_cache.insert(server_name.to_string(), ja3_hash.clone());
-        ja3_hash
+        if !self.shuffle_ciphers {
+            self.ja3_cache.insert(server_name.to_string(), ja3_hash.clone());
+        }
+        Ok(ja3_hash)
     }
     
     /// Get current browser profile
@@ -470,7 +763,7 @@ impl TlsFingerprintManager {
     
     /// Force profile rotation
     pub fn force_rotation(&mut self) {
-        let new_profile = Self::select_weighted_profile();
+        let new_profile = Self::select_weighted_profile(&mut self.rng, &self.profile_weights);
         self.profile_history.push((SystemTime::now(), new_profile.clone()));
         self.current_profile = new_profile;
         self.ja3_cache.clear();
@@ -479,7 +772,7 @@ impl TlsFingerprintManager {
     /// Get profile statistics
     pub fn get_stats(&self) -> TlsFingerprintStats {
         TlsFingerprintStats {
-            current_profile: format!("{:?}", self.current_profile),
+            current_profile: self.current_profile.name().to_string(),
             rotations_count: self.profile_history.len(),
             ja3_cache_size: self.ja3_cache.len(),
             rotation_enabled: self.rotation_enabled,
@@ -502,13 +795,244 @@ pub struct TlsFingerprintStats {
     pub rotation_enabled: bool,
 }
 
-/// Simple hash function for JA3 (replace with MD5 in production)
-fn calculate_simple_hash(input: &str) -> u64 {
-    let mut hash = 0u64;
-    for byte in input.bytes() {
-        hash = hash.wrapping_mul(31).wrapping_add(byte as u64);
+/// JA3 digest of a JA3 string: its MD5 as lowercase hex
+fn ja3_hash(ja3_string: &str) -> String {
+    md5(ja3_string.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The JA3 fields "version,ciphers,extensions,elliptic_curves,ec_point_formats"
+/// in decimal, lists dash-joined and GREASE values dropped
+fn ja3_string(version: u16, ciphers: &[u16], extensions: &[u16], curves: &[u16], point_formats: &[u8]) -> String {
+    fn join(values: &[u16]) -> String {
+        values.iter().filter(|&&v| !is_grease(v)).map(|v| v.to_string()).collect::<Vec<_>>().join("-")
+    }
+    let point_formats: Vec<String> = point_formats.iter().map(|v| v.to_string()).collect();
+    format!(
+        "{},{},{},{},{}",
+        version,
+        join(ciphers),
+        join(extensions),
+        join(curves),
+        point_formats.join("-")
+    )
+}
+
+const MD5_SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+const MD5_K: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+/// MD5 (RFC 1321). JA3 uses it as an identifier and Shadowsocks key
+/// derivation for compatibility, so its weakness as a hash does not matter here.
+pub(crate) fn md5(input: &[u8]) -> [u8; 16] {
+    let mut message = input.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((input.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for chunk in message.chunks_exact(64) {
+        let words: Vec<u32> = chunk.chunks_exact(4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]])).collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(MD5_K[i])
+                .wrapping_add(words[g])
+                .rotate_left(MD5_SHIFTS[(i / 16) * 4 + i % 4]);
+            (a, d, c) = (d, c, b);
+            b = b.wrapping_add(rotated);
+        }
+        for (word, add) in state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+
+    let mut digest = [0u8; 16];
+    for (out, word) in digest.chunks_exact_mut(4).zip(state) {
+        out.copy_from_slice(&word.to_le_bytes());
     }
-    hash
+    digest
+}
+
+/// GREASE values (RFC 8701) are left out of JA3
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// Bounds-checked reader over a ClientHello
+struct HelloReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> HelloReader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u16_list(data: &[u8]) -> Vec<u16> {
+        data.chunks_exact(2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]]))
+            .filter(|&v| !is_grease(v))
+            .collect()
+    }
+}
+
+/// A received ClientHello, extensions left raw (GREASE included)
+struct ParsedHello<'a> {
+    version: u16,
+    ciphers: Vec<u16>,
+    extensions: Vec<(u16, &'a [u8])>,
+}
+
+/// `record` is the first TLS record of the connection; `None` if it is not
+/// a complete ClientHello
+fn parse_client_hello(record: &[u8]) -> Option<ParsedHello<'_>> {
+    let mut reader = HelloReader { buf: record };
+    if reader.u8()? != 0x16 {
+        return None;
+    }
+    reader.take(4)?; // version, record length
+    if reader.u8()? != 0x01 {
+        return None;
+    }
+    let len = reader.take(3)?;
+    let len = (len[0] as usize) << 16 | (len[1] as usize) << 8 | len[2] as usize;
+    let mut hello = HelloReader { buf: reader.take(len)? };
+
+    let version = hello.u16()?;
+    hello.take(32)?; // random
+    let session_id_len = hello.u8()? as usize;
+    hello.take(session_id_len)?;
+    let cipher_len = hello.u16()? as usize;
+    let ciphers = HelloReader::u16_list(hello.take(cipher_len)?);
+    let compression_len = hello.u8()? as usize;
+    hello.take(compression_len)?;
+
+    let mut extensions = Vec::new();
+    if !hello.buf.is_empty() {
+        let ext_len = hello.u16()? as usize;
+        let mut exts = HelloReader { buf: hello.take(ext_len)? };
+        while !exts.buf.is_empty() {
+            let ext_type = exts.u16()?;
+            let data_len = exts.u16()? as usize;
+            extensions.push((ext_type, exts.take(data_len)?));
+        }
+    }
+    Some(ParsedHello { version, ciphers, extensions })
+}
+
+/// JA3 of a ClientHello received from a client, hashed the same way as
+/// `generate_ja3_fingerprint`. `record` is the first TLS record of the
+/// connection; `None` if it is not a complete ClientHello.
+pub fn ja3_from_client_hello(record: &[u8]) -> Option<String> {
+    ja3_string_from_client_hello(record).map(|ja3| ja3_hash(&ja3))
+}
+
+/// The unhashed JA3 string of a ClientHello record
+fn ja3_string_from_client_hello(record: &[u8]) -> Option<String> {
+    let hello = parse_client_hello(record)?;
+    let mut extensions = Vec::new();
+    let mut curves = Vec::new();
+    let mut point_formats = Vec::new();
+    for &(ext_type, data) in &hello.extensions {
+        if is_grease(ext_type) {
+            continue;
+        }
+        extensions.push(ext_type);
+        let mut data = HelloReader { buf: data };
+        match ext_type {
+            0x000a => {
+                let list_len = data.u16()? as usize;
+                curves = HelloReader::u16_list(data.take(list_len)?);
+            }
+            0x000b => {
+                let list_len = data.u8()? as usize;
+                point_formats = data.take(list_len)?.to_vec();
+            }
+            _ => {}
+        }
+    }
+    Some(ja3_string(hello.version, &hello.ciphers, &extensions, &curves, &point_formats))
+}
+
+/// What a client asks for in its ClientHello, for routing before any handshake
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientHelloSummary {
+    /// host_name entry of server_name
+    pub sni: Option<String>,
+    /// ALPN protocols in the client's preference order
+    pub alpn: Vec<String>,
+}
+
+impl ClientHelloSummary {
+    /// Whether the client offers HTTP/2 over TLS
+    pub fn offers_h2(&self) -> bool {
+        self.alpn.iter().any(|p| p == "h2")
+    }
+}
+
+/// SNI and ALPN of a received ClientHello; `None` if `record` is not a
+/// complete ClientHello, empty fields if it lacks the extensions
+pub fn summarize_client_hello(record: &[u8]) -> Option<ClientHelloSummary> {
+    let hello = parse_client_hello(record)?;
+    let mut summary = ClientHelloSummary::default();
+    for &(ext_type, data) in &hello.extensions {
+        let mut data = HelloReader { buf: data };
+        match ext_type {
+            0x0000 => {
+                let list_len = data.u16()? as usize;
+                let mut names = HelloReader { buf: data.take(list_len)? };
+                while !names.buf.is_empty() {
+                    let name_type = names.u8()?;
+                    let name_len = names.u16()? as usize;
+                    let name = names.take(name_len)?;
+                    if name_type == 0 {
+                        summary.sni = Some(String::from_utf8_lossy(name).into_owned());
+                    }
+                }
+            }
+            0x0010 => {
+                let list_len = data.u16()? as usize;
+                let mut protocols = HelloReader { buf: data.take(list_len)? };
+                while !protocols.buf.is_empty() {
+                    let len = protocols.u8()? as usize;
+                    summary.alpn.push(String::from_utf8_lossy(protocols.take(len)?).into_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(summary)
 }
 
 /// Randomize TLS handshake timing
@@ -518,10 +1042,11 @@ pub struct TlsTimingRandomizer {
 }
 
 impl TlsTimingRandomizer {
+    /// Jitter is zero in deterministic mode
     pub fn new(base_delay_ms: u64, jitter_range_ms: u64) -> Self {
         Self {
             base_delay_ms,
-            jitter_range_ms,
+            jitter_range_ms: if crate::determinism::is_deterministic() { 0 } else { jitter_range_ms },
         }
     }
     
@@ -536,9 +1061,11 @@ impl TlsTimingRandomizer {
     
     /// Get delay between certificate validation steps
     pub fn get_cert_validation_delay(&self) -> std::time::Duration {
-        let mut rng = rand::thread_rng();
         // Certificate validation typically takes 5-50ms on mobile
-        let delay = rng.gen_range(5..=50);
+        if crate::determinism::is_deterministic() {
+            return std::time::Duration::from_millis(5);
+        }
+        let delay = rand::thread_rng().gen_range(5..=50);
         std::time::Duration::from_millis(delay)
     }
 }
@@ -565,7 +1092,7 @@ mod tests {
     #[test]
     fn test_client_hello_generation() {
         let mut manager = TlsFingerprintManager::new();
-        let client_hello = manager.generate_client_hello("example.com");
+        let client_hello = manager.generate_client_hello("example.com").unwrap();
         
         // Should start with TLS record header
         assert_eq!(client_hello[0], 0x16); // Handshake
@@ -575,20 +1102,361 @@ mod tests {
         assert!(client_hello.len() < 1000);
     }
     
+    #[test]
+    fn test_record_length_excludes_record_header() {
+        let mut manager = TlsFingerprintManager::new();
+        for profile in [
+            MobileBrowserProfile::Safari17,
+            MobileBrowserProfile::Chrome120Mobile,
+            MobileBrowserProfile::Firefox121Mobile,
+            MobileBrowserProfile::Samsung21,
+            MobileBrowserProfile::Edge120Mobile,
+        ] {
+            manager.current_profile = profile;
+            let hello = manager.generate_client_hello("example.com").unwrap();
+            // The record length counts what follows the 5-byte header, and
+            // the handshake length what follows its own 4-byte header
+            let record_len = u16::from_be_bytes([hello[3], hello[4]]) as usize;
+            assert_eq!(record_len, hello.len() - 5, "{}", manager.current_profile.name());
+            let handshake_len = u32::from_be_bytes([0, hello[6], hello[7], hello[8]]) as usize;
+            assert_eq!(handshake_len, hello.len() - 9, "{}", manager.current_profile.name());
+        }
+    }
+    
+    #[test]
+    fn test_client_hello_template_cache() {
+        let mut manager = TlsFingerprintManager::new();
+        let first = manager.generate_client_hello("example.com").unwrap();
+        let second = manager.generate_client_hello("example.com").unwrap();
+        assert_eq!(manager.template_cache.len(), 1);
+        
+        // Record (5) + handshake header (4) + version (2), then the 32-byte
+        // random; the x25519 public key at the end of key_share is fresh too
+        let random = 11..43;
+        let key = hello_extensions(&first).into_iter().find(|(id, _)| *id == 0x0033).unwrap().1;
+        let key = key.end - 32..key.end;
+        assert_eq!(first.len(), second.len());
+        assert_eq!(first[..random.start], second[..random.start]);
+        assert_eq!(first[random.end..key.start], second[random.end..key.start]);
+        assert_eq!(first[key.end..], second[key.end..]);
+        assert_ne!(first[key.clone()], second[key.clone()]);
+        
+        // A different SNI only changes the server_name extension and lengths
+        let other = manager.generate_client_hello("example.org").unwrap();
+        assert_eq!(other.len(), first.len());
+        let differing: Vec<usize> = (random.end..first.len())
+            .filter(|i| !key.contains(i) && first[*i] != other[*i])
+            .collect();
+        assert_eq!(differing.len(), 3); // "com" -> "org"
+    }
+    
+    /// Cipher suites of a hello: after record/handshake headers, version, random and empty session id
+    fn hello_ciphers(hello: &[u8]) -> Vec<u16> {
+        let len = u16::from_be_bytes([hello[44], hello[45]]) as usize;
+        hello[46..46 + len].chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect()
+    }
+    
+    #[test]
+    fn test_cipher_shuffle() {
+        let mut manager = TlsFingerprintManager::new();
+        manager.current_profile = MobileBrowserProfile::Chrome120Mobile;
+        let profile_order = manager.current_profile.get_tls_fingerprint().cipher_suites;
+        
+        let fixed_a = hello_ciphers(&manager.generate_client_hello("example.com").unwrap());
+        let fixed_b = hello_ciphers(&manager.generate_client_hello("example.com").unwrap());
+        assert_eq!(fixed_a, profile_order);
+        assert_eq!(fixed_a, fixed_b);
+        
+        manager.set_shuffle_ciphers(true);
+        manager.seed_cipher_shuffle(7);
+        let shuffled_a = hello_ciphers(&manager.generate_client_hello("example.com").unwrap());
+        let ja3_a = manager.generate_ja3_fingerprint("example.com").unwrap();
+        let shuffled_b = hello_ciphers(&manager.generate_client_hello("example.com").unwrap());
+        let ja3_b = manager.generate_ja3_fingerprint("example.com").unwrap();
+        assert_ne!(shuffled_a, shuffled_b);
+        assert_ne!(ja3_a, ja3_b);
+        
+        // Same suites, different order
+        let (mut sorted_a, mut sorted_profile) = (shuffled_a.clone(), profile_order.clone());
+        sorted_a.sort();
+        sorted_profile.sort();
+        assert_eq!(sorted_a, sorted_profile);
+    }
+    
+    #[test]
+    fn test_sni_length_guard() {
+        let mut manager = TlsFingerprintManager::new();
+        assert!(manager.generate_client_hello("example.com").is_ok());
+        
+        let overlong = "a".repeat(MAX_SNI_LEN + 1);
+        assert_eq!(
+            manager.generate_client_hello(&overlong),
+            Err(TlsFingerprintError::ServerNameTooLong { len: MAX_SNI_LEN + 1, max: MAX_SNI_LEN })
+        );
+        
+        // Raising the limit cannot exceed what the length fields can encode
+        manager.set_max_sni_len(usize::MAX);
+        let huge = "a".repeat(SNI_HARD_CAP + 1);
+        assert!(matches!(manager.generate_client_hello(&huge), Err(TlsFingerprintError::ServerNameTooLong { .. })));
+        assert!(matches!(manager.generate_client_hello(&"a".repeat(SNI_HARD_CAP)), Err(TlsFingerprintError::HelloTooLarge(_))));
+    }
+    
+    #[test]
+    fn test_profiles_file_replaces_builtin_rotation() {
+        let dir = std::env::temp_dir().join(format!("litebike-profiles-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("profiles.json");
+        std::fs::write(&path, r#"[
+            {"name": "Chrome131Android", "weight": 9, "tls_version": "Tls13",
+             "cipher_suites": [4865, 4866, 4867, 49195], "extensions": [0, 10, 11, 13, 16, 43, 51],
+             "elliptic_curves": [29, 23], "signature_algorithms": [1027, 2052],
+             "alpn_protocols": ["h2"], "compress_certificate": false, "early_data": false, "session_ticket": false},
+            {"name": "Retired", "weight": 0, "tls_version": "Tls12",
+             "cipher_suites": [49199], "extensions": [0], "elliptic_curves": [23], "signature_algorithms": [1025],
+             "alpn_protocols": [], "compress_certificate": false, "early_data": false, "session_ticket": false}
+        ]"#).unwrap();
+
+        let mut manager = TlsFingerprintManager::from_profiles_file(&path).unwrap();
+        for _ in 0..20 {
+            manager.force_rotation();
+            assert_eq!(manager.current_profile().name(), "Chrome131Android");
+        }
+        assert_eq!(manager.get_stats().current_profile, "Chrome131Android");
+        let hello = manager.generate_client_hello("example.com").unwrap();
+        assert_eq!(hello_ciphers(&hello), vec![0x1301, 0x1302, 0x1303, 0xc02b]);
+        assert!(hello_extension_ids(&hello).contains(&0x0033));
+
+        std::fs::write(&path, r#"[{"name": "Off", "weight": 0, "tls_version": "Tls13", "cipher_suites": [],
+            "extensions": [], "elliptic_curves": [], "signature_algorithms": [], "alpn_protocols": [],
+            "compress_certificate": false, "early_data": false, "session_ticket": false}]"#).unwrap();
+        let err = TlsFingerprintManager::from_profiles_file(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        std::fs::write(&path, "[{\"name\": \"NoFields\", \"weight\": 1}]").unwrap();
+        assert_eq!(TlsFingerprintManager::from_profiles_file(&path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(TlsFingerprintManager::from_profiles_file(&path).is_err());
+    }
+
+    #[test]
+    fn test_sticky_never_rotates() {
+        let mut manager = TlsFingerprintManager::sticky(MobileBrowserProfile::Safari17);
+        // A rotation an hour ago would make a rotating manager due again
+        let hour_ago = SystemTime::now() - std::time::Duration::from_secs(3600);
+        manager.profile_history.push((hour_ago, MobileBrowserProfile::Safari17));
+        for _ in 0..100 {
+            manager.maybe_rotate_profile();
+            assert_eq!(manager.current_profile(), &MobileBrowserProfile::Safari17);
+        }
+        assert!(!manager.get_stats().rotation_enabled);
+    }
+
     #[test]
     fn test_ja3_fingerprint_generation() {
         let mut manager = TlsFingerprintManager::new();
-        let ja3_1 = manager.generate_ja3_fingerprint("example.com");
-        let ja3_2 = manager.generate_ja3_fingerprint("example.com");
+        let ja3_1 = manager.generate_ja3_fingerprint("example.com").unwrap();
+        let ja3_2 = manager.generate_ja3_fingerprint("example.com").unwrap();
         
         // Should be consistent for same domain
         assert_eq!(ja3_1, ja3_2);
         
-        // Should be different for different domains
-        let ja3_3 = manager.generate_ja3_fingerprint("different.com");
-        // Note: May be same due to same profile, but cached separately
+        // A lowercase MD5 hex digest, as JA3 databases list them
+        assert_eq!(ja3_1.len(), 32);
+        assert!(ja3_1.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')));
+        
+        // Same profile, so the same digest, but cached per server name
+        let ja3_3 = manager.generate_ja3_fingerprint("different.com").unwrap();
+        assert_eq!(ja3_3, ja3_1);
+        assert_eq!(manager.ja3_cache.len(), 2);
+        assert_eq!(manager.ja3_cache["different.com"], ja3_3);
     }
     
+    #[test]
+    fn test_ja3_is_md5_of_the_ja3_string() {
+        let hex = |s: &str| md5(s.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(hex(""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex("The quick brown fox jumps over the lazy dog"), "9e107d9d372bb6826bd81d3542a419d6");
+
+        // Example from the JA3 reference implementation, with GREASE mixed in
+        let ja3 = ja3_hash(&ja3_string(
+            769,
+            &[0x0a0a, 47, 53, 5, 10, 49161, 49162, 49171, 49172, 50, 56, 19, 4],
+            &[0, 10, 0xfafa, 11],
+            &[0x1a1a, 23, 24, 25],
+            &[0],
+        ));
+        assert_eq!(ja3, "ada70206e40642a3e4461f35503241d5");
+    }
+
+    #[test]
+    fn test_ja3_matches_the_hello_sent() {
+        for (profile, _) in builtin_profile_weights() {
+            let mut manager = TlsFingerprintManager::sticky(profile.clone());
+            let hello = manager.generate_client_hello("example.com").unwrap();
+            assert_eq!(
+                manager.generate_ja3_fingerprint("example.com").unwrap(),
+                ja3_from_client_hello(&hello).unwrap(),
+                "{:?}",
+                profile
+            );
+        }
+    }
+
+    /// Extensions of a hello in the order sent, as type and data range
+    fn hello_extensions(hello: &[u8]) -> Vec<(u16, std::ops::Range<usize>)> {
+        let ciphers_len = u16::from_be_bytes([hello[44], hello[45]]) as usize;
+        let compression = 46 + ciphers_len;
+        let mut pos = compression + 1 + hello[compression] as usize + 2;
+        let mut extensions = Vec::new();
+        while pos + 4 <= hello.len() {
+            let len = u16::from_be_bytes([hello[pos + 2], hello[pos + 3]]) as usize;
+            extensions.push((u16::from_be_bytes([hello[pos], hello[pos + 1]]), pos + 4..pos + 4 + len));
+            pos += 4 + len;
+        }
+        assert_eq!(pos, hello.len());
+        extensions
+    }
+
+    fn hello_extension_ids(hello: &[u8]) -> Vec<u16> {
+        hello_extensions(hello).into_iter().map(|(id, _)| id).collect()
+    }
+
+    #[test]
+    fn test_client_hello_follows_profile_extension_order() {
+        let sent = |profile: MobileBrowserProfile| {
+            hello_extension_ids(&TlsFingerprintManager::sticky(profile).generate_client_hello("example.com").unwrap())
+        };
+        let chrome = sent(MobileBrowserProfile::Chrome120Mobile);
+        let firefox = sent(MobileBrowserProfile::Firefox121Mobile);
+        assert_eq!(chrome, [0x0000, 0x000b, 0x000a, 0x0023, 0x0010, 0x0005, 0x000d, 0x0012, 0x0033, 0x002b, 0x002a, 0x001b]);
+        assert_eq!(firefox, [0x0000, 0x000a, 0x000b, 0x0023, 0x0010, 0x0033, 0x002b, 0x000d]);
+
+        // Every sent extension keeps its place in the profile's list
+        for (profile, ids) in [(MobileBrowserProfile::Chrome120Mobile, chrome), (MobileBrowserProfile::Firefox121Mobile, firefox)] {
+            let order = profile.get_tls_fingerprint().extensions;
+            let positions: Vec<usize> = ids.iter().map(|id| order.iter().position(|x| x == id).unwrap()).collect();
+            assert!(positions.windows(2).all(|w| w[0] < w[1]));
+        }
+    }
+
+    #[test]
+    fn test_ec_point_formats_sent_and_counted_in_ja3() {
+        for profile in [MobileBrowserProfile::Chrome120Mobile, MobileBrowserProfile::Safari17, MobileBrowserProfile::Firefox121Mobile] {
+            let hello = TlsFingerprintManager::sticky(profile.clone()).generate_client_hello("example.com").unwrap();
+            let extensions = hello_extensions(&hello);
+            let (_, body) = extensions.iter().find(|(id, _)| *id == 0x000b).unwrap();
+            assert_eq!(hello[body.clone()], [0x01, 0x00], "{:?}", profile);
+
+            // The JA3 read off those bytes lists ec_point_formats (11) and
+            // the uncompressed format, and is the one the manager reports
+            let ja3 = ja3_string_from_client_hello(&hello).unwrap();
+            let fields: Vec<&str> = ja3.split(',').collect();
+            assert_eq!(fields[0], "771", "{:?}", profile);
+            assert!(fields[2].split('-').any(|id| id == "11"), "{:?}", profile);
+            assert_eq!(fields[4], "0", "{:?}", profile);
+            let manager_ja3 = TlsFingerprintManager::sticky(profile.clone()).generate_ja3_fingerprint("example.com").unwrap();
+            assert_eq!(manager_ja3, ja3_hash(&ja3), "{:?}", profile);
+        }
+    }
+
+    #[test]
+    fn test_inbound_client_hello_ja3() {
+        let extensions: &[u8] = &[
+            0x1a, 0x1a, 0x00, 0x00, // GREASE
+            0x00, 0x00, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x00, 0x05, b'a', b'.', b'b', b'c', b'd', // server_name
+            0x00, 0x0a, 0x00, 0x06, 0x00, 0x04, 0x00, 0x1d, 0x00, 0x17, // supported_groups
+            0x00, 0x0b, 0x00, 0x02, 0x01, 0x00, // ec_point_formats
+        ];
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0x42; 32]);
+        body.push(0); // session id
+        body.extend_from_slice(&[0x00, 0x06, 0x13, 0x01, 0x0a, 0x0a, 0xc0, 0x2f]);
+        body.extend_from_slice(&[0x01, 0x00]); // null compression
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(extensions);
+        let mut handshake = vec![0x01, 0x00];
+        handshake.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&body);
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+
+        // md5("771,4865-49199,0-10-11,29-23,0"), GREASE dropped everywhere
+        assert_eq!(ja3_from_client_hello(&record).as_deref(), Some("bca193bf3b6d2156cfbe0e6b4b306d3e"));
+
+        // Truncation anywhere and lengths that overrun their field give None
+        for len in 0..record.len() {
+            assert_eq!(ja3_from_client_hello(&record[..len]), None, "truncated to {}", len);
+        }
+        let supported_groups = record.len() - 16;
+        let mut overrun = record.clone();
+        overrun[supported_groups + 5] = 0x40;
+        assert_eq!(ja3_from_client_hello(&overrun), None);
+        let mut not_a_hello = record.clone();
+        not_a_hello[5] = 0x02;
+        assert_eq!(ja3_from_client_hello(&not_a_hello), None);
+    }
+
+    #[test]
+    fn test_tls13_hello_carries_key_share_and_supported_versions() {
+        let hello = TlsFingerprintManager::sticky(MobileBrowserProfile::Safari17).generate_client_hello("example.com").unwrap();
+        assert_eq!(hello[9..11], [0x03, 0x03]); // legacy_version
+        let extensions = hello_extensions(&hello);
+        let data = |id: u16| &hello[extensions.iter().find(|(e, _)| *e == id).unwrap().1.clone()];
+        
+        assert_eq!(data(0x002b), [0x04, 0x03, 0x04, 0x03, 0x03]);
+        let key_share = data(0x0033);
+        assert_eq!(key_share[..6], [0x00, 0x24, 0x00, 0x1d, 0x00, 0x20]);
+        assert_eq!(key_share.len(), 6 + 32);
+        
+        // key_share keeps the group listed first in supported_groups
+        assert_eq!(data(0x000a)[2..4], [0x00, 0x1d]);
+    }
+
+    /// Feed our hello to a rustls TLS 1.3 server and return its first record
+    fn tls13_server_reply(hello: &[u8]) -> Vec<u8> {
+        use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+        use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+        use rustls::sign::{CertifiedKey, SingleCertAndKey};
+
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &ring::rand::SystemRandom::new()).unwrap();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(pkcs8.as_ref().to_vec()));
+        let provider = rustls::crypto::aws_lc_rs::default_provider();
+        let signer = provider.key_provider.load_private_key(key).unwrap();
+        // The server only signs with the key; nothing checks the certificate itself
+        let certified = CertifiedKey::new(vec![CertificateDer::from(vec![0x30, 0x00])], signer);
+        let config = rustls::ServerConfig::builder_with_provider(std::sync::Arc::new(provider))
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_no_client_auth()
+            .with_cert_resolver(std::sync::Arc::new(SingleCertAndKey::from(certified)));
+
+        let mut server = rustls::ServerConnection::new(std::sync::Arc::new(config)).unwrap();
+        server.read_tls(&mut &hello[..]).unwrap();
+        server.process_new_packets().expect("server rejected the ClientHello");
+        let mut reply = Vec::new();
+        server.write_tls(&mut reply).unwrap();
+        reply
+    }
+
+    #[test]
+    fn test_tls13_server_accepts_client_hello() {
+        // SHA-256 of "HelloRetryRequest", the random a retry request carries
+        const RETRY_RANDOM: [u8; 8] = [0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11];
+        for profile in [MobileBrowserProfile::Chrome120Mobile, MobileBrowserProfile::Safari17, MobileBrowserProfile::Firefox121Mobile] {
+            let hello = TlsFingerprintManager::sticky(profile.clone()).generate_client_hello("example.com").unwrap();
+            let reply = tls13_server_reply(&hello);
+
+            // Handshake record holding a ServerHello rather than a retry
+            assert_eq!(reply[0], 0x16, "{:?}", profile);
+            assert_eq!(reply[5], 0x02, "{:?}", profile);
+            assert_ne!(reply[11..19], RETRY_RANDOM, "{:?} had its key share refused", profile);
+            // supported_versions selects TLS 1.3; key_share answers on x25519
+            let server_hello = &reply[5..];
+            assert!(server_hello.windows(6).any(|w| w == [0x00, 0x2b, 0x00, 0x02, 0x03, 0x04]), "{:?}", profile);
+            assert!(server_hello.windows(6).any(|w| w == [0x00, 0x33, 0x00, 0x24, 0x00, 0x1d]), "{:?}", profile);
+        }
+    }
+
     #[test]
     fn test_timing_randomization() {
         let randomizer = TlsTimingRandomizer::new(10, 20);
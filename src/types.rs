@@ -1,5 +1,6 @@
 use std::fmt::{Display, Formatter};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ffi::CString;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -55,6 +56,7 @@ pub enum ProtocolType {
     I2p = 0x31,
     Onion = 0x32,
     Freenet = 0x33,
+    Socks4 = 0x34,
     Raw = 0xFF,
 }
 
@@ -88,6 +90,21 @@ pub enum Socks5Reply {
     AddressTypeNotSupported = 0x08,
 }
 
+impl Socks5Reply {
+    /// Reply for a CONNECT whose outbound dial failed with `error`. A name
+    /// that did not resolve counts as an unreachable host.
+    pub fn for_connect_error(error: &std::io::Error) -> Self {
+        use std::io::ErrorKind;
+        match error.kind() {
+            ErrorKind::ConnectionRefused => Socks5Reply::ConnectionRefused,
+            ErrorKind::TimedOut => Socks5Reply::TtlExpired,
+            ErrorKind::NetworkUnreachable | ErrorKind::NetworkDown => Socks5Reply::NetworkUnreachable,
+            ErrorKind::HostUnreachable | ErrorKind::NotFound => Socks5Reply::HostUnreachable,
+            _ => Socks5Reply::GeneralFailure,
+        }
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HttpMethod {
@@ -146,7 +163,8 @@ pub enum UpnpAction {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TargetAddress {
     Ipv4 { addr: Ipv4Addr, port: u16 },
-    Ipv6 { addr: Ipv6Addr, port: u16 },
+    /// `scope` is the zone after `%` (`fe80::1%eth0`), an interface name or index
+    Ipv6 { addr: Ipv6Addr, port: u16, scope: Option<String> },
     Domain { host: String, port: u16 },
 }
 
@@ -265,11 +283,76 @@ impl Display for ProtocolType {
             ProtocolType::I2p => write!(f, "I2P"),
             ProtocolType::Onion => write!(f, "Onion"),
             ProtocolType::Freenet => write!(f, "Freenet"),
+            ProtocolType::Socks4 => write!(f, "SOCKS4"),
             ProtocolType::Raw => write!(f, "RAW"),
         }
     }
 }
 
+/// Read back a discriminant written as `protocol as u8`
+impl TryFrom<u8> for ProtocolType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        const ALL: [ProtocolType; 53] = [
+            ProtocolType::Http,
+            ProtocolType::Https,
+            ProtocolType::Socks5,
+            ProtocolType::Connect,
+            ProtocolType::Doh,
+            ProtocolType::Upnp,
+            ProtocolType::Bonjour,
+            ProtocolType::Shadowsocks,
+            ProtocolType::Tls,
+            ProtocolType::Udp,
+            ProtocolType::Tcp,
+            ProtocolType::Pac,
+            ProtocolType::WebRtc,
+            ProtocolType::Quic,
+            ProtocolType::Ssh,
+            ProtocolType::Ftp,
+            ProtocolType::Smtp,
+            ProtocolType::Pop3,
+            ProtocolType::Imap,
+            ProtocolType::Irc,
+            ProtocolType::Xmpp,
+            ProtocolType::Mqtt,
+            ProtocolType::Websocket,
+            ProtocolType::H2c,
+            ProtocolType::Rtsp,
+            ProtocolType::Sip,
+            ProtocolType::Dns,
+            ProtocolType::Dhcp,
+            ProtocolType::Snmp,
+            ProtocolType::Ntp,
+            ProtocolType::Ldap,
+            ProtocolType::Kerberos,
+            ProtocolType::Radius,
+            ProtocolType::Syslog,
+            ProtocolType::Telnet,
+            ProtocolType::Rlogin,
+            ProtocolType::Vnc,
+            ProtocolType::Rdp,
+            ProtocolType::X11,
+            ProtocolType::Smb,
+            ProtocolType::Nfs,
+            ProtocolType::Tftp,
+            ProtocolType::BitTorrent,
+            ProtocolType::Gnutella,
+            ProtocolType::Kazaa,
+            ProtocolType::Skype,
+            ProtocolType::TeamViewer,
+            ProtocolType::Tor,
+            ProtocolType::I2p,
+            ProtocolType::Onion,
+            ProtocolType::Freenet,
+            ProtocolType::Socks4,
+            ProtocolType::Raw,
+        ];
+        ALL.into_iter().find(|p| *p as u8 == value).ok_or(value)
+    }
+}
+
 impl From<u16> for StandardPort {
     fn from(port: u16) -> Self {
         match port {
@@ -298,17 +381,46 @@ impl TargetAddress {
     pub fn new(host: &str, port: u16) -> Self {
         if let Ok(ipv4) = host.parse::<Ipv4Addr>() {
             Self::Ipv4 { addr: ipv4, port }
-        } else if let Ok(ipv6) = host.parse::<Ipv6Addr>() {
-            Self::Ipv6 { addr: ipv6, port }
+        } else if let Some((addr, scope)) = Self::parse_scoped_ipv6(host) {
+            Self::Ipv6 { addr, port, scope }
         } else {
             Self::Domain { host: host.to_string(), port }
         }
     }
 
+    /// Accepts `fe80::1`, `fe80::1%eth0` and the bracketed forms of both
+    fn parse_scoped_ipv6(host: &str) -> Option<(Ipv6Addr, Option<String>)> {
+        let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+        match host.split_once('%') {
+            Some((addr, scope)) if !scope.is_empty() => Some((addr.parse().ok()?, Some(scope.to_string()))),
+            Some(_) => None,
+            None => Some((host.parse().ok()?, None)),
+        }
+    }
+
+    /// Numeric zones are used as-is; names are looked up, and an unknown
+    /// interface yields `None` since the address is unreachable without it
+    fn scope_id(scope: &str) -> Option<u32> {
+        if let Ok(index) = scope.parse::<u32>() {
+            return Some(index);
+        }
+        let name = CString::new(scope).ok()?;
+        match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+            0 => None,
+            index => Some(index),
+        }
+    }
+
     pub fn to_socket_addr(&self, resolved_ip: Option<IpAddr>) -> Option<SocketAddr> {
         match self {
             Self::Ipv4 { addr, port } => Some(SocketAddr::new(IpAddr::V4(*addr), *port)),
-            Self::Ipv6 { addr, port } => Some(SocketAddr::new(IpAddr::V6(*addr), *port)),
+            Self::Ipv6 { addr, port, scope } => {
+                let scope_id = match scope {
+                    Some(scope) => Self::scope_id(scope)?,
+                    None => 0,
+                };
+                Some(SocketAddr::V6(SocketAddrV6::new(*addr, *port, 0, scope_id)))
+            }
             Self::Domain { port, .. } => resolved_ip.map(|ip| SocketAddr::new(ip, *port)),
         }
     }
@@ -322,6 +434,7 @@ impl TargetAddress {
     pub fn host(&self) -> String {
         match self {
             Self::Ipv4 { addr, .. } => addr.to_string(),
+            Self::Ipv6 { addr, scope: Some(scope), .. } => format!("[{}%{}]", addr, scope),
             Self::Ipv6 { addr, .. } => format!("[{}]", addr),
             Self::Domain { host, .. } => host.clone(),
         }
@@ -339,7 +452,8 @@ impl Display for TargetAddress {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Ipv4 { addr, port } => write!(f, "{}:{}", addr, port),
-            Self::Ipv6 { addr, port } => write!(f, "[{}]:{}", addr, port),
+            Self::Ipv6 { addr, port, scope: Some(scope) } => write!(f, "[{}%{}]:{}", addr, scope, port),
+            Self::Ipv6 { addr, port, .. } => write!(f, "[{}]:{}", addr, port),
             Self::Domain { host, port } => write!(f, "{}:{}", host, port),
         }
     }
@@ -355,6 +469,20 @@ impl ShadowsocksMethod {
         }
     }
 
+    /// Per-session salt, as long as the key
+    pub fn salt_length(&self) -> usize {
+        self.key_length()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ShadowsocksMethod::Aes128Gcm => "aes-128-gcm",
+            ShadowsocksMethod::Aes192Gcm => "aes-192-gcm",
+            ShadowsocksMethod::Aes256Gcm => "aes-256-gcm",
+            ShadowsocksMethod::Chacha20IetfPoly1305 => "chacha20-ietf-poly1305",
+        }
+    }
+
     pub fn nonce_length(&self) -> usize {
         match self {
             ShadowsocksMethod::Aes128Gcm | 
@@ -377,6 +505,80 @@ impl From<&str> for ShadowsocksMethod {
     }
 }
 
+/// Encode a SOCKS5 reply (RFC 1928 §6) carrying `bound` as BND.ADDR/BND.PORT
+pub fn build_socks5_reply(reply: Socks5Reply, bound: SocketAddr) -> Vec<u8> {
+    let mut out = vec![0x05, reply as u8, 0x00];
+    push_socks5_addr(&mut out, bound);
+    out
+}
+
+/// Encode a SOCKS4 reply: VN 0, CD 0x5A (granted) or 0x5B (rejected), then
+/// DSTPORT/DSTIP, which clients ignore; IPv6 addresses are sent as zeros
+pub fn build_socks4_reply(granted: bool, bound: SocketAddr) -> [u8; 8] {
+    let mut out = [0u8; 8];
+    out[1] = if granted { 0x5A } else { 0x5B };
+    if let IpAddr::V4(ip) = bound.ip() {
+        out[2..4].copy_from_slice(&bitbang_u16(bound.port()));
+        out[4..].copy_from_slice(&ip.octets());
+    }
+    out
+}
+
+/// Wrap `payload` in the SOCKS5 UDP request header (RFC 1928 §7) naming `source`
+pub fn build_socks5_udp_datagram(source: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(22 + payload.len());
+    out.extend_from_slice(&[0x00, 0x00, 0x00]);
+    push_socks5_addr(&mut out, source);
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Split a SOCKS5 UDP datagram into FRAG, DST.ADDR/DST.PORT and the payload.
+/// `None` when the header is truncated or names an unknown address type.
+pub fn parse_socks5_udp_datagram(datagram: &[u8]) -> Option<(u8, TargetAddress, &[u8])> {
+    let (&frag, rest) = datagram.get(2..)?.split_first()?;
+    let (target, rest) = parse_socks5_addr(rest)?;
+    Some((frag, target, rest))
+}
+
+/// Split ATYP, address and port off the front of `bytes`, as used by SOCKS5
+/// requests and Shadowsocks headers. `None` when truncated or unknown.
+pub fn parse_socks5_addr(bytes: &[u8]) -> Option<(TargetAddress, &[u8])> {
+    let (&atyp, rest) = bytes.split_first()?;
+    let (host, rest) = match atyp {
+        a if a == AddressType::Ipv4 as u8 => {
+            let octets: [u8; 4] = rest.get(..4)?.try_into().ok()?;
+            (Ipv4Addr::from(octets).to_string(), &rest[4..])
+        }
+        a if a == AddressType::Ipv6 as u8 => {
+            let octets: [u8; 16] = rest.get(..16)?.try_into().ok()?;
+            (Ipv6Addr::from(octets).to_string(), &rest[16..])
+        }
+        a if a == AddressType::DomainName as u8 => {
+            let (&len, rest) = rest.split_first()?;
+            let name = std::str::from_utf8(rest.get(..len as usize)?).ok()?;
+            (name.to_string(), &rest[len as usize..])
+        }
+        _ => return None,
+    };
+    let port = unbang_u16(rest.get(..2)?);
+    Some((TargetAddress::new(&host, port), &rest[2..]))
+}
+
+fn push_socks5_addr(out: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(AddressType::Ipv4 as u8);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(AddressType::Ipv6 as u8);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&bitbang_u16(addr.port()));
+}
+
 pub fn bitbang_u16(value: u16) -> [u8; 2] {
     value.to_be_bytes()
 }
@@ -385,6 +587,10 @@ pub fn bitbang_u32(value: u32) -> [u8; 4] {
     value.to_be_bytes()
 }
 
+pub fn bitbang_u64(value: u64) -> [u8; 8] {
+    value.to_be_bytes()
+}
+
 pub fn unbang_u16(bytes: &[u8]) -> u16 {
     u16::from_be_bytes([bytes[0], bytes[1]])
 }
@@ -393,6 +599,12 @@ pub fn unbang_u32(bytes: &[u8]) -> u32 {
     u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
 }
 
+pub fn unbang_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(buf)
+}
+
 pub fn extract_bits(value: u8, start: u8, length: u8) -> u8 {
     let mask = (1u8 << length) - 1;
     (value >> start) & mask
@@ -401,4 +613,30 @@ pub fn extract_bits(value: u8, start: u8, length: u8) -> u8 {
 pub fn set_bits(value: u8, start: u8, length: u8, bits: u8) -> u8 {
     let mask = ((1u8 << length) - 1) << start;
     (value & !mask) | ((bits << start) & mask)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ipv6_scope_round_trips() {
+        let target = TargetAddress::new("fe80::1%eth0", 443);
+        assert_eq!(
+            target,
+            TargetAddress::Ipv6 { addr: "fe80::1".parse().unwrap(), port: 443, scope: Some("eth0".to_string()) }
+        );
+        assert_eq!(target.to_string(), "[fe80::1%eth0]:443");
+        assert_eq!(TargetAddress::new(&target.host(), 443), target);
+
+        let numeric = TargetAddress::new("fe80::1%7", 443);
+        match numeric.to_socket_addr(None) {
+            Some(SocketAddr::V6(addr)) => {
+                assert_eq!(*addr.ip(), "fe80::1".parse::<Ipv6Addr>().unwrap());
+                assert_eq!(addr.scope_id(), 7);
+            }
+            other => panic!("expected scoped SocketAddrV6, got {:?}", other),
+        }
+        assert_eq!(TargetAddress::new("fe80::1%no-such-if0", 443).to_socket_addr(None), None);
+    }
+}
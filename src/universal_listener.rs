@@ -1,132 +1,622 @@
+use std::collections::{HashMap, HashSet};
 use std::io;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
-use tokio::net::TcpStream;
-use log::{debug, info};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use log::{debug, info, warn};
 
 
 use crate::posix_sockets::posix_peek;
+use crate::tls_fingerprint::{ja3_from_client_hello, summarize_client_hello, ClientHelloSummary};
+use crate::tcp_fingerprint::{bind_with_options, TcpTuningOptions};
+use crate::util::Backoff;
+use crate::types::{build_socks4_reply, build_socks5_reply, ProtocolType, Socks5Reply};
 
 /// Protocol detection result
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Protocol {
     Http,
     Socks5,
+    Socks4,     // SOCKS4 and SOCKS4a
     WebSocket,
     WebRTC,
     Pac,        // Proxy Auto-Config
     Wpad,       // Web Proxy Auto-Discovery
     Bonjour,    // mDNS/DNS-SD
     Upnp,       // UPnP discovery
+    H2c,        // HTTP/2 cleartext with prior knowledge
     Unknown,
 }
 
-/// Detects the protocol based on the first few bytes using POSIX peek when available
+/// Detector output in the routing/stats vocabulary. WPAD is PAC served at a
+/// different path, so both become `ProtocolType::Pac`; `Unknown` becomes `Raw`.
+impl From<Protocol> for ProtocolType {
+    fn from(protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Http => ProtocolType::Http,
+            Protocol::Socks5 => ProtocolType::Socks5,
+            Protocol::Socks4 => ProtocolType::Socks4,
+            Protocol::WebSocket => ProtocolType::Websocket,
+            Protocol::WebRTC => ProtocolType::WebRtc,
+            Protocol::Pac | Protocol::Wpad => ProtocolType::Pac,
+            Protocol::Bonjour => ProtocolType::Bonjour,
+            Protocol::Upnp => ProtocolType::Upnp,
+            Protocol::H2c => ProtocolType::H2c,
+            Protocol::Unknown => ProtocolType::Raw,
+        }
+    }
+}
+
+/// Fails with the original value for protocols the detector never reports
+impl TryFrom<ProtocolType> for Protocol {
+    type Error = ProtocolType;
+
+    fn try_from(protocol: ProtocolType) -> Result<Self, Self::Error> {
+        match protocol {
+            ProtocolType::Http => Ok(Protocol::Http),
+            ProtocolType::Socks5 => Ok(Protocol::Socks5),
+            ProtocolType::Socks4 => Ok(Protocol::Socks4),
+            ProtocolType::Websocket => Ok(Protocol::WebSocket),
+            ProtocolType::WebRtc => Ok(Protocol::WebRTC),
+            ProtocolType::Pac => Ok(Protocol::Pac),
+            ProtocolType::Bonjour => Ok(Protocol::Bonjour),
+            ProtocolType::Upnp => Ok(Protocol::Upnp),
+            ProtocolType::H2c => Ok(Protocol::H2c),
+            ProtocolType::Raw => Ok(Protocol::Unknown),
+            other => Err(other),
+        }
+    }
+}
+
+/// Detects the protocol based on the first few bytes. Reads as much as each
+/// read returns, and keeps reading while the bytes so far are only a prefix
+/// of a signature, so a record header split across segments is still
+/// recognised. A TLS ClientHello is read to the end of its record.
 pub async fn detect_protocol<S>(stream: &mut S) -> io::Result<(Protocol, Vec<u8>)>
 where
     S: AsyncRead + Unpin,
 {
-    let mut buffer = vec![0u8; 1024];
-    let n = stream.read(&mut buffer).await?;
-    
-    if n == 0 {
-        return Ok((Protocol::Unknown, vec![]));
+    let mut buffer = Vec::with_capacity(MAX_DETECTION_BYTES);
+    let mut chunk = vec![0u8; MAX_DETECTION_BYTES];
+    loop {
+        let room = MAX_DETECTION_BYTES - buffer.len();
+        let n = stream.read(&mut chunk[..room]).await?;
+        buffer.extend_from_slice(&chunk[..n]);
+        if n == 0 || !ProtocolDetector::is_truncated_signature(&buffer) {
+            break;
+        }
     }
-    
-    buffer.truncate(n);
-    
-    // SOCKS5 starts with version byte 0x05
-    if n >= 2 && buffer[0] == 0x05 {
-        debug!("Detected SOCKS5 protocol");
-        return Ok((Protocol::Socks5, buffer));
+
+    let protocol = ProtocolDetector::shared().detect(&buffer);
+    if protocol == Protocol::Unknown {
+        read_rest_of_tls_record(stream, &mut buffer, MAX_DETECTION_BYTES).await?;
     }
-    
-    // Check for text-based protocols
-    if let Ok(text) = std::str::from_utf8(&buffer[..std::cmp::min(n, 512)]) {
-        let text_upper = text.to_uppercase();
-        
-        // HTTP methods: GET, POST, PUT, DELETE, HEAD, OPTIONS, CONNECT, PATCH
-        if text.starts_with("GET ") || 
-           text.starts_with("POST ") || 
-           text.starts_with("PUT ") || 
-           text.starts_with("DELETE ") || 
-           text.starts_with("HEAD ") || 
-           text.starts_with("OPTIONS ") || 
-           text.starts_with("CONNECT ") || 
-           text.starts_with("PATCH ") {
-            
-            // Check for WebSocket upgrade
-            if text_upper.contains("UPGRADE: WEBSOCKET") {
-                debug!("Detected WebSocket protocol");
-                return Ok((Protocol::WebSocket, buffer));
+    Ok((protocol, buffer))
+}
+
+/// Largest TLS record: 5 header bytes and up to 2^14 bytes of plaintext
+const MAX_TLS_RECORD: usize = 5 + 16384;
+
+/// Whether `buffer` starts with a TLS handshake record header
+fn is_tls_handshake(buffer: &[u8]) -> bool {
+    buffer.len() >= 5 && buffer[0] == 0x16 && buffer[1] == 0x03
+}
+
+/// Bytes still missing from the TLS record `buffer` starts with, within
+/// `cap`; 0 when it is not a TLS handshake record
+fn tls_record_shortfall(buffer: &[u8], cap: usize) -> usize {
+    if !is_tls_handshake(buffer) {
+        return 0;
+    }
+    let record = 5 + u16::from_be_bytes([buffer[3], buffer[4]]) as usize;
+    record.min(cap).saturating_sub(buffer.len())
+}
+
+/// Complete a TLS record begun in `buffer`, up to `cap` bytes in all, so
+/// the ClientHello can be summarised
+async fn read_rest_of_tls_record<S>(stream: &mut S, buffer: &mut Vec<u8>, cap: usize) -> io::Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    loop {
+        let missing = tls_record_shortfall(buffer, cap);
+        if missing == 0 {
+            return Ok(());
+        }
+        let mut chunk = vec![0u8; missing];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// `detect_protocol` reading no more than detection needs: 2 bytes settle
+/// SOCKS5, a STUN header takes 20, HTTP reads to the end of its head. When
+/// the built-in signatures find nothing, reading continues up to the largest
+/// `min_bytes` of the custom detectors in `handlers`. Costs a read per step
+/// instead of one, in exchange for a buffer sized to the protocol. Like
+/// `ProtocolDetector::feed`, it waits for 12 bytes of an unrecognised opening.
+pub async fn detect_protocol_windowed<S>(stream: &mut S, handlers: &ProtocolHandlers) -> io::Result<(Protocol, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let detector = ProtocolDetector::shared();
+    let mut state = DetectionState::new();
+    let protocol = loop {
+        let have = state.buffered().len();
+        let want = ProtocolDetector::next_window(state.buffered()).clamp(have + 1, MAX_DETECTION_BYTES);
+        let mut chunk = vec![0u8; want - have];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break detector.finish(&mut state);
+        }
+        if let Some(protocol) = detector.feed(&mut state, &chunk[..n]) {
+            break protocol;
+        }
+    };
+
+    let mut buffer = state.into_buffer();
+    if protocol == Protocol::Unknown {
+        read_rest_of_tls_record(stream, &mut buffer, MAX_DETECTION_BYTES).await?;
+        let wanted = handlers.custom.iter().map(|(d, _)| d.min_bytes()).max().unwrap_or(0).min(MAX_DETECTION_BYTES);
+        while !buffer.is_empty() && buffer.len() < wanted {
+            let mut chunk = vec![0u8; wanted - buffer.len()];
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                break;
             }
-            
-            // Check for PAC file request
-            if text.contains("/proxy.pac") || text.contains("/wpad.dat") {
-                if text.contains("/wpad.dat") {
-                    debug!("Detected WPAD request");
-                    return Ok((Protocol::Wpad, buffer));
+            buffer.extend_from_slice(&chunk[..n]);
+        }
+    }
+    Ok((protocol, buffer))
+}
+
+/// A single step reported to a `DetectionTracer`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DetectionEvent {
+    /// A signature was evaluated against the input
+    Checked { signature: &'static str, matched: bool },
+    /// Detection finished with this protocol
+    Decided(Protocol),
+}
+
+/// Callback receiving every signature check and the final decision
+pub type DetectionTracer = Arc<dyn Fn(&DetectionEvent) + Send + Sync>;
+
+/// Pause after a failed accept so a persistent error (fd exhaustion) cannot
+/// spin; doubles while accepts keep failing, up to `ACCEPT_RETRY_MAX`
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(50);
+const ACCEPT_RETRY_MAX: Duration = Duration::from_secs(1);
+
+/// Next connection from `listener`. Accept errors belong to the connection
+/// that failed (a client reset before accept completed, fd exhaustion), not
+/// to the listener, so they are logged and accepting resumes.
+pub async fn accept_next(listener: &TcpListener) -> (TcpStream, SocketAddr) {
+    let mut backoff = Backoff::new(ACCEPT_RETRY_DELAY, ACCEPT_RETRY_MAX);
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(e) => {
+                warn!("Accept failed, still listening: {}", e);
+                tokio::time::sleep(backoff.next_delay()).await;
+            }
+        }
+    }
+}
+
+/// Ports `bind_in_range` tries before giving up on the range
+const BIND_RANGE_ATTEMPTS: usize = 64;
+
+/// Picks the port `bind_in_range` starts from; one stream for the process,
+/// so binds in deterministic mode do not all start at the same port
+fn port_rng() -> &'static Mutex<rand::rngs::StdRng> {
+    static RNG: OnceLock<Mutex<rand::rngs::StdRng>> = OnceLock::new();
+    RNG.get_or_init(|| Mutex::new(crate::determinism::rng()))
+}
+
+/// Listen on `ip` at a random free port of `ports` rather than a fixed one
+/// port scanners would probe; `0..=0` leaves the choice to the kernel.
+/// Whatever is advertised (dock manifest, PAC, SSDP) must then come from the
+/// listener's `local_addr`. `options` are set before each bind.
+pub fn bind_in_range(ip: IpAddr, ports: RangeInclusive<u16>, options: &TcpTuningOptions) -> io::Result<std::net::TcpListener> {
+    use rand::Rng;
+    let (first, last) = (*ports.start(), *ports.end());
+    if first > last {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("empty port range {}-{}", first, last)));
+    }
+    let len = (last - first) as usize + 1;
+    let start = port_rng().lock().unwrap().gen_range(0..len);
+    let mut last_error = None;
+    for i in 0..len.min(BIND_RANGE_ATTEMPTS) {
+        let port = first + ((start + i) % len) as u16;
+        match bind_with_options(SocketAddr::new(ip, port), options) {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => last_error = Some(e),
+            result => return result,
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("no free port in {}-{}: {}", first, last, last_error.map_or_else(String::new, |e| e.to_string())),
+    ))
+}
+
+/// `first-last`, or a single port, for `bind_in_range`
+pub fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let bad = || format!("invalid port range {:?}, expected first-last", s);
+    let (first, last) = s.split_once('-').unwrap_or((s, s));
+    let first: u16 = first.trim().parse().map_err(|_| bad())?;
+    let last: u16 = last.trim().parse().map_err(|_| bad())?;
+    if first > last {
+        return Err(bad());
+    }
+    Ok(first..=last)
+}
+
+/// Bytes a `DetectionState` buffers before giving up and deciding
+pub const MAX_DETECTION_BYTES: usize = 1024;
+
+/// Smallest opening `ProtocolDetector::tor_candidate` will judge
+const TOR_CANDIDATE_MIN_BYTES: usize = 256;
+
+/// Bits per byte; 256 random bytes average about 7.2, text stays under 5
+const TOR_CANDIDATE_MIN_ENTROPY: f64 = 7.0;
+
+const HTTP_METHODS: [&[u8]; 8] = [
+    b"GET ", b"POST ", b"PUT ", b"DELETE ", b"HEAD ", b"OPTIONS ", b"CONNECT ", b"PATCH ",
+];
+const SSDP_METHODS: [&[u8]; 2] = [b"M-SEARCH ", b"NOTIFY "];
+
+/// Client connection preface of HTTP/2 with prior knowledge (RFC 9113 §3.4)
+const H2C_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// How a connection that may carry HTTP/2 is routed. Cleartext prior
+/// knowledge goes to the h2c handler; over TLS, `h2` is negotiated inside
+/// the handshake, so the connection is relayed by SNI untouched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Http2Route {
+    H2c,
+    /// A ClientHello; `offers_h2` tells whether HTTP/2 may follow
+    TlsRelay(ClientHelloSummary),
+}
+
+/// Per-connection state for incremental detection with `ProtocolDetector::feed`.
+/// Holds the bytes seen so far so a handshake split across reads is judged whole.
+#[derive(Debug, Clone, Default)]
+pub struct DetectionState {
+    buffer: Vec<u8>,
+    decided: Option<Protocol>,
+}
+
+impl DetectionState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bytes fed so far, to be replayed to the protocol handler
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    pub fn into_buffer(self) -> Vec<u8> {
+        self.buffer
+    }
+
+    pub fn decided(&self) -> Option<Protocol> {
+        self.decided
+    }
+}
+
+/// Signature-based protocol classifier used by the universal listener.
+///
+/// Detection is stateless: `detect` and `feed` take `&self`, and all
+/// per-connection data lives in a `DetectionState`. The detector is `Send +
+/// Sync` and cheap to clone (the tracer is an `Arc`), so one instance can be
+/// shared behind an `Arc` by every connection task.
+#[derive(Default, Clone)]
+pub struct ProtocolDetector {
+    tracer: Option<DetectionTracer>,
+}
+
+impl ProtocolDetector {
+    pub fn new() -> Self {
+        Self { tracer: None }
+    }
+
+    /// Process-wide untraced detector. Signature tables are `static`, so
+    /// `new()` only costs an empty `Option` (a few ns in a release build);
+    /// this handle saves even that on the per-connection path and is a
+    /// refcount bump to clone into tasks.
+    pub fn shared() -> Arc<ProtocolDetector> {
+        static SHARED: OnceLock<Arc<ProtocolDetector>> = OnceLock::new();
+        SHARED.get_or_init(|| Arc::new(ProtocolDetector::new())).clone()
+    }
+
+    /// Report each checked signature and the decision to `tracer`
+    pub fn with_tracer(mut self, tracer: DetectionTracer) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    #[inline]
+    fn check(&self, signature: &'static str, matched: bool) -> bool {
+        if let Some(ref tracer) = self.tracer {
+            tracer(&DetectionEvent::Checked { signature, matched });
+        }
+        matched
+    }
+
+    #[inline]
+    fn decide(&self, protocol: Protocol) -> Protocol {
+        if let Some(ref tracer) = self.tracer {
+            tracer(&DetectionEvent::Decided(protocol));
+        }
+        protocol
+    }
+
+    /// Add `data` to `state` and return the protocol once it is certain.
+    /// `None` means the bytes so far are a prefix of some signature and more
+    /// are needed; after `MAX_DETECTION_BYTES` the best guess is final.
+    /// Once decided, further data is not buffered.
+    pub fn feed(&self, state: &mut DetectionState, data: &[u8]) -> Option<Protocol> {
+        if state.decided.is_some() {
+            return state.decided;
+        }
+        state.buffer.extend_from_slice(data);
+        if state.buffer.len() < MAX_DETECTION_BYTES && Self::needs_more(&state.buffer) {
+            return None;
+        }
+        state.decided = Some(self.detect(&state.buffer));
+        state.decided
+    }
+
+    /// Decide on whatever `state` holds, e.g. when the peer stops sending
+    pub fn finish(&self, state: &mut DetectionState) -> Protocol {
+        *state.decided.get_or_insert_with(|| self.detect(&state.buffer))
+    }
+
+    /// Whether `buffer` could still grow into a different decision
+    fn needs_more(buffer: &[u8]) -> bool {
+        let n = buffer.len();
+        if n < 2 {
+            return true;
+        }
+        if buffer[0] == 0x05 || is_socks4(buffer) {
+            return false;
+        }
+        if n < H2C_PREFACE.len() && H2C_PREFACE.starts_with(buffer) {
+            return true;
+        }
+        let matching = |methods: &[&'static [u8]]| {
+            methods.iter().copied().find(|m| buffer.starts_with(m) || m.starts_with(buffer))
+        };
+        if let Some(method) = matching(&HTTP_METHODS) {
+            // HTTP headers can still turn the request into WebSocket
+            return n < method.len() || !buffer.windows(4).any(|w| w == b"\r\n\r\n");
+        }
+        if let Some(method) = matching(&SSDP_METHODS) {
+            return n < method.len();
+        }
+        // STUN needs its 20-byte header, mDNS its 12-byte one
+        let maybe_stun = buffer[..2] == [0x00, 0x01] && n < 20;
+        maybe_stun || n < 12
+    }
+
+    /// Whether `buffer` stops short of a signature it has begun: a TLS
+    /// record header, the h2c preface or a request method. Unlike
+    /// `needs_more` it never waits on bytes the peer may not send
+    fn is_truncated_signature(buffer: &[u8]) -> bool {
+        let n = buffer.len();
+        let strict_prefix = |signature: &[u8]| signature.len() > n && signature.starts_with(buffer);
+        n < 2
+            || (buffer[0] == 0x16 && n < 5)
+            || strict_prefix(H2C_PREFACE)
+            || HTTP_METHODS.iter().chain(SSDP_METHODS.iter()).any(|m| strict_prefix(m))
+    }
+
+    /// Length `buffer` should reach before detection is worth retrying
+    fn next_window(buffer: &[u8]) -> usize {
+        let n = buffer.len();
+        if n < 2 {
+            return 2;
+        }
+        let partial = |methods: &[&'static [u8]]| {
+            methods.iter().copied().find(|m| m.len() > n && m.starts_with(buffer))
+        };
+        if let Some(method) = partial(&HTTP_METHODS).or_else(|| partial(&SSDP_METHODS)) {
+            return method.len();
+        }
+        if H2C_PREFACE.len() > n && H2C_PREFACE.starts_with(buffer) {
+            return H2C_PREFACE.len();
+        }
+        if HTTP_METHODS.iter().any(|m| buffer.starts_with(m)) {
+            // Headers end wherever they end
+            return MAX_DETECTION_BYTES;
+        }
+        if buffer[..2] == [0x00, 0x01] {
+            20
+        } else {
+            12
+        }
+    }
+
+    /// How strongly `buffer` matches `protocol`, 0 (no evidence) to 255.
+    /// A bare signature scores low; a well-formed handshake scores high.
+    pub fn confidence(protocol: Protocol, buffer: &[u8]) -> u8 {
+        let n = buffer.len();
+        match protocol {
+            // Method count agrees with the bytes that follow
+            Protocol::Socks5 if n >= 2 && n >= 2 + buffer[1] as usize && buffer[1] > 0 => 250,
+            Protocol::Socks5 => 120,
+            // Port, address and the userid's terminating NUL are all there
+            Protocol::Socks4 if n >= 9 && buffer[8..].contains(&0) => 250,
+            Protocol::Socks4 => 120,
+            Protocol::Http | Protocol::WebSocket | Protocol::Pac | Protocol::Wpad => {
+                let line_end = buffer.windows(2).position(|w| w == b"\r\n").unwrap_or(n);
+                let request_line = &buffer[..line_end];
+                if request_line.windows(7).any(|w| w == b" HTTP/1") {
+                    250
                 } else {
-                    debug!("Detected PAC request");
-                    return Ok((Protocol::Pac, buffer));
+                    120
                 }
             }
-            
-            debug!("Detected HTTP protocol");
-            return Ok((Protocol::Http, buffer));
+            Protocol::WebRTC => 250,
+            Protocol::Upnp => 200,
+            // Only the full 24-byte preface is detected
+            Protocol::H2c => 250,
+            // QR bit and opcode are a weak signal on arbitrary binary data
+            Protocol::Bonjour => 100,
+            Protocol::Unknown => 0,
         }
-        
-        // UPnP M-SEARCH (SSDP)
-        if text.starts_with("M-SEARCH ") {
-            debug!("Detected UPnP M-SEARCH");
-            return Ok((Protocol::Upnp, buffer));
+    }
+
+    /// `detect` plus the `confidence` of the decision
+    pub fn detect_scored(&self, buffer: &[u8]) -> (Protocol, u8) {
+        let protocol = self.detect(buffer);
+        (protocol, Self::confidence(protocol, buffer))
+    }
+
+    /// Flag unclassified bytes that look like a Tor/obfs4 handshake: a
+    /// uniformly random opening with no TLS record header. Only a hint for
+    /// policy and logging, so it scores low and never changes routing.
+    /// Needs `TOR_CANDIDATE_MIN_BYTES` because short samples cannot reach
+    /// the entropy threshold even when random.
+    pub fn tor_candidate(buffer: &[u8]) -> Option<(ProtocolType, u8)> {
+        let sample = &buffer[..buffer.len().min(MAX_DETECTION_BYTES)];
+        if sample.len() < TOR_CANDIDATE_MIN_BYTES || sample.starts_with(&[0x16, 0x03]) {
+            return None;
         }
-        
-        // UPnP NOTIFY
-        if text.starts_with("NOTIFY ") {
-            debug!("Detected UPnP NOTIFY");
-            return Ok((Protocol::Upnp, buffer));
+        let mut counts = [0usize; 256];
+        for &b in sample {
+            counts[b as usize] += 1;
         }
+        let len = sample.len() as f64;
+        let entropy: f64 = counts
+            .iter()
+            .filter(|&&c| c > 0)
+            .map(|&c| {
+                let p = c as f64 / len;
+                -p * p.log2()
+            })
+            .sum();
+        (entropy >= TOR_CANDIDATE_MIN_ENTROPY).then_some((ProtocolType::Tor, 40))
     }
-    
-    // Binary protocol detection
-    
-    // WebRTC STUN binding request (starts with 0x00 0x01)
-    if n >= 20 && buffer[0] == 0x00 && buffer[1] == 0x01 {
-        // STUN magic cookie at bytes 4-7: 0x2112A442
-        if n >= 8 && 
-           buffer[4] == 0x21 && 
-           buffer[5] == 0x12 && 
-           buffer[6] == 0xA4 && 
-           buffer[7] == 0x42 {
-            debug!("Detected WebRTC STUN");
-            return Ok((Protocol::WebRTC, buffer));
+
+    /// `Http2Route` for `buffer`; `None` when it is neither the h2c
+    /// preface nor a ClientHello
+    pub fn http2_route(&self, buffer: &[u8]) -> Option<Http2Route> {
+        if self.detect(buffer) == Protocol::H2c {
+            return Some(Http2Route::H2c);
         }
+        summarize_client_hello(buffer).map(Http2Route::TlsRelay)
     }
-    
-    // mDNS/Bonjour (DNS packets on port 5353)
-    // DNS header starts with transaction ID (2 bytes) followed by flags
-    // mDNS typically has flags with QR=0 (query) or QR=1 (response)
-    if n >= 12 {
-        // Check for DNS-like structure
-        let flags = (buffer[2] as u16) << 8 | buffer[3] as u16;
-        let opcode = (flags >> 11) & 0x0F;
-        
-        // Standard query
-        if opcode == 0 {
-            // Check for mDNS multicast bit (bit 15)
-            if (flags & 0x8000) != 0 {
-                debug!("Detected Bonjour/mDNS protocol");
-                return Ok((Protocol::Bonjour, buffer));
+
+    /// Classify the first bytes of a connection
+    pub fn detect(&self, buffer: &[u8]) -> Protocol {
+        let n = buffer.len();
+
+        // SOCKS5 starts with version byte 0x05
+        if self.check("socks5.version", n >= 2 && buffer[0] == 0x05) {
+            debug!("Detected SOCKS5 protocol");
+            return self.decide(Protocol::Socks5);
+        }
+
+        if self.check("socks4.version", is_socks4(buffer)) {
+            debug!("Detected SOCKS4 protocol");
+            return self.decide(Protocol::Socks4);
+        }
+
+        if self.check("h2c.preface", buffer.starts_with(H2C_PREFACE)) {
+            debug!("Detected HTTP/2 cleartext preface");
+            return self.decide(Protocol::H2c);
+        }
+
+        // Check for text-based protocols
+        if let Ok(text) = std::str::from_utf8(&buffer[..std::cmp::min(n, 512)]) {
+            // HTTP methods: GET, POST, PUT, DELETE, HEAD, OPTIONS, CONNECT, PATCH
+            let is_http = text.starts_with("GET ") ||
+               text.starts_with("POST ") ||
+               text.starts_with("PUT ") ||
+               text.starts_with("DELETE ") ||
+               text.starts_with("HEAD ") ||
+               text.starts_with("OPTIONS ") ||
+               text.starts_with("CONNECT ") ||
+               text.starts_with("PATCH ");
+
+            if self.check("http.method", is_http) {
+                // Check for WebSocket upgrade
+                if self.check("websocket.upgrade", text.to_uppercase().contains("UPGRADE: WEBSOCKET")) {
+                    debug!("Detected WebSocket protocol");
+                    return self.decide(Protocol::WebSocket);
+                }
+
+                // Check for PAC file request
+                if self.check("wpad.path", text.contains("/wpad.dat")) {
+                    debug!("Detected WPAD request");
+                    return self.decide(Protocol::Wpad);
+                }
+                if self.check("pac.path", text.contains("/proxy.pac")) {
+                    debug!("Detected PAC request");
+                    return self.decide(Protocol::Pac);
+                }
+
+                debug!("Detected HTTP protocol");
+                return self.decide(Protocol::Http);
             }
+
+            // UPnP M-SEARCH (SSDP)
+            if self.check("ssdp.msearch", text.starts_with("M-SEARCH ")) {
+                debug!("Detected UPnP M-SEARCH");
+                return self.decide(Protocol::Upnp);
+            }
+
+            // UPnP NOTIFY
+            if self.check("ssdp.notify", text.starts_with("NOTIFY ")) {
+                debug!("Detected UPnP NOTIFY");
+                return self.decide(Protocol::Upnp);
+            }
+        }
+
+        // Binary protocol detection
+
+        // WebRTC STUN binding request (starts with 0x00 0x01),
+        // STUN magic cookie at bytes 4-7: 0x2112A442
+        let is_stun = n >= 20 && buffer[0] == 0x00 && buffer[1] == 0x01 &&
+            buffer[4..8] == [0x21, 0x12, 0xA4, 0x42];
+        if self.check("stun.magic_cookie", is_stun) {
+            debug!("Detected WebRTC STUN");
+            return self.decide(Protocol::WebRTC);
+        }
+
+        // mDNS/Bonjour (DNS packets on port 5353)
+        // DNS header starts with transaction ID (2 bytes) followed by flags;
+        // a standard query opcode with the QR bit set is treated as mDNS
+        let is_mdns = n >= 12 && {
+            let flags = (buffer[2] as u16) << 8 | buffer[3] as u16;
+            let opcode = (flags >> 11) & 0x0F;
+            opcode == 0 && (flags & 0x8000) != 0
+        };
+        if self.check("mdns.header", is_mdns) {
+            debug!("Detected Bonjour/mDNS protocol");
+            return self.decide(Protocol::Bonjour);
         }
+
+        debug!("Unknown protocol detected");
+        self.decide(Protocol::Unknown)
     }
-    
-    debug!("Unknown protocol detected");
-    Ok((Protocol::Unknown, buffer))
 }
 
 /// Specialized protocol detection for TcpStream using POSIX peek when available
 
+/// SOCKS4 version byte followed by CONNECT or BIND
+fn is_socks4(buffer: &[u8]) -> bool {
+    buffer.len() >= 2 && buffer[0] == 0x04 && matches!(buffer[1], 0x01 | 0x02)
+}
+
 pub fn detect_protocol_posix(stream: &TcpStream) -> io::Result<Protocol> {
     let mut buffer = [0u8; 512];
     let n = posix_peek(stream, &mut buffer)?;
@@ -140,6 +630,10 @@ pub fn detect_protocol_posix(stream: &TcpStream) -> io::Result<Protocol> {
         debug!("POSIX: Detected SOCKS5 protocol");
         return Ok(Protocol::Socks5);
     }
+    if is_socks4(&buffer[..n]) {
+        debug!("POSIX: Detected SOCKS4 protocol");
+        return Ok(Protocol::Socks4);
+    }
     
     // Check for text-based protocols
     if let Ok(text) = std::str::from_utf8(&buffer[..std::cmp::min(n, 256)]) {
@@ -173,6 +667,138 @@ pub fn detect_protocol_posix(stream: &TcpStream) -> io::Result<Protocol> {
     Ok(Protocol::Unknown)
 }
 
+/// How refused clients that speak neither HTTP nor SOCKS5 are closed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RawReject {
+    /// Close normally without a word
+    #[default]
+    Drop,
+    /// Abort with a TCP RST so the client fails fast instead of waiting
+    Reset,
+}
+
+/// Answer a refused client in its own protocol so it does not retry
+/// blindly: HTTP gets a 403 page, SOCKS5 (once its request was read)
+/// `ConnectionNotAllowed`, anything else nothing. Shuts down the write side.
+pub async fn reject<S>(stream: &mut S, protocol: Protocol, reason: &str) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    match protocol {
+        Protocol::Http | Protocol::WebSocket | Protocol::Pac | Protocol::Wpad => {
+            let body = format!(
+                "<!DOCTYPE html>\n<html><head><title>403 Forbidden</title>\
+                 <style>body{{font-family:sans-serif;margin:3em;color:#333}}h1{{color:#b00}}</style></head>\
+                 <body><h1>Blocked by LiteBike</h1><p>{}</p></body></html>\n",
+                html_escape(reason)
+            );
+            let response = format!(
+                "HTTP/1.1 403 Forbidden\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await?;
+        }
+        Protocol::Socks5 => {
+            let unbound = SocketAddr::from(([0, 0, 0, 0], 0));
+            stream.write_all(&build_socks5_reply(Socks5Reply::ConnectionNotAllowed, unbound)).await?;
+        }
+        Protocol::Socks4 => {
+            let unbound = SocketAddr::from(([0, 0, 0, 0], 0));
+            stream.write_all(&build_socks4_reply(false, unbound)).await?;
+        }
+        _ => {}
+    }
+    debug!("Rejected {:?} client: {}", protocol, reason);
+    stream.shutdown().await
+}
+
+/// `reject` for a client still on its socket, honouring `raw` for
+/// connections that are neither HTTP nor SOCKS5
+pub async fn reject_tcp(
+    mut stream: PrefixedStream<TcpStream>,
+    protocol: Protocol,
+    reason: &str,
+    raw: RawReject,
+) -> io::Result<()> {
+    let is_raw = !matches!(
+        protocol,
+        Protocol::Http | Protocol::WebSocket | Protocol::Pac | Protocol::Wpad | Protocol::Socks5 | Protocol::Socks4
+    );
+    if is_raw && raw == RawReject::Reset {
+        debug!("Resetting {:?} client: {}", protocol, reason);
+        return reset_on_close(&stream.inner);
+    }
+    reject(&mut stream, protocol, reason).await
+}
+
+/// Make dropping `stream` send RST instead of FIN. tokio deprecates
+/// `set_linger` because a non-zero linger blocks on drop; zero never does.
+#[allow(deprecated)]
+pub fn reset_on_close(stream: &TcpStream) -> io::Result<()> {
+    stream.set_linger(Some(Duration::ZERO))
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// PROXY protocol v2 signature (HAProxy proxy-protocol.txt §2.2)
+pub const PROXY_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Build a PROXY protocol v2 header announcing a TCP connection from
+/// `client` to `target`. Mixed families are sent as IPv6 with the IPv4
+/// side mapped, since one header carries a single address family.
+pub fn emit_proxy_protocol_v2(client: SocketAddr, target: SocketAddr) -> Vec<u8> {
+    let mut header = PROXY_V2_SIGNATURE.to_vec();
+    header.push(0x21); // version 2, PROXY command
+    match (client.ip(), target.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            header.push(0x11); // TCP over IPv4
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                IpAddr::V6(v6) => v6,
+            };
+            header.push(0x21); // TCP over IPv6
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&v6(src).octets());
+            header.extend_from_slice(&v6(dst).octets());
+        }
+    }
+    header.extend_from_slice(&client.port().to_be_bytes());
+    header.extend_from_slice(&target.port().to_be_bytes());
+    header
+}
+
+/// Decode a PROXY protocol v2 header for TCP, returning the client and
+/// target addresses and the header length
+pub fn parse_proxy_protocol_v2(data: &[u8]) -> Option<(SocketAddr, SocketAddr, usize)> {
+    if data.len() < 16 || data[..12] != PROXY_V2_SIGNATURE || data[12] != 0x21 {
+        return None;
+    }
+    let len = u16::from_be_bytes([data[14], data[15]]) as usize;
+    let body = data.get(16..16 + len)?;
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+    let (client, target) = match data[13] {
+        0x11 if len >= 12 => {
+            let src: [u8; 4] = body[0..4].try_into().ok()?;
+            let dst: [u8; 4] = body[4..8].try_into().ok()?;
+            (SocketAddr::from((src, port(8))), SocketAddr::from((dst, port(10))))
+        }
+        0x21 if len >= 36 => {
+            let src: [u8; 16] = body[0..16].try_into().ok()?;
+            let dst: [u8; 16] = body[16..32].try_into().ok()?;
+            (SocketAddr::from((src, port(32))), SocketAddr::from((dst, port(34))))
+        }
+        _ => return None,
+    };
+    Some((client, target, 16 + len))
+}
 
 /// Wrapper stream that prefixes read operations with buffered data
 pub struct PrefixedStream<S> {
@@ -241,34 +867,228 @@ where
     }
 }
 
-/// Handler function type
+/// Handler function type. The handler owns the connection: the
+/// `PrefixedStream` first replays the bytes consumed by detection, then reads
+/// from the socket, and dropping it closes the connection.
 pub type ProtocolHandler = Box<dyn Fn(PrefixedStream<TcpStream>) -> std::pin::Pin<Box<dyn std::future::Future<Output = io::Result<()>> + Send>> + Send + Sync>;
 
 /// Protocol handlers collection
 pub struct ProtocolHandlers {
     pub http: ProtocolHandler,
     pub socks5: ProtocolHandler,
+    /// SOCKS4 and 4a clients; without it they go to `socks5`, which then
+    /// has to accept version 4 requests as well
+    pub socks4: Option<ProtocolHandler>,
     pub websocket: Option<ProtocolHandler>,
     pub webrtc: Option<ProtocolHandler>,
     pub pac: Option<ProtocolHandler>,
     pub wpad: Option<ProtocolHandler>,
     pub bonjour: Option<ProtocolHandler>,
     pub upnp: Option<ProtocolHandler>,
+    /// HTTP/2 with prior knowledge; without it h2c goes to `fallback`
+    pub h2c: Option<ProtocolHandler>,
+    /// TLS clients, to be relayed by SNI; h2 negotiated over TLS arrives here too
+    pub tls: Option<ProtocolHandler>,
+    /// Receives greeted connections on server-speaks-first ports
+    pub server_first: Option<ProtocolHandler>,
+    /// Receives unknown protocols and detections below `UnifiedPortConfig::min_confidence`
+    pub fallback: Option<ProtocolHandler>,
+    /// Protocols added with `register_boxed`, tried in registration order
+    pub custom: Vec<(Box<dyn CustomProtocolDetector>, ProtocolHandler)>,
+}
+
+impl ProtocolHandlers {
+    /// Route connections `detector` claims to `handler`
+    pub fn register_boxed(&mut self, detector: Box<dyn CustomProtocolDetector>, handler: ProtocolHandler) {
+        self.custom.push((detector, handler));
+    }
+}
+
+/// Detector for a protocol the built-in `ProtocolDetector` does not know,
+/// for handlers shipped outside this crate.
+///
+/// `matches` sees the connection's first bytes: at least `min_bytes` and
+/// at most `max_bytes` of them (capped at `MAX_DETECTION_BYTES`), not
+/// necessarily a whole message. It is consulted only when the built-in
+/// detector returns `Protocol::Unknown`, before the fallback handler, and
+/// runs on the accept path, so it must be cheap and never block.
+pub trait CustomProtocolDetector: Send + Sync {
+    /// Used in logs
+    fn name(&self) -> &str;
+
+    /// Bytes needed before `matches` can answer; windowed detection reads this far
+    fn min_bytes(&self) -> usize {
+        1
+    }
+
+    /// Bytes past which `matches` learns nothing more
+    fn max_bytes(&self) -> usize {
+        MAX_DETECTION_BYTES
+    }
+
+    fn matches(&self, buffer: &[u8]) -> bool;
+}
+
+/// Protocols where the server sends the first line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerFirstProtocol {
+    Smtp,
+    Ftp,
+}
+
+impl ServerFirstProtocol {
+    /// Banner sent to a client that waits for the server
+    pub fn greeting(&self) -> &'static [u8] {
+        match self {
+            ServerFirstProtocol::Smtp => b"220 litebike ESMTP ready\r\n",
+            ServerFirstProtocol::Ftp => b"220 litebike FTP ready\r\n",
+        }
+    }
+}
+
+/// Local ports whose clients may stay silent until greeted
+#[derive(Debug, Clone)]
+pub struct ServerFirstConfig {
+    pub ports: HashMap<u16, ServerFirstProtocol>,
+    /// How long a silent client gets to speak before it is greeted
+    pub wait: Duration,
+}
+
+impl Default for ServerFirstConfig {
+    fn default() -> Self {
+        Self {
+            ports: HashMap::new(),
+            wait: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Routing policy for the shared port
+#[derive(Debug, Clone, Default)]
+pub struct UnifiedPortConfig {
+    pub server_first: ServerFirstConfig,
+    /// Detections scoring below this go to `ProtocolHandlers::fallback`
+    /// instead of the matched handler; 0 routes every match
+    pub min_confidence: u8,
+    /// JA3 hashes (as `tls_fingerprint::ja3_from_client_hello` computes
+    /// them) of TLS clients to drop before any handler sees them
+    pub ja3_blocklist: HashSet<String>,
+    /// Detect with `detect_protocol_windowed` rather than one full-size read
+    pub windowed_detection: bool,
+    /// How JA3-blocked and other raw clients are closed
+    pub raw_reject: RawReject,
 }
 
 /// Handle a connection with protocol detection
 pub async fn handle_connection(
+    stream: TcpStream,
+    handlers: &ProtocolHandlers,
+) -> io::Result<()> {
+    handle_connection_with(stream, handlers, &UnifiedPortConfig::default()).await
+}
+
+/// `handle_connection` under `config`. A client on one of the server-first
+/// ports which sends nothing within the wait is greeted and handed to
+/// `handlers.server_first` instead of blocking detection forever.
+pub async fn handle_connection_with(
     mut stream: TcpStream,
     handlers: &ProtocolHandlers,
+    config: &UnifiedPortConfig,
 ) -> io::Result<()> {
+    let server_first = &config.server_first;
     let peer_addr = stream.peer_addr()?;
     info!("New connection from {}", peer_addr);
     
-    let (protocol, buffer) = detect_protocol(&mut stream).await?;
+    let local_port = stream.local_addr()?.port();
+    if let Some(&protocol) = server_first.ports.get(&local_port) {
+        let mut probe = [0u8; 1];
+        match tokio::time::timeout(server_first.wait, stream.peek(&mut probe)).await {
+            Ok(Ok(0)) => {
+                debug!("{} closed before sending anything", peer_addr);
+                return Ok(());
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                info!("{} is silent on port {}, greeting as {:?}", peer_addr, local_port, protocol);
+                stream.write_all(protocol.greeting()).await?;
+                return match handlers.server_first {
+                    Some(ref handler) => handler(PrefixedStream::new(stream, Vec::new())).await,
+                    None => Err(io::Error::new(io::ErrorKind::Unsupported, "No server-first handler configured")),
+                };
+            }
+        }
+    }
+    
+    let (protocol, mut buffer) = if config.windowed_detection {
+        detect_protocol_windowed(&mut stream, handlers).await?
+    } else {
+        detect_protocol(&mut stream).await?
+    };
+    let screening = !config.ja3_blocklist.is_empty() && is_tls_handshake(&buffer);
+    if screening {
+        // A ClientHello longer than the detection window is read whole
+        read_rest_of_tls_record(&mut stream, &mut buffer, MAX_TLS_RECORD).await?;
+    }
+    let confidence = ProtocolDetector::confidence(protocol, &buffer);
+    // Logged for every TLS client, so a blocklist can be built from the log
+    match ja3_from_client_hello(&buffer) {
+        Some(ja3) if config.ja3_blocklist.contains(&ja3) => {
+            info!("Dropping {}: blocklisted JA3 {}", peer_addr, ja3);
+            let reason = format!("blocklisted JA3 {}", ja3);
+            return reject_tcp(PrefixedStream::new(stream, buffer), Protocol::Unknown, &reason, config.raw_reject).await;
+        }
+        Some(ja3) => info!("{} TLS JA3 {}", peer_addr, ja3),
+        // A hello the blocklist cannot be checked against is not let through
+        None if screening => {
+            info!("Dropping {}: ClientHello incomplete or spread over several records", peer_addr);
+            return reject_tcp(PrefixedStream::new(stream, buffer), Protocol::Unknown, "unreadable ClientHello", config.raw_reject).await;
+        }
+        None => {}
+    }
+    if protocol == Protocol::Unknown {
+        if let Some((flag, flag_confidence)) = ProtocolDetector::tor_candidate(&buffer) {
+            info!("{} looks like a {} handshake (confidence {})", peer_addr, flag, flag_confidence);
+        }
+    }
+    let tls_hello = match (protocol, &handlers.tls) {
+        (Protocol::Unknown, Some(_)) => summarize_client_hello(&buffer),
+        _ => None,
+    };
+    
+    let custom = match protocol {
+        Protocol::Unknown => handlers.custom.iter().find(|(detector, _)| {
+            buffer.len() >= detector.min_bytes().min(MAX_DETECTION_BYTES)
+                && detector.matches(&buffer[..buffer.len().min(detector.max_bytes())])
+        }),
+        _ => None,
+    };
     
     // Create a prefixed stream that includes the already-read bytes
     let prefixed_stream = PrefixedStream::new(stream, buffer);
     
+    if let (Some(hello), Some(handler)) = (tls_hello, &handlers.tls) {
+        info!("Routing {} to TLS handler (SNI {:?}, h2 offered: {})", peer_addr, hello.sni, hello.offers_h2());
+        return handler(prefixed_stream).await;
+    }
+    
+    if let Some((detector, handler)) = custom {
+        info!("Routing {} to {} handler", peer_addr, detector.name());
+        return handler(prefixed_stream).await;
+    }
+    
+    if protocol == Protocol::Unknown || confidence < config.min_confidence {
+        if let Some(ref handler) = handlers.fallback {
+            info!("Routing {} to fallback handler ({:?}, confidence {})", peer_addr, protocol, confidence);
+            return handler(prefixed_stream).await;
+        }
+        if protocol != Protocol::Unknown {
+            info!("{:?} from {} below confidence threshold ({} < {}), closing connection",
+                protocol, peer_addr, confidence, config.min_confidence);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Low-confidence protocol match"));
+        }
+    }
+    
     match protocol {
         Protocol::Http => {
             info!("Routing {} to HTTP handler", peer_addr);
@@ -278,6 +1098,16 @@ pub async fn handle_connection(
             info!("Routing {} to SOCKS5 handler", peer_addr);
             (handlers.socks5)(prefixed_stream).await
         }
+        Protocol::Socks4 => {
+            if let Some(ref handler) = handlers.socks4 {
+                info!("Routing {} to SOCKS4 handler", peer_addr);
+                handler(prefixed_stream).await
+            } else {
+                // The SOCKS5 handler sees the version byte and serves both
+                info!("Routing {} (SOCKS4) to SOCKS5 handler", peer_addr);
+                (handlers.socks5)(prefixed_stream).await
+            }
+        }
         Protocol::WebSocket => {
             if let Some(ref handler) = handlers.websocket {
                 info!("Routing {} to WebSocket handler", peer_addr);
@@ -332,6 +1162,18 @@ pub async fn handle_connection(
                 Err(io::Error::new(io::ErrorKind::InvalidData, "UPnP not supported"))
             }
         }
+        Protocol::H2c => {
+            if let Some(ref handler) = handlers.h2c {
+                info!("Routing {} to h2c handler", peer_addr);
+                handler(prefixed_stream).await
+            } else if let Some(ref handler) = handlers.fallback {
+                info!("h2c from {} but no handler configured, using fallback", peer_addr);
+                handler(prefixed_stream).await
+            } else {
+                info!("h2c from {} but no handler configured", peer_addr);
+                Err(io::Error::new(io::ErrorKind::InvalidData, "h2c not supported"))
+            }
+        }
         Protocol::Unknown => {
             info!("Unknown protocol from {}, closing connection", peer_addr);
             Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown protocol"))
@@ -343,6 +1185,403 @@ pub async fn handle_connection(
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_proxy_protocol_v2_round_trip() {
+        let client: SocketAddr = "192.168.43.20:51234".parse().unwrap();
+        let target: SocketAddr = "93.184.216.34:443".parse().unwrap();
+        let header = emit_proxy_protocol_v2(client, target);
+        assert_eq!(header.len(), 28);
+        assert_eq!(parse_proxy_protocol_v2(&header), Some((client, target, 28)));
+
+        let target6: SocketAddr = "[2001:db8::1]:8443".parse().unwrap();
+        let header = emit_proxy_protocol_v2(client, target6);
+        let (decoded_client, decoded_target, len) = parse_proxy_protocol_v2(&header).unwrap();
+        assert_eq!(len, 52);
+        assert_eq!(decoded_client.port(), client.port());
+        assert_eq!(decoded_client.ip(), IpAddr::V6("::ffff:192.168.43.20".parse().unwrap()));
+        assert_eq!(decoded_target, target6);
+    }
+
+    #[tokio::test]
+    async fn test_shared_detector_across_tasks() {
+        let detector = Arc::new(ProtocolDetector::new());
+        let inputs: [(&[u8], Protocol); 5] = [
+            (b"GET /index.html HTTP/1.1\r\nHost: a\r\n\r\n", Protocol::Http),
+            (b"GET /chat HTTP/1.1\r\nUpgrade: websocket\r\n\r\n", Protocol::WebSocket),
+            (b"\x05\x01\x00", Protocol::Socks5),
+            (b"\x04\x01\x00\x50\x01\x02\x03\x04user\x00", Protocol::Socks4),
+            (b"M-SEARCH * HTTP/1.1\r\nST: ssdp:all\r\n\r\n", Protocol::Upnp),
+        ];
+
+        let mut tasks = Vec::new();
+        for _ in 0..8 {
+            for (input, expected) in inputs {
+                let detector = Arc::clone(&detector);
+                tasks.push(tokio::spawn(async move {
+                    // Feed a few bytes at a time, as a fragmented handshake arrives
+                    let mut state = DetectionState::new();
+                    let mut decided = None;
+                    for chunk in input.chunks(3) {
+                        decided = detector.feed(&mut state, chunk);
+                        if decided.is_some() {
+                            break;
+                        }
+                        tokio::task::yield_now().await;
+                    }
+                    let protocol = decided.unwrap_or_else(|| detector.finish(&mut state));
+                    assert_eq!(protocol, expected);
+                    assert!(input.starts_with(state.buffered()));
+                }));
+            }
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+    }
+
+    #[test]
+    fn test_shared_detector_handles_agree() {
+        let a = ProtocolDetector::shared();
+        let b = ProtocolDetector::shared();
+        assert!(Arc::ptr_eq(&a, &b));
+        let inputs: [&[u8]; 4] = [
+            b"GET / HTTP/1.1\r\n\r\n",
+            &[0x05, 0x01, 0x00],
+            b"M-SEARCH * HTTP/1.1\r\n",
+            b"\x16\x03\x01\x00\x05hello",
+        ];
+        for input in inputs {
+            assert_eq!(a.detect_scored(input), b.detect_scored(input));
+        }
+    }
+
+    #[test]
+    fn test_tor_candidate_flags_random_bytes_only() {
+        use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+        let mut random = vec![0u8; 512];
+        StdRng::seed_from_u64(7).fill_bytes(&mut random);
+        let detector = ProtocolDetector::new();
+        assert_eq!(detector.detect(&random), Protocol::Unknown);
+        let (flag, confidence) = ProtocolDetector::tor_candidate(&random).expect("random bytes should be flagged");
+        assert_eq!(flag, ProtocolType::Tor);
+        assert!(confidence < 100);
+
+        let mut http = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n".to_vec();
+        http.resize(512, b'a');
+        assert_eq!(ProtocolDetector::tor_candidate(&http), None);
+        // Too short to judge, however random
+        assert_eq!(ProtocolDetector::tor_candidate(&random[..64]), None);
+    }
+
+    #[test]
+    fn test_feed_waits_for_websocket_headers() {
+        let detector = ProtocolDetector::new();
+        let mut state = DetectionState::new();
+        assert_eq!(detector.feed(&mut state, b"GE"), None);
+        assert_eq!(detector.feed(&mut state, b"T /chat HTTP/1.1\r\n"), None);
+        assert_eq!(detector.feed(&mut state, b"Upgrade: websocket\r\n\r\n"), Some(Protocol::WebSocket));
+    }
+
+    /// Handler for routes a test never expects to take
+    fn unused_handler() -> ProtocolHandler {
+        Box::new(|_| Box::pin(async { Err(io::Error::new(io::ErrorKind::Other, "unexpected handler")) }))
+    }
+
+    /// Handlers with every route unused or absent; tests override the
+    /// fields they exercise
+    fn test_handlers() -> ProtocolHandlers {
+        ProtocolHandlers {
+            http: unused_handler(),
+            socks5: unused_handler(),
+            socks4: None,
+            websocket: None,
+            webrtc: None,
+            pac: None,
+            wpad: None,
+            bonjour: None,
+            upnp: None,
+            h2c: None,
+            tls: None,
+            server_first: None,
+            fallback: None,
+            custom: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_first_smtp_delayed_client() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handlers = ProtocolHandlers {
+            server_first: Some(Box::new(|mut stream| Box::pin(async move {
+                let mut line = [0u8; 13];
+                stream.read_exact(&mut line).await?;
+                assert_eq!(&line, b"EHLO client\r\n");
+                stream.write_all(b"250 litebike\r\n").await
+            }))),
+            ..test_handlers()
+        };
+        let config = UnifiedPortConfig {
+            server_first: ServerFirstConfig {
+                ports: HashMap::from([(addr.port(), ServerFirstProtocol::Smtp)]),
+                wait: Duration::from_millis(50),
+            },
+            ..Default::default()
+        };
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_connection_with(stream, &handlers, &config).await
+        });
+
+        // Like a real SMTP client: connect, then wait for the banner
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let mut banner = vec![0u8; ServerFirstProtocol::Smtp.greeting().len()];
+        client.read_exact(&mut banner).await.unwrap();
+        assert!(banner.starts_with(b"220 "));
+        client.write_all(b"EHLO client\r\n").await.unwrap();
+        let mut reply = [0u8; 14];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"250 litebike\r\n");
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_min_confidence_routes_weak_match_to_fallback() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let routed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recording = |name: &'static str| -> ProtocolHandler {
+            let routed = routed.clone();
+            Box::new(move |_| {
+                routed.lock().unwrap().push(name);
+                Box::pin(async { Ok(()) })
+            })
+        };
+        let handlers = ProtocolHandlers {
+            http: recording("http"),
+            socks5: recording("socks5"),
+            fallback: Some(recording("fallback")),
+            ..test_handlers()
+        };
+        let config = UnifiedPortConfig { min_confidence: 200, ..Default::default() };
+
+        let weak_http: &[u8] = b"GET something-else";
+        let strong_socks5: &[u8] = b"\x05\x01\x00";
+        let (protocol, confidence) = ProtocolDetector::new().detect_scored(weak_http);
+        assert_eq!(protocol, Protocol::Http);
+        assert!(confidence < config.min_confidence);
+        for input in [weak_http, strong_socks5] {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(input).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_connection_with(stream, &handlers, &config).await.unwrap();
+        }
+        assert_eq!(*routed.lock().unwrap(), vec!["fallback", "socks5"]);
+    }
+
+    #[tokio::test]
+    async fn test_socks4_routes_to_its_handler_or_socks5() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let routed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recording = |name: &'static str| -> ProtocolHandler {
+            let routed = routed.clone();
+            Box::new(move |_| {
+                routed.lock().unwrap().push(name);
+                Box::pin(async { Ok(()) })
+            })
+        };
+        let socks4_request: &[u8] = b"\x04\x01\x00\x50\x7f\x00\x00\x01bike\x00";
+        let with_socks4 = ProtocolHandlers { socks5: recording("socks5"), socks4: Some(recording("socks4")), ..test_handlers() };
+        let without = ProtocolHandlers { socks5: recording("socks5"), ..test_handlers() };
+        for handlers in [&with_socks4, &without] {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(socks4_request).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_connection(stream, handlers).await.unwrap();
+        }
+        assert_eq!(*routed.lock().unwrap(), vec!["socks4", "socks5"]);
+    }
+
+    #[tokio::test]
+    async fn test_ja3_blocklist_drops_matching_client_hello() {
+        use crate::tls_fingerprint::{MobileBrowserProfile, TlsFingerprintManager};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let routed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recording = |name: &'static str| -> ProtocolHandler {
+            let routed = routed.clone();
+            Box::new(move |_| {
+                routed.lock().unwrap().push(name);
+                Box::pin(async { Ok(()) })
+            })
+        };
+        let handlers = ProtocolHandlers {
+            http: recording("http"),
+            socks5: recording("socks5"),
+            fallback: Some(recording("tls")),
+            ..test_handlers()
+        };
+
+        let scanner = TlsFingerprintManager::sticky(MobileBrowserProfile::Safari17).generate_client_hello("example.com").unwrap();
+        let browser = TlsFingerprintManager::sticky(MobileBrowserProfile::Chrome120Mobile).generate_client_hello("example.com").unwrap();
+        let blocked = ja3_from_client_hello(&scanner).unwrap();
+        assert_ne!(ja3_from_client_hello(&browser), Some(blocked.clone()));
+        let config = UnifiedPortConfig { ja3_blocklist: HashSet::from([blocked]), ..Default::default() };
+
+        for hello in [&scanner, &browser] {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(hello).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_connection_with(stream, &handlers, &config).await.unwrap();
+        }
+        assert_eq!(*routed.lock().unwrap(), vec!["tls"]);
+    }
+
+    /// `hello` with a padding extension of `len` zero bytes appended and
+    /// every enclosing length fixed up
+    fn pad_client_hello(hello: &[u8], len: usize) -> Vec<u8> {
+        let ciphers = 46 + u16::from_be_bytes([hello[44], hello[45]]) as usize;
+        let extensions = ciphers + 1 + hello[ciphers] as usize;
+        let mut padded = hello.to_vec();
+        padded.extend_from_slice(&0x0015u16.to_be_bytes());
+        padded.extend_from_slice(&(len as u16).to_be_bytes());
+        padded.resize(padded.len() + len, 0);
+        let grow = |at: usize, width: usize, padded: &mut Vec<u8>| {
+            let field = &mut padded[at..at + width];
+            let value = field.iter().fold(0usize, |acc, &b| acc << 8 | b as usize) + 4 + len;
+            for (i, byte) in field.iter_mut().enumerate() {
+                *byte = (value >> (8 * (width - 1 - i))) as u8;
+            }
+        };
+        grow(3, 2, &mut padded);
+        grow(6, 3, &mut padded);
+        grow(extensions, 2, &mut padded);
+        padded
+    }
+
+    #[tokio::test]
+    async fn test_ja3_blocklist_fails_closed_on_long_or_cut_hellos() {
+        use crate::tls_fingerprint::{MobileBrowserProfile, TlsFingerprintManager};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let routed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recording = |name: &'static str| -> ProtocolHandler {
+            let routed = routed.clone();
+            Box::new(move |_| {
+                routed.lock().unwrap().push(name);
+                Box::pin(async { Ok(()) })
+            })
+        };
+        let handlers = ProtocolHandlers { fallback: Some(recording("tls")), ..test_handlers() };
+
+        // Longer than the detection window, and sent in two segments
+        let hello = TlsFingerprintManager::sticky(MobileBrowserProfile::Safari17).generate_client_hello("example.com").unwrap();
+        let long = pad_client_hello(&hello, 2 * MAX_DETECTION_BYTES);
+        let blocked = ja3_from_client_hello(&long).unwrap();
+        let config = UnifiedPortConfig { ja3_blocklist: HashSet::from([blocked]), ..Default::default() };
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let server = tokio::spawn(async move { handle_connection_with(stream, &handlers, &config).await.map(|_| (handlers, config)) });
+        client.write_all(&long[..MAX_DETECTION_BYTES / 2]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        client.write_all(&long[MAX_DETECTION_BYTES / 2..]).await.unwrap();
+        let (handlers, config) = server.await.unwrap().unwrap();
+        assert!(routed.lock().unwrap().is_empty());
+
+        // A hello cut short cannot be checked, so it is not let through either
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&hello[..hello.len() - 10]).await.unwrap();
+        client.shutdown().await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        handle_connection_with(stream, &handlers, &config).await.unwrap();
+        assert!(routed.lock().unwrap().is_empty());
+
+        // The unpadded hello has another JA3 and passes
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&hello).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        handle_connection_with(stream, &handlers, &config).await.unwrap();
+        assert_eq!(*routed.lock().unwrap(), vec!["tls"]);
+    }
+
+    /// What a third-party crate would ship: a detector and handler for a
+    /// toy protocol that echoes one line back
+    struct EchoProtocol;
+
+    impl CustomProtocolDetector for EchoProtocol {
+        fn name(&self) -> &str {
+            "echo"
+        }
+
+        fn min_bytes(&self) -> usize {
+            b"ECHO ".len()
+        }
+
+        fn matches(&self, buffer: &[u8]) -> bool {
+            buffer.starts_with(b"ECHO ")
+        }
+    }
+
+    fn echo_handler() -> ProtocolHandler {
+        Box::new(|mut stream| Box::pin(async move {
+            let mut line = Vec::new();
+            let mut byte = [0u8; 1];
+            while line.last() != Some(&b'\n') {
+                stream.read_exact(&mut byte).await?;
+                line.push(byte[0]);
+            }
+            stream.write_all(&line[b"ECHO ".len()..]).await
+        }))
+    }
+
+    /// Handlers that only serve `EchoProtocol`; anything else is an error
+    fn echo_only_handlers() -> ProtocolHandlers {
+        let mut handlers = ProtocolHandlers { fallback: Some(unused_handler()), ..test_handlers() };
+        handlers.register_boxed(Box::new(EchoProtocol), echo_handler());
+        handlers
+    }
+
+    #[tokio::test]
+    async fn test_registered_custom_protocol_handles_connection() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handlers = echo_only_handlers();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_connection_with(stream, &handlers, &UnifiedPortConfig::default()).await
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"ECHO hello third party\n").await.unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"hello third party\n");
+        server.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_protocol_type_conversion() {
+        assert_eq!(ProtocolType::from(Protocol::Socks5), ProtocolType::Socks5);
+        assert_eq!(Protocol::try_from(ProtocolType::Socks5), Ok(Protocol::Socks5));
+        assert_eq!(ProtocolType::from(Protocol::Wpad), ProtocolType::Pac);
+        assert_eq!(Protocol::try_from(ProtocolType::Ssh), Err(ProtocolType::Ssh));
+        assert_eq!(ProtocolType::from(Protocol::Http), ProtocolType::Http);
+        assert_eq!(Protocol::try_from(ProtocolType::Http), Ok(Protocol::Http));
+        assert_eq!(ProtocolType::from(Protocol::Unknown), ProtocolType::Raw);
+        assert_eq!(Protocol::try_from(ProtocolType::Raw), Ok(Protocol::Unknown));
+        // The detector leaves TLS as Unknown, so there is no Protocol for it
+        assert_eq!(Protocol::try_from(ProtocolType::Tls), Err(ProtocolType::Tls));
+        for protocol in [Protocol::Http, Protocol::Socks5, Protocol::WebSocket, Protocol::WebRTC,
+                         Protocol::Pac, Protocol::Bonjour, Protocol::Upnp, Protocol::H2c, Protocol::Unknown] {
+            assert_eq!(Protocol::try_from(ProtocolType::from(protocol)), Ok(protocol));
+        }
+    }
+
     #[tokio::test]
     async fn test_detect_http_get() {
         let data = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
@@ -353,6 +1592,81 @@ mod tests {
         assert_eq!(buffer, data.to_vec());
     }
 
+    #[tokio::test]
+    async fn test_detect_tls_header_split_across_reads() {
+        let hello = crate::tls_fingerprint::TlsFingerprintManager::sticky(
+            crate::tls_fingerprint::MobileBrowserProfile::Safari17,
+        )
+        .generate_client_hello("split.example")
+        .unwrap();
+        // The kernel hands over two bytes of the record header, then the rest in pieces
+        let mut stream = tokio_test::io::Builder::new()
+            .read(&hello[..2])
+            .read(&hello[2..4])
+            .read(&hello[4..40])
+            .read(&hello[40..])
+            .build();
+
+        let (protocol, buffer) = detect_protocol(&mut stream).await.unwrap();
+        assert_eq!(protocol, Protocol::Unknown);
+        assert_eq!(buffer, hello);
+        let summary = summarize_client_hello(&buffer).expect("TLS ClientHello not recognised");
+        assert_eq!(summary.sni.as_deref(), Some("split.example"));
+
+        // Windowed detection completes the record the same way
+        let mut stream = tokio_test::io::Builder::new().read(&hello[..1]).read(&hello[1..]).build();
+        let (_, buffer) = detect_protocol_windowed(&mut stream, &echo_only_handlers()).await.unwrap();
+        assert_eq!(buffer, hello);
+    }
+
+    #[tokio::test]
+    async fn test_windowed_detection_reads_only_what_protocol_needs() {
+        let handlers = echo_only_handlers();
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let (protocol, buffer) = detect_protocol_windowed(&mut server, &handlers).await.unwrap();
+        assert_eq!(protocol, Protocol::Socks5);
+        assert_eq!(buffer, [0x05, 0x01]);
+        // The method byte is left for the SOCKS5 handler
+        let mut rest = [0u8; 1];
+        server.read_exact(&mut rest).await.unwrap();
+        assert_eq!(rest, [0x00]);
+
+        // Unknown to the built-ins: read on to the custom detector's minimum only
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(b"ECHO hello third party\n").await.unwrap();
+        let (protocol, buffer) = detect_protocol_windowed(&mut server, &handlers).await.unwrap();
+        assert_eq!(protocol, Protocol::Unknown);
+        assert_eq!(buffer, b"ECHO hello t");
+    }
+
+    #[test]
+    fn test_http2_route_splits_tls_h2_from_h2c() {
+        let detector = ProtocolDetector::new();
+        let mut manager = crate::tls_fingerprint::TlsFingerprintManager::sticky(
+            crate::tls_fingerprint::MobileBrowserProfile::Chrome120Mobile,
+        );
+        let hello = manager.generate_client_hello("h2.example").unwrap();
+        assert_eq!(detector.detect(&hello), Protocol::Unknown);
+        match detector.http2_route(&hello) {
+            Some(Http2Route::TlsRelay(summary)) => {
+                assert_eq!(summary.sni.as_deref(), Some("h2.example"));
+                assert!(summary.offers_h2());
+            }
+            other => panic!("expected TLS relay, got {:?}", other),
+        }
+
+        let mut preface = H2C_PREFACE.to_vec();
+        preface.extend_from_slice(&[0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00]); // empty SETTINGS
+        assert_eq!(detector.http2_route(&preface), Some(Http2Route::H2c));
+        assert_eq!(detector.detect(&preface), Protocol::H2c);
+        // Incremental detection waits out the preface rather than calling it unknown
+        let mut state = DetectionState::new();
+        assert_eq!(detector.feed(&mut state, &preface[..10]), None);
+        assert_eq!(detector.feed(&mut state, &preface[10..]), Some(Protocol::H2c));
+        assert_eq!(detector.http2_route(b"GET / HTTP/1.1\r\n\r\n"), None);
+    }
+
     #[tokio::test]
     async fn test_detect_socks5() {
         let data = b"\x05\x01\x00"; // SOCKS5, 1 method, no auth
@@ -376,4 +1690,18 @@ mod tests {
         
         assert_eq!(result, b"Hello, World!");
     }
+
+    #[test]
+    fn test_detection_tracer_socks5() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let detector = ProtocolDetector::new()
+            .with_tracer(Arc::new(move |event: &DetectionEvent| sink.lock().unwrap().push(event.clone())));
+
+        assert_eq!(detector.detect(b"\x05\x01\x00"), Protocol::Socks5);
+        assert_eq!(*events.lock().unwrap(), vec![
+            DetectionEvent::Checked { signature: "socks5.version", matched: true },
+            DetectionEvent::Decided(Protocol::Socks5),
+        ]);
+    }
 }
\ No newline at end of file
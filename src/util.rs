@@ -0,0 +1,206 @@
+// Small helpers shared across subsystems
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::sync::{mpsc, oneshot};
+
+/// Exponential backoff for reconnect and retry loops. The n-th delay
+/// (counting from zero) is `base * 2^n` capped at `max`, less a random share
+/// of up to `jitter` (0.0-1.0) so that clients failing together do not retry
+/// in lockstep. Deterministic mode drops the jitter.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    pub base: Duration,
+    pub max: Duration,
+    pub jitter: f64,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self { base, max, jitter: 0.0, attempt: 0 }
+    }
+
+    /// Shave up to `jitter` (clamped to 0.0-1.0) off every delay
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Delay before the next retry; each call doubles the one after it
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.base.saturating_mul(1 << self.attempt.min(16)).min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        if self.jitter <= 0.0 || crate::determinism::is_deterministic() {
+            return delay;
+        }
+        delay.mul_f64(1.0 - rand::thread_rng().gen_range(0.0..self.jitter))
+    }
+
+    /// Start again from `base`, e.g. once a connection succeeded
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Delays handed out since the last reset
+    pub fn attempts(&self) -> u32 {
+        self.attempt
+    }
+}
+
+enum WriterOp {
+    Write(Vec<u8>),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Appends to a file from a thread of its own, so relay tasks never wait on
+/// the disk. The file is opened there too. The first error is logged, then
+/// the writer is off and later writes are dropped: a full disk costs the
+/// record, never the connection.
+#[derive(Debug)]
+pub struct BackgroundWriter {
+    tx: mpsc::UnboundedSender<WriterOp>,
+    failed: Arc<AtomicBool>,
+}
+
+impl BackgroundWriter {
+    /// Start the writer thread; `open` runs on it and `label` names the
+    /// file in the log
+    pub fn spawn<F, W>(label: String, open: F) -> Self
+    where
+        F: FnOnce() -> io::Result<W> + Send + 'static,
+        W: Write,
+    {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let failed = Arc::new(AtomicBool::new(false));
+        let flag = failed.clone();
+        let fail = move |e: io::Error| {
+            log::warn!("{}: {}; no longer writing it", label, e);
+            flag.store(true, Ordering::Relaxed);
+        };
+        let spawned = std::thread::Builder::new().name("litebike-writer".to_string()).spawn(move || {
+            let mut file = match open() {
+                Ok(file) => file,
+                Err(e) => return fail(e),
+            };
+            while let Some(op) = rx.blocking_recv() {
+                let result = match op {
+                    WriterOp::Write(bytes) => file.write_all(&bytes),
+                    WriterOp::Flush(done) => {
+                        let result = file.flush();
+                        let _ = done.send(());
+                        result
+                    }
+                };
+                if let Err(e) = result {
+                    return fail(e);
+                }
+            }
+            if let Err(e) = file.flush() {
+                fail(e);
+            }
+        });
+        if let Err(e) = spawned {
+            log::warn!("writer thread failed to start: {}", e);
+            failed.store(true, Ordering::Relaxed);
+        }
+        Self { tx, failed }
+    }
+
+    /// Queue `bytes`; dropped once the writer has failed
+    pub fn write(&self, bytes: Vec<u8>) {
+        if !self.has_failed() {
+            let _ = self.tx.send(WriterOp::Write(bytes));
+        }
+    }
+
+    /// Wait until everything queued so far is on its way to the file
+    pub async fn flush(&self) {
+        let (done, wait) = oneshot::channel();
+        if !self.has_failed() && self.tx.send(WriterOp::Flush(done)).is_ok() {
+            let _ = wait.await;
+        }
+    }
+
+    pub fn has_failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_caps_resets_and_jitters() {
+        let ms = Duration::from_millis;
+        let mut backoff = Backoff::new(ms(100), ms(1000));
+        let delays: Vec<Duration> = (0..6).map(|_| backoff.next_delay()).collect();
+        assert_eq!(delays, vec![ms(100), ms(200), ms(400), ms(800), ms(1000), ms(1000)]);
+        assert_eq!(backoff.attempts(), 6);
+
+        // Far past the shift limit the delay stays at the cap
+        for _ in 0..100 {
+            backoff.next_delay();
+        }
+        assert_eq!(backoff.next_delay(), ms(1000));
+
+        backoff.reset();
+        assert_eq!(backoff.attempts(), 0);
+        assert_eq!(backoff.next_delay(), ms(100));
+
+        // Jitter only ever shortens a delay, by at most its share
+        let mut backoff = Backoff::new(ms(100), Duration::from_secs(60)).with_jitter(0.5);
+        for attempt in 0..10u32 {
+            let full = ms(100) * 2u32.pow(attempt);
+            let delay = backoff.next_delay();
+            assert!(delay <= full && delay >= full / 2, "attempt {}: {:?} outside {:?}", attempt, delay, full);
+        }
+        assert_eq!(Backoff::new(ms(1), ms(1)).with_jitter(7.0).jitter, 1.0);
+    }
+
+    /// Accepts `room` bytes, then fails like a full disk
+    struct Full {
+        room: usize,
+        written: Arc<std::sync::Mutex<Vec<u8>>>,
+    }
+
+    impl Write for Full {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf.len() > self.room {
+                return Err(io::Error::other("no space left on device"));
+            }
+            self.room -= buf.len();
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_background_writer_turns_off_after_an_error() {
+        let written = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let file = Full { room: 8, written: written.clone() };
+        let writer = BackgroundWriter::spawn("test file".to_string(), move || Ok(file));
+        writer.write(b"first".to_vec());
+        writer.flush().await;
+        assert!(!writer.has_failed());
+
+        writer.write(b"too long".to_vec());
+        writer.write(b"ok".to_vec());
+        writer.flush().await;
+        assert!(writer.has_failed());
+        assert_eq!(*written.lock().unwrap(), b"first");
+
+        let unopened = BackgroundWriter::spawn("missing".to_string(), || std::fs::File::open("/nonexistent/litebike"));
+        unopened.flush().await;
+        assert!(unopened.has_failed());
+    }
+}